@@ -0,0 +1,119 @@
+//! Building and rendering expositions with the [`ExpositionBuilder`]. Run with
+//! `RUSTFLAGS="--cfg speedtest_bench" cargo bench --bench exposition`.
+
+#[cfg(speedtest_bench)]
+mod exposition {
+    use std::time::SystemTime;
+
+    use criterion::Criterion;
+    use prometheus_speedtest::{
+        config::PingConfig,
+        ping::{PingResult, PingTarget},
+        prometheus::{ExpositionArena, ExpositionBuilder},
+        speedtest::{SpeedtestData, SpeedtestResult, SpeedtestSample, SpeedtestSummary},
+    };
+
+    /// 100 targets with 10 quantiles each
+    fn ping_results() -> (Vec<PingResult>, PingConfig) {
+        let config = PingConfig {
+            quantiles: vec![0., 0.1, 0.25, 0.5, 0.75, 0.9, 0.95, 0.99, 0.999, 1.].into(),
+            ..Default::default()
+        };
+        let results = (0..100)
+            .map(|i| {
+                let samples = (0..60).map(|it| 10. + it as f32 / 4.).collect();
+                PingResult::mock(PingTarget::Ip([10, 0, 0, i].into()), samples, &config)
+            })
+            .collect();
+        (results, config)
+    }
+
+    fn write_pings(builder: &mut ExpositionBuilder, results: &[PingResult], config: &PingConfig) {
+        for result in results {
+            result.write_prometheus(builder, config);
+        }
+    }
+
+    pub fn ping(c: &mut Criterion) {
+        let (results, config) = ping_results();
+        c.bench_function("ping exposition, 100 targets", |b| {
+            b.iter(|| {
+                let alloc = ExpositionArena::new();
+                let mut builder = ExpositionBuilder::new(&alloc);
+                write_pings(&mut builder, &results, &config);
+                builder.render().len()
+            })
+        });
+        c.bench_function("ping exposition, 100 targets, pooled arena", |b| {
+            b.iter(|| {
+                ExpositionArena::with_pooled(|alloc| {
+                    let mut builder = ExpositionBuilder::new(alloc);
+                    write_pings(&mut builder, &results, &config);
+                    builder.render().len()
+                })
+            })
+        });
+    }
+
+    pub fn speedtest(c: &mut Criterion) {
+        let config = PingConfig::default();
+        let summary = || {
+            let samples = vec![
+                SpeedtestSample {
+                    bytes: 125_000.,
+                    seconds: 0.05,
+                };
+                600
+            ];
+            let total = samples.iter().copied().sum();
+            let data = SpeedtestData {
+                samples,
+                total,
+                tcp: None,
+                url: None,
+            };
+            Some(SpeedtestSummary::digest_data(data, &config.quantiles))
+        };
+        let result = SpeedtestResult {
+            down: summary(),
+            up: summary(),
+            concurrent: false,
+            server: None,
+            interface: None,
+            protocol: None,
+            measured_at: SystemTime::now(),
+        };
+        c.bench_function("speedtest exposition", |b| {
+            b.iter(|| {
+                let alloc = ExpositionArena::new();
+                let mut builder = ExpositionBuilder::new(&alloc);
+                result.write_prometheus(&mut builder, &config.quantiles);
+                builder.render().len()
+            })
+        });
+    }
+
+    pub fn rendering(c: &mut Criterion) {
+        let (results, config) = ping_results();
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        write_pings(&mut builder, &results, &config);
+        c.bench_function("to_string, 100 targets", |b| {
+            b.iter(|| builder.to_string().len())
+        });
+        c.bench_function("render, 100 targets", |b| b.iter(|| builder.render().len()));
+    }
+}
+
+#[cfg(speedtest_bench)]
+criterion::criterion_group!(
+    benches,
+    exposition::ping,
+    exposition::speedtest,
+    exposition::rendering
+);
+#[cfg(speedtest_bench)]
+criterion::criterion_main!(benches);
+
+#[cfg(not(speedtest_bench))]
+fn main() {}
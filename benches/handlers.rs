@@ -0,0 +1,79 @@
+//! Handler hot paths: the ping exposition, the ping JSON and the speedtest
+//! digest. `PingResult::mock` is only compiled with `--cfg speedtest_bench`, run with
+//! `RUSTFLAGS="--cfg speedtest_bench" cargo bench --bench handlers`.
+
+#[cfg(speedtest_bench)]
+mod handlers {
+    use std::hint::black_box;
+
+    use criterion::Criterion;
+    use prometheus_speedtest::{
+        config::PingConfig,
+        json::JsonSchema,
+        ping::{results_to_versioned_json, PingResult, PingTarget},
+        prometheus::{ExpositionArena, ExpositionBuilder},
+        speedtest::{SpeedtestData, SpeedtestSample, SpeedtestSummary},
+    };
+
+    fn ping_results(config: &PingConfig) -> Vec<PingResult> {
+        (0..10)
+            .map(|i| {
+                let samples = (0..60).map(|it| 10. + it as f32 / 4.).collect();
+                PingResult::mock(PingTarget::Ip([10, 0, 0, i].into()), samples, config)
+            })
+            .collect()
+    }
+
+    pub fn ping(c: &mut Criterion) {
+        let config = PingConfig::default();
+        let results = ping_results(&config);
+        c.bench_function("ping exposition, 10 targets", |b| {
+            b.iter(|| {
+                let alloc = ExpositionArena::new();
+                let mut builder = ExpositionBuilder::new(&alloc);
+                for result in &results {
+                    result.write_prometheus(&mut builder, &config);
+                }
+                builder.render()
+            })
+        });
+        for schema in [JsonSchema::V1, JsonSchema::V2] {
+            c.bench_function(&format!("ping JSON {schema:?}, 10 targets"), |b| {
+                b.iter(|| {
+                    let json = results_to_versioned_json(black_box(&results), schema);
+                    serde_json::to_string_pretty(&json).unwrap()
+                })
+            });
+        }
+    }
+
+    pub fn speedtest(c: &mut Criterion) {
+        let quantiles = PingConfig::default().quantiles;
+        let samples: Vec<_> = (0..1000)
+            .map(|i| SpeedtestSample {
+                bytes: 100_000. + (i * 7919 % 1000) as f64 * 100.,
+                seconds: 0.05,
+            })
+            .collect();
+        let total = samples.iter().copied().sum();
+        c.bench_function("speedtest digest, 1000 samples", |b| {
+            b.iter(|| {
+                let data = SpeedtestData {
+                    samples: samples.clone(),
+                    total,
+                    tcp: None,
+                    url: None,
+                };
+                SpeedtestSummary::digest_data(data, &quantiles)
+            })
+        });
+    }
+}
+
+#[cfg(speedtest_bench)]
+criterion::criterion_group!(benches, handlers::ping, handlers::speedtest);
+#[cfg(speedtest_bench)]
+criterion::criterion_main!(benches);
+
+#[cfg(not(speedtest_bench))]
+fn main() {}
@@ -0,0 +1,37 @@
+//! Summarizes arbitrary samples and errors, run with
+//! `cargo +nightly fuzz run digest_data` from the repository root.
+
+#![no_main]
+
+use std::io;
+
+use libfuzzer_sys::fuzz_target;
+use prometheus_speedtest::ping::{OutlierRejection, PingErrorKind, PingSummary};
+
+const QUANTILES: [f64; 7] = [0., 0.25, 0.5, 0.75, 0.9, 0.99, 1.];
+
+fn error(byte: u8) -> PingErrorKind {
+    match byte % 7 {
+        0 => PingErrorKind::IncorrectBufferSize,
+        1 => PingErrorKind::MalformedPacket,
+        2 => PingErrorKind::IOError {
+            kind: io::ErrorKind::PermissionDenied,
+        },
+        3 => PingErrorKind::Timeout {},
+        4 => PingErrorKind::EchoRequestPacket,
+        5 => PingErrorKind::NetworkError,
+        _ => PingErrorKind::IdenticalRequests,
+    }
+}
+
+fuzz_target!(|input: (Vec<f32>, Vec<u8>)| {
+    let (samples, errors) = input;
+    let errors: Vec<_> = errors.into_iter().map(error).collect();
+    let rejection = OutlierRejection {
+        max_deviations: 3.,
+        apply_to_quantiles: true,
+    };
+    for rejection in [None, Some(&rejection)] {
+        PingSummary::digest_data(samples.clone(), errors.clone(), &QUANTILES, rejection);
+    }
+});
@@ -0,0 +1,61 @@
+//! Reads the floats of both exposition formats back with reference parsers
+//! and compares the bits, run with `cargo +nightly fuzz run go_floats` from
+//! the repository root.
+
+#![no_main]
+
+use hexf_parse::{parse_hexf32, parse_hexf64};
+use libfuzzer_sys::fuzz_target;
+use prometheus_speedtest::prometheus::SerializeGoFloat;
+
+fn go_string(float: &impl SerializeGoFloat) -> String {
+    let mut out = String::new();
+    float.serialize_go_float(&mut out).unwrap();
+    out
+}
+
+fn decimal_string(float: &impl SerializeGoFloat) -> String {
+    let mut out = String::new();
+    float.serialize_decimal_float(&mut out).unwrap();
+    out
+}
+
+/// Values that Go spells out instead of writing a number
+fn special(s: &str) -> Option<f64> {
+    match s {
+        "NaN" => Some(f64::NAN),
+        "+Inf" => Some(f64::INFINITY),
+        "-Inf" => Some(f64::NEG_INFINITY),
+        "0" => Some(0.),
+        "-0" => Some(-0.),
+        _ => None,
+    }
+}
+
+fn check(float: f64, parsed: f64, s: &str) {
+    if float.is_nan() {
+        assert!(parsed.is_nan(), "{s}");
+    } else {
+        assert_eq!(parsed.to_bits(), float.to_bits(), "{s}");
+    }
+}
+
+fuzz_target!(|input: (u64, u32)| {
+    let (bits64, bits32) = input;
+
+    let float = f64::from_bits(bits64);
+    let s = go_string(&float);
+    let parsed = special(&s).unwrap_or_else(|| parse_hexf64(&s, false).unwrap());
+    check(float, parsed, &s);
+    let s = decimal_string(&float);
+    let parsed = special(&s).unwrap_or_else(|| s.parse().unwrap());
+    check(float, parsed, &s);
+
+    let float = f32::from_bits(bits32);
+    let s = go_string(&float);
+    let parsed = special(&s).unwrap_or_else(|| parse_hexf32(&s, false).unwrap() as f64);
+    check(float as f64, parsed, &s);
+    let s = decimal_string(&float);
+    let parsed = special(&s).unwrap_or_else(|| s.parse::<f32>().unwrap() as f64);
+    check(float as f64, parsed, &s);
+});
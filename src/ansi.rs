@@ -0,0 +1,143 @@
+//! Colored tables for terminals, see [`AnsiRenderer`].
+
+use serde::Deserialize;
+
+use crate::{
+    config::ThresholdConfig,
+    ping::{results_to_table, PingResult},
+    speedtest::SpeedtestResult,
+    text::decimal,
+};
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RED: &str = "\x1b[31m";
+
+/// `color` query parameter of the human readable endpoints
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ColorChoice {
+    /// Colored unless the client asked for a specific charset
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Formats the cells of human readable tables, highlighting latency and loss
+/// by [`ThresholdConfig`]. Without color, cells are plain text.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AnsiRenderer<'a> {
+    thresholds: &'a ThresholdConfig,
+    color: bool,
+}
+
+impl<'a> AnsiRenderer<'a> {
+    pub fn new(thresholds: &'a ThresholdConfig, color: bool) -> Self {
+        Self { thresholds, color }
+    }
+
+    pub fn render_ping(&self, results: &[PingResult]) -> String {
+        results_to_table(results, self).to_string()
+    }
+
+    pub fn render_speedtest(&self, result: &SpeedtestResult) -> String {
+        result.to_table(self).to_string()
+    }
+
+    pub fn header(&self, text: &str) -> String {
+        self.paint(BOLD, text.to_owned())
+    }
+
+    pub fn error(&self, text: String) -> String {
+        self.paint(RED, text)
+    }
+
+    /// Latency in milliseconds with one decimal
+    pub fn latency(&self, ms: Option<f64>) -> String {
+        let Some(ms) = ms else {
+            return decimal(None);
+        };
+        let style = grade(
+            ms,
+            self.thresholds.latency_warn_ms,
+            self.thresholds.latency_bad_ms,
+        );
+        self.paint(style, decimal(Some(ms)))
+    }
+
+    /// Packet loss from 0 to 1, shown in percent
+    pub fn loss(&self, loss: f64) -> String {
+        let percent = loss * 100.;
+        let style = grade(
+            percent,
+            self.thresholds.loss_warn_percent,
+            self.thresholds.loss_bad_percent,
+        );
+        self.paint(style, format!("{percent:.1}%"))
+    }
+
+    fn paint(&self, style: &str, text: String) -> String {
+        if self.color {
+            format!("{style}{text}{RESET}")
+        } else {
+            text
+        }
+    }
+}
+
+fn grade(value: f64, warn: f64, bad: f64) -> &'static str {
+    if value > bad {
+        RED
+    } else if value > warn {
+        YELLOW
+    } else {
+        GREEN
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::PingConfig, ping::PingTarget};
+
+    fn results() -> Vec<PingResult> {
+        let config = PingConfig {
+            quantiles: vec![0.5, 0.9].into(),
+            ..Default::default()
+        };
+        [("10.0.0.1", 12.), ("10.0.0.2", 80.), ("10.0.0.3", 400.)]
+            .into_iter()
+            .map(|(ip, ms)| {
+                let target = PingTarget::Ip(ip.parse().unwrap());
+                PingResult::mock(target, vec![ms; 4], &config)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ping_is_graded_by_thresholds() {
+        let thresholds = ThresholdConfig::default();
+        assert_eq!(
+            AnsiRenderer::new(&thresholds, true).render_ping(&results()),
+            "\x1b[1mtarget\x1b[0m    \x1b[1mmean ms\x1b[0m  \x1b[1mp50 ms\x1b[0m  \x1b[1mp90 ms\x1b[0m  \x1b[1mloss\x1b[0m\n\
+             10.0.0.1     \x1b[32m12.0\x1b[0m    \x1b[32m15.9\x1b[0m    \x1b[32m15.9\x1b[0m  \x1b[32m0.0%\x1b[0m\n\
+             10.0.0.2     \x1b[33m80.0\x1b[0m   \x1b[33m127.9\x1b[0m   \x1b[33m127.9\x1b[0m  \x1b[32m0.0%\x1b[0m\n\
+             10.0.0.3    \x1b[31m400.0\x1b[0m   \x1b[31m511.9\x1b[0m   \x1b[31m511.9\x1b[0m  \x1b[32m0.0%\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn no_color_is_plain_text() {
+        let thresholds = ThresholdConfig::default();
+        let plain = AnsiRenderer::new(&thresholds, false).render_ping(&results());
+        assert!(!plain.contains('\x1b'));
+        assert!(plain.starts_with("target    mean ms  p50 ms  p90 ms  loss\n"));
+        assert_eq!(
+            AnsiRenderer::new(&thresholds, true).loss(0.02),
+            "\x1b[33m2.0%\x1b[0m"
+        );
+    }
+}
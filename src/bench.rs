@@ -0,0 +1,182 @@
+//! `bench` subcommand, a breakdown of where the time of a download goes.
+//!
+//! DNS, the TCP connection and the TLS handshake are timed on a probe
+//! connection to the download endpoint, since the HTTP client does not expose
+//! them. The download itself is the regular measurement.
+
+use std::{
+    borrow::Cow,
+    fmt::{self, Display},
+    io,
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_native_tls::{native_tls, TlsConnector};
+use url::{Host, Url};
+
+use crate::{
+    config::Config,
+    speedtest::{http::HttpSpeedtestProvider, SpeedtestSummary, StandardSpeedtestProvider},
+    text::{decimal, Table},
+};
+
+#[derive(Debug, Error)]
+pub(crate) enum BenchError {
+    #[error("the bench subcommand requires the Http or LibreSpeed speedtest provider")]
+    UnsupportedProvider,
+    #[error("download endpoint {0} has no host")]
+    NoHost(Url),
+    #[error("connecting failed: {0}")]
+    Connect(#[from] io::Error),
+    #[error("TLS handshake failed: {0}")]
+    Tls(#[from] native_tls::Error),
+    #[error("download failed: {0}")]
+    Download(#[from] reqwest::Error),
+}
+
+/// Durations of the phases of one download
+#[derive(Debug, Clone)]
+pub(crate) struct BenchReport {
+    /// Absent if the endpoint is an IP address
+    pub dns: Option<Duration>,
+    pub connect: Duration,
+    /// Absent for plain HTTP
+    pub tls: Option<Duration>,
+    /// From sending the first request until the first byte of the body
+    pub first_byte: Option<Duration>,
+    /// Median rate of the download samples in bits per second
+    pub steady_state_bps: Option<u64>,
+    pub bytes: f64,
+    pub total: Duration,
+}
+
+/// Probes the download endpoint and downloads once.
+pub(crate) async fn run_bench(config: &Config) -> Result<BenchReport, BenchError> {
+    let mut provider = match &config.speedtest.provider {
+        StandardSpeedtestProvider::Http(provider) => Cow::Borrowed(provider),
+        StandardSpeedtestProvider::LibreSpeed(provider) => Cow::Owned(provider.to_http()),
+        // The probe connection would be TCP
+        #[cfg(feature = "quic")]
+        StandardSpeedtestProvider::Quic(_) => return Err(BenchError::UnsupportedProvider),
+        StandardSpeedtestProvider::Null(_) => return Err(BenchError::UnsupportedProvider),
+    };
+    if let Some(interface) = &config.speedtest.interface {
+        provider.to_mut().interface = Some(interface.clone());
+    }
+    let start = Instant::now();
+    let (dns, connect, tls) = probe_connection(&provider).await?;
+    let (data, first_byte) = provider.measure_download_timed().await?;
+    let bytes = data.total.bytes;
+    let steady_state_bps = (!data.samples.is_empty()).then(|| {
+        let summary = SpeedtestSummary::digest_data(data, &[0.5]);
+        summary.quantiles[0].1
+    });
+    Ok(BenchReport {
+        dns,
+        connect,
+        tls,
+        first_byte,
+        steady_state_bps,
+        bytes,
+        total: start.elapsed(),
+    })
+}
+
+async fn probe_connection(
+    provider: &HttpSpeedtestProvider,
+) -> Result<(Option<Duration>, Duration, Option<Duration>), BenchError> {
+    let url = &provider.download_endpoint;
+    let no_host = || BenchError::NoHost(url.clone());
+    let port = url.port_or_known_default().ok_or_else(no_host)?;
+    let (addr, dns) = match url.host().ok_or_else(no_host)? {
+        Host::Domain(domain) => {
+            let start = Instant::now();
+            let addr = tokio::net::lookup_host((domain, port)).await?.next();
+            let addr = addr.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+            (addr, Some(start.elapsed()))
+        }
+        Host::Ipv4(ip) => ((ip, port).into(), None),
+        Host::Ipv6(ip) => ((ip, port).into(), None),
+    };
+
+    let start = Instant::now();
+    let stream = tokio::time::timeout(provider.connect_timeout, TcpStream::connect(addr))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    let connect = start.elapsed();
+
+    if url.scheme() != "https" {
+        return Ok((dns, connect, None));
+    }
+    let mut builder = native_tls::TlsConnector::builder();
+    builder
+        .min_protocol_version(provider.min_tls_version.map(Into::into))
+        .max_protocol_version(provider.max_tls_version.map(Into::into));
+    let connector = TlsConnector::from(builder.build()?);
+    let start = Instant::now();
+    connector
+        .connect(url.host_str().unwrap_or_default(), stream)
+        .await?;
+    Ok((dns, connect, Some(start.elapsed())))
+}
+
+impl Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |duration: Option<Duration>| decimal(duration.map(|it| it.as_secs_f64() * 1e3));
+        let mut table = Table::new(vec!["phase".to_owned(), "ms".to_owned()]);
+        table.push(vec!["dns".to_owned(), ms(self.dns)]);
+        table.push(vec!["connect".to_owned(), ms(Some(self.connect))]);
+        table.push(vec!["tls handshake".to_owned(), ms(self.tls)]);
+        table.push(vec!["first byte".to_owned(), ms(self.first_byte)]);
+        table.push(vec!["total".to_owned(), ms(Some(self.total))]);
+        write!(f, "{table}")?;
+        let mbps = self.steady_state_bps.map(|bps| bps as f64 / 1e6);
+        writeln!(
+            f,
+            "\nsteady state: {} Mbit/s, {} MB downloaded",
+            decimal(mbps),
+            decimal(Some(self.bytes / 1e6))
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::speedtest::null::NullSpeedtestProvider;
+
+    #[test]
+    fn report_is_a_table() {
+        let report = BenchReport {
+            dns: None,
+            connect: Duration::from_micros(1500),
+            tls: Some(Duration::from_millis(12)),
+            first_byte: Some(Duration::from_millis(40)),
+            steady_state_bps: Some(94_300_000),
+            bytes: 117_000_000.,
+            total: Duration::from_secs(10),
+        };
+        assert_eq!(
+            report.to_string(),
+            "phase               ms\n\
+             dns                  -\n\
+             connect            1.5\n\
+             tls handshake     12.0\n\
+             first byte        40.0\n\
+             total          10000.0\n\
+             \n\
+             steady state: 94.3 Mbit/s, 117.0 MB downloaded\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn requires_http_provider() {
+        let mut config = Config::default();
+        config.speedtest.provider =
+            StandardSpeedtestProvider::Null(NullSpeedtestProvider::default());
+        let error = run_bench(&config).await.unwrap_err();
+        assert!(matches!(error, BenchError::UnsupportedProvider));
+    }
+}
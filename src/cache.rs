@@ -0,0 +1,245 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::watch;
+
+use crate::{
+    config::Config,
+    phases::PhaseRecorder,
+    ping::{dns_cache::DnsCache, perform_ping, PingResult},
+    speedtest::{perform_speedtest, server_info::ServerInfoCache, SpeedtestResult},
+};
+
+/// Result of a speedtest shared by all requests that waited for it
+pub(crate) type SharedSpeedtest = Result<Arc<SpeedtestResult>, Arc<reqwest::Error>>;
+
+/// Keeps the most recent successful measurements for `server.cache_ttl`.
+/// Without a TTL, every request runs a fresh measurement. Requests arriving
+/// while a measurement is running wait for it instead of starting another.
+#[derive(Debug)]
+pub(crate) struct MeasurementCache {
+    ttl: Option<Duration>,
+    ping: Slot<Vec<PingResult>>,
+    speedtest: Slot<SpeedtestResult>,
+    running_ping: Running<Arc<Vec<PingResult>>>,
+    running_speedtest: Running<SharedSpeedtest>,
+}
+
+impl MeasurementCache {
+    pub fn new(ttl: Option<Duration>) -> Self {
+        Self {
+            ttl,
+            ping: Slot::default(),
+            speedtest: Slot::default(),
+            running_ping: Running::default(),
+            running_speedtest: Running::default(),
+        }
+    }
+
+    /// Pings the configured targets. Only the request that starts the
+    /// measurement records its phases in `phases`.
+    pub async fn ping(
+        &self,
+        config: Arc<Config>,
+        dns: Arc<DnsCache>,
+        phases: PhaseRecorder,
+    ) -> Arc<Vec<PingResult>> {
+        if let Some(cached) = self.ping.get(self.ttl) {
+            return cached;
+        }
+        let data = (self.running_ping)
+            .join_or_start(|| async move {
+                let targets = config.ping.servers.clone();
+                Arc::new(perform_ping(config, targets, dns, phases).await)
+            })
+            .await;
+        self.ping.store(data, self.ttl)
+    }
+
+    /// Measures the speed like [`ping`](Self::ping) measures the latency.
+    pub async fn speedtest(
+        &self,
+        config: Arc<Config>,
+        server_info: Arc<ServerInfoCache>,
+        phases: PhaseRecorder,
+    ) -> SharedSpeedtest {
+        if let Some(cached) = self.speedtest.get(self.ttl) {
+            return Ok(cached);
+        }
+        let data = (self.running_speedtest)
+            .join_or_start(|| async move {
+                match perform_speedtest(config, server_info, phases).await {
+                    Ok(data) => Ok(Arc::new(data)),
+                    Err(error) => Err(Arc::new(error)),
+                }
+            })
+            .await?;
+        Ok(self.speedtest.store(data, self.ttl))
+    }
+
+    /// Removes the cached ping results, returns whether there were any.
+    pub fn clear_ping(&self) -> bool {
+        self.ping.clear()
+    }
+
+    /// Removes the cached speedtest result, returns whether there was one.
+    pub fn clear_speedtest(&self) -> bool {
+        self.speedtest.clear()
+    }
+}
+
+#[derive(Debug)]
+struct Slot<T>(Mutex<Option<(Instant, Arc<T>)>>);
+
+impl<T> Default for Slot<T> {
+    fn default() -> Self {
+        Self(Mutex::new(None))
+    }
+}
+
+impl<T> Slot<T> {
+    fn get(&self, ttl: Option<Duration>) -> Option<Arc<T>> {
+        let ttl = ttl?;
+        let slot = self.0.lock().unwrap();
+        slot.as_ref()
+            .filter(|(stored, _)| stored.elapsed() < ttl)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn store(&self, value: Arc<T>, ttl: Option<Duration>) -> Arc<T> {
+        if ttl.is_some() {
+            *self.0.lock().unwrap() = Some((Instant::now(), value.clone()));
+        }
+        value
+    }
+
+    fn clear(&self) -> bool {
+        self.0.lock().unwrap().take().is_some()
+    }
+}
+
+/// Measurement in progress, which receives its result once it finished
+#[derive(Debug)]
+struct Running<R>(Mutex<Option<watch::Receiver<Option<R>>>>);
+
+impl<R> Default for Running<R> {
+    fn default() -> Self {
+        Self(Mutex::new(None))
+    }
+}
+
+impl<R: Clone + Send + Sync + 'static> Running<R> {
+    /// Waits for the running measurement, or starts the one created by
+    /// `start` if none is running. Measurements run on their own task, so
+    /// they finish for the other requests even if the one that started them
+    /// went away.
+    async fn join_or_start<F>(&self, start: impl FnOnce() -> F) -> R
+    where
+        F: Future<Output = R> + Send + 'static,
+    {
+        let mut receiver = {
+            let mut running = self.0.lock().unwrap();
+            // A finished measurement is only replaced by the next one, as is
+            // one that panicked and dropped its sender
+            match running
+                .as_ref()
+                .filter(|receiver| receiver.borrow().is_none() && receiver.has_changed().is_ok())
+            {
+                Some(receiver) => receiver.clone(),
+                None => {
+                    let (sender, receiver) = watch::channel(None);
+                    let measurement = start();
+                    tokio::spawn(async move { sender.send_replace(Some(measurement.await)) });
+                    running.insert(receiver).clone()
+                }
+            }
+        };
+        let result = receiver.wait_for(Option::is_some).await;
+        let result = match result.map(|result| result.clone()) {
+            Ok(result) => result,
+            Err(_) => {
+                // Let the next request start a new measurement
+                let mut running = self.0.lock().unwrap();
+                if (running.as_ref()).is_some_and(|it| it.same_channel(&receiver)) {
+                    *running = None;
+                }
+                drop(running);
+                panic!("measurement panicked");
+            }
+        };
+        result.unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slot_respects_ttl_and_clear() {
+        let ttl = Some(Duration::from_secs(60));
+        let slot = Slot::default();
+        assert!(slot.get(ttl).is_none());
+
+        slot.store(Arc::new(1), ttl);
+        assert_eq!(slot.get(ttl).as_deref(), Some(&1));
+        assert!(slot.get(Some(Duration::ZERO)).is_none());
+        assert!(slot.get(None).is_none());
+
+        assert!(slot.clear());
+        assert!(!slot.clear());
+        assert!(slot.get(ttl).is_none());
+    }
+
+    #[test]
+    fn slot_without_ttl_stores_nothing() {
+        let slot = Slot::default();
+        slot.store(Arc::new(1), None);
+        assert!(!slot.clear());
+    }
+
+    #[tokio::test]
+    async fn concurrent_measurements_are_coalesced() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        let running = Arc::new(Running::default());
+        let started = Arc::new(AtomicU32::new(0));
+        let measure = |started: Arc<AtomicU32>| {
+            move || async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                started.fetch_add(1, Ordering::Relaxed) + 1
+            }
+        };
+
+        // The request that started the measurement disconnects
+        let first = tokio::spawn({
+            let (running, started) = (running.clone(), started.clone());
+            async move { running.join_or_start(measure(started)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let second = running.join_or_start(measure(started.clone()));
+        first.abort();
+
+        assert_eq!(second.await, 1);
+        assert_eq!(running.join_or_start(measure(started)).await, 2);
+    }
+
+    #[tokio::test]
+    async fn panicked_measurements_are_restarted() {
+        let running = Arc::new(Running::default());
+        let waiter = tokio::spawn({
+            let running = running.clone();
+            async move {
+                running
+                    .join_or_start(|| async { panic!("measurement failed") })
+                    .await
+            }
+        });
+        assert!(waiter.await.unwrap_err().is_panic());
+
+        assert_eq!(running.join_or_start(|| async { 1 }).await, 1);
+    }
+}
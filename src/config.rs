@@ -1,39 +1,406 @@
 use std::{
+    collections::BTreeMap,
     fs, io,
-    net::{IpAddr, Ipv4Addr},
-    path::PathBuf,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::{Deref, DerefMut, RangeInclusive},
+    path::{Path, PathBuf},
     time::Duration,
 };
 
 use clap::{Parser, Subcommand};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
 
 use crate::{
-    ping::PingTarget,
-    speedtest::{http::HttpSpeedtestProvider, StandardSpeedtestProvider},
+    ping::{
+        dns_cache::DomainResolution, netns, null::PingProvider, AddressFamily, IcmpSocketType,
+        OutlierRejection, PingSchedule, PingTarget,
+    },
+    prometheus::{MetricNaming, PNameBuf},
+    secret::Secret,
+    speedtest::{
+        http::{HttpSpeedtestProvider, Redirects, TotalTime, UploadEncoding, UploadPayload},
+        server_info::ServerLookup,
+        StandardSpeedtestProvider,
+    },
 };
 
-pub(crate) fn load_config() -> io::Result<Config> {
+/// Loads the configuration and returns it along with the subcommand to run.
+pub(crate) fn load_config() -> Result<(Config, Option<Command>), ConfigError> {
     let args = Args::parse();
 
     if let Some(Command::PrintDefaultConfig) = args.command {
-        println!("{}", toml::to_string_pretty(&Config::default()).unwrap());
+        crate::print_output(format_args!(
+            "{}\n",
+            toml::to_string_pretty(&Config::default()).unwrap()
+        ));
         std::process::exit(0);
     }
 
-    let mut config = if let Some(path) = &args.config {
-        toml::from_str(&fs::read_to_string(path)?)
-            .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?
-    } else {
-        Config::default()
-    };
+    let mut config = config_from_args(&args)?;
+    if args.quiet {
+        config.log.banner = false;
+    }
+    validate_config(&mut config)?;
+    Ok((config, args.command))
+}
+
+fn config_from_args(args: &Args) -> Result<Config, ConfigError> {
+    match &args.config {
+        Some(path) => read_config(path),
+        None if args.require_config => Err(ConfigError::Missing),
+        None => Ok(Config::default()),
+    }
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum ConfigError {
+    #[error("could not read the config file: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid config file {}: {source}", file.display())]
+    Parse {
+        source: toml::de::Error,
+        file: PathBuf,
+    },
+    #[error("invalid config value {field}: {message}")]
+    Validation { field: String, message: String },
+    #[error("no config file given, but --require-config is set")]
+    Missing,
+}
+
+fn read_config(path: &Path) -> Result<Config, ConfigError> {
+    toml::from_str(&fs::read_to_string(path)?).map_err(|source| ConfigError::Parse {
+        source,
+        file: path.to_owned(),
+    })
+}
+
+/// Checks the constraints that serde does not and fills in defaults that
+/// depend on other fields.
+fn validate_config(config: &mut Config) -> Result<(), ConfigError> {
+    validate_quantiles("ping.quantiles", &mut config.ping.quantiles)?;
+    validate_quantiles("speedtest.quantiles", &mut config.speedtest.quantiles)?;
+
+    match (config.ping.samples, config.ping.total_duration) {
+        (Some(_), Some(_)) => {
+            return Err(invalid_config(
+                "ping.total_duration",
+                "is mutually exclusive with ping.samples",
+            ))
+        }
+        (None, Some(_)) if config.ping.delay.is_zero() => {
+            return Err(invalid_config(
+                "ping.total_duration",
+                "requires a non-zero ping.delay",
+            ))
+        }
+        (None, None) => config.ping.samples = Some(DEFAULT_PING_SAMPLES),
+        _ => {}
+    }
+
+    if config.ping.dns_min_ttl > config.ping.dns_max_ttl {
+        return Err(invalid_config(
+            "ping.dns_min_ttl",
+            "must not exceed ping.dns_max_ttl",
+        ));
+    }
+
+    if let Some((first, last)) = config.ping.source_port_range {
+        if first == 0 || first > last {
+            return Err(invalid_config(
+                "ping.source_port_range",
+                "must be a non-empty range of non-zero ports",
+            ));
+        }
+    }
+
+    let protected = ["/cache", "/cache/ping", "/cache/speedtest"];
+    if let Some(path) =
+        (config.server.auth_exempt_paths.iter()).find(|path| !protected.contains(&path.as_str()))
+    {
+        return Err(invalid_config(
+            "server.auth_exempt_paths",
+            format!(
+                "{path} never requires server.auth_token, only {} do",
+                protected.join(", ")
+            ),
+        ));
+    }
+
+    match &config.speedtest.provider {
+        StandardSpeedtestProvider::Http(provider) => {
+            if let (Some(min), Some(max)) = (provider.min_tls_version, provider.max_tls_version) {
+                if min > max {
+                    return Err(invalid_config(
+                        "speedtest.provider.Http.min_tls_version",
+                        "must not exceed max_tls_version",
+                    ));
+                }
+            }
+            if let Some(window) = &provider.sample_window {
+                if window.step.is_zero() || window.step > window.width {
+                    return Err(invalid_config(
+                        "speedtest.provider.Http.sample_window.step",
+                        "must be non-zero and not exceed width",
+                    ));
+                }
+            }
+            validate_transfers("speedtest.provider.Http", provider)?;
+        }
+        StandardSpeedtestProvider::LibreSpeed(provider) => {
+            validate_transfers("speedtest.provider.LibreSpeed", &provider.to_http())?;
+        }
+        #[cfg(feature = "quic")]
+        StandardSpeedtestProvider::Quic(provider) => {
+            validate_transfers("speedtest.provider.Quic", &provider.to_http())?;
+        }
+        StandardSpeedtestProvider::Null(_) => {}
+    }
 
-    config
-        .speedtest
-        .quantiles
-        .sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+    let thresholds = &config.thresholds;
+    if thresholds.latency_warn_ms > thresholds.latency_bad_ms
+        || thresholds.loss_warn_percent > thresholds.loss_bad_percent
+    {
+        return Err(invalid_config(
+            "thresholds",
+            "must not warn above their bad values",
+        ));
+    }
+
+    validate_ping_targets(&config.ping.servers)?;
+    validate_latency_thresholds(&config.ping)?;
+    validate_static_metrics(&config.static_metrics)?;
+    validate_renames(&config.metrics.rename)
+}
+
+/// Bounds of `upload_chunk_size`, smaller chunks spend the upload on
+/// requests and larger ones are held in memory at once
+const UPLOAD_CHUNK_SIZES: RangeInclusive<usize> = 1024..=100_000_000;
+/// Bounds of `download_duration` and `upload_duration`
+const TRANSFER_DURATIONS: RangeInclusive<Duration> =
+    Duration::from_secs(1)..=Duration::from_secs(600);
+
+/// Ensures that the uploads and downloads of `provider`, configured at
+/// `field`, are of reasonable size and duration, and that uploads have data.
+fn validate_transfers(field: &str, provider: &HttpSpeedtestProvider) -> Result<(), ConfigError> {
+    if !UPLOAD_CHUNK_SIZES.contains(&provider.upload_chunk_size) {
+        return Err(invalid_config(
+            format!("{field}.upload_chunk_size"),
+            format!(
+                "must be between {} and {} bytes",
+                UPLOAD_CHUNK_SIZES.start(),
+                UPLOAD_CHUNK_SIZES.end()
+            ),
+        ));
+    }
+    if provider.upload_payload == UploadPayload::Pattern(Vec::new()) {
+        return Err(invalid_config(
+            format!("{field}.upload_payload.pattern"),
+            "must not be empty",
+        ));
+    }
+    for (name, duration) in [
+        ("download_duration", provider.download_duration),
+        ("upload_duration", provider.upload_duration),
+    ] {
+        if !TRANSFER_DURATIONS.contains(&duration) {
+            return Err(invalid_config(
+                format!("{field}.{name}"),
+                format!(
+                    "must be between {} and {}",
+                    humantime::format_duration(*TRANSFER_DURATIONS.start()),
+                    humantime::format_duration(*TRANSFER_DURATIONS.end())
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Ensures that every latency threshold is positive and belongs to a
+/// configured target.
+fn validate_latency_thresholds(config: &PingConfig) -> Result<(), ConfigError> {
+    for (target, &threshold) in &config.latency_thresholds_ms {
+        let field = || format!("ping.latency_thresholds_ms.\"{target}\"");
+        if !(threshold.is_finite() && threshold > 0.) {
+            return Err(invalid_config(field(), "must be a positive number"));
+        }
+        if !config.servers.iter().any(|it| it.to_string() == *target) {
+            return Err(invalid_config(field(), "is not one of ping.servers"));
+        }
+    }
+    Ok(())
+}
 
-    Ok(config)
+/// Ensures that namespaced targets are supported and not nested.
+fn validate_ping_targets(targets: &[PingTarget]) -> Result<(), ConfigError> {
+    for target in targets {
+        let PingTarget::Namespaced { netns, target } = target else {
+            continue;
+        };
+        if !netns::SUPPORTED {
+            return Err(invalid_config(
+                "ping.servers",
+                "targets with a netns require Linux and the netns feature",
+            ));
+        }
+        netns::validate_name(netns).map_err(|error| invalid_config("ping.servers", error))?;
+        if matches!(**target, PingTarget::Namespaced { .. }) {
+            return Err(invalid_config(
+                "ping.servers",
+                format!("target in netns {netns} must not be namespaced again"),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Prefixes of the metrics written by the exporter itself
+const RESERVED_METRIC_PREFIXES: &[&str] = &[
+    "ping_",
+    "packet_loss",
+    "network_speed_",
+    "speedtest_",
+    "measurement_error",
+    "exporter_",
+];
+
+/// Ensures that static metrics are non-empty and collide with no real metric.
+fn validate_static_metrics(metrics: &BTreeMap<PNameBuf, f64>) -> Result<(), ConfigError> {
+    for name in metrics.keys() {
+        if name.is_empty() {
+            return Err(invalid_config("static_metrics", "contains an empty name"));
+        }
+        if RESERVED_METRIC_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+        {
+            return Err(invalid_config(
+                format!("static_metrics.{}", &**name),
+                "collides with a metric of the exporter",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Ensures that no metric is renamed to an empty name or to the same name as
+/// another one, whose lines would end up below the same header.
+fn validate_renames(renames: &BTreeMap<PNameBuf, PNameBuf>) -> Result<(), ConfigError> {
+    let mut targets = BTreeMap::new();
+    for (name, renamed) in renames {
+        if renamed.is_empty() {
+            return Err(invalid_config(
+                format!("metrics.rename.{}", &**name),
+                "is empty",
+            ));
+        }
+        if let Some(other) = targets.insert(renamed, name) {
+            return Err(invalid_config(
+                format!("metrics.rename.{}", &**name),
+                format!(
+                    "has the same target {} as metrics.rename.{}",
+                    &**renamed, &***other
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Sorts the quantiles and ensures that they are finite and unique.
+fn validate_quantiles(field: &str, quantiles: &mut [f64]) -> Result<(), ConfigError> {
+    if let Some(q) = quantiles.iter().find(|q| !q.is_finite()) {
+        return Err(invalid_config(
+            field,
+            format!("contains non-finite value {q}"),
+        ));
+    }
+    quantiles.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+    if let Some(pair) = quantiles.windows(2).find(|pair| pair[0] == pair[1]) {
+        return Err(invalid_config(
+            field,
+            format!("contains duplicate value {}", pair[0]),
+        ));
+    }
+    Ok(())
+}
+
+/// Quantiles to compute, configured as a list such as `[0.5, 0.99]` or as
+/// names mapped to values such as `{ p50 = 0.5, p99 = 0.99 }`. Names are
+/// exposed as an additional `name` label.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Quantiles {
+    values: Vec<f64>,
+    names: Vec<(f64, String)>,
+}
+
+impl Quantiles {
+    pub fn name(&self, quantile: f64) -> Option<&str> {
+        self.names
+            .iter()
+            .find(|(q, _)| *q == quantile)
+            .map(|(_, name)| name.as_str())
+    }
+}
+
+impl From<Vec<f64>> for Quantiles {
+    fn from(values: Vec<f64>) -> Self {
+        Self {
+            values,
+            names: Vec::new(),
+        }
+    }
+}
+
+impl Deref for Quantiles {
+    type Target = Vec<f64>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.values
+    }
+}
+
+impl DerefMut for Quantiles {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.values
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum QuantilesRepr {
+    List(Vec<f64>),
+    Named(BTreeMap<String, f64>),
+}
+
+impl Serialize for Quantiles {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.names.is_empty() {
+            return self.values.serialize(serializer);
+        }
+        let named = self.names.iter().map(|(q, name)| (name.clone(), *q));
+        QuantilesRepr::Named(named.collect()).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Quantiles {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match QuantilesRepr::deserialize(deserializer)? {
+            QuantilesRepr::List(values) => values.into(),
+            QuantilesRepr::Named(named) => Self {
+                values: named.values().copied().collect(),
+                names: named.into_iter().map(|(name, q)| (q, name)).collect(),
+            },
+        })
+    }
+}
+
+fn invalid_config(field: impl Into<String>, message: impl Into<String>) -> ConfigError {
+    ConfigError::Validation {
+        field: field.into(),
+        message: message.into(),
+    }
 }
 
 #[derive(Parser)]
@@ -42,6 +409,13 @@ pub(crate) struct Args {
     #[arg(short, long)]
     /// Path to the configuration file
     pub config: Option<PathBuf>,
+    #[arg(long)]
+    /// Refuses to start with the default configuration if no `--config` is
+    /// given
+    pub require_config: bool,
+    #[arg(short, long)]
+    /// Omits the startup banner from the log, like `log.banner = false`
+    pub quiet: bool,
     #[command(subcommand)]
     pub command: Option<Command>,
 }
@@ -50,21 +424,86 @@ pub(crate) struct Args {
 pub(crate) enum Command {
     /// Prints the default configuration file and exits
     PrintDefaultConfig,
+    /// Runs a tiny ping and download measurement, exits with a non-zero
+    /// status if it failed
+    Selftest,
+    /// Downloads once and prints how long DNS, connecting, the TLS
+    /// handshake and the first byte took
+    Bench,
+    /// Periodically measures and writes the exposition to a file, e.g. for
+    /// the textfile collector of node_exporter, instead of serving it
+    Textfile {
+        /// File to write, replaced atomically on every measurement
+        path: PathBuf,
+        /// Time between the starts of two measurements
+        #[arg(long, default_value = "5m", value_parser = humantime::parse_duration)]
+        interval: Duration,
+        /// Randomly shortens or lengthens each interval by up to this fraction
+        /// of it, so that exporters with the same interval spread out
+        #[arg(long, default_value_t = 0., value_parser = parse_fraction)]
+        jitter: f64,
+    },
+}
+
+fn parse_fraction(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(fraction) if (0. ..=1.).contains(&fraction) => Ok(fraction),
+        Ok(_) => Err("must be between 0 and 1".to_owned()),
+        Err(error) => Err(error.to_string()),
+    }
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, default)]
-pub(crate) struct Config {
+pub struct Config {
     pub server: ServerConfig,
     pub ping: PingConfig,
     pub speedtest: SpeedtestConfig,
+    pub json: JsonConfig,
+    pub thresholds: ThresholdConfig,
+    pub metrics: MetricsConfig,
+    pub log: LogConfig,
+    /// Constant gauges emitted alongside the measurements, e.g. the line rate
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub static_metrics: BTreeMap<PNameBuf, f64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, default)]
-pub(crate) struct ServerConfig {
+pub struct ServerConfig {
+    /// The unspecified IPv4 address listens on the unspecified IPv6 address
+    /// as well
     pub address: IpAddr,
     pub port: u16,
+    /// Addresses to listen on instead of `address` and `port`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub listen: Vec<SocketAddr>,
+    /// Exposes additional data for debugging, such as individual pings
+    pub debug_endpoints: bool,
+    /// Successful measurements are reused for this long, disabled if absent
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub cache_ttl: Option<Duration>,
+    /// Bearer token required by the `DELETE /cache` endpoints
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<Secret<String>>,
+    /// `DELETE /cache` endpoints that do not require `auth_token`. The
+    /// health endpoints and all other routes never require it.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub auth_exempt_paths: Vec<String>,
+    /// Directory with translations of the index page, named
+    /// `index.<language>.html`, `index.<language>.txt` and
+    /// `index.<language>.ansi.txt`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index_dir: Option<PathBuf>,
+    pub limits: LimitsConfig,
+    /// Requests taking longer are logged with a warning, but not aborted
+    #[serde(with = "humantime_serde")]
+    pub soft_deadline: Duration,
+    pub health: HealthConfig,
 }
 
 impl Default for ServerConfig {
@@ -72,19 +511,177 @@ impl Default for ServerConfig {
         Self {
             address: Ipv4Addr::UNSPECIFIED.into(),
             port: 9090,
+            listen: Vec::new(),
+            debug_endpoints: false,
+            cache_ttl: None,
+            auth_token: None,
+            auth_exempt_paths: Vec::new(),
+            index_dir: None,
+            limits: LimitsConfig::default(),
+            soft_deadline: Duration::from_secs(120),
+            health: HealthConfig::default(),
+        }
+    }
+}
+
+/// Listener serving only `/healthz` and `/readyz`, e.g. a management port
+/// that is only reachable from localhost
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct HealthConfig {
+    pub address: IpAddr,
+    /// The main listener serves the health endpoints if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            address: Ipv4Addr::LOCALHOST.into(),
+            port: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct LogConfig {
+    /// Logs the license notice, version, addresses and enabled subsystems
+    /// on startup
+    pub banner: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self { banner: true }
+    }
+}
+
+/// Limits of incoming requests, exceeding them responds with status 413 or
+/// 431, and of the expositions in responses
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct LimitsConfig {
+    /// No endpoint reads a body, so only small ones are accepted
+    pub max_body_bytes: usize,
+    /// Total size of all header names and values
+    pub max_header_bytes: usize,
+    /// Larger expositions are abandoned once they exceed it and answered
+    /// with status 500
+    pub max_exposition_bytes: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 4096,
+            max_header_bytes: 8192,
+            max_exposition_bytes: 4 * 1024 * 1024,
         }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, default)]
-pub(crate) struct PingConfig {
+pub struct PingConfig {
     pub servers: Vec<PingTarget>,
     #[serde(with = "humantime_serde")]
     pub delay: Duration,
-    pub samples: usize,
+    /// Fixed number of pings per target. Mutually exclusive with
+    /// `total_duration`; defaults to [`DEFAULT_PING_SAMPLES`] if neither is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub samples: Option<usize>,
+    /// Send as many pings as fit into this window instead of a fixed count.
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub total_duration: Option<Duration>,
     pub payload_size: usize,
-    pub quantiles: Vec<f64>,
+    /// Payload sizes that consecutive pings cycle through instead of
+    /// `payload_size`, each reported as its own `ping_ms` series
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub payload_sizes: Vec<usize>,
+    pub quantiles: Quantiles,
+    pub max_concurrent_targets: usize,
+    /// `Null` answers pings without sending them, e.g. for tests
+    pub provider: PingProvider,
+    pub socket_type: IcmpSocketType,
+    /// Inclusive range of source ports that the ICMP sockets of concurrent
+    /// pings are bound to, one port each. Datagram sockets on Linux use the
+    /// port as the ICMP identifier.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_port_range: Option<(u16, u16)>,
+    /// Maximum number of answered pings per target kept for debugging, the
+    /// slowest ones along with all lost pings
+    pub debug_sample_limit: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outlier_rejection: Option<OutlierRejection>,
+    /// Lower bound for how long resolved domain targets are cached
+    #[serde(with = "humantime_serde")]
+    pub dns_min_ttl: Duration,
+    /// Upper bound for how long resolved domain targets are cached
+    #[serde(with = "humantime_serde")]
+    pub dns_max_ttl: Duration,
+    /// Keeps pinging the first resolved address of a domain target until it
+    /// was unreachable `pin_max_failures` times in a row
+    pub pin_resolution: bool,
+    pub pin_max_failures: u32,
+    /// Resolves domain targets to IPv6 addresses if available, IPv4
+    /// addresses are preferred otherwise
+    pub ipv6_first: bool,
+    /// Only resolves domain targets to IPv6 addresses
+    pub ipv6_only: bool,
+    /// Which of the addresses of a domain target are pinged
+    pub domain_resolution: DomainResolution,
+    /// Measurements within this window make up `ping_availability_ratio`
+    #[serde(with = "humantime_serde")]
+    pub availability_window: Duration,
+    /// Latencies in milliseconds per target, in the form used by
+    /// `ping.servers`, that `ping_over_threshold` compares the mean and p99
+    /// latency to
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub latency_thresholds_ms: BTreeMap<String, f32>,
+}
+
+pub(crate) const DEFAULT_PING_SAMPLES: usize = 60;
+
+impl PingConfig {
+    pub fn schedule(&self) -> PingSchedule {
+        match self.total_duration {
+            Some(duration) => PingSchedule::Duration(duration),
+            None => PingSchedule::Samples(self.samples.unwrap_or(DEFAULT_PING_SAMPLES)),
+        }
+    }
+
+    /// Sizes of the payloads that pings cycle through
+    pub fn payloads(&self) -> &[usize] {
+        if self.payload_sizes.is_empty() {
+            std::slice::from_ref(&self.payload_size)
+        } else {
+            &self.payload_sizes
+        }
+    }
+
+    /// Configured latency threshold of `target` in milliseconds
+    pub fn latency_threshold_ms(&self, target: &PingTarget) -> Option<f32> {
+        if self.latency_thresholds_ms.is_empty() {
+            return None;
+        }
+        self.latency_thresholds_ms.get(&target.to_string()).copied()
+    }
+
+    /// Address families looked up for domain targets, in order of preference
+    pub fn address_families(&self) -> &'static [AddressFamily] {
+        use AddressFamily::*;
+        match (self.ipv6_only, self.ipv6_first) {
+            (true, _) => &[Ipv6],
+            (false, true) => &[Ipv6, Ipv4],
+            (false, false) => &[Ipv4, Ipv6],
+        }
+    }
 }
 
 impl Default for PingConfig {
@@ -97,18 +694,95 @@ impl Default for PingConfig {
                 PingTarget::Domain("google.com".to_owned()),
             ],
             delay: Duration::from_secs(1),
-            samples: 60,
+            samples: Some(DEFAULT_PING_SAMPLES),
+            total_duration: None,
             payload_size: 512,
-            quantiles: vec![0., 0.25, 0.5, 0.75, 0.9, 0.99, 1.],
+            payload_sizes: Vec::new(),
+            quantiles: vec![0., 0.25, 0.5, 0.75, 0.9, 0.99, 1.].into(),
+            max_concurrent_targets: 16,
+            provider: PingProvider::Icmp,
+            socket_type: IcmpSocketType::Auto,
+            source_port_range: None,
+            debug_sample_limit: 60,
+            outlier_rejection: None,
+            dns_min_ttl: Duration::ZERO,
+            dns_max_ttl: Duration::from_secs(60 * 60),
+            pin_resolution: false,
+            pin_max_failures: 3,
+            ipv6_first: false,
+            ipv6_only: false,
+            domain_resolution: DomainResolution::First,
+            availability_window: Duration::from_secs(5 * 60),
+            latency_thresholds_ms: BTreeMap::new(),
         }
     }
 }
 
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct JsonConfig {
+    /// Adds speedtest values in Mbit/s to JSON responses
+    pub include_mbps: bool,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct MetricsConfig {
+    /// `conventional` exposes latencies in seconds under names following the
+    /// Prometheus conventions, e.g. `ping_duration_seconds` instead of
+    /// `ping_ms`
+    pub naming: MetricNaming,
+    /// Exposes metrics under different names, e.g. `ping_ms =
+    /// "probe_rtt_ms"`, applied after `naming`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub rename: BTreeMap<PNameBuf, PNameBuf>,
+}
+
+/// Values above which ping results are highlighted in yellow or red for
+/// terminals
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, default)]
-pub(crate) struct SpeedtestConfig {
+pub struct ThresholdConfig {
+    pub latency_warn_ms: f64,
+    pub latency_bad_ms: f64,
+    pub loss_warn_percent: f64,
+    pub loss_bad_percent: f64,
+}
+
+impl Default for ThresholdConfig {
+    fn default() -> Self {
+        Self {
+            latency_warn_ms: 50.,
+            latency_bad_ms: 150.,
+            loss_warn_percent: 1.,
+            loss_bad_percent: 5.,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct SpeedtestConfig {
     pub provider: StandardSpeedtestProvider,
-    pub quantiles: Vec<f64>,
+    pub quantiles: Quantiles,
+    /// Bounds each upload or download measurement, including retries
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub provider_timeout: Option<Duration>,
+    /// Measures download and upload at the same time. This halves the
+    /// duration of a speedtest, but both directions compete for bandwidth,
+    /// which lowers the results on links where acknowledgements of one
+    /// direction are delayed by traffic in the other.
+    pub allow_concurrent_dl_ul: bool,
+    /// Labels the speedtest metrics with the AS and country of the server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_lookup: Option<ServerLookup>,
+    /// Network interface to measure on, e.g. `"eth0"`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
 }
 
 impl Default for SpeedtestConfig {
@@ -125,8 +799,316 @@ impl Default for SpeedtestConfig {
                 download_duration: Duration::from_secs(30),
                 upload_duration: Duration::from_secs(30),
                 upload_chunk_size: 1_000_000,
+                upload_content_type: HttpSpeedtestProvider::default_upload_content_type(),
+                upload_encoding: UploadEncoding::Raw,
+                upload_payload: UploadPayload::Random,
+                early_stop: None,
+                sample_window: None,
+                max_download_bytes: None,
+                total_time: TotalTime::LastChunk,
+                min_tls_version: None,
+                max_tls_version: None,
+                connect_timeout: HttpSpeedtestProvider::default_connect_timeout(),
+                read_timeout: None,
+                root_certificates: Vec::new(),
+                http3: false,
+                redirects: Redirects::Follow,
+                pool_max_idle_per_host: None,
+                pool_idle_timeout: None,
+                interface: None,
             }),
-            quantiles: vec![0., 0.25, 0.5, 0.75, 0.9, 0.99, 1.],
+            quantiles: vec![0., 0.25, 0.5, 0.75, 0.9, 0.99, 1.].into(),
+            provider_timeout: None,
+            allow_concurrent_dl_ul: false,
+            server_lookup: None,
+            interface: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_are_never_printed() {
+        let config: Config = toml::from_str("server.auth_token = \"hunter2\"").unwrap();
+        let token = config.server.auth_token.as_ref().unwrap();
+        assert_eq!(token.expose(), "hunter2");
+        for printed in [
+            format!("{config:?}"),
+            toml::to_string_pretty(&config).unwrap(),
+            serde_json::to_string(&config).unwrap(),
+        ] {
+            assert!(!printed.contains("hunter2"), "{printed}");
+            assert!(printed.contains("<redacted>"), "{printed}");
+        }
+    }
+
+    #[test]
+    fn latency_thresholds_belong_to_targets() {
+        let config = |toml| {
+            let mut config = toml::from_str::<Config>(toml).unwrap();
+            validate_config(&mut config)
+        };
+        config("ping.latency_thresholds_ms.\"8.8.8.8\" = 50").unwrap();
+        let error = config("ping.latency_thresholds_ms.\"10.0.0.1\" = 50").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid config value ping.latency_thresholds_ms.\"10.0.0.1\": \
+             is not one of ping.servers"
+        );
+        assert!(config("ping.latency_thresholds_ms.\"google.com\" = 0").is_err());
+    }
+
+    #[test]
+    fn static_metrics_must_not_collide() {
+        let metrics = toml::from_str::<Config>("static_metrics.link_capacity_bps = 1e9")
+            .unwrap()
+            .static_metrics;
+        validate_static_metrics(&metrics).unwrap();
+
+        let metrics = toml::from_str::<Config>("static_metrics.ping_ms = 1")
+            .unwrap()
+            .static_metrics;
+        let error = validate_static_metrics(&metrics).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid config value static_metrics.ping_ms: collides with a metric of the exporter"
+        );
+
+        // Self-monitoring metrics and the conventional names are reserved too
+        for name in ["exporter_dns_lookups_total", "ping_duration_seconds"] {
+            let metrics = toml::from_str::<Config>(&format!("static_metrics.{name} = 1"))
+                .unwrap()
+                .static_metrics;
+            assert!(validate_static_metrics(&metrics).is_err(), "{name}");
         }
+
+        assert!(toml::from_str::<Config>("static_metrics.Capacity = 1").is_err());
+    }
+
+    #[test]
+    fn syntax_errors_name_file_and_line() {
+        let path = std::env::temp_dir().join(format!("config-{}.toml", std::process::id()));
+        fs::write(&path, "[ping]\nsamples = \n").unwrap();
+        let error = read_config(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(&error, ConfigError::Parse { file, .. } if *file == path));
+        let message = error.to_string();
+        assert!(message.contains(&path.display().to_string()), "{message}");
+        assert!(message.contains("line 2"), "{message}");
+
+        let missing = read_config(&path).unwrap_err();
+        assert!(matches!(missing, ConfigError::Io(_)));
+    }
+
+    #[test]
+    fn validation_errors_name_the_field() {
+        let mut config =
+            toml::from_str::<Config>("ping = { samples = 10, total_duration = \"5s\" }").unwrap();
+        let error = validate_config(&mut config).unwrap_err();
+        assert!(
+            matches!(&error, ConfigError::Validation { field, .. } if field == "ping.total_duration"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn source_port_ranges_must_not_be_empty() {
+        let validate = |range| {
+            let mut config =
+                toml::from_str::<Config>(&format!("ping.source_port_range = {range}")).unwrap();
+            validate_config(&mut config)
+        };
+        validate("[41000, 41009]").unwrap();
+        validate("[41000, 41000]").unwrap();
+        validate("[41009, 41000]").unwrap_err();
+        validate("[0, 10]").unwrap_err();
+    }
+
+    #[test]
+    fn transfers_must_be_reasonable() {
+        let validate = |provider: &str, key: &str, value: toml::Value| {
+            let mut config = Config::default();
+            if provider == "LibreSpeed" {
+                config.speedtest.provider = toml::from_str(
+                    "LibreSpeed = { server = \"https://speed.example.com/backend/\" }",
+                )
+                .unwrap();
+            }
+            let mut table = toml::Table::try_from(&config).unwrap();
+            let provider_table = table["speedtest"]["provider"][provider].as_table_mut();
+            provider_table.unwrap().insert(key.to_owned(), value);
+            let mut config: Config = table.try_into().unwrap();
+            validate_config(&mut config).map_err(|error| match error {
+                ConfigError::Validation { field, .. } => field,
+                error => panic!("{error}"),
+            })
+        };
+        for provider in ["Http", "LibreSpeed"] {
+            validate(provider, "upload_chunk_size", 1024.into()).unwrap();
+            validate(provider, "upload_chunk_size", 100_000_000.into()).unwrap();
+            validate(provider, "download_duration", "1s".into()).unwrap();
+            validate(provider, "upload_duration", "10m".into()).unwrap();
+            for (key, value) in [
+                ("upload_chunk_size", 0.into()),
+                ("upload_chunk_size", 1023.into()),
+                ("upload_chunk_size", 100_000_001.into()),
+                ("download_duration", "999ms".into()),
+                ("upload_duration", "10m 1s".into()),
+            ] {
+                assert_eq!(
+                    validate(provider, key, value),
+                    Err(format!("speedtest.provider.{provider}.{key}")),
+                    "{key}"
+                );
+            }
+        }
+        let payload = |toml| toml::Value::Table(toml::from_str(toml).unwrap());
+        validate("Http", "upload_payload", payload("pattern = [1, 2]")).unwrap();
+        assert_eq!(
+            validate("Http", "upload_payload", payload("pattern = []")),
+            Err("speedtest.provider.Http.upload_payload.pattern".to_owned())
+        );
+    }
+
+    #[test]
+    fn renames_must_be_distinct() {
+        let renames = |toml| toml::from_str::<Config>(toml).map(|config| config.metrics.rename);
+        let valid = renames("metrics.rename = { ping_ms = \"probe_rtt_ms\" }").unwrap();
+        validate_renames(&valid).unwrap();
+
+        let clashing = renames(
+            "metrics.rename = { ping_ms = \"probe_rtt_ms\", ping_mean_ms = \"probe_rtt_ms\" }",
+        )
+        .unwrap();
+        assert_eq!(
+            validate_renames(&clashing).unwrap_err().to_string(),
+            concat!(
+                "invalid config value metrics.rename.ping_ms: ",
+                "has the same target probe_rtt_ms as metrics.rename.ping_mean_ms"
+            )
+        );
+        let empty = renames("metrics.rename = { ping_ms = \"\" }").unwrap();
+        assert!(validate_renames(&empty).is_err());
+        assert!(renames("metrics.rename = { ping_ms = \"probe-rtt\" }").is_err());
+    }
+
+    #[test]
+    fn namespaced_targets() {
+        let servers = toml::from_str::<Config>(
+            r#"ping.servers = ["1.1.1.1", { netns = "blue", target = "10.0.0.1" }]"#,
+        )
+        .unwrap()
+        .ping
+        .servers;
+        assert_eq!(servers[1].to_string(), "blue/10.0.0.1");
+        assert_eq!(servers[1].netns(), Some("blue"));
+        assert_eq!(servers[1].host(), &PingTarget::Ip([10, 0, 0, 1].into()));
+        assert_eq!(validate_ping_targets(&servers).is_ok(), netns::SUPPORTED);
+
+        let nested = PingTarget::Namespaced {
+            netns: "blue".to_owned(),
+            target: Box::new(servers[1].clone()),
+        };
+        assert!(validate_ping_targets(&[nested]).is_err());
+    }
+
+    #[test]
+    fn quantiles_may_be_named() {
+        let named = toml::from_str::<Config>("ping.quantiles = { p99 = 0.99, p50 = 0.5 }")
+            .unwrap()
+            .ping
+            .quantiles;
+        assert_eq!(named.name(0.99), Some("p99"));
+        assert_eq!(named.name(0.9), None);
+        assert_eq!(toml::to_string(&named).unwrap(), "p50 = 0.5\np99 = 0.99\n");
+
+        let plain = toml::from_str::<Config>("ping.quantiles = [0.5, 0.99]")
+            .unwrap()
+            .ping
+            .quantiles;
+        assert_eq!(*plain, [0.5, 0.99]);
+        assert_eq!(plain.name(0.5), None);
+    }
+
+    #[test]
+    fn only_protected_paths_can_be_exempt() {
+        let mut config: Config =
+            toml::from_str("server.auth_exempt_paths = [\"/cache/ping\"]").unwrap();
+        validate_config(&mut config).unwrap();
+
+        config.server.auth_exempt_paths.push("/healthz".to_owned());
+        let error = validate_config(&mut config).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid config value server.auth_exempt_paths: /healthz never requires \
+             server.auth_token, only /cache, /cache/ping, /cache/speedtest do"
+        );
+    }
+
+    #[test]
+    fn quantiles_are_sorted() {
+        let mut quantiles = [0.9, 0., 0.5];
+        validate_quantiles("quantiles", &mut quantiles).unwrap();
+        assert_eq!(quantiles, [0., 0.5, 0.9]);
+    }
+
+    #[test]
+    fn duplicate_quantiles_are_rejected() {
+        let mut quantiles = [0.5, 0.99, 0.5];
+        let error = validate_quantiles("quantiles", &mut quantiles).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid config value quantiles: contains duplicate value 0.5"
+        );
+    }
+
+    #[test]
+    fn non_finite_quantiles_are_rejected() {
+        for q in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let mut quantiles = [0.5, q];
+            assert!(validate_quantiles("quantiles", &mut quantiles).is_err());
+        }
+    }
+
+    #[test]
+    fn missing_config_can_be_required() {
+        let args = Args::try_parse_from(["prometheus-speedtest"]).unwrap();
+        config_from_args(&args).unwrap();
+
+        let args = Args::try_parse_from(["prometheus-speedtest", "--require-config"]).unwrap();
+        let error = config_from_args(&args).unwrap_err();
+        assert!(matches!(error, ConfigError::Missing), "{error}");
+
+        let args = [
+            "prometheus-speedtest",
+            "--require-config",
+            "print-default-config",
+        ];
+        let args = Args::try_parse_from(args).unwrap();
+        assert!(matches!(args.command, Some(Command::PrintDefaultConfig)));
+    }
+
+    #[test]
+    fn speedtest_interface_is_parsed() {
+        let config = toml::from_str::<Config>("speedtest.interface = \"eth0\"").unwrap();
+        assert_eq!(config.speedtest.interface.as_deref(), Some("eth0"));
+        let StandardSpeedtestProvider::Http(provider) = config.speedtest.provider.bound_to("eth0")
+        else {
+            panic!("the default provider is Http");
+        };
+        assert_eq!(provider.interface.as_deref(), Some("eth0"));
+
+        let config = toml::from_str::<Config>(
+            "[speedtest.provider.LibreSpeed]\nserver = \"https://speed.example.com/backend/\"",
+        )
+        .unwrap();
+        assert!(config.speedtest.interface.is_none());
+        let bound = config.speedtest.provider.bound_to("wlan0");
+        assert!(
+            matches!(bound, StandardSpeedtestProvider::Http(p) if p.interface.as_deref() == Some("wlan0"))
+        );
     }
 }
@@ -0,0 +1,64 @@
+//! `/healthz` and `/readyz` for orchestrators and load balancers, served by
+//! the main listener or a separate one configured by `[server.health]`.
+
+use axum::{routing::get, Router};
+use http::StatusCode;
+
+use crate::ping::{self, IcmpSocketType, ICMP_PRIVILEGES};
+
+/// Health routes for any router state, so that they can be merged into the
+/// main router or served on their own
+pub(crate) fn health_routes<S: Clone + Send + Sync + 'static>(
+    socket_type: IcmpSocketType,
+) -> Router<S> {
+    Router::new()
+        .route("/healthz", get(get_healthz))
+        .route("/readyz", get(move || get_readyz(socket_type)))
+}
+
+/// The exporter is running
+async fn get_healthz() -> &'static str {
+    "ok\n"
+}
+
+/// The exporter can ping, which requires privileges the container may lack
+async fn get_readyz(socket_type: IcmpSocketType) -> (StatusCode, String) {
+    match ping::check_icmp(socket_type) {
+        Ok(opened) => (
+            StatusCode::OK,
+            format!("ready, pinging from {opened} sockets\n"),
+        ),
+        Err(error) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("ICMP sockets are unavailable: {error} ({ICMP_PRIVILEGES})\n"),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn only_health_endpoints_are_served() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        let app = health_routes::<()>(IcmpSocketType::Auto);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let get = |path: &str| reqwest::get(format!("http://{addr}{path}"));
+        let healthz = get("/healthz").await.unwrap();
+        assert_eq!(healthz.status(), StatusCode::OK);
+        assert_eq!(healthz.text().await.unwrap(), "ok\n");
+        let readyz = get("/readyz").await.unwrap().status();
+        assert!(matches!(
+            readyz,
+            StatusCode::OK | StatusCode::SERVICE_UNAVAILABLE
+        ));
+        assert_eq!(get("/ping").await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,165 @@
+use std::{collections::HashMap, fs, io, path::Path};
+
+/// Language of the built-in pages
+pub(crate) const DEFAULT_LANGUAGE: &str = "en";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum PageVariant {
+    Html,
+    Text,
+    /// Plain text with colors for terminals
+    Ansi,
+}
+
+impl PageVariant {
+    /// Checked in this order, `.ansi.txt` would also match `.txt`
+    const ALL: [Self; 3] = [Self::Ansi, Self::Html, Self::Text];
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Html => "html",
+            Self::Text => "txt",
+            Self::Ansi => "ansi.txt",
+        }
+    }
+
+    fn builtin(self) -> &'static str {
+        match self {
+            Self::Html => include_str!("public/index.html"),
+            Self::Text => include_str!("public/index.txt"),
+            Self::Ansi => include_str!("public/index.ansi.txt"),
+        }
+    }
+}
+
+/// Translations of the index page, loaded from files named
+/// `index.<language>.<html|txt|ansi.txt>` in `server.index_dir`.
+#[derive(Debug, Default)]
+pub(crate) struct IndexPages {
+    /// Keyed by lowercase language tag
+    translations: HashMap<(String, PageVariant), String>,
+}
+
+impl IndexPages {
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let mut translations = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let Some(rest) = file_name.to_str().and_then(|it| it.strip_prefix("index.")) else {
+                continue;
+            };
+            let Some((language, variant)) = PageVariant::ALL.into_iter().find_map(|variant| {
+                let language = rest
+                    .strip_suffix(variant.extension())?
+                    .strip_suffix('.')
+                    .filter(|it| is_language_tag(it))?;
+                Some((language.to_ascii_lowercase(), variant))
+            }) else {
+                continue;
+            };
+            translations.insert((language, variant), fs::read_to_string(entry.path())?);
+        }
+        Ok(Self { translations })
+    }
+
+    /// Returns the language and content of the page that best matches an
+    /// `Accept-Language` header, falling back to [`DEFAULT_LANGUAGE`].
+    pub fn negotiate<'a>(
+        &'a self,
+        accept_language: Option<&str>,
+        variant: PageVariant,
+    ) -> (&'a str, &'a str) {
+        let ranges = accept_language
+            .map(parse_accept_language)
+            .unwrap_or_default();
+        for range in &ranges {
+            // RFC 4647 lookup: `de-at` also matches `de`
+            let mut range = range.as_str();
+            loop {
+                if range == "*" || range == DEFAULT_LANGUAGE {
+                    return (DEFAULT_LANGUAGE, variant.builtin());
+                }
+                if let Some(((language, _), page)) = self
+                    .translations
+                    .get_key_value(&(range.to_owned(), variant))
+                {
+                    return (language, page);
+                }
+                match range.rsplit_once('-') {
+                    Some((prefix, _)) => range = prefix,
+                    None => break,
+                }
+            }
+        }
+        (DEFAULT_LANGUAGE, variant.builtin())
+    }
+}
+
+fn is_language_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag
+            .split('-')
+            .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_alphanumeric()))
+}
+
+/// Lowercase language ranges ordered by descending quality, excluding `q=0`
+fn parse_accept_language(header: &str) -> Vec<String> {
+    let mut ranges: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let range = params.next()?.trim();
+            if range != "*" && !is_language_tag(range) {
+                return None;
+            }
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.), |q| q.trim().parse::<f32>().ok())?;
+            (quality > 0.).then(|| (range.to_ascii_lowercase(), quality))
+        })
+        .collect();
+    // Stable, so equally preferred ranges keep their order
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranges.into_iter().map(|(range, _)| range).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pages() -> IndexPages {
+        let mut translations = HashMap::new();
+        for language in ["de", "pt-br"] {
+            translations.insert(
+                (language.to_owned(), PageVariant::Html),
+                language.to_owned(),
+            );
+        }
+        IndexPages { translations }
+    }
+
+    #[test]
+    fn accept_language_is_ordered_by_quality() {
+        assert_eq!(
+            parse_accept_language("fr;q=0.5, de-AT, en;q=0.8, it;q=0, *;q=0.1"),
+            ["de-at", "en", "fr", "*"]
+        );
+    }
+
+    #[test]
+    fn languages_fall_back_to_prefix_and_default() {
+        let pages = pages();
+        let language = |header| pages.negotiate(header, PageVariant::Html).0;
+        assert_eq!(language(Some("de-AT, en;q=0.5")), "de");
+        assert_eq!(language(Some("pt-BR")), "pt-br");
+        assert_eq!(language(Some("pt")), DEFAULT_LANGUAGE);
+        assert_eq!(language(Some("fr, de;q=0.9")), "de");
+        assert_eq!(language(Some("en, de")), DEFAULT_LANGUAGE);
+        assert_eq!(language(None), DEFAULT_LANGUAGE);
+        assert_eq!(
+            pages.negotiate(Some("de"), PageVariant::Text).0,
+            DEFAULT_LANGUAGE
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::prometheus::{ExpositionBuilder, MetricType, PName};
+
+/// Number of requests currently being handled per route. Only the routes
+/// given on creation are counted, so unknown paths cannot add series.
+#[derive(Debug, Default)]
+pub(crate) struct InflightRequests(BTreeMap<&'static str, AtomicUsize>);
+
+impl InflightRequests {
+    pub fn new(paths: impl IntoIterator<Item = &'static str>) -> Self {
+        Self(
+            paths
+                .into_iter()
+                .map(|path| (path, AtomicUsize::new(0)))
+                .collect(),
+        )
+    }
+
+    /// Counts a request to `path` until the guard is dropped, e.g. when the
+    /// response was sent or the client went away.
+    pub fn start(&self, path: &str) -> Option<InflightGuard<'_>> {
+        let count = self.0.get(path)?;
+        count.fetch_add(1, Ordering::Relaxed);
+        Some(InflightGuard(count))
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, path: &str) -> Option<usize> {
+        self.0.get(path).map(|count| count.load(Ordering::Relaxed))
+    }
+
+    pub fn write_prometheus(&self, builder: &mut ExpositionBuilder) {
+        builder.add_metric(
+            PName::new("exporter_inflight_requests").unwrap(),
+            MetricType::Gauge,
+            "number of requests currently being handled",
+            |mut builder| {
+                for (path, count) in &self.0 {
+                    let count = count.load(Ordering::Relaxed);
+                    builder.add_line_labeled(PName::new("path").unwrap(), *path, &count, None);
+                }
+            },
+        );
+    }
+}
+
+pub(crate) struct InflightGuard<'a>(&'a AtomicUsize);
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_known_paths_are_counted() {
+        let inflight = InflightRequests::new(["/ping"]);
+        let guard = inflight.start("/ping");
+        assert!(inflight.start("/unknown").is_none());
+        assert_eq!(inflight.get("/ping"), Some(1));
+        drop(guard);
+        assert_eq!(inflight.get("/ping"), Some(0));
+    }
+}
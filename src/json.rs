@@ -0,0 +1,81 @@
+use std::time::SystemTime;
+
+use serde::{ser::SerializeMap, Serialize, Serializer};
+
+/// Shape of JSON responses. Schema 1 is the default and must stay stable,
+/// schema 2 is selected by `?schema=2` or [`APPLICATION_VND_SPEEDTEST_V2_JSON`].
+///
+/// [`APPLICATION_VND_SPEEDTEST_V2_JSON`]: crate::APPLICATION_VND_SPEEDTEST_V2_JSON
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum JsonSchema {
+    /// Quantiles as `[quantile, value]` pairs
+    #[default]
+    V1,
+    /// Quantiles as an object keyed by quantile, with RFC 3339 timestamps
+    V2,
+}
+
+impl JsonSchema {
+    pub fn from_version(version: u8) -> Result<Self, String> {
+        match version {
+            1 => Ok(Self::V1),
+            2 => Ok(Self::V2),
+            _ => Err(format!("unknown JSON schema {version}, expected 1 or 2")),
+        }
+    }
+}
+
+/// Result of serializing a value in one of the [`JsonSchema`]s
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Versioned<V1, V2> {
+    V1(V1),
+    V2(V2),
+}
+
+/// Serializes `[(quantile, value)]` pairs as `{"quantile": value}`
+pub(crate) fn serialize_quantile_map<T: Serialize, S: Serializer>(
+    quantiles: &[(f64, T)],
+    ser: S,
+) -> Result<S::Ok, S::Error> {
+    let mut map = ser.serialize_map(Some(quantiles.len()))?;
+    for (quantile, value) in quantiles {
+        map.serialize_entry(&quantile.to_string(), value)?;
+    }
+    map.end()
+}
+
+/// Serializes a timestamp in RFC 3339 format, e.g. `2024-04-01T12:00:00.000Z`
+pub(crate) fn serialize_timestamp<S: Serializer>(
+    time: &SystemTime,
+    ser: S,
+) -> Result<S::Ok, S::Error> {
+    ser.collect_str(&humantime::format_rfc3339_millis(*time))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Shape {
+        #[serde(serialize_with = "serialize_quantile_map")]
+        quantiles: Vec<(f64, u64)>,
+        #[serde(serialize_with = "serialize_timestamp")]
+        at: SystemTime,
+    }
+
+    #[test]
+    fn quantiles_and_timestamps() {
+        let shape = Shape {
+            quantiles: vec![(0.5, 12), (0.99, 20)],
+            at: UNIX_EPOCH + Duration::from_millis(1_500),
+        };
+        assert_eq!(
+            serde_json::to_string(&shape).unwrap(),
+            r#"{"quantiles":{"0.5":12,"0.99":20},"at":"1970-01-01T00:00:01.500Z"}"#
+        );
+    }
+}
@@ -0,0 +1,2043 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    future::IntoFuture,
+    io::{self, Write},
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Query, Request, State},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+    Extension, RequestExt, Router,
+};
+use config::{load_config, Command, Config, MetricsConfig};
+use hickory_resolver::TokioAsyncResolver;
+use http::{header, HeaderMap, HeaderValue, StatusCode};
+use lazy_static::lazy_static;
+use mime::{
+    Mime, APPLICATION, APPLICATION_JSON, HTML, JSON, PLAIN, TEXT, TEXT_HTML, TEXT_PLAIN,
+    TEXT_PLAIN_UTF_8,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{net::TcpListener, task::JoinSet};
+use tracing::{info, info_span, warn, Instrument, Level};
+
+use crate::{
+    ansi::{AnsiRenderer, ColorChoice},
+    cache::{MeasurementCache, SharedSpeedtest},
+    health::health_routes,
+    index::{IndexPages, PageVariant},
+    inflight::InflightRequests,
+    json::JsonSchema,
+    phases::{PhaseRecorder, ScrapePhases},
+    ping::{
+        availability::AvailabilityHistory, dns_cache::DnsCache, perform_ping,
+        reply_order::ReplyOrderTotals, results_to_versioned_json, PingResult, PingTarget,
+    },
+    process::write_process_metrics,
+    prometheus::{ExpositionArena, ExpositionBuilder, ExpositionFormat, MetricType, PName},
+    selftest::{run_selftest, SelftestLimiter},
+    speedtest::server_info::ServerInfoCache,
+    traffic::{ExpositionSizes, ResponseBytes},
+};
+
+pub mod ansi;
+pub mod bench;
+pub mod cache;
+pub mod config;
+pub mod health;
+pub mod index;
+pub mod inflight;
+pub mod json;
+pub mod listen;
+pub mod phases;
+pub mod ping;
+pub mod process;
+pub mod prometheus;
+pub mod secret;
+pub mod selftest;
+pub mod speedtest;
+#[cfg(feature = "embedded")]
+pub mod stderr_log;
+pub mod text;
+pub mod textfile;
+pub mod traffic;
+
+lazy_static! {
+    static ref TEXT_PLAIN_UTF_8_VERSION_4: Mime =
+        "text/plain; version=0.0.4; charset=utf-8".parse().unwrap();
+    static ref APPLICATION_OPENMETRICS: Mime = "application/openmetrics-text".parse().unwrap();
+    /// JSON in [`JsonSchema::V2`]
+    pub(crate) static ref APPLICATION_VND_SPEEDTEST_V2_JSON: Mime =
+        "application/vnd.speedtest.v2+json".parse().unwrap();
+    static ref APPLICATION_OPENMETRICS_VERSION_1: Mime =
+        "application/openmetrics-text; version=1.0.0; charset=utf-8"
+            .parse()
+            .unwrap();
+}
+
+pub type Resolver = TokioAsyncResolver;
+
+#[cfg(not(any(feature = "embedded", feature = "multi-thread")))]
+compile_error!("enable either the default `multi-thread` feature or `embedded`");
+
+/// Runs the command given on the command line, by default the exporter
+pub async fn run() -> Result<(), Box<dyn Error>> {
+    let (config, command) = match load_config() {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            eprintln!("{error}");
+            std::process::exit(1);
+        }
+    };
+    if let Some(Command::Selftest) = command {
+        ping::preflight(config.ping.socket_type);
+        let report = run_selftest(&config, Arc::default()).await;
+        print_output(format_args!(
+            "{}\n",
+            serde_json::to_string_pretty(&report).unwrap()
+        ));
+        std::process::exit(if report.passed { 0 } else { 1 });
+    }
+    if let Some(Command::Bench) = command {
+        match bench::run_bench(&config).await {
+            Ok(report) => print_output(report),
+            Err(error) => {
+                eprintln!("{error}");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    #[cfg(feature = "embedded")]
+    tracing::subscriber::set_global_default(stderr_log::StderrSubscriber::new(Level::INFO))
+        .expect("setting default subscriber failed");
+    #[cfg(not(feature = "embedded"))]
+    {
+        let subscriber = tracing_subscriber::FmtSubscriber::builder()
+            // all spans/events with a level higher than TRACE (e.g, debug, info, warn, etc.)
+            // will be written to stdout.
+            .with_max_level(Level::INFO)
+            // completes the builder.
+            .finish();
+        tracing::subscriber::set_global_default(subscriber)
+            .expect("setting default subscriber failed");
+    }
+
+    ping::preflight(config.ping.socket_type);
+
+    if let Some(Command::Textfile {
+        path,
+        interval,
+        jitter,
+    }) = command
+    {
+        log_banner(&config, &[]);
+        textfile::run_textfile(Arc::new(config), &path, interval, jitter).await?;
+        return Ok(());
+    }
+
+    let health = &config.server.health;
+    let health_bind_to = health.port.map(|port| (health.address, port));
+    let socket_type = config.ping.socket_type;
+
+    let config = Arc::new(config);
+    let app = create_router(config.clone())?;
+
+    let listeners = listen::bind_all(&config.server)?;
+    let mut addresses = listeners
+        .iter()
+        .map(TcpListener::local_addr)
+        .collect::<io::Result<Vec<_>>>()?;
+    let health_listener = match health_bind_to {
+        Some(health_bind_to) => {
+            let listener = TcpListener::bind(health_bind_to).await?;
+            info!(address = %listener.local_addr()?, "Listening for health checks");
+            addresses.push(listener.local_addr()?);
+            Some(listener)
+        }
+        None => None,
+    };
+    log_banner(&config, &addresses);
+
+    let mut servers = JoinSet::new();
+    for listener in listeners {
+        let app = app
+            .clone()
+            .into_make_service_with_connect_info::<SocketAddr>();
+        servers.spawn(axum::serve(listener, app).into_future());
+    }
+    if let Some(listener) = health_listener {
+        servers.spawn(axum::serve(listener, health_routes::<()>(socket_type)).into_future());
+    }
+    while let Some(served) = servers.join_next().await {
+        served??;
+    }
+    Ok(())
+}
+
+/// Prints the output of a one-shot subcommand. A reader that closed the pipe
+/// early, such as `head -1`, got what it wanted, so the process exits
+/// successfully.
+pub(crate) fn print_output(output: impl Display) {
+    let mut stdout = io::stdout().lock();
+    if let Err(error) = write!(stdout, "{output}").and_then(|()| stdout.flush()) {
+        if error.kind() == io::ErrorKind::BrokenPipe {
+            std::process::exit(0);
+        }
+        eprintln!("could not write the output: {error}");
+        std::process::exit(1);
+    }
+}
+
+/// Logs the license notice and what the process is about to do, unless
+/// disabled by `log.banner` or `--quiet`.
+fn log_banner(config: &Config, addresses: &[SocketAddr]) {
+    if !config.log.banner {
+        return;
+    }
+    for line in include_str!("startup-notice.txt").lines() {
+        info!("{line}");
+    }
+    let addresses = addresses
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    info!(
+        version = env!("CARGO_PKG_VERSION"),
+        addresses = addresses.join(", "),
+        subsystems = enabled_subsystems(config).join(", "),
+        "Starting"
+    );
+}
+
+/// Names of the optional parts of the exporter that the config enables
+fn enabled_subsystems(config: &Config) -> Vec<&'static str> {
+    [
+        ("ping", !config.ping.servers.is_empty()),
+        ("speedtest", config.speedtest.provider.endpoint().is_some()),
+        ("cache", config.server.cache_ttl.is_some()),
+        ("auth", config.server.auth_token.is_some()),
+        ("health", config.server.health.port.is_some()),
+        ("debug_endpoints", config.server.debug_endpoints),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect()
+}
+
+/// State shared by all handlers
+pub(crate) struct AppState {
+    pub config: Arc<Config>,
+    pub cache: MeasurementCache,
+    pub dns: Arc<DnsCache>,
+    pub server_info: Arc<ServerInfoCache>,
+    pub scrape_phases: ScrapePhases,
+    pub index: IndexPages,
+    pub selftest: SelftestLimiter,
+    pub inflight: InflightRequests,
+    pub availability: AvailabilityHistory,
+    pub reply_order: ReplyOrderTotals,
+    pub response_bytes: ResponseBytes,
+    pub exposition_sizes: ExpositionSizes,
+}
+
+/// Paths of all routes, requests to them are counted by [`InflightRequests`]
+const ROUTES: [&str; 8] = [
+    "/",
+    "/ping",
+    "/speedtest",
+    "/metrics",
+    "/selftest",
+    "/cache",
+    "/cache/ping",
+    "/cache/speedtest",
+];
+
+impl AppState {
+    pub fn new(config: Arc<Config>) -> io::Result<Self> {
+        let index = match &config.server.index_dir {
+            Some(dir) => IndexPages::load(dir)?,
+            None => IndexPages::default(),
+        };
+        Ok(Self {
+            cache: MeasurementCache::new(config.server.cache_ttl),
+            dns: Arc::default(),
+            server_info: Arc::default(),
+            scrape_phases: ScrapePhases::default(),
+            index,
+            selftest: SelftestLimiter::default(),
+            inflight: InflightRequests::new(ROUTES),
+            availability: AvailabilityHistory::default(),
+            reply_order: ReplyOrderTotals::default(),
+            response_bytes: ResponseBytes::new(ROUTES),
+            exposition_sizes: ExpositionSizes::default(),
+            config,
+        })
+    }
+
+    /// Renders the exposition of `endpoint` within `server.limits` and
+    /// records its size
+    fn render_exposition(
+        &self,
+        endpoint: &'static str,
+        format: ExpositionFormat,
+        write: impl FnOnce(&mut ExpositionBuilder),
+    ) -> Result<String, ExpositionTooLarge> {
+        let limit = self.config.server.limits.max_exposition_bytes;
+        let rendered = render_limited_exposition(format, &self.config.metrics, limit, write);
+        let bytes = match &rendered {
+            Ok(exposition) => exposition.len(),
+            Err(error) => error.bytes,
+        };
+        self.exposition_sizes.record(endpoint, bytes);
+        rendered
+    }
+}
+
+/// Routes of the exporter, with the resolver of the system configuration
+pub fn create_router(config: Arc<Config>) -> io::Result<Router> {
+    create_router_with_dns(config, Arc::default())
+}
+
+/// Routes of the exporter that resolve domain targets through `dns`, e.g. a
+/// [`DnsCache::with_lookup`]
+pub fn create_router_with_dns(config: Arc<Config>, dns: Arc<DnsCache>) -> io::Result<Router> {
+    let state = Arc::new(AppState {
+        dns,
+        ..AppState::new(config)?
+    });
+    let health = match state.config.server.health.port {
+        Some(_) => Router::new(),
+        None => health_routes(state.config.ping.socket_type),
+    };
+    // Methods other than GET, HEAD and the DELETE of `/cache` are answered
+    // with 405 by the method routers
+    Ok(Router::new()
+        .route("/", get(get_index))
+        .route("/ping", get(get_ping))
+        .route("/speedtest", get(get_speedtest))
+        .route("/metrics", get(get_metrics))
+        .route("/selftest", get(get_selftest))
+        .route("/cache", delete(delete_cache))
+        .route("/cache/ping", delete(delete_cache))
+        .route("/cache/speedtest", delete(delete_cache))
+        .merge(health)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            enforce_limits,
+        ))
+        .layer(middleware::from_fn_with_state(state.clone(), log_traffic))
+        .with_state(state))
+}
+
+/// Rejects requests exceeding `server.limits` before they reach a handler
+async fn enforce_limits(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let limits = &state.config.server.limits;
+    let header_bytes: usize = (req.headers().iter())
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum();
+    if header_bytes > limits.max_header_bytes {
+        return StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE.into_response();
+    }
+    let content_length = (req.headers().get(header::CONTENT_LENGTH))
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+    if content_length.is_some_and(|length| length > limits.max_body_bytes as u64) {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    }
+
+    // Chunked bodies have no length, buffering at most the limit catches them
+    let (parts, body) = req.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, limits.max_body_bytes).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    next.run(Request::from_parts(parts, body.into())).await
+}
+
+/// Id correlating the log lines of a request, available to handlers as an
+/// extension
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RequestId(pub String);
+
+const X_REQUEST_ID: &str = "x-request-id";
+/// Longer `X-Request-Id`s are truncated
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+impl RequestId {
+    /// Takes the id from `X-Request-Id` or the trace id of a W3C
+    /// `traceparent`, otherwise generates a random one.
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let request_id = headers
+            .get(X_REQUEST_ID)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| {
+                let id = value.chars().filter(|ch| ch.is_ascii_graphic());
+                id.take(MAX_REQUEST_ID_LEN).collect::<String>()
+            })
+            .filter(|id| !id.is_empty());
+        let trace_id = || {
+            let traceparent = headers.get("traceparent")?.to_str().ok()?;
+            // version-traceid-parentid-flags
+            let trace_id = traceparent.split('-').nth(1)?;
+            let valid = trace_id.len() == 32
+                && trace_id.bytes().all(|ch| ch.is_ascii_hexdigit())
+                && trace_id.bytes().any(|ch| ch != b'0');
+            valid.then(|| trace_id.to_ascii_lowercase())
+        };
+        Self(request_id.or_else(trace_id).unwrap_or_else(|| {
+            let id_num: u32 = rand::thread_rng().gen();
+            format!("{id_num:08X}")
+        }))
+    }
+
+    /// The id in a color derived from it, so that interleaved log lines of
+    /// concurrent requests are easy to tell apart
+    fn colored(&self) -> String {
+        use palette::{hsl::Hsl, FromColor, Srgb};
+        let hash = (self.0.bytes()).fold(0u32, |hash, byte| {
+            hash.wrapping_mul(31).wrapping_add(byte.into())
+        });
+        let (r, g, b) = Srgb::from_color(Hsl::new((hash % 360) as f32, 1., 0.75)).into_components();
+        format!(
+            "\x1b[38;2;{r};{g};{b}m{id}\x1b[0m",
+            r = (r * 255.) as u8,
+            g = (g * 255.) as u8,
+            b = (b * 255.) as u8,
+            id = self.0,
+        )
+    }
+}
+
+async fn log_traffic(State(state): State<Arc<AppState>>, mut req: Request, next: Next) -> Response {
+    // Responses usually take a long time, this helps tracking them
+    let request_id = RequestId::from_headers(req.headers());
+    let id = request_id.colored();
+    let header_value = HeaderValue::from_str(&request_id.0).ok();
+    req.extensions_mut().insert(request_id);
+
+    struct Latency(Duration);
+    impl std::fmt::Display for Latency {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            if self.0.as_secs() == 0 {
+                let ns = self.0.subsec_nanos();
+                let ms = ns / 1_000_000;
+                let frac_ms = (ns / 1000) % 1000;
+                write!(f, "{ms}.{frac_ms:03}ms")
+            } else {
+                write!(f, "{:.3}s", self.0.as_secs_f32())
+            }
+        }
+    }
+
+    let ConnectInfo(source) = req
+        .extract_parts::<ConnectInfo<SocketAddr>>()
+        .await
+        .unwrap();
+    let method = req.method();
+    let path = req.uri().path().to_owned();
+    info!(%id, %method, path, %source, "Request");
+
+    let _inflight = state.inflight.start(&path);
+    let start = Instant::now();
+    let response = next.run(req).instrument(info_span!("request", %id));
+    tokio::pin!(response);
+    let soft_deadline = state.config.server.soft_deadline;
+    let mut res = tokio::select! {
+        res = &mut response => res,
+        () = tokio::time::sleep(soft_deadline) => {
+            let soft_deadline = humantime::format_duration(soft_deadline);
+            warn!(%id, path, %soft_deadline, "Request exceeds soft deadline");
+            response.await
+        }
+    };
+    let latency = Latency(start.elapsed());
+
+    let status = res.status().as_u16();
+    let content_type = (res.headers().get(header::CONTENT_TYPE))
+        .and_then(|it| it.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    // Unknown for streamed bodies, which are still counted as they are sent
+    let bytes = http_body::Body::size_hint(res.body()).exact();
+    info!(%id, status, content_type, bytes, %latency, "Response");
+    if let Some(value) = header_value {
+        res.headers_mut().insert(X_REQUEST_ID, value);
+    }
+    res.map(|body| state.response_bytes.count(&path, &content_type, body))
+}
+
+async fn get_index(State(state): State<Arc<AppState>>, headers: HeaderMap) -> impl IntoResponse {
+    let is_terminal = is_terminal_client(&headers);
+
+    let response_type = if let Some(accept) = headers
+        .get(header::ACCEPT)
+        .and_then(|it| it.to_str().ok())
+        .and_then(|it| it.parse::<accept_header::Accept>().ok())
+    {
+        // Terminals should receive plaintext as default
+        let available = if is_terminal {
+            [TEXT_PLAIN, TEXT_HTML]
+        } else {
+            [TEXT_HTML, TEXT_PLAIN]
+        };
+
+        'negotiate: {
+            for media_type in &accept.types {
+                if available.contains(&media_type.mime) {
+                    break 'negotiate media_type.mime.clone();
+                }
+            }
+
+            if accept.wildcard.is_some() {
+                break 'negotiate available[0].clone();
+            }
+
+            return StatusCode::NOT_ACCEPTABLE.into_response();
+        }
+    } else {
+        TEXT_HTML
+    };
+
+    let (variant, content_type) = match (response_type.type_(), response_type.subtype()) {
+        (TEXT, PLAIN) if is_terminal => (PageVariant::Ansi, mime::TEXT_PLAIN_UTF_8),
+        (TEXT, PLAIN) => (PageVariant::Text, mime::TEXT_PLAIN_UTF_8),
+        (TEXT, HTML) => (PageVariant::Html, mime::TEXT_HTML_UTF_8),
+        _ => unreachable!(),
+    };
+    let accept_language = headers
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|it| it.to_str().ok());
+    let (language, page) = state.index.negotiate(accept_language, variant);
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, content_type.as_ref()),
+            (header::CONTENT_LANGUAGE, language),
+            (header::VARY, "accept, accept-language, user-agent"),
+        ],
+        page.to_owned(),
+    )
+        .into_response()
+}
+
+/// Whether the request was sent by a command line client such as curl
+fn is_terminal_client(headers: &HeaderMap) -> bool {
+    headers.get(header::USER_AGENT).is_some_and(|ua| {
+        let bytes = ua.as_bytes();
+        bytes.starts_with(b"curl/") || bytes.starts_with(b"Wget/")
+    })
+}
+
+/// Whether a table for humans should be rendered instead of the exposition
+/// format: only for terminals that did not explicitly ask for a versioned
+/// `text/plain`, so scrapes are unaffected.
+fn wants_human_readable(headers: &HeaderMap, response_type: &Mime) -> bool {
+    if !is_terminal_client(headers) || response_type.essence_str() != TEXT_PLAIN.essence_str() {
+        return false;
+    }
+    !accepted_text_plain(headers).any(|mime| mime.get_param("version").is_some())
+}
+
+/// Whether tables for terminals are colored. Unless forced by the `color`
+/// parameter, colors are omitted if an explicit charset was requested.
+fn use_color(headers: &HeaderMap, choice: ColorChoice) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            !accepted_text_plain(headers).any(|mime| mime.get_param("charset").is_some())
+        }
+    }
+}
+
+/// `text/plain` entries of the `Accept` header including their parameters,
+/// which accept_header drops except for `q`
+fn accepted_text_plain(headers: &HeaderMap) -> impl Iterator<Item = Mime> + '_ {
+    let accept = headers.get(header::ACCEPT).and_then(|it| it.to_str().ok());
+    accept
+        .into_iter()
+        .flat_map(|accept| accept.split(','))
+        .filter_map(|media_type| media_type.trim().parse::<Mime>().ok())
+        .filter(|mime| mime.essence_str() == TEXT_PLAIN.essence_str())
+}
+
+/// Tiny measurement for health checks, responds with 503 if it failed
+async fn get_selftest(State(state): State<Arc<AppState>>) -> Response<String> {
+    let report = state.selftest.run(&state.config, state.dns.clone()).await;
+    let status = if report.passed {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Response::builder()
+        .header(header::CONTENT_TYPE, APPLICATION_JSON.as_ref())
+        .status(status)
+        .body(serde_json::to_string_pretty(&*report).unwrap())
+        .unwrap()
+}
+
+/// `format` query parameter, which takes precedence over the `Accept` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ResponseFormat {
+    Json,
+    Prometheus,
+    Openmetrics,
+}
+
+/// Response type requested by `format`, or negotiated from the `Accept`
+/// header without it
+fn negotiate_response_type(
+    headers: &HeaderMap,
+    format: Option<ResponseFormat>,
+) -> Result<Mime, StatusCode> {
+    match format {
+        Some(ResponseFormat::Json) => Ok(APPLICATION_JSON),
+        Some(ResponseFormat::Prometheus) => Ok(TEXT_PLAIN_UTF_8_VERSION_4.clone()),
+        Some(ResponseFormat::Openmetrics) => Ok(APPLICATION_OPENMETRICS_VERSION_1.clone()),
+        None => negotiate_prometheus_mime(headers),
+    }
+}
+
+fn negotiate_prometheus_mime(headers: &HeaderMap) -> Result<Mime, StatusCode> {
+    let mut response_type = if let Some(accept) = headers
+        .get(header::ACCEPT)
+        .and_then(|it| it.to_str().ok())
+        .and_then(|it| it.parse::<accept_header::Accept>().ok())
+    {
+        // Parameters such as `version` are ignored for matching
+        let available = [
+            TEXT_PLAIN,
+            APPLICATION_JSON,
+            APPLICATION_OPENMETRICS.clone(),
+            APPLICATION_VND_SPEEDTEST_V2_JSON.clone(),
+        ];
+
+        'negotiate: {
+            for media_type in &accept.types {
+                let essence = media_type.mime.essence_str();
+                if let Some(mime) = available.iter().find(|it| it.essence_str() == essence) {
+                    break 'negotiate mime.clone();
+                }
+            }
+
+            if accept.wildcard.is_some() {
+                break 'negotiate available[0].clone();
+            }
+
+            return Err(StatusCode::NOT_ACCEPTABLE);
+        }
+    } else {
+        TEXT_PLAIN_UTF_8_VERSION_4.clone()
+    };
+    if response_type.type_() == TEXT && response_type.get_param("version").is_none() {
+        TEXT_PLAIN_UTF_8_VERSION_4.clone_into(&mut response_type);
+    } else if response_type == *APPLICATION_OPENMETRICS {
+        APPLICATION_OPENMETRICS_VERSION_1.clone_into(&mut response_type);
+    }
+    Ok(response_type)
+}
+
+/// Exposition format for a negotiated response type, `None` for JSON
+fn exposition_format(response_type: &Mime) -> Option<ExpositionFormat> {
+    match (response_type.type_(), response_type.subtype()) {
+        (TEXT, PLAIN) => Some(ExpositionFormat::Prometheus),
+        (APPLICATION, JSON) => None,
+        _ if response_type.essence_str() == APPLICATION_OPENMETRICS.essence_str() => {
+            Some(ExpositionFormat::OpenMetrics)
+        }
+        _ if *response_type == *APPLICATION_VND_SPEEDTEST_V2_JSON => None,
+        _ => unreachable!(),
+    }
+}
+
+/// Content type of a response, tables for humans are no exposition format
+fn content_type(response_type: &Mime, human_readable: bool) -> &str {
+    if human_readable {
+        TEXT_PLAIN_UTF_8.as_ref()
+    } else {
+        response_type.as_ref()
+    }
+}
+
+/// JSON schema selected by the vendor media type or the `schema` parameter
+fn json_schema(response_type: &Mime, schema: Option<u8>) -> Result<JsonSchema, String> {
+    if *response_type == *APPLICATION_VND_SPEEDTEST_V2_JSON {
+        return Ok(JsonSchema::V2);
+    }
+    schema.map_or(Ok(JsonSchema::V1), JsonSchema::from_version)
+}
+
+fn bad_request(message: String) -> Response<String> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, TEXT_PLAIN_UTF_8.as_ref())
+        .status(StatusCode::BAD_REQUEST)
+        .body(message)
+        .unwrap()
+}
+
+#[derive(Debug, Deserialize)]
+struct SchemaQuery {
+    /// Version of the JSON schema, see [`JsonSchema`]
+    schema: Option<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PingQuery {
+    /// Comma separated subset of the configured targets
+    targets: Option<String>,
+    /// Version of the JSON schema, see [`JsonSchema`]
+    schema: Option<u8>,
+    /// Whether tables for terminals are colored
+    #[serde(default)]
+    color: ColorChoice,
+    /// Non-zero adds every individual ping to the Prometheus exposition
+    #[serde(default)]
+    raw: u8,
+    format: Option<ResponseFormat>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SpeedtestQuery {
+    /// Version of the JSON schema, see [`JsonSchema`]
+    schema: Option<u8>,
+    /// Whether tables for terminals are colored
+    #[serde(default)]
+    color: ColorChoice,
+    format: Option<ResponseFormat>,
+}
+
+async fn get_ping(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<PingQuery>,
+    request_id: Option<Extension<RequestId>>,
+    headers: HeaderMap,
+) -> Response<String> {
+    let AppState {
+        config,
+        cache,
+        dns,
+        scrape_phases,
+        inflight,
+        response_bytes,
+        exposition_sizes,
+        availability,
+        reply_order,
+        ..
+    } = &*state;
+    let response_type = match negotiate_response_type(&headers, query.format) {
+        Ok(ty) => ty,
+        Err(code) => {
+            return Response::builder()
+                .status(code)
+                .body(String::new())
+                .unwrap()
+        }
+    };
+
+    let schema = match json_schema(&response_type, query.schema) {
+        Ok(schema) => schema,
+        Err(message) => return bad_request(message),
+    };
+
+    let raw = query.raw != 0;
+    if raw && !config.server.debug_endpoints {
+        return bad_request("raw samples require server.debug_endpoints".to_owned());
+    }
+
+    let targets = match query
+        .targets
+        .as_deref()
+        .map(|names| select_targets(&config.ping.servers, names))
+    {
+        Some(Ok(targets)) => Some(targets),
+        Some(Err(message)) => return bad_request(message),
+        None => None,
+    };
+
+    let phases = PhaseRecorder::default();
+    // Only complete measurements are cached
+    let data = match &targets {
+        Some(targets) => Arc::new(
+            perform_ping(config.clone(), targets.clone(), dns.clone(), phases.clone()).await,
+        ),
+        None => {
+            cache
+                .ping(config.clone(), dns.clone(), phases.clone())
+                .await
+        }
+    };
+    availability.record(&data, config.ping.availability_window);
+    reply_order.record(&data);
+    scrape_phases.update("ping", &phases);
+
+    let human_readable = query.format.is_none() && wants_human_readable(&headers, &response_type);
+    let rendering = PhaseRecorder::default();
+    let response = rendering.time("rendering", || match exposition_format(&response_type) {
+        Some(_) if human_readable => Ok(AnsiRenderer::new(
+            &config.thresholds,
+            use_color(&headers, query.color),
+        )
+        .render_ping(&data)),
+        Some(format) => state.render_exposition("ping", format, |builder| {
+            for result in data.iter() {
+                result.write_prometheus(builder, &config.ping);
+                if raw {
+                    result.write_raw_prometheus(builder);
+                }
+            }
+            availability.write_prometheus(builder, &data, config.ping.availability_window);
+            reply_order.write_prometheus(builder, &data);
+            dns.write_prometheus(builder);
+            ping::write_icmp_socket_info(builder, &config.ping);
+            write_static_metrics(builder, config);
+            scrape_phases.write_prometheus(builder);
+            inflight.write_prometheus(builder);
+            response_bytes.write_prometheus(builder);
+            exposition_sizes.write_prometheus(builder);
+            write_process_metrics(builder);
+        }),
+        None => match &targets {
+            Some(targets) => {
+                #[derive(Serialize)]
+                struct Filtered<'a, R> {
+                    targets: &'a [PingTarget],
+                    results: R,
+                }
+                Ok(serde_json::to_string_pretty(&Filtered {
+                    targets,
+                    results: results_to_versioned_json(&data, schema),
+                })
+                .unwrap())
+            }
+            None => Ok(
+                serde_json::to_string_pretty(&results_to_versioned_json(&data, schema)).unwrap(),
+            ),
+        },
+    });
+    scrape_phases.update("ping", &rendering);
+    let response = match response {
+        Ok(response) => response,
+        Err(error) => {
+            return error_to_500(
+                &error,
+                "exposition_too_large",
+                request_id.as_deref(),
+                &response_type,
+            )
+        }
+    };
+
+    Response::builder()
+        .header(
+            header::CONTENT_TYPE,
+            content_type(&response_type, human_readable),
+        )
+        .header(header::VARY, "accept, user-agent")
+        .status(StatusCode::OK)
+        .body(response)
+        .unwrap()
+}
+
+async fn get_speedtest(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SpeedtestQuery>,
+    request_id: Option<Extension<RequestId>>,
+    headers: HeaderMap,
+) -> Response<String> {
+    let AppState {
+        config,
+        cache,
+        server_info,
+        scrape_phases,
+        inflight,
+        response_bytes,
+        exposition_sizes,
+        ..
+    } = &*state;
+    let response_type = match negotiate_response_type(&headers, query.format) {
+        Ok(ty) => ty,
+        Err(code) => {
+            return Response::builder()
+                .status(code)
+                .body(String::new())
+                .unwrap()
+        }
+    };
+    let schema = match json_schema(&response_type, query.schema) {
+        Ok(schema) => schema,
+        Err(message) => return bad_request(message),
+    };
+
+    let phases = PhaseRecorder::default();
+    let data = match (cache)
+        .speedtest(config.clone(), server_info.clone(), phases.clone())
+        .await
+    {
+        Ok(data) => data,
+        Err(error) => {
+            let kind = speedtest::error_kind(&error);
+            return error_to_500(&error, kind, request_id.as_deref(), &response_type);
+        }
+    };
+    scrape_phases.update("speedtest", &phases);
+
+    let human_readable = query.format.is_none() && wants_human_readable(&headers, &response_type);
+    let rendering = PhaseRecorder::default();
+    let response = rendering.time("rendering", || match exposition_format(&response_type) {
+        Some(_) if human_readable => Ok(AnsiRenderer::new(
+            &config.thresholds,
+            use_color(&headers, query.color),
+        )
+        .render_speedtest(&data)),
+        Some(format) => state.render_exposition("speedtest", format, |builder| {
+            data.write_prometheus(builder, &config.speedtest.quantiles);
+            write_static_metrics(builder, config);
+            scrape_phases.write_prometheus(builder);
+            inflight.write_prometheus(builder);
+            response_bytes.write_prometheus(builder);
+            exposition_sizes.write_prometheus(builder);
+            write_process_metrics(builder);
+        }),
+        None => Ok(serde_json::to_string_pretty(
+            &data.to_versioned_json(schema, config.json.include_mbps),
+        )
+        .unwrap()),
+    });
+    scrape_phases.update("speedtest", &rendering);
+    let response = match response {
+        Ok(response) => response,
+        Err(error) => {
+            return error_to_500(
+                &error,
+                "exposition_too_large",
+                request_id.as_deref(),
+                &response_type,
+            )
+        }
+    };
+
+    Response::builder()
+        .header(
+            header::CONTENT_TYPE,
+            content_type(&response_type, human_readable),
+        )
+        .header(header::VARY, "accept, user-agent")
+        .status(StatusCode::OK)
+        .body(response)
+        .unwrap()
+}
+
+async fn get_metrics(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SchemaQuery>,
+    request_id: Option<Extension<RequestId>>,
+    headers: HeaderMap,
+) -> Response<String> {
+    let AppState {
+        config,
+        cache,
+        dns,
+        server_info,
+        scrape_phases,
+        inflight,
+        response_bytes,
+        exposition_sizes,
+        availability,
+        reply_order,
+        ..
+    } = &*state;
+    let response_type = match negotiate_prometheus_mime(&headers) {
+        Ok(ty) => ty,
+        Err(code) => {
+            return Response::builder()
+                .status(code)
+                .body(String::new())
+                .unwrap()
+        }
+    };
+    let schema = match json_schema(&response_type, query.schema) {
+        Ok(schema) => schema,
+        Err(message) => return bad_request(message),
+    };
+
+    let phases = PhaseRecorder::default();
+    let (ping_data, speedtest_data) = tokio::join!(
+        cache.ping(config.clone(), dns.clone(), phases.clone()),
+        cache.speedtest(config.clone(), server_info.clone(), phases.clone())
+    );
+    availability.record(&ping_data, config.ping.availability_window);
+    reply_order.record(&ping_data);
+    scrape_phases.update("metrics", &phases);
+
+    let rendering = PhaseRecorder::default();
+    let response = rendering.time("rendering", || match exposition_format(&response_type) {
+        Some(format) => state.render_exposition("metrics", format, |builder| {
+            write_measurements(
+                builder,
+                config,
+                dns,
+                availability,
+                reply_order,
+                &ping_data,
+                &speedtest_data,
+            );
+            scrape_phases.write_prometheus(builder);
+            inflight.write_prometheus(builder);
+            response_bytes.write_prometheus(builder);
+            exposition_sizes.write_prometheus(builder);
+            write_process_metrics(builder);
+        }),
+        None => {
+            #[derive(Serialize)]
+            struct Data<P, S> {
+                ping: P,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                speedtest: Option<S>,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                speedtest_error: Option<String>,
+                /// Only present along with an error, for finding its logs
+                #[serde(skip_serializing_if = "Option::is_none")]
+                request_id: Option<String>,
+            }
+
+            let failed = speedtest_data.is_err();
+
+            Ok(serde_json::to_string_pretty(&Data {
+                ping: results_to_versioned_json(&ping_data, schema),
+                speedtest: speedtest_data
+                    .as_deref()
+                    .ok()
+                    .map(|data| data.to_versioned_json(schema, config.json.include_mbps)),
+                speedtest_error: speedtest_data.as_ref().err().map(ToString::to_string),
+                request_id: (request_id.as_deref())
+                    .filter(|_| failed)
+                    .map(|RequestId(id)| id.clone()),
+            })
+            .unwrap())
+        }
+    });
+    scrape_phases.update("metrics", &rendering);
+    let response = match response {
+        Ok(response) => response,
+        Err(error) => {
+            return error_to_500(
+                &error,
+                "exposition_too_large",
+                request_id.as_deref(),
+                &response_type,
+            )
+        }
+    };
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, response_type.as_ref())
+        .status(StatusCode::OK)
+        .body(response)
+        .unwrap()
+}
+
+/// Picks the configured targets named by `names`, a comma separated list of
+/// targets in their display form.
+fn select_targets(configured: &[PingTarget], names: &str) -> Result<Vec<PingTarget>, String> {
+    let mut selected = Vec::new();
+    let mut unknown = Vec::new();
+    for name in names.split(',').map(str::trim).filter(|it| !it.is_empty()) {
+        match configured.iter().find(|target| target.to_string() == name) {
+            Some(target) => {
+                if !selected.contains(target) {
+                    selected.push(target.clone());
+                }
+            }
+            None => unknown.push(name),
+        }
+    }
+    if !unknown.is_empty() {
+        let valid = configured
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        return Err(format!(
+            "unknown targets: {}\nvalid targets: {}",
+            unknown.join(", "),
+            valid.join(", ")
+        ));
+    }
+    Ok(selected)
+}
+
+/// Invalidates cached measurements, all of them for `/cache` or the one
+/// named by the last path segment
+async fn delete_cache(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(source): ConnectInfo<SocketAddr>,
+    uri: http::Uri,
+    headers: HeaderMap,
+) -> StatusCode {
+    let AppState { config, cache, .. } = &*state;
+    let exempt = (config.server.auth_exempt_paths.iter()).any(|path| path == uri.path());
+    if let Some(token) = config.server.auth_token.as_ref().filter(|_| !exempt) {
+        let authorized = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|it| it.as_bytes().strip_prefix(b"Bearer "))
+            .is_some_and(|given| constant_time_eq(given, token.expose().as_bytes()));
+        if !authorized {
+            warn!(source = %source.ip(), "Unauthorized cache invalidation");
+            return StatusCode::UNAUTHORIZED;
+        }
+    }
+
+    let (ping, speedtest) = match uri.path() {
+        "/cache/ping" => (cache.clear_ping(), false),
+        "/cache/speedtest" => (false, cache.clear_speedtest()),
+        _ => (cache.clear_ping(), cache.clear_speedtest()),
+    };
+    info!(
+        source = %source.ip(),
+        path = uri.path(),
+        ping,
+        speedtest,
+        "Invalidated measurement cache"
+    );
+    StatusCode::NO_CONTENT
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn render_exposition(
+    format: ExpositionFormat,
+    metrics: &MetricsConfig,
+    write: impl FnOnce(&mut ExpositionBuilder),
+) -> String {
+    render_limited_exposition(format, metrics, usize::MAX, write).unwrap()
+}
+
+#[derive(Debug, Error)]
+#[error("exposition exceeds server.limits.max_exposition_bytes ({limit}) after {bytes} bytes")]
+struct ExpositionTooLarge {
+    /// Bytes added until the limit was exceeded
+    bytes: usize,
+    limit: usize,
+}
+
+/// Like [`render_exposition`], but only renders expositions of at most
+/// `limit` bytes. Metrics are no longer added once the limit is exceeded, so
+/// oversized expositions neither fill the arena nor allocate their output.
+fn render_limited_exposition(
+    format: ExpositionFormat,
+    metrics: &MetricsConfig,
+    limit: usize,
+    write: impl FnOnce(&mut ExpositionBuilder),
+) -> Result<String, ExpositionTooLarge> {
+    ExpositionArena::with_pooled(|alloc| {
+        let mut builder = ExpositionBuilder::with_format(alloc, format);
+        builder.set_naming(metrics.naming);
+        builder.set_renames(&metrics.rename);
+        builder.set_size_limit(limit);
+        write(&mut builder);
+        if builder.exceeds_size_limit() {
+            let bytes = builder.added_len();
+            warn!(bytes, limit, "Exposition exceeds the size limit");
+            return Err(ExpositionTooLarge { bytes, limit });
+        }
+        if cfg!(debug_assertions) {
+            for error in builder.validate().err().unwrap_or_default() {
+                warn!(%error, "Invalid exposition");
+            }
+        }
+        Ok(builder.render())
+    })
+}
+
+/// Writes `measurement_error{error}` labeled with the kind of `error`, since
+/// its message contains URLs and addresses that would make up a new series
+/// on every failure
+fn write_measurement_error(builder: &mut ExpositionBuilder, error: &reqwest::Error) {
+    builder.add_metric(
+        PName::new("measurement_error").unwrap(),
+        MetricType::Gauge,
+        "measurement failed",
+        |mut builder| {
+            builder.add_line_labeled(
+                PName::new("error").unwrap(),
+                speedtest::error_kind(error),
+                &1,
+                None,
+            );
+        },
+    );
+}
+
+/// Writes the results of a combined measurement along with the static
+/// metrics, failed measurements as `measurement_error`.
+fn write_measurements(
+    builder: &mut ExpositionBuilder,
+    config: &Config,
+    dns: &DnsCache,
+    availability: &AvailabilityHistory,
+    reply_order: &ReplyOrderTotals,
+    ping_data: &[PingResult],
+    speedtest_data: &SharedSpeedtest,
+) {
+    for result in ping_data {
+        result.write_prometheus(builder, &config.ping);
+    }
+    availability.write_prometheus(builder, ping_data, config.ping.availability_window);
+    reply_order.write_prometheus(builder, ping_data);
+    dns.write_prometheus(builder);
+    ping::write_icmp_socket_info(builder, &config.ping);
+    let measurement = PName::new("measurement").unwrap();
+    match speedtest_data {
+        Ok(data) => data.write_prometheus(builder, &config.speedtest.quantiles),
+        Err(error) => builder.with_label(measurement, "speedtest", |builder| {
+            write_measurement_error(builder, error);
+        }),
+    }
+    write_static_metrics(builder, config);
+}
+
+fn write_static_metrics(builder: &mut ExpositionBuilder, config: &Config) {
+    for (name, value) in &config.static_metrics {
+        builder.add_metric(
+            name,
+            MetricType::Gauge,
+            "static metric from the configuration",
+            |mut builder| builder.add_line(value, None),
+        );
+    }
+}
+
+/// Responds with `error` as plain text, or as `{"error", "kind", "request_id"}`
+/// to clients that negotiated JSON.
+#[cold]
+fn error_to_500(
+    error: &dyn Error,
+    kind: &str,
+    request_id: Option<&RequestId>,
+    response_type: &Mime,
+) -> Response<String> {
+    let (content_type, body) = match exposition_format(response_type) {
+        Some(_) => (TEXT_PLAIN_UTF_8.as_ref(), error.to_string()),
+        None => {
+            #[derive(Serialize)]
+            struct ErrorJson<'a> {
+                error: String,
+                kind: &'a str,
+                /// For finding the logs of the failed request
+                #[serde(skip_serializing_if = "Option::is_none")]
+                request_id: Option<&'a str>,
+            }
+
+            let body = ErrorJson {
+                error: error.to_string(),
+                kind,
+                request_id: request_id.map(|RequestId(id)| id.as_str()),
+            };
+            (
+                response_type.as_ref(),
+                serde_json::to_string_pretty(&body).unwrap(),
+            )
+        }
+    };
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(body)
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::Config,
+        ping::{PingResult, PingTarget},
+        prometheus::MetricNaming,
+        speedtest::{
+            null::NullSpeedtestProvider, perform_speedtest, SpeedtestSample,
+            StandardSpeedtestProvider,
+        },
+    };
+
+    use super::*;
+
+    fn null_config() -> Config {
+        let mut config = Config::default();
+        let sample = SpeedtestSample {
+            bytes: 125_000.,
+            seconds: 1.,
+        };
+        config.speedtest.provider = StandardSpeedtestProvider::Null(NullSpeedtestProvider {
+            download_samples: vec![sample; 10],
+            upload_samples: vec![sample; 5],
+        });
+        config
+    }
+
+    #[tokio::test]
+    async fn null_speedtest_exposition() {
+        let config = Arc::new(null_config());
+        let data = perform_speedtest(config.clone(), Arc::default(), PhaseRecorder::default())
+            .await
+            .unwrap();
+        let exposition = render_exposition(
+            ExpositionFormat::Prometheus,
+            &MetricsConfig::default(),
+            |builder| data.write_prometheus(builder, &config.speedtest.quantiles),
+        );
+
+        assert!(exposition.contains("# TYPE network_speed_bps summary\n"));
+        assert!(exposition.contains("network_speed_mean_bps{direction=\"down\"} 1000000\n"));
+        assert!(exposition.contains("network_speed_mean_bps{direction=\"up\"} 1000000\n"));
+        assert!(exposition.contains("network_speed_bps_count{direction=\"down\"} 10\n"));
+        assert!(exposition.contains("network_speed_bps_count{direction=\"up\"} 5\n"));
+        assert!(exposition.contains("speedtest_concurrent 0\n"));
+        assert!(exposition
+            .contains("speedtest_measurement_info{direction=\"down\", field=\"samples\"} 10\n"));
+        assert!(exposition
+            .contains("speedtest_measurement_info{direction=\"up\", field=\"complete\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn concurrent_speedtest_is_flagged() {
+        let mut config = null_config();
+        config.speedtest.allow_concurrent_dl_ul = true;
+        let config = Arc::new(config);
+        let data = perform_speedtest(config.clone(), Arc::default(), PhaseRecorder::default())
+            .await
+            .unwrap();
+        let exposition = render_exposition(
+            ExpositionFormat::Prometheus,
+            &MetricsConfig::default(),
+            |builder| data.write_prometheus(builder, &config.speedtest.quantiles),
+        );
+
+        assert!(exposition.contains("speedtest_concurrent 1\n"));
+        assert!(exposition.contains("network_speed_bps_count{direction=\"up\"} 5\n"));
+    }
+
+    #[tokio::test]
+    async fn speedtest_exposes_phase_durations() {
+        let state = Arc::new(AppState::new(Arc::new(null_config())).unwrap());
+        let scrape = || {
+            get_speedtest(
+                State(state.clone()),
+                Query(SpeedtestQuery {
+                    schema: None,
+                    color: ColorChoice::Never,
+                    format: None,
+                }),
+                None,
+                HeaderMap::new(),
+            )
+        };
+
+        // Rendering is only known after the first response was rendered
+        scrape().await;
+        let exposition = scrape().await.into_body();
+
+        for phase in ["download", "upload", "digest", "rendering"] {
+            assert!(
+                exposition.contains(&format!(
+                    "exporter_phase_duration_seconds{{endpoint=\"speedtest\", phase=\"{phase}\"}}"
+                )),
+                "missing phase {phase}"
+            );
+        }
+    }
+
+    #[test]
+    fn mock_ping_exposition() {
+        let config = null_config();
+        let data = [
+            PingResult::mock(
+                PingTarget::Ip([127, 0, 0, 1].into()),
+                vec![1., 2., 3., f32::NAN],
+                &config.ping,
+            ),
+            PingResult::mock(
+                PingTarget::Domain("localhost".to_owned()),
+                vec![4.; 4],
+                &config.ping,
+            ),
+        ];
+        let exposition = render_exposition(
+            ExpositionFormat::Prometheus,
+            &MetricsConfig::default(),
+            |builder| {
+                for result in &data {
+                    result.write_prometheus(builder, &config.ping);
+                }
+            },
+        );
+
+        assert!(exposition.contains("# TYPE ping_ms summary\n"));
+        assert!(exposition.contains("ping_ms_count{target=\"127.0.0.1\"} 3\n"));
+        assert!(exposition.contains("ping_ms_count{target=\"localhost\"} 4\n"));
+        assert!(exposition.contains("packet_loss{target=\"127.0.0.1\"} +0x1.p-2\n"));
+        assert!(exposition
+            .contains("ping_measurement_info{target=\"127.0.0.1\", field=\"samples\"} 3\n"));
+        assert!(exposition
+            .contains("ping_measurement_info{target=\"localhost\", field=\"errors\"} 0\n"));
+        assert!(
+            exposition.contains("ping_address_family{target=\"127.0.0.1\", family=\"ipv4\"} 1\n")
+        );
+    }
+
+    #[test]
+    fn openmetrics_exposition_declares_units() {
+        let config = null_config();
+        let data = PingResult::mock(
+            PingTarget::Ip([127, 0, 0, 1].into()),
+            vec![1.],
+            &config.ping,
+        );
+        let exposition = render_exposition(
+            ExpositionFormat::OpenMetrics,
+            &MetricsConfig::default(),
+            |builder| {
+                data.write_prometheus(builder, &config.ping);
+            },
+        );
+
+        assert!(exposition.contains(
+            "# TYPE ping_configured_delay_seconds gauge\n\
+             # UNIT ping_configured_delay_seconds seconds\n"
+        ));
+        // OpenMetrics does not allow hexfloats
+        assert!(exposition.contains("\nping_configured_delay_seconds{target=\"127.0.0.1\"} 1.0\n"));
+        assert!(!exposition.contains("0x"));
+        assert!(exposition.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn metrics_are_renamed() {
+        let config = null_config();
+        let data = PingResult::mock(
+            PingTarget::Ip([127, 0, 0, 1].into()),
+            vec![1.],
+            &config.ping,
+        );
+        let metrics: MetricsConfig = toml::from_str(
+            r#"rename = { ping_ms = "probe_rtt_ms", ping_duration_seconds = "probe_rtt" }"#,
+        )
+        .unwrap();
+        let exposition = render_exposition(ExpositionFormat::Prometheus, &metrics, |builder| {
+            data.write_prometheus(builder, &config.ping);
+        });
+        assert!(exposition.contains("# TYPE probe_rtt_ms summary\n"));
+        assert!(exposition.contains("\nprobe_rtt_ms_count{target=\"127.0.0.1\"} 1\n"));
+        assert!(!exposition.contains("ping_ms"));
+
+        // Renames apply to the conventional names, which lose their unit
+        let metrics = MetricsConfig {
+            naming: MetricNaming::Conventional,
+            ..metrics
+        };
+        let exposition = render_exposition(ExpositionFormat::OpenMetrics, &metrics, |builder| {
+            data.write_prometheus(builder, &config.ping);
+        });
+        assert!(exposition.contains("# TYPE probe_rtt summary\n"));
+        assert!(!exposition.contains("# UNIT probe_rtt "));
+    }
+
+    #[test]
+    fn ping_targets_are_filtered() {
+        let configured = Config::default().ping.servers;
+
+        let selected = select_targets(&configured, "1.1.1.1, google.com,1.1.1.1").unwrap();
+        assert_eq!(
+            selected,
+            [
+                PingTarget::Ip([1, 1, 1, 1].into()),
+                PingTarget::Domain("google.com".to_owned())
+            ]
+        );
+
+        let error = select_targets(&configured, "example.com").unwrap_err();
+        assert_eq!(
+            error,
+            "unknown targets: example.com\nvalid targets: 8.8.8.8, 9.9.9.9, 1.1.1.1, google.com"
+        );
+    }
+
+    #[test]
+    fn terminals_receive_tables() {
+        let headers = |user_agent: &str, accept: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::USER_AGENT, user_agent.parse().unwrap());
+            if let Some(accept) = accept {
+                headers.insert(header::ACCEPT, accept.parse().unwrap());
+            }
+            headers
+        };
+        let human = |user_agent, accept| {
+            let headers = headers(user_agent, accept);
+            let response_type = negotiate_prometheus_mime(&headers).unwrap();
+            wants_human_readable(&headers, &response_type)
+        };
+
+        // Terminals
+        assert!(human("curl/8.5.0", Some("*/*")));
+        assert!(human("Wget/1.21.4", None));
+        assert!(human("curl/8.5.0", Some("text/plain")));
+        assert!(!human("curl/8.5.0", Some("text/plain;version=0.0.4")));
+        assert!(!human("curl/8.5.0", Some("application/json")));
+        // Browsers
+        assert!(!human(
+            "Mozilla/5.0 (X11; Linux x86_64)",
+            Some("text/html,*/*;q=0.8")
+        ));
+        // Scrapers
+        assert!(!human(
+            "Prometheus/2.51.0",
+            Some("application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5")
+        ));
+        assert!(!human("Prometheus/2.51.0", None));
+
+        let accept = |accept| headers("curl/8.5.0", Some(accept));
+        assert!(use_color(&accept("*/*"), ColorChoice::Auto));
+        assert!(!use_color(
+            &accept("text/plain; charset=utf-8"),
+            ColorChoice::Auto
+        ));
+        assert!(use_color(
+            &accept("text/plain; charset=utf-8"),
+            ColorChoice::Always
+        ));
+        assert!(!use_color(&accept("*/*"), ColorChoice::Never));
+
+        assert!(is_terminal_client(&headers("curl/8.5.0", None)));
+        assert!(!is_terminal_client(&headers("Mozilla/5.0", None)));
+    }
+
+    #[tokio::test]
+    async fn format_parameter_overrides_accept() {
+        let state = Arc::new(AppState::new(Arc::new(null_config())).unwrap());
+        let speedtest = |format| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::USER_AGENT, "curl/8.5.0".parse().unwrap());
+            headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+            get_speedtest(
+                State(state.clone()),
+                Query(SpeedtestQuery {
+                    schema: None,
+                    color: ColorChoice::Never,
+                    format,
+                }),
+                None,
+                headers,
+            )
+        };
+        let content_type = |response: Response<String>| {
+            response.headers()[header::CONTENT_TYPE]
+                .to_str()
+                .unwrap()
+                .to_owned()
+        };
+
+        let response = speedtest(None).await;
+        assert_eq!(content_type(response), APPLICATION_JSON.as_ref());
+        let response = speedtest(Some(ResponseFormat::Prometheus)).await;
+        assert_eq!(content_type(response), TEXT_PLAIN_UTF_8_VERSION_4.as_ref());
+        let response = speedtest(Some(ResponseFormat::Openmetrics)).await;
+        assert!(response.body().ends_with("# EOF\n"));
+
+        let uri: http::Uri = "/ping?format=xml".parse().unwrap();
+        let rejection = Query::<PingQuery>::try_from_uri(&uri).unwrap_err();
+        assert_eq!(rejection.status(), StatusCode::BAD_REQUEST);
+        let uri: http::Uri = "/ping?format=openmetrics".parse().unwrap();
+        let Query(query) = Query::<PingQuery>::try_from_uri(&uri).unwrap();
+        assert_eq!(query.format, Some(ResponseFormat::Openmetrics));
+    }
+
+    #[tokio::test]
+    async fn curl_speedtest_is_a_table() {
+        let state = Arc::new(AppState::new(Arc::new(null_config())).unwrap());
+        let mut headers = HeaderMap::new();
+        headers.insert(header::USER_AGENT, "curl/8.5.0".parse().unwrap());
+        let response = get_speedtest(
+            State(state),
+            Query(SpeedtestQuery {
+                schema: None,
+                color: ColorChoice::Never,
+                format: None,
+            }),
+            None,
+            headers,
+        )
+        .await;
+
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            TEXT_PLAIN_UTF_8.as_ref()
+        );
+        assert_eq!(
+            response.into_body(),
+            "direction  mean Mbit/s  p50  p90\ndown               1.0  1.0  1.0\nup                 1.0  1.0  1.0\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn measurement_errors_are_labeled_by_kind() {
+        // Nothing listens on the port of a closed listener
+        let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let error = reqwest::get(&url).await.unwrap_err();
+
+        let exposition = render_exposition(
+            ExpositionFormat::Prometheus,
+            &MetricsConfig::default(),
+            |builder| write_measurement_error(builder, &error),
+        );
+        assert!(exposition.contains("\nmeasurement_error{error=\"connect\"} 1\n"));
+        assert!(!exposition.contains(&url));
+    }
+
+    #[tokio::test]
+    async fn speedtest_errors_follow_the_negotiated_type() {
+        // Nothing listens on the port of a closed listener
+        let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let endpoint: url::Url = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        drop(listener);
+        let mut config = Config::default();
+        let StandardSpeedtestProvider::Http(provider) = &mut config.speedtest.provider else {
+            unreachable!("the default provider is HTTP");
+        };
+        provider.download_endpoint = endpoint.clone();
+        provider.upload_endpoint = endpoint;
+        let state = Arc::new(AppState::new(Arc::new(config)).unwrap());
+
+        let speedtest = |accept: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, HeaderValue::from_static(accept));
+            get_speedtest(
+                State(state.clone()),
+                Query(SpeedtestQuery {
+                    schema: None,
+                    color: ColorChoice::Never,
+                    format: None,
+                }),
+                None,
+                headers,
+            )
+        };
+
+        let response = speedtest("application/json").await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            APPLICATION_JSON.as_ref()
+        );
+        let body: serde_json::Value = serde_json::from_str(response.body()).unwrap();
+        assert_eq!(body["kind"], "connect");
+        assert!(body["error"]
+            .as_str()
+            .is_some_and(|error| !error.is_empty()));
+
+        let response = speedtest("text/plain").await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            TEXT_PLAIN_UTF_8.as_ref()
+        );
+        assert!(!response.body().starts_with('{'));
+    }
+
+    #[tokio::test]
+    async fn health_endpoints_move_to_their_own_listener() {
+        for (port, expected) in [(None, 200), (Some(9091), 404)] {
+            let mut config = null_config();
+            config.server.health.port = port;
+            let app = create_router(Arc::new(config)).unwrap();
+            let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+                .await
+                .unwrap();
+            let url = format!("http://{}/healthz", listener.local_addr().unwrap());
+            tokio::spawn(async move {
+                let app = app.into_make_service_with_connect_info::<SocketAddr>();
+                axum::serve(listener, app).await.unwrap();
+            });
+            assert_eq!(reqwest::get(url).await.unwrap().status(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_and_unexpected_requests_are_rejected() {
+        let mut config = null_config();
+        config.server.limits.max_body_bytes = 16;
+        config.server.limits.max_header_bytes = 256;
+        let app = create_router(Arc::new(config)).unwrap();
+        let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let url = format!("http://{}/cache", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        let client = reqwest::Client::new();
+        let status = |request: reqwest::RequestBuilder| async move {
+            request.send().await.unwrap().status()
+        };
+        assert_eq!(status(client.delete(&url).body("small")).await, 204);
+        assert_eq!(status(client.get(&url)).await, 405);
+        assert_eq!(
+            status(client.post(url.replace("cache", "metrics"))).await,
+            405
+        );
+        assert_eq!(status(client.delete(&url).body("x".repeat(17))).await, 413);
+        let chunks = [Ok::<_, io::Error>("x".repeat(10)), Ok("x".repeat(10))];
+        let chunked = reqwest::Body::wrap_stream(tokio_stream::iter(chunks));
+        assert_eq!(status(client.delete(&url).body(chunked)).await, 413);
+        let cookie = "x".repeat(300);
+        assert_eq!(
+            status(client.delete(&url).header(header::COOKIE, cookie)).await,
+            431
+        );
+    }
+
+    #[test]
+    fn request_id_from_headers() {
+        let id = |pairs: &[(&'static str, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, value.parse().unwrap());
+            }
+            RequestId::from_headers(&headers).0
+        };
+        let traceparent = "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01";
+
+        assert_eq!(id(&[(X_REQUEST_ID, "abc-123")]), "abc-123");
+        assert_eq!(
+            id(&[(X_REQUEST_ID, &"x".repeat(100))]).len(),
+            MAX_REQUEST_ID_LEN
+        );
+        assert_eq!(
+            id(&[("traceparent", traceparent)]),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(
+            id(&[(X_REQUEST_ID, "abc"), ("traceparent", traceparent)]),
+            "abc"
+        );
+        let zero_trace = "00-00000000000000000000000000000000-00f067aa0ba902b7-01";
+        for headers in [
+            &[][..],
+            &[("traceparent", zero_trace)],
+            &[(X_REQUEST_ID, " ")],
+        ] {
+            let random = id(headers);
+            assert_eq!(random.len(), 8, "{headers:?}");
+            assert!(random.bytes().all(|ch| ch.is_ascii_hexdigit()));
+        }
+        assert!(RequestId("abc".to_owned())
+            .colored()
+            .ends_with("mabc\x1b[0m"));
+    }
+
+    #[tokio::test]
+    async fn request_id_is_echoed() {
+        // Speedtests fail since nothing listens on the port of a closed listener
+        let closed = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let endpoint: url::Url = format!("http://{}/", closed.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        drop(closed);
+        let mut config = Config::default();
+        let StandardSpeedtestProvider::Http(provider) = &mut config.speedtest.provider else {
+            unreachable!("the default provider is HTTP");
+        };
+        provider.download_endpoint = endpoint.clone();
+        provider.upload_endpoint = endpoint;
+        let app = create_router(Arc::new(config)).unwrap();
+        let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let url = format!("http://{addr}/cache");
+        tokio::spawn(async move {
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        let client = reqwest::Client::new();
+        let response = client
+            .delete(&url)
+            .header(X_REQUEST_ID, "from-proxy")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[X_REQUEST_ID], "from-proxy");
+        let response = client.delete(&url).send().await.unwrap();
+        assert_eq!(response.headers()[X_REQUEST_ID].len(), 8);
+
+        // Error bodies carry the id for finding the logs of the request
+        let response = client
+            .get(format!("http://{addr}/speedtest"))
+            .header(header::ACCEPT, APPLICATION_JSON.as_ref())
+            .header(X_REQUEST_ID, "failed-speedtest")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        assert_eq!(body["kind"], "connect");
+        assert_eq!(body["request_id"], "failed-speedtest");
+    }
+
+    #[tokio::test]
+    async fn raw_samples_require_debug_endpoints() {
+        let mut config = null_config();
+        config.ping.servers.clear();
+        let app = create_router(Arc::new(config)).unwrap();
+        let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let url = format!("http://{}/ping", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        let response = reqwest::get(format!("{url}?raw=1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = reqwest::get(format!("{url}?raw=0")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn exempt_paths_skip_the_auth_token() {
+        let mut config = null_config();
+        config.server = toml::from_str(
+            r#"
+auth_token = "hunter2"
+auth_exempt_paths = ["/cache/ping"]
+"#,
+        )
+        .unwrap();
+        let app = create_router(Arc::new(config)).unwrap();
+        let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        let client = reqwest::Client::new();
+        let delete = |path: &str| client.delete(format!("{url}{path}"));
+        let response = delete("/cache/ping").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = delete("/cache").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = delete("/cache")
+            .bearer_auth("hunter2")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn response_bytes_are_counted() {
+        let mut config = null_config();
+        config.ping.servers.clear();
+        let app = create_router(Arc::new(config)).unwrap();
+        let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let url = format!("http://{}/metrics", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        let response = reqwest::get(&url).await.unwrap();
+        let length = response.headers()[header::CONTENT_LENGTH].clone();
+        assert_eq!(response.text().await.unwrap().len().to_string(), length);
+        let exposition = reqwest::get(&url).await.unwrap().text().await.unwrap();
+        assert!(exposition.contains(&format!(
+            "\nexporter_response_bytes_total{{path=\"/metrics\", \
+             content_type=\"text/plain; version=0.0.4; charset=utf-8\"}} {}\n",
+            length.to_str().unwrap()
+        )));
+    }
+
+    #[tokio::test]
+    async fn oversized_expositions_are_rejected() {
+        let mut config = null_config();
+        config.server.limits.max_exposition_bytes = 64 * 1024;
+        let state = Arc::new(AppState::new(Arc::new(config)).unwrap());
+        let huge = |builder: &mut ExpositionBuilder| {
+            builder.add_metric(
+                PName::new("huge").unwrap(),
+                MetricType::Gauge,
+                "artificially huge metric",
+                |mut builder| {
+                    for i in 0..10_000 {
+                        builder.add_line_labeled(PName::new("i").unwrap(), &i, &i, None);
+                    }
+                },
+            );
+        };
+        let error = (state)
+            .render_exposition("ping", ExpositionFormat::Prometheus, huge)
+            .unwrap_err();
+        assert!(error.bytes > error.limit, "{error}");
+
+        let exposition = (state)
+            .render_exposition("metrics", ExpositionFormat::Prometheus, |builder| {
+                state.exposition_sizes.write_prometheus(builder);
+            })
+            .unwrap();
+        assert!(exposition.contains(&format!(
+            "\nexporter_exposition_bytes{{endpoint=\"ping\"}} {}\n",
+            error.bytes
+        )));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static("text/plain"));
+        let response = get_speedtest(
+            State(state.clone()),
+            Query(SpeedtestQuery {
+                schema: None,
+                color: ColorChoice::Never,
+                format: None,
+            }),
+            None,
+            headers.clone(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut config = null_config();
+        config.server.limits.max_exposition_bytes = 16;
+        let state = Arc::new(AppState::new(Arc::new(config)).unwrap());
+        let response = get_speedtest(
+            State(state),
+            Query(SpeedtestQuery {
+                schema: None,
+                color: ColorChoice::Never,
+                format: None,
+            }),
+            None,
+            headers,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(response.body().contains("max_exposition_bytes"));
+    }
+
+    #[tokio::test]
+    async fn slow_requests_are_counted_and_completed() {
+        use tokio::sync::Notify;
+
+        let mut config = null_config();
+        config.server.soft_deadline = Duration::from_millis(20);
+        let state = Arc::new(AppState::new(Arc::new(config)).unwrap());
+        let (started, release) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+        let handler = {
+            let (started, release) = (started.clone(), release.clone());
+            || async move {
+                started.notify_one();
+                release.notified().await;
+                "pong"
+            }
+        };
+        let app = Router::new()
+            .route("/ping", get(handler))
+            .layer(middleware::from_fn_with_state(state.clone(), log_traffic))
+            .with_state(state.clone());
+        let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let url = format!("http://{}/ping", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        let request = tokio::spawn(async move { reqwest::get(url).await?.text().await });
+        started.notified().await;
+        assert_eq!(state.inflight.get("/ping"), Some(1));
+        let exposition = render_exposition(
+            ExpositionFormat::Prometheus,
+            &MetricsConfig::default(),
+            |builder| {
+                state.inflight.write_prometheus(builder);
+            },
+        );
+        assert!(exposition.contains("exporter_inflight_requests{path=\"/ping\"} 1\n"));
+        assert!(exposition.contains("exporter_inflight_requests{path=\"/metrics\"} 0\n"));
+
+        // Exceeding the soft deadline only logs a warning
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        release.notify_one();
+        assert_eq!(request.await.unwrap().unwrap(), "pong");
+        assert_eq!(state.inflight.get("/ping"), Some(0));
+    }
+
+    #[test]
+    fn prometheus_mime_negotiation() {
+        let negotiate = |accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, accept.parse().unwrap());
+            negotiate_prometheus_mime(&headers)
+        };
+
+        assert_eq!(
+            negotiate("text/plain;version=0.0.4").unwrap(),
+            *TEXT_PLAIN_UTF_8_VERSION_4
+        );
+        assert_eq!(
+            negotiate("application/openmetrics-text;version=1.0.0,text/plain;q=0.5").unwrap(),
+            *APPLICATION_OPENMETRICS_VERSION_1
+        );
+        assert_eq!(negotiate("application/json").unwrap(), APPLICATION_JSON);
+        let v2 = negotiate("application/vnd.speedtest.v2+json").unwrap();
+        assert_eq!(json_schema(&v2, None), Ok(JsonSchema::V2));
+        assert_eq!(json_schema(&APPLICATION_JSON, Some(2)), Ok(JsonSchema::V2));
+        assert_eq!(json_schema(&APPLICATION_JSON, None), Ok(JsonSchema::V1));
+        assert!(json_schema(&APPLICATION_JSON, Some(3)).is_err());
+        assert_eq!(negotiate("*/*").unwrap(), *TEXT_PLAIN_UTF_8_VERSION_4);
+        assert_eq!(
+            negotiate("image/png").unwrap_err(),
+            StatusCode::NOT_ACCEPTABLE
+        );
+    }
+
+    #[test]
+    fn banner_lists_enabled_subsystems() {
+        let mut config = Config::default();
+        assert_eq!(enabled_subsystems(&config), ["ping", "speedtest"]);
+
+        config.ping.servers.clear();
+        config.speedtest.provider = StandardSpeedtestProvider::Null(NullSpeedtestProvider {
+            download_samples: Vec::new(),
+            upload_samples: Vec::new(),
+        });
+        config.server.cache_ttl = Some(Duration::from_secs(60));
+        config.server.debug_endpoints = true;
+        assert_eq!(enabled_subsystems(&config), ["cache", "debug_endpoints"]);
+    }
+}
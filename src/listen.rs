@@ -0,0 +1,131 @@
+//! Listeners of the main server, one per address of `server.listen` or of
+//! `server.address` and `server.port`.
+
+use std::{
+    io,
+    net::{Ipv6Addr, SocketAddr},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+use crate::config::ServerConfig;
+
+/// Backlog of pending connections, as used by the standard library
+const BACKLOG: i32 = 128;
+
+/// Addresses to listen on. The unspecified IPv4 address also listens on the
+/// unspecified IPv6 address, since it does not accept IPv6 connections on
+/// every system.
+pub(crate) fn listen_addresses(config: &ServerConfig) -> Vec<SocketAddr> {
+    if !config.listen.is_empty() {
+        return config.listen.clone();
+    }
+    let mut addrs = vec![SocketAddr::new(config.address, config.port)];
+    if config.address.is_unspecified() && config.address.is_ipv4() {
+        addrs.push(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), config.port));
+    }
+    addrs
+}
+
+/// Binds an IPv6 address without its IPv4-mapped addresses, so that it does
+/// not conflict with an IPv4 listener on the same port.
+pub(crate) fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Binds every address of [`listen_addresses`]. Addresses that cannot be
+/// bound, e.g. because the host lacks IPv6, are skipped with a warning as
+/// long as any other address could be bound.
+pub(crate) fn bind_all(config: &ServerConfig) -> io::Result<Vec<TcpListener>> {
+    let mut listeners = Vec::new();
+    let mut last_error = None;
+    for addr in listen_addresses(config) {
+        match bind(addr) {
+            Ok(listener) => {
+                info!(address = %listener.local_addr()?, "Listening");
+                listeners.push(listener);
+            }
+            Err(error) => {
+                warn!(%addr, %error, "Could not listen");
+                last_error = Some(io::Error::new(
+                    error.kind(),
+                    format!("could not listen on {addr}: {error}"),
+                ));
+            }
+        }
+    }
+    match last_error {
+        Some(error) if listeners.is_empty() => Err(error),
+        _ => Ok(listeners),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    #[test]
+    fn unspecified_ipv4_listens_on_both_families() {
+        let mut config = ServerConfig::default();
+        let addrs = |config: &ServerConfig| -> Vec<String> {
+            (listen_addresses(config).iter())
+                .map(ToString::to_string)
+                .collect()
+        };
+        assert_eq!(addrs(&config), ["0.0.0.0:9090", "[::]:9090"]);
+        config.address = Ipv4Addr::LOCALHOST.into();
+        assert_eq!(addrs(&config), ["127.0.0.1:9090"]);
+        config.address = Ipv6Addr::UNSPECIFIED.into();
+        assert_eq!(addrs(&config), ["[::]:9090"]);
+        config.listen = vec!["127.0.0.1:1".parse().unwrap()];
+        assert_eq!(addrs(&config), ["127.0.0.1:1"]);
+    }
+
+    #[tokio::test]
+    async fn families_share_a_port() {
+        let v4 = bind((Ipv4Addr::UNSPECIFIED, 0).into()).unwrap();
+        let port = v4.local_addr().unwrap().port();
+        let v6 = match bind((Ipv6Addr::UNSPECIFIED, port).into()) {
+            Ok(v6) => v6,
+            // Hosts without IPv6 cannot conflict
+            Err(error) if error.kind() == io::ErrorKind::AddrNotAvailable => return,
+            Err(error) => panic!("{error}"),
+        };
+        assert_eq!(v6.local_addr().unwrap().port(), port);
+    }
+
+    #[tokio::test]
+    async fn unavailable_addresses_are_skipped() {
+        let taken = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let config = ServerConfig {
+            listen: vec![
+                taken.local_addr().unwrap(),
+                (IpAddr::from(Ipv4Addr::LOCALHOST), 0).into(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(bind_all(&config).unwrap().len(), 1);
+
+        let config = ServerConfig {
+            listen: vec![taken.local_addr().unwrap()],
+            ..Default::default()
+        };
+        let error = bind_all(&config).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("could not listen on 127.0.0.1:"));
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::debug;
+
+use crate::prometheus::{ExpositionBuilder, MetricType, PName};
+
+/// Records how long the phases of a single scrape took. Phases that run
+/// concurrently, such as resolving multiple targets, report their longest
+/// occurrence.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PhaseRecorder(Arc<Mutex<BTreeMap<&'static str, Duration>>>);
+
+impl PhaseRecorder {
+    pub fn record(&self, phase: &'static str, duration: Duration) {
+        let mut phases = self.0.lock().unwrap();
+        let longest = phases.entry(phase).or_default();
+        *longest = duration.max(*longest);
+    }
+
+    pub fn time<R>(&self, phase: &'static str, f: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+        let result = f();
+        self.record(phase, start.elapsed());
+        result
+    }
+
+    pub async fn time_async<F: Future>(&self, phase: &'static str, future: F) -> F::Output {
+        let start = Instant::now();
+        let result = future.await;
+        self.record(phase, start.elapsed());
+        result
+    }
+}
+
+/// Most recent phase durations of every endpoint
+#[derive(Debug, Default)]
+pub(crate) struct ScrapePhases(Mutex<BTreeMap<&'static str, BTreeMap<&'static str, Duration>>>);
+
+impl ScrapePhases {
+    /// Replaces the durations of all phases recorded for `endpoint`.
+    pub fn update(&self, endpoint: &'static str, recorder: &PhaseRecorder) {
+        let recorded = recorder.0.lock().unwrap();
+        let mut endpoints = self.0.lock().unwrap();
+        let phases = endpoints.entry(endpoint).or_default();
+        for (&phase, &duration) in recorded.iter() {
+            debug!(endpoint, phase, ?duration, "Scrape phase finished");
+            phases.insert(phase, duration);
+        }
+    }
+
+    pub fn write_prometheus(&self, builder: &mut ExpositionBuilder) {
+        let endpoints = self.0.lock().unwrap();
+        if endpoints.is_empty() {
+            return;
+        }
+        builder.add_metric_with_unit(
+            PName::new("exporter_phase_duration_seconds").unwrap(),
+            MetricType::Gauge,
+            Some(PName::UNIT_SECONDS),
+            "duration of the most recent scrape phases in seconds",
+            |mut builder| {
+                for (endpoint, phases) in endpoints.iter() {
+                    builder.with_label(PName::new("endpoint").unwrap(), *endpoint, |builder| {
+                        for (phase, duration) in phases {
+                            builder.add_line_labeled(
+                                PName::new("phase").unwrap(),
+                                *phase,
+                                &duration.as_secs_f64(),
+                                None,
+                            );
+                        }
+                    });
+                }
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_phases_keep_longest() {
+        let recorder = PhaseRecorder::default();
+        recorder.record("resolution", Duration::from_millis(20));
+        recorder.record("resolution", Duration::from_millis(10));
+        let phases = recorder.0.lock().unwrap();
+        assert_eq!(phases["resolution"], Duration::from_millis(20));
+    }
+}
@@ -1,116 +1,590 @@
 use core::fmt;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::Display,
+    future::Future,
     io,
-    net::IpAddr,
-    ops::{DerefMut, Div},
-    sync::Arc,
-    time::Duration,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::Div,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use hdrhistogram::Histogram;
-use hickory_resolver::error::ResolveError;
+use hickory_resolver::{
+    error::{ResolveError, ResolveErrorKind},
+    proto::op::ResponseCode,
+};
 use rand::RngCore;
 use serde::{Deserialize, Serialize, Serializer};
 use surge_ping::{IcmpPacket, PingIdentifier, PingSequence, SurgeError};
 use thiserror::Error;
-use tokio::{sync::Mutex, task::JoinSet};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::{info, warn};
 
 use crate::{
-    config::Config,
-    prometheus::{ExpositionBuilder, MetricType, PName},
-    Resolver,
+    ansi::AnsiRenderer,
+    config::{Config, PingConfig, Quantiles},
+    json::{serialize_quantile_map, serialize_timestamp, JsonSchema, Versioned},
+    phases::PhaseRecorder,
+    prometheus::{ExpositionBuilder, ExpositionMetricBuilder, MetricType, PName},
+    text::Table,
+};
+
+use self::{
+    dns_cache::{AddressLookup, DnsCache, DomainResolution, LazyResolver},
+    null::PingProvider,
 };
 
-pub(crate) async fn perform_ping(config: Arc<Config>) -> Result<Vec<PingResult>, ResolveError> {
-    let resolver = Resolver::tokio_from_system_conf()?;
-    let resolver = Arc::new(Mutex::new(resolver));
+pub mod availability;
+pub mod dns_cache;
+pub mod netns;
+pub mod null;
+pub mod reply_order;
+
+/// Pings `targets`. Unless `dns` has its own lookup, a resolver is only
+/// created for domain targets, which fail on their own if it cannot be
+/// created.
+pub(crate) async fn perform_ping(
+    config: Arc<Config>,
+    targets: Vec<PingTarget>,
+    dns: Arc<DnsCache>,
+    phases: PhaseRecorder,
+) -> Vec<PingResult> {
+    if let Some(lookup) = dns.lookup() {
+        return ping_targets(config, targets, dns, phases, lookup).await;
+    }
+    let resolver = LazyResolver::default();
+    let results = ping_targets(config, targets, dns.clone(), phases, resolver.clone()).await;
+    match resolver.is_available() {
+        Some(false) => {
+            warn!("Could not create a resolver from the system configuration");
+            dns.set_resolver_available(false);
+        }
+        Some(true) => dns.set_resolver_available(true),
+        None => {}
+    }
+    results
+}
+
+async fn ping_targets(
+    config: Arc<Config>,
+    targets: Vec<PingTarget>,
+    dns: Arc<DnsCache>,
+    phases: PhaseRecorder,
+    resolver: impl AddressLookup + Clone + 'static,
+) -> Vec<PingResult> {
+    let payloads: Arc<[Box<[u8]>]> = (config.ping.payloads().iter())
+        .map(|&size| {
+            let mut payload = vec![0; size].into_boxed_slice();
+            rand::thread_rng().fill_bytes(&mut payload[..]);
+            payload
+        })
+        .collect();
 
-    let mut payload = vec![0; config.ping.payload_size].into_boxed_slice();
-    rand::thread_rng().fill_bytes(&mut payload[..]);
-    let payload = Arc::new(payload);
+    // Results are reported in the given order, each target only once
+    let mut unique = targets;
+    let mut seen = HashSet::with_capacity(unique.len());
+    unique.retain(|target| seen.insert(target.clone()));
+    let order = unique.clone();
 
-    let mut set = JoinSet::<PingResult>::new();
-    for target in config.ping.servers.iter().cloned() {
+    let max_concurrent = config.ping.max_concurrent_targets;
+    let results = join_limited(unique.into_iter(), max_concurrent, |target| {
         let resolver = resolver.clone();
-        let payload = payload.clone();
+        let payloads = payloads.clone();
         let config = config.clone();
-        set.spawn(async move {
-            let mut resolver = resolver.lock().await;
-            let addr = match target.resolve(resolver.deref_mut()).await {
-                Ok(addr) => addr,
-                Err(err) => {
-                    return PingResult {
-                        target,
-                        summary: None,
-                        error: Some(err.to_string()),
-                    }
+        let dns = dns.clone();
+        let phases = phases.clone();
+        async move {
+            let resolved = match target.host() {
+                PingTarget::Ip(ip) => Ok((vec![*ip], None)),
+                PingTarget::Namespaced { .. } => unreachable!("hosts are not namespaced"),
+                PingTarget::Domain(domain) => phases
+                    .time_async("resolution", dns.resolve(&resolver, domain, &config.ping))
+                    .await
+                    .map(|resolution| (resolution.addrs, Some(resolution.changes))),
+            };
+            let resolution_changes = resolved.as_ref().ok().and_then(|(_, changes)| *changes);
+            let resolve_errors = match target.host() {
+                PingTarget::Domain(domain) => dns.resolve_errors(domain),
+                _ => BTreeMap::new(),
+            };
+            let failed = |target: PingTarget, address: Option<IpAddr>, err: PingPrepareError| {
+                warn!(%target, error = %err, "Could not prepare ping");
+                PingResult {
+                    target,
+                    summary: None,
+                    by_payload: BTreeMap::new(),
+                    error: Some(err.to_string()),
+                    resolve_error: err.resolve_reason(),
+                    error_kind: err.error_kind(),
+                    raw_samples: Vec::new(),
+                    resolution_changes,
+                    address_family: address.map(AddressFamily::of),
+                    address,
+                    resolve_errors: resolve_errors.clone(),
+                    measure_duration: None,
+                    measured_at: SystemTime::now(),
                 }
             };
-            drop(resolver);
-            let (samples, errors) =
-                sample_pings(addr, config.ping.samples, config.ping.delay, payload).await;
-            PingResult {
-                target,
-                summary: Some(PingSummary::digest_data(
-                    samples,
-                    errors,
-                    &config.ping.quantiles,
-                )),
-                error: None,
+            let addrs = match resolved {
+                Ok((addrs, _)) => addrs,
+                Err(err) => return (target.clone(), vec![failed(target, None, err)]),
+            };
+            // Every address becomes its own series, even if there is only one
+            let labeled = matches!(target.host(), PingTarget::Domain(_))
+                && config.ping.domain_resolution == DomainResolution::All;
+
+            let count = addrs.len();
+            let pings = join_limited(addrs.into_iter().enumerate(), count, |(index, addr)| {
+                let target = target.clone();
+                let config = config.clone();
+                let payloads = payloads.clone();
+                let phases = phases.clone();
+                async move {
+                    if let PingProvider::Null(provider) = &config.ping.provider {
+                        let sampled =
+                            provider.sample(config.ping.schedule(), config.ping.delay, &payloads);
+                        return (index, addr, Ok((sampled, Duration::ZERO)));
+                    }
+                    let source_port = config.ping.source_port_range.map(next_source_port);
+                    let client = target_client(&target, addr, config.ping.socket_type, source_port);
+                    let pinged = match client {
+                        Ok(client) => {
+                            let start = Instant::now();
+                            let sampled = phases
+                                .time_async(
+                                    "measurement",
+                                    sample_pings(
+                                        &client,
+                                        addr,
+                                        config.ping.schedule(),
+                                        config.ping.delay,
+                                        payloads,
+                                    ),
+                                )
+                                .await;
+                            Ok((sampled, start.elapsed()))
+                        }
+                        Err(err) => Err(err),
+                    };
+                    (index, addr, pinged)
+                }
+            })
+            .await;
+            let mut pings = pings;
+            pings.sort_unstable_by_key(|(index, ..)| *index);
+
+            let mut results = Vec::with_capacity(pings.len());
+            for (_, addr, pinged) in pings {
+                let address = labeled.then_some(addr);
+                let ((mut raw_samples, errors, reordered), measure_duration) = match pinged {
+                    Ok(pinged) => pinged,
+                    Err(err) => {
+                        results.push(failed(target.clone(), address, err));
+                        continue;
+                    }
+                };
+                let samples: Vec<f32> = raw_samples.iter().map(|sample| sample.ms).collect();
+                let by_payload = match config.ping.payload_sizes.is_empty() {
+                    true => BTreeMap::new(),
+                    false => digest_by_payload(&raw_samples, &config.ping),
+                };
+                if let PingTarget::Domain(domain) = target.host() {
+                    dns.report(domain, &samples);
+                }
+                if config.server.debug_endpoints {
+                    keep_slowest_samples(&mut raw_samples, config.ping.debug_sample_limit);
+                } else {
+                    raw_samples = Vec::new();
+                }
+                let mut summary = phases.time("digest", || {
+                    PingSummary::digest_data(
+                        samples,
+                        errors,
+                        &config.ping.quantiles,
+                        config.ping.outlier_rejection.as_ref(),
+                    )
+                });
+                summary.reordered = reordered;
+                results.push(PingResult {
+                    target: target.clone(),
+                    summary: Some(summary),
+                    by_payload,
+                    error: None,
+                    resolve_error: None,
+                    error_kind: None,
+                    raw_samples,
+                    resolution_changes,
+                    address_family: Some(AddressFamily::of(addr)),
+                    address,
+                    resolve_errors: resolve_errors.clone(),
+                    measure_duration: Some(measure_duration),
+                    measured_at: SystemTime::now(),
+                });
+            }
+            (target, results)
+        }
+    })
+    .await;
+
+    let mut by_target: HashMap<PingTarget, Vec<PingResult>> = results.into_iter().collect();
+    let results: Vec<PingResult> = order
+        .iter()
+        .filter_map(|target| by_target.remove(target))
+        .flatten()
+        .collect();
+    for result in &results {
+        info!(%result, "Ping finished");
+    }
+    results
+}
+
+/// Runs `task` for every item with at most `max_concurrent` tasks in flight
+/// and collects the results in completion order, or in the order of the items
+/// on embedded builds, which poll them on the current task instead of
+/// spawning them.
+async fn join_limited<T, R, F, Fut>(
+    items: impl ExactSizeIterator<Item = T>,
+    max_concurrent: usize,
+    task: F,
+) -> Vec<R>
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = R> + Send + 'static,
+    R: Send + 'static,
+{
+    let semaphore = Arc::new(Semaphore::new(max_concurrent.max(1)));
+
+    #[cfg(feature = "embedded")]
+    {
+        let futures = items.map(task).map(|future| {
+            let semaphore = semaphore.clone();
+            async move {
+                let _permit = semaphore.acquire_owned().await.unwrap();
+                future.await
             }
         });
+        futures_util::future::join_all(futures).await
+    }
+
+    #[cfg(not(feature = "embedded"))]
+    {
+        join_spawned(items, semaphore, task).await
+    }
+}
+
+#[cfg(not(feature = "embedded"))]
+async fn join_spawned<T, R, F, Fut>(
+    items: impl ExactSizeIterator<Item = T>,
+    semaphore: Arc<Semaphore>,
+    mut task: F,
+) -> Vec<R>
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = R> + Send + 'static,
+    R: Send + 'static,
+{
+    let mut results = Vec::with_capacity(items.len());
+
+    let mut set = JoinSet::<R>::new();
+    for item in items {
+        let permit = semaphore.clone().acquire_owned().await.unwrap();
+        let future = task(item);
+        set.spawn(async move {
+            let result = future.await;
+            drop(permit);
+            result
+        });
     }
 
-    let mut results = Vec::with_capacity(config.ping.servers.len());
     while let Some(join_result) = set.join_next().await {
-        let result = join_result.unwrap();
-        results.push(result);
+        results.push(join_result.unwrap());
+    }
+    results
+}
+
+/// How many pings are sent to each target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PingSchedule {
+    /// A fixed number of pings.
+    Samples(usize),
+    /// As many pings as fit into the window at the configured delay.
+    Duration(Duration),
+}
+
+/// A single ping of a measurement.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PingSample {
+    pub seq: usize,
+    /// Time since the start of the measurement at which the ping was sent
+    #[serde(serialize_with = "serialize_duration_secs")]
+    pub offset: Duration,
+    /// Round trip time in milliseconds, `NaN` if lost
+    pub ms: f32,
+    /// Size of the payload in bytes
+    pub payload: usize,
+}
+
+fn serialize_duration_secs<S: Serializer>(
+    duration: &Duration,
+    ser: S,
+) -> Result<<S as Serializer>::Ok, <S as Serializer>::Error> {
+    ser.serialize_f64(duration.as_secs_f64())
+}
+
+/// Time to wait for outstanding replies after a [`PingSchedule::Duration`]
+/// window has closed.
+const DURATION_TIMEOUT_GRACE: Duration = Duration::from_secs(1);
+
+/// Type of socket used for sending pings
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IcmpSocketType {
+    /// Tries an unprivileged datagram socket first and falls back to a raw
+    /// socket. Raw sockets are preferred on Windows, which lacks the former.
+    #[default]
+    Auto,
+    Raw,
+    Dgram,
+}
+
+impl IcmpSocketType {
+    fn hint(self) -> socket2::Type {
+        match self {
+            Self::Auto if cfg!(windows) => socket2::Type::RAW,
+            Self::Auto | Self::Dgram => socket2::Type::DGRAM,
+            Self::Raw => socket2::Type::RAW,
+        }
     }
 
-    Ok(results)
+    fn from_socket(typ: socket2::Type) -> Self {
+        if typ == socket2::Type::RAW {
+            Self::Raw
+        } else {
+            Self::Dgram
+        }
+    }
+}
+
+impl Display for IcmpSocketType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Auto => "auto",
+            Self::Raw => "raw",
+            Self::Dgram => "dgram",
+        })
+    }
+}
+
+/// Privileges required to open an ICMP socket on this platform.
+pub const ICMP_PRIVILEGES: &str = if cfg!(windows) {
+    "raw ICMP sockets require running as administrator"
+} else if cfg!(target_os = "macos") {
+    "unprivileged ICMP datagram sockets should be available, raw sockets require root"
+} else if cfg!(target_os = "linux") {
+    "raw sockets require CAP_NET_RAW, datagram sockets require the group ID \
+    to be within the sysctl net.ipv4.ping_group_range"
+} else {
+    "raw ICMP sockets usually require root"
+};
+
+/// Offset of the next source port within `ping.source_port_range`, shared by
+/// all pings so that concurrent measurements never bind the same port
+static NEXT_SOURCE_PORT: AtomicU16 = AtomicU16::new(0);
+
+/// Picks the next port of the inclusive range `(first, last)`. Ports are only
+/// unique among as many concurrent pings as the range is long.
+fn next_source_port((first, last): (u16, u16)) -> u16 {
+    let len = u32::from(last - first) + 1;
+    // Wrapping at the length instead of 65536 keeps the order of the ports
+    // for lengths that do not divide it
+    let offset = NEXT_SOURCE_PORT
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |offset| {
+            Some(((u32::from(offset) + 1) % len) as u16)
+        })
+        .unwrap();
+    // The offset may stem from a longer range before a reload
+    first + (u32::from(offset) % len) as u16
+}
+
+/// Creates an ICMP client for the address family of `addr`, bound to
+/// `source_port` if given.
+fn icmp_client(
+    addr: IpAddr,
+    socket_type: IcmpSocketType,
+    source_port: Option<u16>,
+) -> io::Result<surge_ping::Client> {
+    let (kind, unspecified) = match addr {
+        IpAddr::V4(_) => (surge_ping::ICMP::V4, IpAddr::from(Ipv4Addr::UNSPECIFIED)),
+        IpAddr::V6(_) => (surge_ping::ICMP::V6, IpAddr::from(Ipv6Addr::UNSPECIFIED)),
+    };
+    let mut builder = surge_ping::Config::builder()
+        .kind(kind)
+        .sock_type_hint(socket_type.hint());
+    if let Some(port) = source_port {
+        builder = builder.bind(SocketAddr::new(unspecified, port));
+    }
+    let config = builder.build();
+    // surge_ping silently falls back to the other socket type
+    let client = surge_ping::Client::new(&config)?;
+    let opened = IcmpSocketType::from_socket(client.get_socket().get_type());
+    if socket_type != IcmpSocketType::Auto && opened != socket_type {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("could not open {socket_type} socket"),
+        ));
+    }
+    Ok(client)
+}
+
+/// Creates an ICMP client inside the network namespace of `target`, if any.
+fn target_client(
+    target: &PingTarget,
+    addr: IpAddr,
+    socket_type: IcmpSocketType,
+    source_port: Option<u16>,
+) -> Result<surge_ping::Client, PingPrepareError> {
+    let client = match target.netns() {
+        Some(name) => netns::in_namespace(name, || icmp_client(addr, socket_type, source_port))
+            .map_err(|error| PingPrepareError::Namespace {
+                name: name.to_owned(),
+                error,
+            })?,
+        None => icmp_client(addr, socket_type, source_port),
+    };
+    client.map_err(PingPrepareError::Socket)
+}
+
+/// Checks whether ICMP sockets can be opened and logs the chosen socket type,
+/// or the required privileges otherwise. Pings will fail, but speedtests
+/// remain functional.
+pub(crate) fn preflight(socket_type: IcmpSocketType) {
+    match check_icmp(socket_type) {
+        Ok(opened) => info!(%opened, "ICMP sockets are available"),
+        Err(error) => warn!(%error, "ICMP sockets are unavailable, {ICMP_PRIVILEGES}"),
+    }
+}
+
+/// Opens an ICMP socket and returns its type
+pub(crate) fn check_icmp(socket_type: IcmpSocketType) -> io::Result<IcmpSocketType> {
+    let client = icmp_client(Ipv4Addr::LOCALHOST.into(), socket_type, None)?;
+    Ok(IcmpSocketType::from_socket(client.get_socket().get_type()))
+}
+
+/// Writes `exporter_icmp_socket_info{type}` with the type of socket that pings
+/// are sent from, nothing if they cannot be opened or the `Null` provider
+/// answers the pings
+pub(crate) fn write_icmp_socket_info(builder: &mut ExpositionBuilder, config: &PingConfig) {
+    if let PingProvider::Null(_) = config.provider {
+        return;
+    }
+    let Ok(opened) = check_icmp(config.socket_type) else {
+        return;
+    };
+    builder.add_metric(
+        PName::new("exporter_icmp_socket_info").unwrap(),
+        MetricType::Gauge,
+        "type of ICMP socket that pings are sent from",
+        |mut builder| {
+            builder.add_line_labeled(
+                PName::new("type").unwrap(),
+                opened.to_string().as_str(),
+                &1,
+                None,
+            );
+        },
+    );
 }
 
 async fn sample_pings(
+    client: &surge_ping::Client,
     addr: IpAddr,
-    samples: usize,
+    schedule: PingSchedule,
     delay: Duration,
-    payload: Arc<Box<[u8]>>,
-) -> (Vec<f32>, Vec<PingErrorKind>) {
-    if samples == 0 {
-        return (Vec::new(), Vec::new());
-    }
-    let client = surge_ping::Client::new(&surge_ping::ConfigBuilder::default().build()).unwrap();
+    payloads: Arc<[Box<[u8]>]>,
+) -> (Vec<PingSample>, Vec<PingErrorKind>, u32) {
+    let capacity = match schedule {
+        PingSchedule::Samples(0) => return (Vec::new(), Vec::new(), 0),
+        PingSchedule::Samples(samples) => samples,
+        PingSchedule::Duration(duration) => {
+            (duration.as_secs_f64() / delay.as_secs_f64()).ceil() as usize
+        }
+    };
+    let start_time = Instant::now();
     let mut seq = 0;
+    let mut results = Vec::with_capacity(capacity);
 
     let mut set = JoinSet::<(usize, Result<(IcmpPacket, Duration), SurgeError>)>::new();
     loop {
         let mut pinger = client.pinger(addr, PingIdentifier(0)).await;
-        let payload = payload.clone();
+        let payloads = payloads.clone();
+        let payload = seq % payloads.len();
+        results.push(PingSample {
+            seq,
+            offset: start_time.elapsed(),
+            ms: f32::NAN,
+            payload: payloads[payload].len(),
+        });
         set.spawn(async move {
             (
                 seq,
-                pinger.ping(PingSequence(seq as u16), &payload[..]).await,
+                (pinger.ping(PingSequence(seq as u16), &payloads[payload][..])).await,
             )
         });
 
         seq += 1;
 
-        if seq < samples {
-            tokio::time::sleep(delay).await;
-        } else {
+        let finished = match schedule {
+            PingSchedule::Samples(samples) => seq >= samples,
+            PingSchedule::Duration(duration) => start_time.elapsed() + delay >= duration,
+        };
+        if finished {
             break;
+        } else {
+            tokio::time::sleep(delay).await;
         }
     }
-    let mut results = vec![f32::NAN; samples];
-    let mut errors = Vec::with_capacity(samples);
+    let deadline = match schedule {
+        PingSchedule::Samples(_) => None,
+        PingSchedule::Duration(duration) => Some(start_time + duration + DURATION_TIMEOUT_GRACE),
+    };
+    // Errors are rare, most of the time this never allocates
+    let mut errors = Vec::new();
+    // Replies complete in the order they arrive, a reply to an earlier ping
+    // than the latest answered one was overtaken
+    let mut latest_reply = None;
+    let mut reordered = 0;
 
-    while let Some(join_result) = set.join_next().await {
+    loop {
+        let join_result = match deadline {
+            Some(deadline) => {
+                match tokio::time::timeout_at(deadline.into(), set.join_next()).await {
+                    Ok(join_result) => join_result,
+                    Err(_) => {
+                        // Replies not received within the grace period are lost
+                        errors.extend((0..set.len()).map(|_| PingErrorKind::Timeout {}));
+                        set.abort_all();
+                        break;
+                    }
+                }
+            }
+            None => set.join_next().await,
+        };
+        let Some(join_result) = join_result else {
+            break;
+        };
         let result = join_result.unwrap();
         match result {
             (seq, Ok((_packet, duration))) => {
-                results[seq] = duration.as_secs_f32() * 1000.;
+                results[seq].ms = duration.as_secs_f32() * 1000.;
+                match latest_reply {
+                    Some(latest) if seq < latest => reordered += 1,
+                    _ => latest_reply = Some(seq),
+                }
             }
             (_, Err(err)) => {
                 errors.push(err.into());
@@ -118,15 +592,39 @@ async fn sample_pings(
         };
     }
 
-    (results, errors)
+    (results, errors, reordered)
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(untagged)]
 pub enum PingTarget {
     Ip(IpAddr),
     Domain(String),
+    /// Pinged from within a named network namespace, see [`netns`]. The
+    /// domain of `target` is still resolved in the namespace of the exporter.
+    Namespaced {
+        netns: String,
+        target: Box<PingTarget>,
+    },
+}
+
+impl PingTarget {
+    /// Address or domain of the target, without its network namespace
+    pub fn host(&self) -> &PingTarget {
+        match self {
+            Self::Namespaced { target, .. } => target.host(),
+            target => target,
+        }
+    }
+
+    /// Network namespace to ping from, the one of the exporter if `None`
+    pub fn netns(&self) -> Option<&str> {
+        match self {
+            Self::Namespaced { netns, .. } => Some(netns),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -135,18 +633,91 @@ pub enum PingPrepareError {
     ResolveError(#[from] ResolveError),
     #[error("no IP address found")]
     NoIp,
+    #[error("could not open ICMP socket: {0} ({ICMP_PRIVILEGES})")]
+    Socket(io::Error),
+    #[error("could not enter network namespace {name}: {error}")]
+    Namespace { name: String, error: io::Error },
 }
 
-impl PingTarget {
-    pub async fn resolve(&self, resolver: &mut Resolver) -> Result<IpAddr, PingPrepareError> {
+/// Closed set of reasons why a target could not be resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResolveErrorReason {
+    Nxdomain,
+    Timeout,
+    Servfail,
+    NoIp,
+    Other,
+}
+
+impl ResolveErrorReason {
+    pub fn as_str(self) -> &'static str {
         match self {
-            Self::Ip(ip) => Ok(*ip),
-            Self::Domain(domain) => resolver
-                .lookup_ip(domain)
-                .await?
-                .into_iter()
-                .next()
-                .ok_or(PingPrepareError::NoIp),
+            Self::Nxdomain => "nxdomain",
+            Self::Timeout => "timeout",
+            Self::Servfail => "servfail",
+            Self::NoIp => "no_ip",
+            Self::Other => "other",
+        }
+    }
+}
+
+impl From<&ResolveError> for ResolveErrorReason {
+    fn from(error: &ResolveError) -> Self {
+        match error.kind() {
+            ResolveErrorKind::NoRecordsFound { response_code, .. } => match *response_code {
+                ResponseCode::NXDomain => Self::Nxdomain,
+                ResponseCode::ServFail => Self::Servfail,
+                ResponseCode::NoError => Self::NoIp,
+                _ => Self::Other,
+            },
+            ResolveErrorKind::Timeout => Self::Timeout,
+            ResolveErrorKind::Proto(error) if error.kind().is_timeout() => Self::Timeout,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl PingPrepareError {
+    /// Category of the error if the target could not be resolved
+    pub fn resolve_reason(&self) -> Option<ResolveErrorReason> {
+        match self {
+            Self::ResolveError(error) => Some(error.into()),
+            Self::NoIp => Some(ResolveErrorReason::NoIp),
+            Self::Socket(_) | Self::Namespace { .. } => None,
+        }
+    }
+
+    /// Category of the error if it is not a resolution error
+    pub fn error_kind(&self) -> Option<PingErrorKind> {
+        match self {
+            Self::Socket(error) | Self::Namespace { error, .. } => {
+                Some(PingErrorKind::IOError { kind: error.kind() })
+            }
+            Self::ResolveError(_) | Self::NoIp => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    pub fn of(addr: IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(_) => Self::Ipv4,
+            IpAddr::V6(_) => Self::Ipv6,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Ipv4 => "ipv4",
+            Self::Ipv6 => "ipv6",
         }
     }
 }
@@ -156,6 +727,7 @@ impl Display for PingTarget {
         match self {
             Self::Ip(ip) => <IpAddr as Display>::fmt(ip, f),
             Self::Domain(domain) => f.write_str(domain),
+            Self::Namespaced { netns, target } => write!(f, "{netns}/{target}"),
         }
     }
 }
@@ -165,47 +737,416 @@ pub struct PingResult {
     target: PingTarget,
     #[serde(skip_serializing_if = "Option::is_none")]
     summary: Option<PingSummary>,
+    /// Summaries of the pings of each size if `ping.payload_sizes` is set.
+    /// Errors are only part of `summary`, since they cannot be attributed to
+    /// a ping.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    by_payload: BTreeMap<usize, PingSummary>,
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
+    /// Individual pings, only kept if `server.debug_endpoints` is enabled
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    raw_samples: Vec<PingSample>,
+    /// How often the resolved address of a domain target changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resolution_changes: Option<u64>,
+    /// Family of the pinged address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address_family: Option<AddressFamily>,
+    /// Pinged address of a domain target with `ping.domain_resolution` set to
+    /// `all`, which turns every address into its own result
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<IpAddr>,
+    /// Category of `error` if the target could not be resolved
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resolve_error: Option<ResolveErrorReason>,
+    /// Category of `error` if it is not a resolution error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_kind: Option<PingErrorKind>,
+    /// Resolution failures of a domain target since startup
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    resolve_errors: BTreeMap<ResolveErrorReason, u64>,
+    /// Wall time of pinging the address, absent if it was never pinged. Only
+    /// part of [`JsonSchema::V2`].
+    #[serde(skip)]
+    measure_duration: Option<Duration>,
+    /// When the measurement of the target finished, only part of
+    /// [`JsonSchema::V2`]
+    #[serde(skip)]
+    measured_at: SystemTime,
+}
+
+/// [`PingResult`] in [`JsonSchema::V2`]
+#[derive(Debug, Serialize)]
+pub struct PingResultJsonV2<'a> {
+    target: &'a PingTarget,
+    #[serde(serialize_with = "serialize_timestamp")]
+    measured_at: SystemTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<PingSummaryJsonV2<'a>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    by_payload: BTreeMap<usize, PingSummaryJsonV2<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    raw_samples: &'a [PingSample],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resolution_changes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address_family: Option<AddressFamily>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resolve_error: Option<ResolveErrorReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_kind: Option<PingErrorKind>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    resolve_errors: &'a BTreeMap<ResolveErrorReason, u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    measure_duration_seconds: Option<f64>,
+}
+
+/// [`PingSummary`] in [`JsonSchema::V2`]
+#[derive(Debug, Serialize)]
+pub struct PingSummaryJsonV2<'a> {
+    #[serde(serialize_with = "serialize_quantile_map")]
+    quantiles: &'a [(f64, f32)],
+    mean_ms: f32,
+    stddev: f32,
+    sum: f32,
+    count: usize,
+    sent: usize,
+    loss_percent: f32,
+    outliers_dropped: usize,
+    #[serde(serialize_with = "serialize_error_kind_map")]
+    errors: &'a BTreeMap<PingErrorKind, u32>,
+    reordered: u32,
+}
+
+/// Serializes ping results in the given schema
+pub fn results_to_versioned_json(
+    results: &[PingResult],
+    schema: JsonSchema,
+) -> Versioned<&[PingResult], Vec<PingResultJsonV2<'_>>> {
+    match schema {
+        JsonSchema::V1 => Versioned::V1(results),
+        JsonSchema::V2 => Versioned::V2(results.iter().map(PingResult::to_json_v2).collect()),
+    }
+}
+
+/// Table of the results for terminals, latencies in milliseconds
+pub(crate) fn results_to_table(results: &[PingResult], renderer: &AnsiRenderer) -> Table {
+    let header = ["target", "mean ms", "p50 ms", "p90 ms", "loss"].map(|it| renderer.header(it));
+    let mut table = Table::new(header.to_vec());
+    for result in results {
+        let mut row = vec![result.display_target()];
+        match (&result.summary, &result.error) {
+            (Some(summary), _) => {
+                let quantile = |q| find_quantile(&summary.quantiles, q).map(f64::from);
+                row.extend([
+                    renderer.latency(Some(summary.mean_ms.into())),
+                    renderer.latency(quantile(0.5)),
+                    renderer.latency(quantile(0.9)),
+                    renderer.loss(summary.loss_percent.into()),
+                ]);
+            }
+            (None, Some(error)) => row.push(renderer.error(format!("error: {error}"))),
+            (None, None) => row.push("no data".to_owned()),
+        }
+        table.push(row);
+    }
+    table
 }
 
 impl PingResult {
-    pub fn write_prometheus(&self, builder: &mut ExpositionBuilder) {
+    /// Creates a result from preconfigured samples instead of measuring.
+    #[cfg(any(test, speedtest_bench))]
+    pub fn mock(target: PingTarget, samples: Vec<f32>, config: &PingConfig) -> Self {
+        let address_family = match target.host() {
+            PingTarget::Ip(ip) => Some(AddressFamily::of(*ip)),
+            _ => None,
+        };
+        Self {
+            target,
+            summary: Some(PingSummary::digest_data(
+                samples,
+                Vec::new(),
+                &config.quantiles,
+                config.outlier_rejection.as_ref(),
+            )),
+            by_payload: BTreeMap::new(),
+            error: None,
+            resolve_error: None,
+            error_kind: None,
+            raw_samples: Vec::new(),
+            resolution_changes: None,
+            address_family,
+            address: None,
+            resolve_errors: BTreeMap::new(),
+            measure_duration: None,
+            measured_at: SystemTime::now(),
+        }
+    }
+
+    pub fn to_json_v2(&self) -> PingResultJsonV2<'_> {
+        PingResultJsonV2 {
+            target: &self.target,
+            measured_at: self.measured_at,
+            summary: self.summary.as_ref().map(PingSummary::to_json_v2),
+            by_payload: (self.by_payload.iter())
+                .map(|(payload, summary)| (*payload, summary.to_json_v2()))
+                .collect(),
+            error: self.error.as_deref(),
+            raw_samples: &self.raw_samples,
+            resolution_changes: self.resolution_changes,
+            address_family: self.address_family,
+            address: self.address,
+            resolve_error: self.resolve_error,
+            error_kind: self.error_kind,
+            resolve_errors: &self.resolve_errors,
+            measure_duration_seconds: self.measure_duration.map(|it| it.as_secs_f64()),
+        }
+    }
+
+    /// Whether any ping to the target was answered
+    pub fn is_reachable(&self) -> bool {
+        self.summary
+            .as_ref()
+            .is_some_and(|summary| summary.loss_percent < 1.)
+    }
+
+    pub fn write_prometheus(&self, builder: &mut ExpositionBuilder, config: &PingConfig) {
+        self.with_target_labels(builder, |builder| {
+            self.write_target_metrics(builder, config)
+        })
+    }
+
+    /// Writes every individual ping as `ping_sample_ms{seq}`, which is only
+    /// requested explicitly because of its cardinality. Pings are only kept
+    /// if `server.debug_endpoints` is enabled.
+    pub(crate) fn write_raw_prometheus(&self, builder: &mut ExpositionBuilder) {
+        if !self.raw_samples.is_empty() {
+            self.with_target_labels(builder, |builder| self.write_raw_samples(builder));
+        }
+    }
+
+    /// Runs `write` with the `target`, `netns` and `address` labels of this
+    /// result
+    pub(crate) fn with_target_labels<R>(
+        &self,
+        builder: &mut ExpositionBuilder,
+        write: impl FnOnce(&mut ExpositionBuilder) -> R,
+    ) -> R {
+        let write = |builder: &mut ExpositionBuilder| match self.address {
+            Some(address) => builder.with_label(
+                PName::new("address").unwrap(),
+                address.to_string().as_str(),
+                write,
+            ),
+            None => write(builder),
+        };
         builder.with_label(
             PName::new("target").unwrap(),
-            self.target.to_string().as_str(),
-            |builder| {
-                if let Some(summary) = &self.summary {
-                    summary.write_prometheus(builder);
-                }
+            self.target.host().to_string().as_str(),
+            |builder| match self.target.netns() {
+                Some(netns) => builder.with_label(PName::new("netns").unwrap(), netns, write),
+                None => write(builder),
+            },
+        )
+    }
+
+    /// Target along with the pinged address if it is labeled with it
+    fn display_target(&self) -> String {
+        match self.address {
+            Some(address) => format!("{} ({address})", self.target),
+            None => self.target.to_string(),
+        }
+    }
+
+    fn write_target_metrics(&self, builder: &mut ExpositionBuilder, config: &PingConfig) {
+        let schedule = config.schedule();
+        match schedule {
+            PingSchedule::Samples(samples) => builder.add_metric(
+                PName::new("ping_configured_samples").unwrap(),
+                MetricType::Gauge,
+                "configured number of ping samples",
+                |mut builder| builder.add_line(&samples, None),
+            ),
+            PingSchedule::Duration(duration) => builder.add_metric_with_unit(
+                PName::new("ping_configured_duration_seconds").unwrap(),
+                MetricType::Gauge,
+                Some(PName::UNIT_SECONDS),
+                "configured ping measurement window in seconds",
+                |mut builder| builder.add_line(&duration.as_secs_f64(), None),
+            ),
+        }
+
+        builder.add_metric_with_unit(
+            PName::new("ping_configured_delay_seconds").unwrap(),
+            MetricType::Gauge,
+            Some(PName::UNIT_SECONDS),
+            "configured delay between pings in seconds",
+            |mut builder| builder.add_line(&config.delay.as_secs_f64(), None),
+        );
+
+        if let Some(duration) = self.measure_duration {
+            builder.add_metric_with_unit(
+                PName::new("ping_measure_duration_seconds").unwrap(),
+                MetricType::Gauge,
+                Some(PName::UNIT_SECONDS),
+                "wall time of pinging the target in seconds",
+                |mut builder| builder.add_line(&duration.as_secs_f64(), None),
+            );
+        }
+
+        if let Some(summary) = &self.summary {
+            summary.write_prometheus(builder, &config.quantiles);
+            if let Some(threshold) = config.latency_threshold_ms(&self.target) {
+                builder.add_metric(
+                    PName::new("ping_over_threshold").unwrap(),
+                    MetricType::Gauge,
+                    "whether the mean or p99 latency exceeds the configured threshold",
+                    |mut builder| {
+                        builder.add_line(&u8::from(summary.exceeds(threshold)), None);
+                    },
+                );
+            }
+        }
+        for (payload, summary) in &self.by_payload {
+            builder.with_label(
+                PName::new("payload").unwrap(),
+                payload.to_string().as_str(),
+                |builder| summary.write_latency(builder, &config.quantiles),
+            );
+        }
+        self.write_measurement_info(builder, schedule);
+
+        if let Some(changes) = &self.resolution_changes {
+            builder.add_metric(
+                PName::new("ping_resolution_changes_total").unwrap(),
+                MetricType::Counter,
+                "number of times the resolved address of the target changed",
+                |mut builder| builder.add_line(changes, None),
+            );
+        }
 
-                if let Some(error) = &self.error {
-                    builder.add_metric(
-                        PName::new("ping_error").unwrap(),
-                        MetricType::Gauge,
-                        "ping error",
-                        |mut builder| {
-                            builder.add_line_labeled(
-                                PName::new("error").unwrap(),
-                                error.as_str(),
-                                &1,
-                                None,
-                            );
-                        },
+        if let Some(family) = self.address_family {
+            builder.add_metric(
+                PName::new("ping_address_family").unwrap(),
+                MetricType::Gauge,
+                "address family of the pinged address",
+                |mut builder| {
+                    builder.add_line_labeled(
+                        PName::new("family").unwrap(),
+                        family.as_str(),
+                        &1,
+                        None,
                     );
+                },
+            );
+        }
+
+        if !self.resolve_errors.is_empty() {
+            builder.add_metric(
+                PName::new("ping_resolve_errors_total").unwrap(),
+                MetricType::Counter,
+                "number of failed resolutions of the target by reason",
+                |mut builder| {
+                    for (reason, count) in &self.resolve_errors {
+                        builder.add_line_labeled(
+                            PName::new("reason").unwrap(),
+                            reason.as_str(),
+                            count,
+                            None,
+                        );
+                    }
+                },
+            );
+        }
+
+        // Only the categories are exposed, error messages contain
+        // varying details
+        if let Some(kind) = self.error_kind {
+            builder.add_metric(
+                PName::new("ping_error").unwrap(),
+                MetricType::Gauge,
+                "ping error",
+                |mut builder| {
+                    kind.write_labeled(&mut builder, |builder| builder.add_line(&1, None));
+                },
+            );
+        }
+    }
+
+    /// Condenses how trustworthy the measurement is into a single family:
+    /// replies collected, errors encountered and whether every scheduled
+    /// ping was sent.
+    fn write_measurement_info(&self, builder: &mut ExpositionBuilder, schedule: PingSchedule) {
+        let (samples, errors, complete) = match &self.summary {
+            Some(summary) => (
+                summary.count,
+                summary.errors.values().map(|&count| count as usize).sum(),
+                match schedule {
+                    PingSchedule::Samples(samples) => summary.sent >= samples,
+                    PingSchedule::Duration(_) => true,
+                },
+            ),
+            None => (0, usize::from(self.error.is_some()), false),
+        };
+        builder.add_metric(
+            PName::new("ping_measurement_info").unwrap(),
+            MetricType::Gauge,
+            "measurement quality: samples collected, errors and whether the schedule completed",
+            |mut builder| {
+                let field = PName::new("field").unwrap();
+                builder.add_line_labeled(field, "samples", &samples, None);
+                builder.add_line_labeled(field, "errors", &errors, None);
+                builder.add_line_labeled(field, "complete", &u8::from(complete), None);
+            },
+        );
+    }
+
+    fn write_raw_samples(&self, builder: &mut ExpositionBuilder) {
+        let seq = PName::new("seq").unwrap();
+        let sample_ms = builder.metric_name(PName::new("ping_sample_ms").unwrap());
+        builder.add_metric_with_unit(
+            sample_ms.name,
+            MetricType::Gauge,
+            sample_ms.unit,
+            "round trip time of a single ping",
+            |mut builder| {
+                for sample in &self.raw_samples {
+                    builder.add_line_labeled(seq, &sample.seq, &sample_ms.value(sample.ms), None);
                 }
             },
-        )
+        );
+
+        builder.add_metric_with_unit(
+            PName::new("ping_sample_offset_seconds").unwrap(),
+            MetricType::Gauge,
+            Some(PName::UNIT_SECONDS),
+            "time since the start of the measurement at which a single ping was sent",
+            |mut builder| {
+                for sample in &self.raw_samples {
+                    builder.add_line_labeled(seq, &sample.seq, &sample.offset.as_secs_f64(), None);
+                }
+            },
+        );
     }
 }
 
-#[derive(Debug, Error, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+/// Errors of individual pings. The identifiers returned by
+/// [`as_str`](Self::as_str) are part of the exposition and JSON API and must
+/// not change. Errors are reported in the order of the variants.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum PingErrorKind {
     #[error("buffer size was too small")]
     IncorrectBufferSize,
     #[error("malformed packet")]
     MalformedPacket,
     #[error("io error: {kind}")]
+    #[serde(rename = "io_error")]
     IOError {
         #[serde(serialize_with = "serialize_error_kind")]
         kind: io::ErrorKind,
@@ -223,25 +1164,141 @@ pub enum PingErrorKind {
     IdenticalRequests,
 }
 
+impl PingErrorKind {
+    /// Stable identifier used as the `error` label and JSON key
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::IncorrectBufferSize => "incorrect_buffer_size",
+            Self::MalformedPacket => "malformed_packet",
+            Self::IOError { .. } => "io_error",
+            Self::Timeout {} => "timeout",
+            Self::EchoRequestPacket => "echo_request_packet",
+            Self::NetworkError => "network_error",
+            Self::IdenticalRequests => "identical_requests",
+        }
+    }
+
+    /// Stable identifier of the underlying [`io::ErrorKind`], used as the
+    /// `io_kind` label
+    pub fn io_kind(self) -> Option<String> {
+        match self {
+            Self::IOError { kind } => Some(io_kind_identifier(kind)),
+            _ => None,
+        }
+    }
+
+    /// JSON key of the error, the `io_kind` is appended after a colon
+    fn json_key(self) -> String {
+        match self.io_kind() {
+            Some(io_kind) => format!("{}:{io_kind}", self.as_str()),
+            None => self.as_str().to_owned(),
+        }
+    }
+
+    fn write_labeled<R>(
+        self,
+        builder: &mut ExpositionMetricBuilder<'_, '_>,
+        closure: impl FnOnce(&mut ExpositionMetricBuilder<'_, '_>) -> R,
+    ) -> R {
+        builder.with_label(
+            PName::new("error").unwrap(),
+            self.as_str(),
+            |builder| match self.io_kind() {
+                Some(io_kind) => {
+                    builder.with_label(PName::new("io_kind").unwrap(), io_kind.as_str(), closure)
+                }
+                None => closure(builder),
+            },
+        )
+    }
+}
+
+/// Converts the `CamelCase` variant name of `kind` to `snake_case`. The set
+/// of variants is closed, so is the set of identifiers.
+fn io_kind_identifier(kind: io::ErrorKind) -> String {
+    let name = format!("{kind:?}");
+    let mut identifier = String::with_capacity(name.len() + 4);
+    for (i, ch) in name.chars().enumerate() {
+        if ch.is_ascii_uppercase() {
+            if i != 0 {
+                identifier.push('_');
+            }
+            identifier.push(ch.to_ascii_lowercase());
+        } else {
+            identifier.push(ch);
+        }
+    }
+    identifier
+}
+
 impl From<SurgeError> for PingErrorKind {
     fn from(value: SurgeError) -> Self {
         match value {
             SurgeError::IncorrectBufferSize => Self::IncorrectBufferSize,
             SurgeError::MalformedPacket(..) => Self::MalformedPacket,
-            SurgeError::IOError(err) => Self::IOError { kind: err.kind() },
+            SurgeError::IOError(err) => Self::from(err),
             SurgeError::Timeout { .. } => Self::Timeout { /*seq*/ },
             SurgeError::EchoRequestPacket => Self::EchoRequestPacket,
             SurgeError::NetworkError => Self::NetworkError,
             SurgeError::IdenticalRequests { .. } => Self::IdenticalRequests,
+            // The socket of the client is no longer read from
+            SurgeError::ClientDestroyed => Self::NetworkError,
         }
     }
 }
 
+impl From<io::Error> for PingErrorKind {
+    fn from(value: io::Error) -> Self {
+        Self::IOError { kind: value.kind() }
+    }
+}
+
 fn serialize_error_kind<S: Serializer>(
     kind: &io::ErrorKind,
     ser: S,
 ) -> Result<<S as Serializer>::Ok, <S as Serializer>::Error> {
-    ser.serialize_str(&kind.to_string())
+    ser.serialize_str(&io_kind_identifier(*kind))
+}
+
+/// Excludes samples beyond a number of median absolute deviations from the
+/// mean and standard deviation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutlierRejection {
+    pub max_deviations: f32,
+    /// Also excludes outliers from the quantiles, sum and count
+    #[serde(default)]
+    pub apply_to_quantiles: bool,
+}
+
+impl OutlierRejection {
+    /// Returns the samples without outliers, or `None` if nothing was
+    /// rejected. `sorted` must be sorted and free of `NaN`s.
+    fn apply(&self, sorted: &[f32]) -> Option<Vec<f32>> {
+        let center = median(sorted)?;
+        let mut deviations: Vec<f32> = sorted.iter().map(|x| (x - center).abs()).collect();
+        deviations.sort_unstable_by(f32::total_cmp);
+        let mad = median(&deviations)?;
+        if mad == 0. {
+            return None;
+        }
+        let limit = self.max_deviations * mad;
+        let kept: Vec<f32> = sorted
+            .iter()
+            .copied()
+            .filter(|x| (x - center).abs() <= limit)
+            .collect();
+        (!kept.is_empty() && kept.len() < sorted.len()).then_some(kept)
+    }
+}
+
+fn median(sorted: &[f32]) -> Option<f32> {
+    let n = sorted.len();
+    match n {
+        0 => None,
+        _ if n % 2 == 1 => Some(sorted[n / 2]),
+        _ => Some((sorted[n / 2 - 1] + sorted[n / 2]) / 2.),
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -251,13 +1308,19 @@ pub struct PingSummary {
     pub stddev: f32,
     pub sum: f32,
     pub count: usize,
+    pub sent: usize,
     pub loss_percent: f32,
+    pub outliers_dropped: usize,
     #[serde(serialize_with = "serialize_error_kind_map")]
-    pub errors: HashMap<PingErrorKind, u32>,
+    pub errors: BTreeMap<PingErrorKind, u32>,
+    /// Replies that arrived after the reply to a later ping, only part of
+    /// [`JsonSchema::V2`]
+    #[serde(skip)]
+    pub reordered: u32,
 }
 
 fn serialize_error_kind_map<S: Serializer>(
-    map: &HashMap<PingErrorKind, u32>,
+    map: &BTreeMap<PingErrorKind, u32>,
     ser: S,
 ) -> Result<<S as Serializer>::Ok, <S as Serializer>::Error> {
     use serde::ser::SerializeMap as _;
@@ -265,24 +1328,43 @@ fn serialize_error_kind_map<S: Serializer>(
     let map = map.iter();
     let mut map_ser = ser.serialize_map(Some(map.size_hint().0))?;
     for (k, v) in map {
-        map_ser.serialize_entry(&k.to_string(), v)?;
+        map_ser.serialize_entry(&k.json_key(), v)?;
     }
     map_ser.end()
 }
 
 impl PingSummary {
+    /// Whether the mean or the p99 latency, if it is one of the quantiles,
+    /// exceeds `threshold_ms`
+    pub fn exceeds(&self, threshold_ms: f32) -> bool {
+        self.mean_ms > threshold_ms
+            || find_quantile(&self.quantiles, 0.99).is_some_and(|p99| p99 > threshold_ms)
+    }
+
+    pub fn to_json_v2(&self) -> PingSummaryJsonV2<'_> {
+        PingSummaryJsonV2 {
+            quantiles: &self.quantiles,
+            mean_ms: self.mean_ms,
+            stddev: self.stddev,
+            sum: self.sum,
+            count: self.count,
+            sent: self.sent,
+            loss_percent: self.loss_percent,
+            outliers_dropped: self.outliers_dropped,
+            errors: &self.errors,
+            reordered: self.reordered,
+        }
+    }
+
     pub fn digest_data(
         mut samples: Vec<f32>,
         errors: Vec<PingErrorKind>,
         quantiles: &[f64],
+        outlier_rejection: Option<&OutlierRejection>,
     ) -> Self {
-        let mut error_buckets = HashMap::with_capacity(8);
+        let mut error_buckets = BTreeMap::new();
         for err in errors {
-            if let Some(count) = error_buckets.get_mut(&err) {
-                *count += 1;
-            } else {
-                error_buckets.insert(err, 1);
-            }
+            *error_buckets.entry(err).or_insert(0) += 1;
         }
 
         let mut lost_packets = 0;
@@ -307,18 +1389,27 @@ impl PingSummary {
                 stddev: f32::NAN,
                 sum: f32::NAN,
                 count: 0,
+                sent: total_packets,
                 loss_percent: 1.,
+                outliers_dropped: 0,
+                reordered: 0,
             };
         }
 
+        samples.sort_unstable_by(f32::total_cmp);
+        let filtered = outlier_rejection.and_then(|rejection| rejection.apply(&samples));
+        let stats_samples = filtered.as_deref().unwrap_or(&samples);
+        let quantile_samples = match outlier_rejection {
+            Some(rejection) if rejection.apply_to_quantiles => stats_samples,
+            _ => &samples[..],
+        };
+
         let mut hist = Histogram::<u16>::new(0).unwrap();
-        for sample in &samples {
+        for sample in quantile_samples {
             hist += (*sample * 16.).round() as u64;
         }
-        samples.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
-        let n = samples.len();
-        let sum = samples.iter().sum();
-        let mean_ms = sum / (n as f32);
+        let n = stats_samples.len();
+        let mean_ms = stats_samples.iter().sum::<f32>() / (n as f32);
         Self {
             quantiles: quantiles
                 .iter()
@@ -326,61 +1417,64 @@ impl PingSummary {
                 .map(|q| (q, hist.value_at_quantile(q) as f32 / 16.))
                 .collect(),
             mean_ms,
-            stddev: samples
+            stddev: stats_samples
                 .iter()
                 .map(|val| (*val - mean_ms).powi(2))
                 .sum::<f32>()
                 .div(n as f32)
                 .sqrt(),
-            sum,
-            count: n,
+            sum: quantile_samples.iter().sum(),
+            count: quantile_samples.len(),
+            sent: total_packets,
             loss_percent: lost_packets as f32 / total_packets as f32,
+            outliers_dropped: samples.len() - n,
             errors: error_buckets,
+            reordered: 0,
         }
     }
 
-    pub fn write_prometheus(&self, builder: &mut ExpositionBuilder) {
-        builder.add_metric(
-            PName::new("ping_ms").unwrap(),
-            MetricType::Summary,
-            "ping to target",
-            |mut builder| {
-                for (quantile, value) in &self.quantiles {
-                    builder.add_line_labeled(
-                        PName::QUANTILE,
-                        quantile.to_string().as_str(),
-                        value,
-                        None,
-                    );
-                }
-                builder.with_name(PName::SUFFIX_SUM, |builder| {
-                    builder.add_line(&self.sum, None);
-                });
-                builder.with_name(PName::SUFFIX_COUNT, |builder| {
-                    builder.add_line(&self.count, None);
-                });
-            },
-        );
+    pub fn write_prometheus(&self, builder: &mut ExpositionBuilder, names: &Quantiles) {
+        self.write_latency(builder, names);
 
-        builder.add_metric(
-            PName::new("ping_mean_ms").unwrap(),
+        let mean = builder.metric_name(PName::new("ping_mean_ms").unwrap());
+        builder.add_metric_with_unit(
+            mean.name,
             MetricType::Gauge,
+            mean.unit,
             "mean ping to target",
-            |mut builder| builder.add_line(&self.mean_ms, None),
+            |mut builder| builder.add_line(&mean.value(self.mean_ms), None),
         );
 
-        builder.add_metric(
-            PName::new("ping_stddev").unwrap(),
+        let stddev = builder.metric_name(PName::new("ping_stddev").unwrap());
+        builder.add_metric_with_unit(
+            stddev.name,
             MetricType::Gauge,
+            stddev.unit,
             "ping standard deviation",
-            |mut builder| builder.add_line(&self.stddev, None),
+            |mut builder| builder.add_line(&stddev.value(self.stddev), None),
         );
 
         builder.add_metric(
-            PName::new("packet_loss").unwrap(),
+            PName::new("ping_outliers_dropped").unwrap(),
             MetricType::Gauge,
+            "number of pings excluded from the mean and standard deviation",
+            |mut builder| builder.add_line(&self.outliers_dropped, None),
+        );
+
+        builder.add_metric(
+            PName::new("ping_sent").unwrap(),
+            MetricType::Gauge,
+            "number of pings sent to target",
+            |mut builder| builder.add_line(&self.sent, None),
+        );
+
+        let loss = builder.metric_name(PName::new("packet_loss").unwrap());
+        builder.add_metric_with_unit(
+            loss.name,
+            MetricType::Gauge,
+            loss.unit,
             "packet loss (0 to 1)",
-            |mut builder| builder.add_line(&self.loss_percent, None),
+            |mut builder| builder.add_line(&loss.value(self.loss_percent), None),
         );
 
         builder.add_metric(
@@ -389,14 +1483,921 @@ impl PingSummary {
             "number of ping errors",
             |mut builder| {
                 for (kind, count) in &self.errors {
-                    builder.add_line_labeled(
-                        PName::new("error").unwrap(),
-                        kind.to_string().as_str(),
-                        count,
-                        None,
-                    );
+                    kind.write_labeled(&mut builder, |builder| builder.add_line(count, None));
+                }
+            },
+        );
+
+        builder.add_metric(
+            PName::new("ping_error_kinds").unwrap(),
+            MetricType::Gauge,
+            "number of distinct ping error kinds",
+            |mut builder| builder.add_line(&self.errors.len(), None),
+        );
+    }
+
+    /// Writes the `ping_ms` summary
+    pub fn write_latency(&self, builder: &mut ExpositionBuilder, names: &Quantiles) {
+        let latency = builder.metric_name(PName::new("ping_ms").unwrap());
+        builder.add_metric_with_unit(
+            latency.name,
+            MetricType::Summary,
+            latency.unit,
+            "ping to target",
+            |mut builder| {
+                for (quantile, value) in &self.quantiles {
+                    let value = latency.value(*value);
+                    builder.add_quantile_line(*quantile, names.name(*quantile), &value);
                 }
+                builder.with_name(PName::SUFFIX_SUM, |builder| {
+                    builder.add_line(&latency.value(self.sum), None);
+                });
+                builder.with_name(PName::SUFFIX_COUNT, |builder| {
+                    builder.add_line(&self.count, None);
+                });
+            },
+        );
+    }
+}
+
+/// Keeps the `limit` answered pings with the highest round trip time and all
+/// lost ones in the order they were sent, since these are what is worth
+/// troubleshooting
+fn keep_slowest_samples(samples: &mut Vec<PingSample>, limit: usize) {
+    let mut answered: Vec<&PingSample> = samples.iter().filter(|it| !it.ms.is_nan()).collect();
+    if answered.len() <= limit {
+        return;
+    }
+    answered.sort_unstable_by(|a, b| b.ms.total_cmp(&a.ms));
+    let kept: HashSet<usize> = answered[..limit].iter().map(|it| it.seq).collect();
+    samples.retain(|it| it.ms.is_nan() || kept.contains(&it.seq));
+}
+
+/// Summarizes the samples of each payload size separately
+fn digest_by_payload(samples: &[PingSample], config: &PingConfig) -> BTreeMap<usize, PingSummary> {
+    let mut by_payload = BTreeMap::<usize, Vec<f32>>::new();
+    for sample in samples {
+        by_payload
+            .entry(sample.payload)
+            .or_default()
+            .push(sample.ms);
+    }
+    (by_payload.into_iter())
+        .map(|(payload, samples)| {
+            let summary = PingSummary::digest_data(
+                samples,
+                Vec::new(),
+                &config.quantiles,
+                config.outlier_rejection.as_ref(),
+            );
+            (payload, summary)
+        })
+        .collect()
+}
+
+/// Looks up the value of `quantile` among the computed quantiles
+fn find_quantile<T: Copy>(quantiles: &[(f64, T)], quantile: f64) -> Option<T> {
+    quantiles
+        .iter()
+        .find(|(q, _)| *q == quantile)
+        .map(|(_, value)| *value)
+}
+
+impl Display for PingResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "target {}: ", self.display_target())?;
+        match (&self.summary, &self.error) {
+            (Some(summary), _) => Display::fmt(summary, f),
+            (None, Some(error)) => write!(f, "error={error}"),
+            (None, None) => f.write_str("no data"),
+        }
+    }
+}
+
+impl Display for PingSummary {
+    /// One-line summary, e.g. `p50=12.3ms, p99=45.1ms, loss=0.0%, samples=60`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (label, q) in [("p50", 0.5), ("p99", 0.99)] {
+            if let Some(ms) = find_quantile(&self.quantiles, q) {
+                write!(f, "{label}={ms:.1}ms, ")?;
+            }
+        }
+        write!(
+            f,
+            "loss={:.1}%, samples={}",
+            self.loss_percent * 100.,
+            self.count
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::prometheus::{
+        parser, ExpositionArena, ExpositionFormat, MetricNaming, CONVENTIONAL_NAMES,
+    };
+
+    #[tokio::test]
+    async fn icmp_socket_type_is_exposed_if_available() {
+        let mut config = PingConfig::default();
+        for socket_type in [IcmpSocketType::Auto, IcmpSocketType::Dgram] {
+            config.socket_type = socket_type;
+            let alloc = ExpositionArena::new();
+            let mut builder = ExpositionBuilder::new(&alloc);
+            write_icmp_socket_info(&mut builder, &config);
+            assert_eq!(builder.validate(), Ok(()));
+            let exposition = builder.to_string();
+            match check_icmp(socket_type) {
+                Ok(opened) => assert!(exposition.contains(&format!(
+                    "\nexporter_icmp_socket_info{{type=\"{opened}\"}} 1\n"
+                ))),
+                Err(_) => assert!(!exposition.contains("exporter_icmp_socket_info")),
+            }
+        }
+
+        config.provider = PingProvider::Null(Default::default());
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        write_icmp_socket_info(&mut builder, &config);
+        assert!(!builder.to_string().contains("exporter_icmp_socket_info"));
+    }
+
+    #[test]
+    fn slowest_and_lost_samples_are_kept() {
+        let mut samples: Vec<PingSample> = [10., f32::NAN, 30., 5., 20., f32::NAN]
+            .into_iter()
+            .enumerate()
+            .map(|(seq, ms)| PingSample {
+                seq,
+                offset: Duration::ZERO,
+                ms,
+                payload: 0,
+            })
+            .collect();
+        keep_slowest_samples(&mut samples, 2);
+        let kept: Vec<usize> = samples.iter().map(|it| it.seq).collect();
+        assert_eq!(kept, [1, 2, 4, 5]);
+    }
+
+    fn samples_with_outlier() -> Vec<f32> {
+        let mut samples = vec![10., 11., 9., 10., 12., 8., 10., 11., 9.];
+        samples.push(500.);
+        samples
+    }
+
+    #[test]
+    fn targets_hash_consistently() {
+        let targets: HashSet<PingTarget> = [
+            PingTarget::Ip([1, 1, 1, 1].into()),
+            PingTarget::Ip([1, 1, 1, 1].into()),
+            PingTarget::Domain("one.one.one.one".to_owned()),
+            PingTarget::Domain("one.one.one.one".to_owned()),
+            // Same display form, but not the same target
+            PingTarget::Domain("1.1.1.1".to_owned()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(targets.len(), 3);
+        assert!(targets.contains(&PingTarget::Ip([1, 1, 1, 1].into())));
+        assert!(targets.contains(&PingTarget::Domain("one.one.one.one".to_owned())));
+    }
+
+    #[test]
+    fn resolve_errors_are_categorized() {
+        use hickory_resolver::proto::{
+            op::Query,
+            rr::{Name, RecordType},
+        };
+
+        let no_records = |response_code| {
+            ResolveError::from(ResolveErrorKind::NoRecordsFound {
+                query: Box::new(Query::query(
+                    Name::from_ascii("example.com.").unwrap(),
+                    RecordType::A,
+                )),
+                soa: None,
+                negative_ttl: None,
+                response_code,
+                trusted: true,
+            })
+        };
+        let reason = |error: ResolveError| PingPrepareError::from(error).resolve_reason();
+
+        assert_eq!(
+            reason(no_records(ResponseCode::NXDomain)),
+            Some(ResolveErrorReason::Nxdomain)
+        );
+        assert_eq!(
+            reason(no_records(ResponseCode::ServFail)),
+            Some(ResolveErrorReason::Servfail)
+        );
+        assert_eq!(
+            reason(no_records(ResponseCode::NoError)),
+            Some(ResolveErrorReason::NoIp)
+        );
+        assert_eq!(
+            reason(ResolveErrorKind::Timeout.into()),
+            Some(ResolveErrorReason::Timeout)
+        );
+        assert_eq!(
+            reason(ResolveErrorKind::Message("192.0.2.1:53 refused").into()),
+            Some(ResolveErrorReason::Other)
+        );
+        assert_eq!(
+            PingPrepareError::NoIp.resolve_reason(),
+            Some(ResolveErrorReason::NoIp)
+        );
+        let socket_error = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            PingPrepareError::Socket(socket_error).resolve_reason(),
+            None
+        );
+    }
+
+    #[test]
+    fn digest_data_never_panics() {
+        use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+        const SPECIAL: [f32; 9] = [
+            f32::NAN,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            f32::MAX,
+            f32::MIN,
+            f32::MIN_POSITIVE,
+            -0.,
+            0.,
+            1e-40,
+        ];
+        let errors = [
+            PingErrorKind::Timeout {},
+            PingErrorKind::IdenticalRequests,
+            PingErrorKind::NetworkError,
+        ];
+        let quantiles = [0., 0.25, 0.5, 0.75, 0.9, 0.99, 1.];
+        let rejection = OutlierRejection {
+            max_deviations: 3.,
+            apply_to_quantiles: true,
+        };
+
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        for _ in 0..2000 {
+            let len = rng.gen_range(0..32);
+            let samples = (0..len)
+                .map(|_| match rng.gen_range(0..3) {
+                    0 => *SPECIAL.choose(&mut rng).unwrap(),
+                    1 => f32::from_bits(rng.gen()),
+                    _ => rng.gen_range(0.0..1000.0),
+                })
+                .collect::<Vec<f32>>();
+            let errors = (0..rng.gen_range(0..4))
+                .map(|_| *errors.choose(&mut rng).unwrap())
+                .collect::<Vec<_>>();
+            for rejection in [None, Some(&rejection)] {
+                PingSummary::digest_data(samples.clone(), errors.clone(), &quantiles, rejection);
+            }
+        }
+    }
+
+    #[test]
+    fn json_schemas() {
+        let config = PingConfig {
+            quantiles: vec![0.5].into(),
+            ..Default::default()
+        };
+        let mut result =
+            PingResult::mock(PingTarget::Ip([127, 0, 0, 1].into()), vec![0.25], &config);
+        result.measured_at = SystemTime::UNIX_EPOCH;
+        let results = [result];
+        let json =
+            |schema| serde_json::to_string(&results_to_versioned_json(&results, schema)).unwrap();
+
+        assert_eq!(
+            json(JsonSchema::V1),
+            r#"[{"target":"127.0.0.1","summary":{"quantiles":[[0.5,0.4375]],"mean_ms":0.25,"stddev":0.0,"sum":0.25,"count":1,"sent":1,"loss_percent":0.0,"outliers_dropped":0,"errors":{}},"address_family":"ipv4"}]"#
+        );
+        assert_eq!(
+            json(JsonSchema::V2),
+            r#"[{"target":"127.0.0.1","measured_at":"1970-01-01T00:00:00.000Z","summary":{"quantiles":{"0.5":0.4375},"mean_ms":0.25,"stddev":0.0,"sum":0.25,"count":1,"sent":1,"loss_percent":0.0,"outliers_dropped":0,"errors":{},"reordered":0},"address_family":"ipv4"}]"#
+        );
+    }
+
+    #[test]
+    fn payload_sizes_are_summarized_separately() {
+        let config = PingConfig {
+            payload_sizes: vec![64, 1400],
+            quantiles: vec![0.5].into(),
+            ..Default::default()
+        };
+        let samples: Vec<PingSample> = [(64, 10.), (1400, 30.), (64, 12.), (1400, f32::NAN)]
+            .into_iter()
+            .enumerate()
+            .map(|(seq, (payload, ms))| PingSample {
+                seq,
+                offset: Duration::ZERO,
+                ms,
+                payload,
+            })
+            .collect();
+        let by_payload = digest_by_payload(&samples, &config);
+        assert_eq!(by_payload.keys().collect::<Vec<_>>(), [&64, &1400]);
+        assert_eq!(by_payload[&64].loss_percent, 0.);
+        assert_eq!(by_payload[&1400].loss_percent, 0.5);
+
+        let mut result = PingResult::mock(PingTarget::Ip([10, 0, 0, 1].into()), vec![1.], &config);
+        result.by_payload = by_payload;
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        result.write_prometheus(&mut builder, &config);
+        assert_eq!(builder.validate(), Ok(()));
+        let exposition = builder.to_string();
+        assert!(exposition.contains("\nping_ms_count{target=\"10.0.0.1\"} 1\n"));
+        assert!(exposition.contains("\nping_ms_count{target=\"10.0.0.1\", payload=\"64\"} 2\n"));
+        assert!(exposition.contains("\nping_ms_count{target=\"10.0.0.1\", payload=\"1400\"} 1\n"));
+    }
+
+    #[test]
+    fn namespaced_targets_are_labeled() {
+        let config = PingConfig::default();
+        let target = PingTarget::Namespaced {
+            netns: "blue".to_owned(),
+            target: Box::new(PingTarget::Ip([10, 0, 0, 1].into())),
+        };
+        let result = PingResult::mock(target, vec![1.], &config);
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        result.write_prometheus(&mut builder, &config);
+        assert_eq!(builder.validate(), Ok(()));
+        let exposition = builder.to_string();
+        assert!(exposition
+            .contains(r#"ping_address_family{target="10.0.0.1", netns="blue", family="ipv4"} 1"#));
+    }
+
+    #[test]
+    fn latency_thresholds_are_compared_to_mean_and_p99() {
+        let mut config = PingConfig::default();
+        config
+            .latency_thresholds_ms
+            .insert("10.0.0.1".to_owned(), 50.);
+        let target = PingTarget::Ip([10, 0, 0, 1].into());
+        let over_threshold = |samples: Vec<f32>| {
+            let result = PingResult::mock(target.clone(), samples, &config);
+            let alloc = ExpositionArena::new();
+            let mut builder = ExpositionBuilder::new(&alloc);
+            result.write_prometheus(&mut builder, &config);
+            assert_eq!(builder.validate(), Ok(()));
+            let exposition = builder.to_string();
+            if exposition.contains("\nping_over_threshold{target=\"10.0.0.1\"} 1\n") {
+                true
+            } else {
+                assert!(exposition.contains("\nping_over_threshold{target=\"10.0.0.1\"} 0\n"));
+                false
+            }
+        };
+        assert!(!over_threshold(vec![10.; 100]));
+        assert!(over_threshold(vec![60.; 100]));
+        // Only the tail is slow
+        let mut samples = vec![10.; 100];
+        samples[99] = 500.;
+        samples[98] = 500.;
+        assert!(over_threshold(samples));
+
+        // Other targets are not compared
+        let result = PingResult::mock(PingTarget::Ip([10, 0, 0, 2].into()), vec![60.], &config);
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        result.write_prometheus(&mut builder, &config);
+        assert!(!builder.to_string().contains("ping_over_threshold"));
+    }
+
+    #[test]
+    fn summary_display() {
+        let samples = vec![10., 20., 30., f32::NAN];
+        let summary = PingSummary::digest_data(samples, Vec::new(), &[], None);
+        assert_eq!(summary.to_string(), "loss=25.0%, samples=3");
+    }
+
+    #[test]
+    fn outliers_are_kept_by_default() {
+        let summary = PingSummary::digest_data(samples_with_outlier(), Vec::new(), &[1.], None);
+        assert_eq!(summary.outliers_dropped, 0);
+        assert_eq!(summary.count, 10);
+        assert!(summary.mean_ms > 50.);
+    }
+
+    #[test]
+    fn outliers_are_rejected_from_stats() {
+        let rejection = OutlierRejection {
+            max_deviations: 3.,
+            apply_to_quantiles: false,
+        };
+        let summary =
+            PingSummary::digest_data(samples_with_outlier(), Vec::new(), &[1.], Some(&rejection));
+        assert_eq!(summary.outliers_dropped, 1);
+        assert_eq!(summary.mean_ms, 10.);
+        assert!(summary.stddev < 2.);
+        // Quantiles, sum and count are unaffected
+        assert_eq!(summary.count, 10);
+        assert!(summary.quantiles[0].1 >= 500.);
+    }
+
+    #[test]
+    fn outliers_are_rejected_from_quantiles() {
+        let rejection = OutlierRejection {
+            max_deviations: 3.,
+            apply_to_quantiles: true,
+        };
+        let summary =
+            PingSummary::digest_data(samples_with_outlier(), Vec::new(), &[1.], Some(&rejection));
+        assert_eq!(summary.outliers_dropped, 1);
+        assert_eq!(summary.count, 9);
+        assert_eq!(summary.sum, 90.);
+        assert!(summary.quantiles[0].1 < 20.);
+    }
+
+    #[test]
+    fn constant_samples_have_no_outliers() {
+        let rejection = OutlierRejection {
+            max_deviations: 1.,
+            apply_to_quantiles: true,
+        };
+        let summary = PingSummary::digest_data(vec![5.; 10], Vec::new(), &[0.5], Some(&rejection));
+        assert_eq!(summary.outliers_dropped, 0);
+        assert_eq!(summary.count, 10);
+    }
+
+    #[test]
+    fn surge_errors_map_to_error_kinds() {
+        let cases = [
+            (
+                SurgeError::IncorrectBufferSize,
+                PingErrorKind::IncorrectBufferSize,
+            ),
+            (
+                SurgeError::Timeout {
+                    seq: PingSequence(3),
+                },
+                PingErrorKind::Timeout {},
+            ),
+            (
+                SurgeError::EchoRequestPacket,
+                PingErrorKind::EchoRequestPacket,
+            ),
+            (SurgeError::NetworkError, PingErrorKind::NetworkError),
+            (SurgeError::ClientDestroyed, PingErrorKind::NetworkError),
+            (
+                SurgeError::IOError(io::ErrorKind::PermissionDenied.into()),
+                PingErrorKind::IOError {
+                    kind: io::ErrorKind::PermissionDenied,
+                },
+            ),
+        ];
+        for (error, kind) in cases {
+            assert_eq!(PingErrorKind::from(error), kind);
+        }
+    }
+
+    #[test]
+    fn error_kinds_have_stable_identifiers() {
+        let kind = PingErrorKind::IOError {
+            kind: io::ErrorKind::PermissionDenied,
+        };
+        assert_eq!(kind.as_str(), "io_error");
+        assert_eq!(kind.io_kind().as_deref(), Some("permission_denied"));
+        assert_eq!(kind.json_key(), "io_error:permission_denied");
+        assert_eq!(
+            serde_json::to_string(&kind).unwrap(),
+            r#"{"io_error":{"kind":"permission_denied"}}"#
+        );
+        assert_eq!(PingErrorKind::Timeout {}.json_key(), "timeout");
+        assert_eq!(
+            serde_json::to_string(&PingErrorKind::MalformedPacket).unwrap(),
+            r#""malformed_packet""#
+        );
+    }
+
+    #[test]
+    fn every_error_kind_is_exposed() {
+        use PingErrorKind::*;
+        let kinds = vec![
+            IncorrectBufferSize,
+            MalformedPacket,
+            IOError {
+                kind: io::ErrorKind::BrokenPipe,
+            },
+            IOError {
+                kind: io::ErrorKind::PermissionDenied,
+            },
+            Timeout {},
+            EchoRequestPacket,
+            NetworkError,
+            IdenticalRequests,
+        ];
+        // Fails to compile when a variant is added, which then belongs above
+        for kind in &kinds {
+            match kind {
+                IncorrectBufferSize
+                | MalformedPacket
+                | IOError { .. }
+                | Timeout {}
+                | EchoRequestPacket
+                | NetworkError
+                | IdenticalRequests => {}
+            }
+        }
+
+        let summary = PingSummary::digest_data(vec![1.], kinds.clone(), &[0.5], None);
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        summary.write_prometheus(&mut builder, &Quantiles::default());
+        assert_eq!(builder.validate(), Ok(()));
+        let samples = parser::parse(&builder.to_string()).unwrap();
+
+        let errors: HashSet<_> = samples
+            .iter()
+            .filter(|sample| sample.name == "ping_errors")
+            .map(|sample| {
+                assert_eq!(sample.value, 1.);
+                (sample.label("error"), sample.label("io_kind"))
+            })
+            .collect();
+        assert_eq!(errors.len(), kinds.len());
+        for kind in kinds {
+            let io_kind = kind.io_kind();
+            assert!(errors.contains(&(Some(kind.as_str()), io_kind.as_deref())));
+        }
+    }
+
+    #[test]
+    fn error_kinds_are_ordered() {
+        use PingErrorKind::*;
+        let mut errors = vec![
+            Timeout {},
+            NetworkError,
+            IOError {
+                kind: io::ErrorKind::PermissionDenied,
+            },
+            MalformedPacket,
+            Timeout {},
+            IOError {
+                kind: io::ErrorKind::BrokenPipe,
             },
+        ];
+        let render = |errors: &[PingErrorKind]| {
+            let summary = PingSummary::digest_data(vec![1.], errors.to_vec(), &[0.5], None);
+            let alloc = ExpositionArena::new();
+            let mut builder = ExpositionBuilder::new(&alloc);
+            summary.write_prometheus(&mut builder, &Quantiles::default());
+            (
+                builder.to_string(),
+                serde_json::to_string(&summary).unwrap(),
+            )
+        };
+
+        let (exposition, json) = render(&errors);
+        errors.reverse();
+        assert_eq!(render(&errors), (exposition.clone(), json.clone()));
+        assert!(json.contains(
+            r#""errors":{"malformed_packet":1,"io_error:permission_denied":1,"io_error:broken_pipe":1,"timeout":2,"network_error":1}"#
+        ), "{json}");
+        let lines: Vec<_> = (exposition.lines())
+            .filter(|line| line.starts_with("ping_errors{"))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                r#"ping_errors{error="malformed_packet"} 1"#,
+                r#"ping_errors{error="io_error", io_kind="permission_denied"} 1"#,
+                r#"ping_errors{error="io_error", io_kind="broken_pipe"} 1"#,
+                r#"ping_errors{error="timeout"} 2"#,
+                r#"ping_errors{error="network_error"} 1"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn raw_samples_are_written_on_request() {
+        let config = PingConfig::default();
+        let mut result = PingResult::mock(
+            PingTarget::Ip([10, 0, 0, 1].into()),
+            vec![10., 20.],
+            &config,
+        );
+        result.raw_samples = vec![PingSample {
+            seq: 3,
+            offset: Duration::from_secs(1),
+            ms: 12.,
+            payload: config.payload_size,
+        }];
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        result.write_prometheus(&mut builder, &config);
+        assert!(!builder.to_string().contains("ping_sample_ms"));
+
+        result.write_raw_prometheus(&mut builder);
+        let samples = parser::parse(&builder.to_string()).unwrap();
+        let sample = samples
+            .iter()
+            .find(|it| it.name == "ping_sample_ms")
+            .unwrap();
+        assert_eq!(sample.value, 12.);
+        let labels: Vec<_> = (sample.labels.iter())
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(labels, [("target", "10.0.0.1"), ("seq", "3")]);
+    }
+
+    #[test]
+    fn namings_export_equivalent_data() {
+        let config = PingConfig::default();
+        let mut result = PingResult::mock(
+            PingTarget::Ip([10, 0, 0, 1].into()),
+            vec![10., 20., f32::NAN, 40.],
+            &config,
         );
+        result.raw_samples = [10., f32::NAN]
+            .into_iter()
+            .enumerate()
+            .map(|(seq, ms)| PingSample {
+                seq,
+                offset: Duration::from_secs(seq as u64),
+                ms,
+                payload: config.payload_size,
+            })
+            .collect();
+        let render = |naming, format| {
+            let alloc = ExpositionArena::new();
+            let mut builder = ExpositionBuilder::with_format(&alloc, format);
+            builder.set_naming(naming);
+            result.write_prometheus(&mut builder, &config);
+            result.write_raw_prometheus(&mut builder);
+            assert_eq!(builder.validate(), Ok(()));
+            builder.to_string()
+        };
+        let legacy = render(MetricNaming::Legacy, ExpositionFormat::Prometheus);
+        let conventional = render(MetricNaming::Conventional, ExpositionFormat::Prometheus);
+        let legacy = parser::parse(&legacy).unwrap();
+        let conventional = parser::parse(&conventional).unwrap();
+        assert_eq!(legacy.len(), conventional.len());
+
+        for sample in &legacy {
+            let renamed = CONVENTIONAL_NAMES.iter().find_map(|renamed| {
+                let suffix = sample.name.strip_prefix(&**renamed.legacy)?;
+                matches!(suffix, "" | "_sum" | "_count").then_some((renamed, suffix))
+            });
+            let (name, scale) = match renamed {
+                Some((renamed, "_count")) => (format!("{}_count", &**renamed.conventional), 1.),
+                Some((renamed, suffix)) => (
+                    format!("{}{suffix}", &**renamed.conventional),
+                    f64::from(renamed.scale),
+                ),
+                None => (sample.name.clone(), 1.),
+            };
+            let converted = (conventional.iter())
+                .find(|it| it.name == name && it.labels == sample.labels)
+                .unwrap_or_else(|| panic!("{name} is missing"));
+            let expected = sample.value * scale;
+            assert!(
+                (converted.value - expected).abs() <= expected.abs() * 1e-6
+                    || converted.value.is_nan() && expected.is_nan(),
+                "{name}: {} != {expected}",
+                converted.value
+            );
+        }
+
+        let openmetrics = render(MetricNaming::Conventional, ExpositionFormat::OpenMetrics);
+        assert!(openmetrics.contains("# UNIT ping_duration_seconds seconds\n"));
+        assert!(openmetrics.contains("# UNIT ping_packet_loss_ratio ratio\n"));
+        assert!(!openmetrics.contains("ping_ms"));
+    }
+
+    #[test]
+    fn socket_errors_mention_privileges() {
+        let error = PingPrepareError::Socket(io::ErrorKind::PermissionDenied.into());
+        assert!(error.to_string().contains(ICMP_PRIVILEGES));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires privileges to open ICMP sockets"]
+    async fn ping_localhost() {
+        let addr = Ipv4Addr::LOCALHOST.into();
+        let client = icmp_client(addr, IcmpSocketType::Auto, None).unwrap();
+        let payload = Arc::from([vec![0; 8].into_boxed_slice()]);
+        let (samples, errors, _) = sample_pings(
+            &client,
+            addr,
+            PingSchedule::Samples(2),
+            Duration::from_millis(10),
+            payload,
+        )
+        .await;
+        assert!(errors.is_empty(), "{errors:?}");
+        assert!(samples.iter().all(|sample| !sample.ms.is_nan()));
+    }
+
+    /// Datagram sockets are demultiplexed by the bound port, so concurrent
+    /// pings with `PingIdentifier(0)` collide unless the ports differ
+    #[tokio::test]
+    async fn concurrent_targets_bind_distinct_source_ports() {
+        if check_icmp(IcmpSocketType::Dgram).is_err() {
+            eprintln!("skipped, unprivileged ICMP datagram sockets are unavailable");
+            return;
+        }
+        let mut config = Config::default();
+        config.ping.socket_type = IcmpSocketType::Dgram;
+        config.ping.samples = Some(3);
+        config.ping.delay = Duration::from_millis(10);
+        config.ping.source_port_range = Some((41000, 41009));
+        let targets: Vec<_> = (1..=10)
+            .map(|i| PingTarget::Ip(Ipv4Addr::new(127, 0, 0, i).into()))
+            .collect();
+        let results = ping_targets(
+            Arc::new(config),
+            targets,
+            Arc::default(),
+            PhaseRecorder::default(),
+            LoopbackLookup,
+        )
+        .await;
+
+        assert_eq!(results.len(), 10);
+        for result in &results {
+            // Every socket was bound, none of them collided
+            assert_eq!(result.error, None, "{result}");
+            let errors = &result.summary.as_ref().unwrap().errors;
+            assert!(
+                !errors.contains_key(&PingErrorKind::IdenticalRequests),
+                "{result}"
+            );
+        }
+    }
+
+    #[test]
+    fn source_ports_cycle_through_the_range() {
+        let range = (41000, 41002);
+        let mut previous = next_source_port(range);
+        // Past the 65536 calls at which a wrapping counter would skip ports
+        for _ in 0..70_000 {
+            let port = next_source_port(range);
+            let expected = if previous == range.1 {
+                range.0
+            } else {
+                previous + 1
+            };
+            assert_eq!(port, expected);
+            previous = port;
+        }
+    }
+
+    #[tokio::test]
+    async fn measure_duration_spans_the_window() {
+        let mut config = Config::default();
+        config.ping.samples = None;
+        config.ping.total_duration = Some(Duration::from_millis(200));
+        config.ping.delay = Duration::from_millis(50);
+        let results = ping_targets(
+            Arc::new(config),
+            vec![PingTarget::Ip(Ipv4Addr::LOCALHOST.into())],
+            Arc::default(),
+            PhaseRecorder::default(),
+            LoopbackLookup,
+        )
+        .await;
+
+        // Absent if pinging is not permitted
+        let Some(duration) = results[0].measure_duration else {
+            return;
+        };
+        let window = Duration::from_millis(200);
+        assert!(
+            duration >= window - Duration::from_millis(50)
+                && duration <= window + DURATION_TIMEOUT_GRACE + Duration::from_secs(1),
+            "{duration:?}"
+        );
+        let json = serde_json::to_value(results[0].to_json_v2()).unwrap();
+        assert_eq!(json["measure_duration_seconds"], duration.as_secs_f64());
+    }
+
+    /// Resolves every domain to three loopback addresses
+    #[derive(Clone)]
+    struct LoopbackLookup;
+
+    #[async_trait::async_trait]
+    impl AddressLookup for LoopbackLookup {
+        async fn lookup(
+            &self,
+            _domain: &str,
+            _family: AddressFamily,
+        ) -> Result<dns_cache::Records, ResolveError> {
+            Ok(dns_cache::Records {
+                addrs: (1..=3)
+                    .map(|i| Ipv4Addr::new(127, 0, 0, i).into())
+                    .collect(),
+                valid_until: Instant::now() + Duration::from_secs(60),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn every_address_is_pinged_with_all_resolution() {
+        let mut config = Config::default();
+        config.ping.samples = Some(1);
+        config.ping.domain_resolution = DomainResolution::All;
+        let target = PingTarget::Domain("example.com".to_owned());
+        let results = ping_targets(
+            Arc::new(config),
+            vec![target.clone(), PingTarget::Ip(Ipv4Addr::LOCALHOST.into())],
+            Arc::default(),
+            PhaseRecorder::default(),
+            LoopbackLookup,
+        )
+        .await;
+
+        // Pinging may fail without privileges, the results are kept either way
+        let pinged: Vec<_> = results
+            .iter()
+            .map(|result| (&result.target, result.address))
+            .collect();
+        let address = |i| Some(IpAddr::from(Ipv4Addr::new(127, 0, 0, i)));
+        assert_eq!(
+            pinged,
+            [
+                (&target, address(1)),
+                (&target, address(2)),
+                (&target, address(3)),
+                (&PingTarget::Ip(Ipv4Addr::LOCALHOST.into()), None),
+            ]
+        );
+
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        for result in &results {
+            result.with_target_labels(&mut builder, |builder| {
+                builder.add_metric(
+                    PName::new("up").unwrap(),
+                    MetricType::Gauge,
+                    "up",
+                    |mut builder| builder.add_line(&1, None),
+                )
+            });
+        }
+        assert!(builder
+            .to_string()
+            .contains("\nup{target=\"example.com\", address=\"127.0.0.3\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn ip_targets_do_not_need_a_resolver() {
+        let mut config = Config::default();
+        config.ping.samples = Some(1);
+        let dns = Arc::<DnsCache>::default();
+        let results = perform_ping(
+            Arc::new(config),
+            vec![PingTarget::Ip(Ipv4Addr::LOCALHOST.into())],
+            dns.clone(),
+            PhaseRecorder::default(),
+        )
+        .await;
+        assert_eq!(results.len(), 1);
+
+        let exposition = |dns: &DnsCache| {
+            let alloc = ExpositionArena::new();
+            let mut builder = ExpositionBuilder::new(&alloc);
+            dns.write_prometheus(&mut builder);
+            builder.to_string()
+        };
+        assert_eq!(exposition(&dns), "");
+        dns.set_resolver_available(false);
+        assert!(exposition(&dns).contains("\nping_resolver_available 0\n"));
+    }
+
+    #[tokio::test]
+    async fn join_limited_bounds_in_flight_tasks() {
+        for max in [1, 4] {
+            let in_flight = Arc::new(AtomicUsize::new(0));
+            let peak = Arc::new(AtomicUsize::new(0));
+
+            let results = join_limited(0..50, max, |i| {
+                let in_flight = in_flight.clone();
+                let peak = peak.clone();
+                async move {
+                    let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(current, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    i
+                }
+            })
+            .await;
+
+            let mut results = results;
+            results.sort_unstable();
+            assert_eq!(results, (0..50).collect::<Vec<_>>());
+            assert!(peak.load(Ordering::SeqCst) <= max);
+            assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+        }
     }
 }
@@ -0,0 +1,145 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use super::{PingResult, PingTarget};
+use crate::prometheus::{ExpositionBuilder, MetricType, PName};
+
+/// Share of answered pings of recent measurements per target and labeled
+/// address, smoothing the per-measurement loss over `ping.availability_window`.
+#[derive(Debug, Default)]
+pub(crate) struct AvailabilityHistory(Mutex<HashMap<Series, VecDeque<Measurement>>>);
+
+type Series = (PingTarget, Option<IpAddr>);
+
+#[derive(Debug, Clone, Copy)]
+struct Measurement {
+    measured_at: SystemTime,
+    /// From 0 to 1, 0 if the target could not be pinged at all
+    answered: f64,
+}
+
+impl AvailabilityHistory {
+    /// Adds the results of a measurement and forgets measurements older than
+    /// `window`. Results that were already recorded, e.g. cached ones, are
+    /// ignored.
+    pub fn record(&self, results: &[PingResult], window: Duration) {
+        let oldest = SystemTime::now().checked_sub(window);
+        let mut history = self.0.lock().unwrap();
+        for result in results {
+            let measurements = history.entry(series(result)).or_default();
+            let known =
+                (measurements.back()).is_some_and(|last| last.measured_at >= result.measured_at);
+            if !known {
+                measurements.push_back(Measurement {
+                    measured_at: result.measured_at,
+                    answered: result
+                        .summary
+                        .as_ref()
+                        .map_or(0., |summary| 1. - f64::from(summary.loss_percent)),
+                });
+            }
+            while (measurements.front())
+                .is_some_and(|first| oldest.is_some_and(|oldest| first.measured_at < oldest))
+            {
+                measurements.pop_front();
+            }
+        }
+    }
+
+    /// Mean share of answered pings of the recorded measurements of the
+    /// series of `result`
+    pub fn ratio(&self, result: &PingResult) -> Option<f64> {
+        let history = self.0.lock().unwrap();
+        let measurements = history.get(&series(result)).filter(|it| !it.is_empty())?;
+        let answered: f64 = measurements.iter().map(|it| it.answered).sum();
+        Some(answered / measurements.len() as f64)
+    }
+
+    /// Writes `ping_availability_ratio` of the targets of `results`
+    pub fn write_prometheus(
+        &self,
+        builder: &mut ExpositionBuilder,
+        results: &[PingResult],
+        window: Duration,
+    ) {
+        let window = humantime::format_duration(window).to_string();
+        for result in results {
+            let Some(ratio) = self.ratio(result) else {
+                continue;
+            };
+            result.with_target_labels(builder, |builder| {
+                builder.add_metric(
+                    PName::new("ping_availability_ratio").unwrap(),
+                    MetricType::Gauge,
+                    "share of answered pings over the recent measurements within the window",
+                    |mut builder| {
+                        builder.add_line_labeled(
+                            PName::new("window").unwrap(),
+                            window.as_str(),
+                            &ratio,
+                            None,
+                        );
+                    },
+                );
+            });
+        }
+    }
+}
+
+fn series(result: &PingResult) -> Series {
+    (result.target.clone(), result.address)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prometheus::ExpositionArena;
+
+    use super::*;
+    use crate::config::PingConfig;
+
+    fn result(target: &PingTarget, samples: Vec<f32>, age: Duration) -> PingResult {
+        let mut result = PingResult::mock(target.clone(), samples, &PingConfig::default());
+        result.measured_at = SystemTime::now() - age;
+        result
+    }
+
+    #[test]
+    fn ratio_spans_the_window() {
+        let target = PingTarget::Ip([10, 0, 0, 1].into());
+        let window = Duration::from_secs(300);
+        let history = AvailabilityHistory::default();
+        let lossy = result(
+            &target,
+            vec![1., f32::NAN, f32::NAN, f32::NAN],
+            Duration::ZERO,
+        );
+        let loss = f64::from(lossy.summary.as_ref().unwrap().loss_percent);
+
+        history.record(
+            &[result(&target, vec![1.], Duration::from_secs(600))],
+            window,
+        );
+        assert_eq!(history.ratio(&lossy), None);
+        history.record(
+            &[result(&target, vec![1.], Duration::from_secs(60))],
+            window,
+        );
+        history.record(std::slice::from_ref(&lossy), window);
+        // Cached results are not counted twice
+        history.record(std::slice::from_ref(&lossy), window);
+        assert_eq!(history.ratio(&lossy), Some((1. + 1. - loss) / 2.));
+
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        let results = [result(&target, vec![1.], Duration::ZERO)];
+        history.write_prometheus(&mut builder, &results, window);
+        assert_eq!(builder.validate(), Ok(()));
+        assert!(builder
+            .to_string()
+            .contains("\nping_availability_ratio{target=\"10.0.0.1\", window=\"5m\"} "));
+    }
+}
@@ -0,0 +1,631 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use hickory_resolver::error::ResolveError;
+use serde::{Deserialize, Serialize};
+
+use super::{AddressFamily, PingPrepareError, ResolveErrorReason};
+use crate::{
+    config::PingConfig,
+    prometheus::{ExpositionBuilder, MetricType, PName},
+    Resolver,
+};
+
+/// Addresses of one family, abstracted from the resolver for testing
+#[async_trait]
+pub trait AddressLookup: Send + Sync {
+    async fn lookup(&self, domain: &str, family: AddressFamily) -> Result<Records, ResolveError>;
+}
+
+#[derive(Debug, Clone)]
+pub struct Records {
+    pub addrs: Vec<IpAddr>,
+    pub valid_until: Instant,
+}
+
+#[async_trait]
+impl AddressLookup for Resolver {
+    async fn lookup(&self, domain: &str, family: AddressFamily) -> Result<Records, ResolveError> {
+        Ok(match family {
+            AddressFamily::Ipv4 => {
+                let lookup = self.ipv4_lookup(domain).await?;
+                Records {
+                    addrs: lookup.iter().map(|a| IpAddr::V4(a.0)).collect(),
+                    valid_until: lookup.valid_until(),
+                }
+            }
+            AddressFamily::Ipv6 => {
+                let lookup = self.ipv6_lookup(domain).await?;
+                Records {
+                    addrs: lookup.iter().map(|aaaa| IpAddr::V6(aaaa.0)).collect(),
+                    valid_until: lookup.valid_until(),
+                }
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl<T: AddressLookup + ?Sized> AddressLookup for Arc<T> {
+    async fn lookup(&self, domain: &str, family: AddressFamily) -> Result<Records, ResolveError> {
+        (**self).lookup(domain, family).await
+    }
+}
+
+/// Resolver of the system configuration, only created once a domain is looked
+/// up, so that IP targets can be pinged even if it cannot be created
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LazyResolver(Arc<OnceLock<Result<Resolver, ResolveError>>>);
+
+impl LazyResolver {
+    fn get(&self) -> &Result<Resolver, ResolveError> {
+        self.0.get_or_init(Resolver::tokio_from_system_conf)
+    }
+
+    /// Whether the resolver could be created, `None` if it was not needed
+    pub fn is_available(&self) -> Option<bool> {
+        self.0.get().map(Result::is_ok)
+    }
+}
+
+#[async_trait]
+impl AddressLookup for LazyResolver {
+    async fn lookup(&self, domain: &str, family: AddressFamily) -> Result<Records, ResolveError> {
+        match self.get() {
+            Ok(resolver) => AddressLookup::lookup(resolver, domain, family).await,
+            Err(error) => Err(error.clone()),
+        }
+    }
+}
+
+/// Lookups whose durations make up the quantiles of
+/// `exporter_dns_lookup_duration_seconds`
+const RECENT_LOOKUPS: usize = 1000;
+
+const LOOKUP_QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// Counters of the lookups performed by the resolver and answered by the
+/// [`DnsCache`]
+#[derive(Debug, Default)]
+struct ResolverStats {
+    lookups: u64,
+    cache_hits: u64,
+    failures: BTreeMap<ResolveErrorReason, u64>,
+    duration_sum: Duration,
+    /// Durations of the most recent lookups, in seconds
+    recent: VecDeque<f64>,
+}
+
+impl ResolverStats {
+    fn record(&mut self, duration: Duration, failure: Option<ResolveErrorReason>) {
+        self.lookups += 1;
+        self.duration_sum += duration;
+        if self.recent.len() == RECENT_LOOKUPS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(duration.as_secs_f64());
+        if let Some(reason) = failure {
+            *self.failures.entry(reason).or_default() += 1;
+        }
+    }
+
+    /// Nearest-rank quantiles of the recent lookup durations
+    fn quantiles(&self) -> Vec<(f64, f64)> {
+        let mut sorted = Vec::from(self.recent.clone());
+        sorted.sort_by(f64::total_cmp);
+        LOOKUP_QUANTILES
+            .iter()
+            .map(|&quantile| {
+                let rank = (quantile * sorted.len() as f64).ceil() as usize;
+                (quantile, sorted[rank.clamp(1, sorted.len()) - 1])
+            })
+            .collect()
+    }
+
+    fn write_prometheus(&self, builder: &mut ExpositionBuilder) {
+        builder.add_metric(
+            PName::new("exporter_dns_lookups_total").unwrap(),
+            MetricType::Counter,
+            "number of lookups performed by the resolver, one per address family",
+            |mut builder| builder.add_line(&self.lookups, None),
+        );
+        builder.add_metric(
+            PName::new("exporter_dns_cache_hits_total").unwrap(),
+            MetricType::Counter,
+            "number of resolutions answered by the DNS cache",
+            |mut builder| builder.add_line(&self.cache_hits, None),
+        );
+        builder.add_metric(
+            PName::new("exporter_dns_failures_total").unwrap(),
+            MetricType::Counter,
+            "number of failed lookups by reason",
+            |mut builder| {
+                for (reason, count) in &self.failures {
+                    let label = PName::new("reason").unwrap();
+                    builder.add_line_labeled(label, reason.as_str(), count, None);
+                }
+            },
+        );
+        if self.recent.is_empty() {
+            return;
+        }
+        builder.add_metric_with_unit(
+            PName::new("exporter_dns_lookup_duration_seconds").unwrap(),
+            MetricType::Summary,
+            Some(PName::UNIT_SECONDS),
+            "duration of the recent lookups in seconds",
+            |mut builder| {
+                for (quantile, seconds) in self.quantiles() {
+                    builder.add_quantile_line(quantile, None, &seconds);
+                }
+                builder.with_name(PName::SUFFIX_SUM, |builder| {
+                    builder.add_line(&self.duration_sum.as_secs_f64(), None);
+                });
+                builder.with_name(PName::SUFFIX_COUNT, |builder| {
+                    builder.add_line(&self.lookups, None);
+                });
+            },
+        );
+    }
+}
+
+/// Records the duration and outcome of every lookup of `lookup` in `stats`
+struct Instrumented<'a, L> {
+    lookup: &'a L,
+    stats: &'a Mutex<ResolverStats>,
+}
+
+#[async_trait]
+impl<L: AddressLookup> AddressLookup for Instrumented<'_, L> {
+    async fn lookup(&self, domain: &str, family: AddressFamily) -> Result<Records, ResolveError> {
+        let start = Instant::now();
+        let result = self.lookup.lookup(domain, family).await;
+        let failure = result.as_ref().err().map(ResolveErrorReason::from);
+        self.stats.lock().unwrap().record(start.elapsed(), failure);
+        result
+    }
+}
+
+/// Which of the addresses of a domain target are pinged
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DomainResolution {
+    /// The first address
+    #[default]
+    First,
+    /// Every address, each as its own result labeled with the address
+    All,
+    /// One address per measurement, rotating through all of them
+    RoundRobin,
+}
+
+/// Looks up the families of `ping.address_families` in order and returns the
+/// addresses of the first family that has any along with their expiry.
+async fn lookup_preferred(
+    lookup: &impl AddressLookup,
+    domain: &str,
+    config: &PingConfig,
+) -> Result<(Vec<IpAddr>, Instant), PingPrepareError> {
+    let mut result = Err(PingPrepareError::NoIp);
+    for &family in config.address_families() {
+        result = lookup
+            .lookup(domain, family)
+            .await
+            .map_err(PingPrepareError::from)
+            .and_then(|records| {
+                if records.addrs.is_empty() {
+                    return Err(PingPrepareError::NoIp);
+                }
+                Ok((records.addrs, records.valid_until))
+            });
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
+/// Remembers the resolved addresses of domain targets across measurements,
+/// honoring the record TTLs within `ping.dns_min_ttl` and `ping.dns_max_ttl`.
+#[derive(Default)]
+pub struct DnsCache {
+    /// Replaces the resolver of the system configuration, see
+    /// [`with_lookup`](Self::with_lookup)
+    lookup: Option<Arc<dyn AddressLookup>>,
+    entries: Mutex<HashMap<String, Entry>>,
+    errors: Mutex<HashMap<String, BTreeMap<ResolveErrorReason, u64>>>,
+    /// Whether the resolver could be created the last time it was needed
+    resolver_available: Mutex<Option<bool>>,
+    stats: Mutex<ResolverStats>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// First resolved address
+    addr: IpAddr,
+    /// All resolved addresses, starting with `addr`
+    addrs: Vec<IpAddr>,
+    /// Index into `addrs` of the next measurement for
+    /// [`DomainResolution::RoundRobin`]
+    next: usize,
+    valid_until: Instant,
+    /// Consecutive measurements in which `addr` was unreachable
+    failures: u32,
+    /// How often the resolved address changed
+    changes: u64,
+}
+
+impl Entry {
+    /// Pinned addresses are kept regardless of their TTL until they were
+    /// unreachable `ping.pin_max_failures` times in a row
+    fn is_usable(&self, config: &PingConfig, now: Instant) -> bool {
+        if config.pin_resolution {
+            self.failures < config.pin_max_failures
+        } else {
+            self.valid_until > now
+        }
+    }
+
+    fn resolution(&mut self, mode: DomainResolution) -> Resolution {
+        let addrs = match mode {
+            DomainResolution::First => vec![self.addr],
+            DomainResolution::All => self.addrs.clone(),
+            DomainResolution::RoundRobin => {
+                let addr = self.addrs[self.next % self.addrs.len()];
+                self.next = self.next.wrapping_add(1);
+                vec![addr]
+            }
+        };
+        Resolution {
+            addrs,
+            changes: self.changes,
+        }
+    }
+}
+
+/// Addresses of a domain target to ping and how often the first one changed
+#[derive(Debug, Clone)]
+pub struct Resolution {
+    /// Never empty
+    pub addrs: Vec<IpAddr>,
+    pub changes: u64,
+}
+
+impl DnsCache {
+    /// Resolves domain targets with `lookup` instead of the resolver of the
+    /// system configuration
+    pub fn with_lookup(lookup: impl AddressLookup + 'static) -> Self {
+        Self {
+            lookup: Some(Arc::new(lookup)),
+            ..Self::default()
+        }
+    }
+
+    /// The lookup passed to [`with_lookup`](Self::with_lookup)
+    pub(crate) fn lookup(&self) -> Option<Arc<dyn AddressLookup>> {
+        self.lookup.clone()
+    }
+
+    pub async fn resolve(
+        &self,
+        resolver: &impl AddressLookup,
+        domain: &str,
+        config: &PingConfig,
+    ) -> Result<Resolution, PingPrepareError> {
+        let now = Instant::now();
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(domain) {
+            if entry.is_usable(config, now) {
+                self.stats.lock().unwrap().cache_hits += 1;
+                return Ok(entry.resolution(config.domain_resolution));
+            }
+        }
+
+        let resolver = Instrumented {
+            lookup: resolver,
+            stats: &self.stats,
+        };
+        let (addrs, valid_until) = match lookup_preferred(&resolver, domain, config).await {
+            Ok(resolved) => resolved,
+            Err(error) => {
+                if let Some(reason) = error.resolve_reason() {
+                    let mut errors = self.errors.lock().unwrap();
+                    let errors = errors.entry(domain.to_owned()).or_default();
+                    *errors.entry(reason).or_default() += 1;
+                }
+                return Err(error);
+            }
+        };
+        let ttl = valid_until
+            .saturating_duration_since(now)
+            .clamp(config.dns_min_ttl, config.dns_max_ttl);
+        Ok(self.store(domain, addrs, now + ttl, config.domain_resolution))
+    }
+
+    /// Stores the non-empty `addrs` of `domain`
+    fn store(
+        &self,
+        domain: &str,
+        addrs: Vec<IpAddr>,
+        valid_until: Instant,
+        mode: DomainResolution,
+    ) -> Resolution {
+        let addr = addrs[0];
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .entry(domain.to_owned())
+            .and_modify(|entry| {
+                if entry.addr != addr {
+                    entry.addr = addr;
+                    entry.changes += 1;
+                }
+                // Even the same address gets another chance once re-resolved,
+                // otherwise a pinned one would be looked up again every time
+                entry.failures = 0;
+                entry.addrs.clone_from(&addrs);
+                entry.valid_until = valid_until;
+            })
+            .or_insert_with(|| Entry {
+                addr,
+                addrs: addrs.clone(),
+                next: 0,
+                valid_until,
+                failures: 0,
+                changes: 0,
+            });
+        entry.resolution(mode)
+    }
+
+    /// Number of failed resolutions of `domain` by reason
+    pub fn resolve_errors(&self, domain: &str) -> BTreeMap<ResolveErrorReason, u64> {
+        let errors = self.errors.lock().unwrap();
+        errors.get(domain).cloned().unwrap_or_default()
+    }
+
+    pub fn set_resolver_available(&self, available: bool) {
+        *self.resolver_available.lock().unwrap() = Some(available);
+    }
+
+    /// Writes `ping_resolver_available` and the `exporter_dns_*` statistics
+    /// once a resolver was needed
+    pub fn write_prometheus(&self, builder: &mut ExpositionBuilder) {
+        let Some(available) = *self.resolver_available.lock().unwrap() else {
+            return;
+        };
+        builder.add_metric(
+            PName::new("ping_resolver_available").unwrap(),
+            MetricType::Gauge,
+            "whether the resolver for domain targets could be created",
+            |mut builder| builder.add_line(&u8::from(available), None),
+        );
+        self.stats.lock().unwrap().write_prometheus(builder);
+    }
+
+    /// Records whether any ping to the cached address of `domain` succeeded,
+    /// given the round trip times of a measurement with `NaN` for lost pings.
+    pub fn report(&self, domain: &str, samples: &[f32]) {
+        let reachable = samples.iter().any(|ms| !ms.is_nan());
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(domain) {
+            if reachable {
+                entry.failures = 0;
+            } else {
+                entry.failures += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr},
+        time::Duration,
+    };
+
+    use hickory_resolver::error::ResolveErrorKind;
+
+    use super::*;
+    use crate::prometheus::ExpositionArena;
+
+    /// Resolves every domain to one IPv4 and one IPv6 address, only IPv4
+    /// addresses for `v4.example.com` and three IPv4 addresses for
+    /// `many.example.com`. Lookups of `timeout.example.com` time out.
+    struct MockLookup;
+
+    #[async_trait]
+    impl AddressLookup for MockLookup {
+        async fn lookup(
+            &self,
+            domain: &str,
+            family: AddressFamily,
+        ) -> Result<Records, ResolveError> {
+            if domain == "timeout.example.com" {
+                return Err(ResolveErrorKind::Timeout.into());
+            }
+            let addr = match family {
+                AddressFamily::Ipv4 if domain == "many.example.com" => {
+                    return Ok(Records {
+                        addrs: (1..=3)
+                            .map(|i| Ipv4Addr::new(192, 0, 2, i).into())
+                            .collect(),
+                        valid_until: Instant::now() + Duration::from_secs(60),
+                    })
+                }
+                AddressFamily::Ipv4 => IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)),
+                AddressFamily::Ipv6 if domain == "v4.example.com" => {
+                    return Ok(Records {
+                        addrs: Vec::new(),
+                        valid_until: Instant::now(),
+                    })
+                }
+                AddressFamily::Ipv6 => Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(),
+            };
+            Ok(Records {
+                addrs: vec![addr],
+                valid_until: Instant::now() + Duration::from_secs(60),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn address_family_preference() {
+        let resolve = |domain, ipv6_first, ipv6_only| async move {
+            let config = PingConfig {
+                ipv6_first,
+                ipv6_only,
+                ..Default::default()
+            };
+            lookup_preferred(&MockLookup, domain, &config)
+                .await
+                .map(|(addrs, _)| AddressFamily::of(addrs[0]))
+        };
+
+        let family = resolve("example.com", false, false).await.unwrap();
+        assert_eq!(family, AddressFamily::Ipv4);
+        let family = resolve("example.com", true, false).await.unwrap();
+        assert_eq!(family, AddressFamily::Ipv6);
+        let family = resolve("v4.example.com", true, false).await.unwrap();
+        assert_eq!(family, AddressFamily::Ipv4);
+        let family = resolve("example.com", false, true).await.unwrap();
+        assert_eq!(family, AddressFamily::Ipv6);
+        let error = resolve("v4.example.com", false, true).await.unwrap_err();
+        assert!(matches!(error, PingPrepareError::NoIp));
+    }
+
+    #[test]
+    fn address_changes_are_counted() {
+        let cache = DnsCache::default();
+        let later = Instant::now() + Duration::from_secs(60);
+        let a = IpAddr::from(Ipv4Addr::new(192, 0, 2, 1));
+        let b = IpAddr::from(Ipv4Addr::new(192, 0, 2, 2));
+
+        let store = |domain, addr| {
+            cache
+                .store(domain, vec![addr], later, DomainResolution::First)
+                .changes
+        };
+
+        assert_eq!(store("example.com", a), 0);
+        assert_eq!(store("example.com", a), 0);
+        assert_eq!(store("example.com", b), 1);
+        assert_eq!(store("example.org", b), 0);
+    }
+
+    #[tokio::test]
+    async fn addresses_are_selected_by_mode() {
+        let cache = DnsCache::default();
+        let mut config = PingConfig::default();
+        let addrs: Vec<IpAddr> = (1..=3)
+            .map(|i| Ipv4Addr::new(192, 0, 2, i).into())
+            .collect();
+        let mut resolve = |mode| {
+            config.domain_resolution = mode;
+            let config = config.clone();
+            let cache = &cache;
+            async move {
+                let resolution = cache.resolve(&MockLookup, "many.example.com", &config);
+                resolution.await.unwrap().addrs
+            }
+        };
+
+        assert_eq!(resolve(DomainResolution::First).await, addrs[..1]);
+        assert_eq!(resolve(DomainResolution::All).await, addrs);
+        let mut rotated = Vec::new();
+        for _ in 0..4 {
+            rotated.extend(resolve(DomainResolution::RoundRobin).await);
+        }
+        assert_eq!(rotated, [addrs[0], addrs[1], addrs[2], addrs[0]]);
+    }
+
+    #[test]
+    fn pinned_address_is_kept_until_unreachable() {
+        let mut config = PingConfig {
+            pin_max_failures: 2,
+            ..Default::default()
+        };
+        let cache = DnsCache::default();
+        let now = Instant::now();
+        cache.store(
+            "example.com",
+            vec![Ipv4Addr::LOCALHOST.into()],
+            now,
+            DomainResolution::First,
+        );
+        let usable = |config: &PingConfig| {
+            cache.entries.lock().unwrap()["example.com"].is_usable(config, now)
+        };
+
+        assert!(!usable(&config));
+        config.pin_resolution = true;
+        assert!(usable(&config));
+        // Measurements in which every ping was lost
+        cache.report("example.com", &[f32::NAN, f32::NAN]);
+        assert!(usable(&config));
+        cache.report("example.com", &[]);
+        assert!(!usable(&config));
+        cache.report("example.com", &[f32::NAN, 1.5]);
+        assert!(usable(&config));
+
+        cache.report("example.com", &[f32::NAN]);
+        cache.report("example.com", &[f32::NAN]);
+        assert!(!usable(&config));
+        // Resolving the same address again resets the failures
+        cache.store(
+            "example.com",
+            vec![Ipv4Addr::LOCALHOST.into()],
+            now,
+            DomainResolution::First,
+        );
+        assert!(usable(&config));
+    }
+
+    #[tokio::test]
+    async fn lookups_are_counted() {
+        let cache = DnsCache::default();
+        let config = PingConfig::default();
+        cache
+            .resolve(&MockLookup, "example.com", &config)
+            .await
+            .unwrap();
+        cache
+            .resolve(&MockLookup, "example.com", &config)
+            .await
+            .unwrap();
+        let error = cache.resolve(&MockLookup, "timeout.example.com", &config);
+        error.await.unwrap_err();
+
+        {
+            let stats = cache.stats.lock().unwrap();
+            // The timed out domain is looked up for both address families
+            assert_eq!(stats.lookups, 3);
+            assert_eq!(stats.cache_hits, 1);
+            assert_eq!(stats.failures[&ResolveErrorReason::Timeout], 2);
+            assert_eq!(stats.quantiles().len(), LOOKUP_QUANTILES.len());
+        }
+
+        cache.set_resolver_available(true);
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        cache.write_prometheus(&mut builder);
+        assert_eq!(builder.validate(), Ok(()));
+        let exposition = builder.to_string();
+        assert!(exposition.contains("\nexporter_dns_lookups_total 3\n"));
+        assert!(exposition.contains("\nexporter_dns_cache_hits_total 1\n"));
+        assert!(exposition.contains("\nexporter_dns_failures_total{reason=\"timeout\"} 2\n"));
+        assert!(exposition.contains("\nexporter_dns_lookup_duration_seconds_count 3\n"));
+        assert!(exposition.contains("exporter_dns_lookup_duration_seconds{quantile=\"0.99\"}"));
+    }
+
+    #[test]
+    fn quantiles_use_the_nearest_rank() {
+        let mut stats = ResolverStats::default();
+        for millis in 1..=100 {
+            stats.record(Duration::from_millis(millis), None);
+        }
+        assert_eq!(stats.quantiles(), [(0.5, 0.05), (0.9, 0.09), (0.99, 0.099)]);
+    }
+}
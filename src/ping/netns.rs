@@ -0,0 +1,87 @@
+//! Named network namespaces as managed by `ip netns` (Linux only).
+//!
+//! A socket stays in the namespace it was created in, so only its creation
+//! runs on a short-lived thread that entered the namespace. The thread exits
+//! afterwards instead of switching back.
+
+use std::io;
+
+/// Whether this build can enter network namespaces
+pub const SUPPORTED: bool = cfg!(all(feature = "netns", target_os = "linux"));
+
+/// Where `ip netns add` mounts named namespaces
+#[cfg(all(feature = "netns", target_os = "linux"))]
+const NETNS_RUN_DIR: &str = "/run/netns";
+
+/// Rejects names that would escape [`NETNS_RUN_DIR`].
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(format!("invalid network namespace name {name:?}"));
+    }
+    Ok(())
+}
+
+/// Runs `create` on a thread inside the network namespace `name`, within the
+/// current tokio runtime if there is one.
+#[cfg(all(feature = "netns", target_os = "linux"))]
+pub fn in_namespace<T: Send>(name: &str, create: impl FnOnce() -> T + Send) -> io::Result<T> {
+    use std::{fs::File, os::fd::AsRawFd, path::Path};
+
+    validate_name(name).map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+    let namespace = File::open(Path::new(NETNS_RUN_DIR).join(name))?;
+    let runtime = tokio::runtime::Handle::try_current().ok();
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                let _runtime = runtime.as_ref().map(|runtime| runtime.enter());
+                // SAFETY: `namespace` is a valid descriptor, setns only
+                // affects the calling thread
+                if unsafe { libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+                    let error = io::Error::last_os_error();
+                    return Err(match error.kind() {
+                        io::ErrorKind::PermissionDenied => io::Error::new(
+                            error.kind(),
+                            format!("{error} (entering network namespaces requires CAP_SYS_ADMIN)"),
+                        ),
+                        _ => error,
+                    });
+                }
+                Ok(create())
+            })
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+#[cfg(not(all(feature = "netns", target_os = "linux")))]
+pub fn in_namespace<T: Send>(_name: &str, _create: impl FnOnce() -> T + Send) -> io::Result<T> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "network namespaces require Linux and the netns feature",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_stay_within_run_dir() {
+        assert!(validate_name("blue").is_ok());
+        assert!(validate_name("vrf-mgmt.1").is_ok());
+        for name in ["", ".", "..", "../proc/1/ns/net", "a/b"] {
+            assert!(validate_name(name).is_err(), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn missing_namespace_fails_cleanly() {
+        let error = in_namespace("prometheus-speedtest-missing", || ()).unwrap_err();
+        let expected = if SUPPORTED {
+            io::ErrorKind::NotFound
+        } else {
+            io::ErrorKind::Unsupported
+        };
+        assert_eq!(error.kind(), expected);
+    }
+}
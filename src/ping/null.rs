@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::{PingErrorKind, PingSample, PingSchedule};
+
+/// Where the round trip times of pings come from
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum PingProvider {
+    /// ICMP echo requests of `ping.socket_type`
+    #[default]
+    Icmp,
+    Null(NullPingProvider),
+}
+
+/// Provider answering pings after preconfigured round trip times without any
+/// network access, useful for testing and dry runs. Consecutive pings cycle
+/// through `rtt_ms`, `nan` entries are lost.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct NullPingProvider {
+    pub rtt_ms: Vec<f32>,
+}
+
+impl NullPingProvider {
+    /// Samples like ICMP pings sent at `delay` would, without waiting
+    pub(super) fn sample(
+        &self,
+        schedule: PingSchedule,
+        delay: Duration,
+        payloads: &[Box<[u8]>],
+    ) -> (Vec<PingSample>, Vec<PingErrorKind>, u32) {
+        let count = match schedule {
+            PingSchedule::Samples(samples) => samples,
+            PingSchedule::Duration(duration) => {
+                (duration.as_secs_f64() / delay.as_secs_f64()).ceil() as usize
+            }
+        };
+        let mut errors = Vec::new();
+        let samples = (0..count)
+            .map(|seq| {
+                let ms = match self.rtt_ms.len() {
+                    0 => f32::NAN,
+                    len => self.rtt_ms[seq % len],
+                };
+                if ms.is_nan() {
+                    errors.push(PingErrorKind::Timeout {});
+                }
+                PingSample {
+                    seq,
+                    offset: delay * seq as u32,
+                    ms,
+                    payload: payloads[seq % payloads.len()].len(),
+                }
+            })
+            .collect();
+        (samples, errors, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cycles_through_round_trip_times() {
+        let provider: NullPingProvider = toml::from_str("rtt_ms = [10, 20, nan]").unwrap();
+        let payloads = [
+            vec![0; 8].into_boxed_slice(),
+            vec![0; 16].into_boxed_slice(),
+        ];
+        let (samples, errors, reordered) = provider.sample(
+            PingSchedule::Samples(4),
+            Duration::from_millis(100),
+            &payloads,
+        );
+
+        let ms: Vec<_> = samples.iter().map(|sample| sample.ms).collect();
+        assert_eq!(ms[..2], [10., 20.]);
+        assert!(ms[2].is_nan());
+        assert_eq!(ms[3], 10.);
+        assert_eq!(samples[3].offset, Duration::from_millis(300));
+        assert_eq!(samples[1].payload, 16);
+        assert!(matches!(errors[..], [PingErrorKind::Timeout {}]));
+        assert_eq!(reordered, 0);
+    }
+}
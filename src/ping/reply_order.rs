@@ -0,0 +1,109 @@
+use std::{collections::HashMap, net::IpAddr, sync::Mutex, time::SystemTime};
+
+use super::{PingErrorKind, PingResult, PingTarget};
+use crate::prometheus::{ExpositionBuilder, MetricType, PName};
+
+/// Replies that arrived out of order and pings with a duplicate sequence
+/// number per target and labeled address since startup.
+#[derive(Debug, Default)]
+pub(crate) struct ReplyOrderTotals(Mutex<HashMap<Series, Totals>>);
+
+type Series = (PingTarget, Option<IpAddr>);
+
+#[derive(Debug, Clone, Copy)]
+struct Totals {
+    /// Latest recorded measurement
+    measured_at: SystemTime,
+    reordered: u64,
+    duplicates: u64,
+}
+
+impl ReplyOrderTotals {
+    /// Adds the counts of a measurement. Results that were already recorded,
+    /// e.g. cached ones, are ignored.
+    pub fn record(&self, results: &[PingResult]) {
+        let mut totals = self.0.lock().unwrap();
+        for result in results {
+            let Some(summary) = &result.summary else {
+                continue;
+            };
+            let series = (result.target.clone(), result.address);
+            let totals = totals.entry(series).or_insert(Totals {
+                measured_at: SystemTime::UNIX_EPOCH,
+                reordered: 0,
+                duplicates: 0,
+            });
+            if totals.measured_at >= result.measured_at {
+                continue;
+            }
+            totals.measured_at = result.measured_at;
+            totals.reordered += u64::from(summary.reordered);
+            // The ICMP client discards duplicate replies, but reports a ping
+            // whose sequence number is still awaiting a reply
+            let duplicates = summary.errors.get(&PingErrorKind::IdenticalRequests);
+            totals.duplicates += u64::from(duplicates.copied().unwrap_or(0));
+        }
+    }
+
+    /// Writes `ping_reordered_total` and `ping_duplicate_total` of the
+    /// targets of `results`
+    pub fn write_prometheus(&self, builder: &mut ExpositionBuilder, results: &[PingResult]) {
+        let totals = self.0.lock().unwrap();
+        for result in results {
+            let Some(totals) = totals.get(&(result.target.clone(), result.address)) else {
+                continue;
+            };
+            result.with_target_labels(builder, |builder| {
+                builder.add_metric(
+                    PName::new("ping_reordered_total").unwrap(),
+                    MetricType::Counter,
+                    "number of replies that arrived after the reply to a later ping",
+                    |mut builder| builder.add_line(&totals.reordered, None),
+                );
+                builder.add_metric(
+                    PName::new("ping_duplicate_total").unwrap(),
+                    MetricType::Counter,
+                    "number of pings sent with a sequence number that was still awaiting a reply",
+                    |mut builder| builder.add_line(&totals.duplicates, None),
+                );
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{config::PingConfig, prometheus::ExpositionArena};
+
+    #[test]
+    fn totals_count_every_measurement_once() {
+        let target = PingTarget::Ip([10, 0, 0, 1].into());
+        let result = |reordered, age| {
+            let mut result = PingResult::mock(target.clone(), vec![1., 2.], &PingConfig::default());
+            let summary = result.summary.as_mut().unwrap();
+            summary.reordered = reordered;
+            summary.errors.insert(PingErrorKind::IdenticalRequests, 1);
+            result.measured_at = SystemTime::now() - age;
+            result
+        };
+        let totals = ReplyOrderTotals::default();
+        let older = result(2, Duration::from_secs(60));
+        totals.record(std::slice::from_ref(&older));
+        let newer = [result(1, Duration::ZERO)];
+        totals.record(&newer);
+        // Cached results are not counted twice
+        totals.record(&newer);
+        totals.record(std::slice::from_ref(&older));
+
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        totals.write_prometheus(&mut builder, &newer);
+        assert_eq!(builder.validate(), Ok(()));
+        let exposition = builder.to_string();
+        assert!(exposition.contains("\nping_reordered_total{target=\"10.0.0.1\"} 3\n"));
+        assert!(exposition.contains("\nping_duplicate_total{target=\"10.0.0.1\"} 2\n"));
+    }
+}
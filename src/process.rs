@@ -0,0 +1,133 @@
+//! Resource usage of the exporter itself, read from `/proc/self` on Linux.
+
+use std::io;
+
+use tracing::debug;
+
+use crate::prometheus::{ExpositionBuilder, MetricType, PName};
+
+/// Clock ticks per second of the CPU times in `/proc`, fixed by the kernel
+/// ABI regardless of the actual timer frequency
+#[cfg(target_os = "linux")]
+const USER_HZ: f64 = 100.;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ProcessStats {
+    pub resident_memory_bytes: u64,
+    pub virtual_memory_bytes: u64,
+    /// User and system time
+    pub cpu_seconds: f64,
+    pub open_fds: u64,
+}
+
+impl ProcessStats {
+    #[cfg(target_os = "linux")]
+    pub fn read() -> io::Result<Self> {
+        use std::fs;
+
+        let invalid = |what| io::Error::new(io::ErrorKind::InvalidData, what);
+
+        // The command in parentheses may contain spaces
+        let stat = fs::read_to_string("/proc/self/stat")?;
+        let (_, fields) = stat
+            .rsplit_once(')')
+            .ok_or_else(|| invalid("malformed /proc/self/stat"))?;
+        // Fields after the command start at the third, `utime` is the 14th
+        let mut fields = fields.split_whitespace().skip(11);
+        let mut ticks = || -> io::Result<u64> {
+            (fields.next())
+                .and_then(|it| it.parse().ok())
+                .ok_or_else(|| invalid("malformed CPU time in /proc/self/stat"))
+        };
+        let cpu_ticks = ticks()? + ticks()?;
+
+        let status = fs::read_to_string("/proc/self/status")?;
+        let kib = |key: &str| -> io::Result<u64> {
+            (status.lines())
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+                .and_then(|value| value.trim().strip_suffix(" kB")?.parse::<u64>().ok())
+                .map(|kib| kib * 1024)
+                .ok_or_else(|| invalid("missing memory usage in /proc/self/status"))
+        };
+
+        Ok(Self {
+            resident_memory_bytes: kib("VmRSS")?,
+            virtual_memory_bytes: kib("VmSize")?,
+            cpu_seconds: cpu_ticks as f64 / USER_HZ,
+            open_fds: fs::read_dir("/proc/self/fd")?.count() as u64,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn read() -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn write_prometheus(&self, builder: &mut ExpositionBuilder) {
+        builder.add_metric_with_unit(
+            PName::new("speedtest_process_resident_memory_bytes").unwrap(),
+            MetricType::Gauge,
+            Some(PName::UNIT_BYTES),
+            "resident memory of the exporter in bytes",
+            |mut builder| builder.add_line(&self.resident_memory_bytes, None),
+        );
+        builder.add_metric_with_unit(
+            PName::new("speedtest_process_virtual_memory_bytes").unwrap(),
+            MetricType::Gauge,
+            Some(PName::UNIT_BYTES),
+            "virtual memory of the exporter in bytes",
+            |mut builder| builder.add_line(&self.virtual_memory_bytes, None),
+        );
+        builder.add_metric_with_unit(
+            PName::new("speedtest_process_cpu_seconds_total").unwrap(),
+            MetricType::Counter,
+            Some(PName::UNIT_SECONDS),
+            "user and system CPU time of the exporter in seconds",
+            |mut builder| builder.add_line(&self.cpu_seconds, None),
+        );
+        builder.add_metric(
+            PName::new("speedtest_process_open_fds").unwrap(),
+            MetricType::Gauge,
+            "number of open file descriptors of the exporter",
+            |mut builder| builder.add_line(&self.open_fds, None),
+        );
+    }
+}
+
+/// Writes the [`ProcessStats`] if they are available on this platform
+pub(crate) fn write_process_metrics(builder: &mut ExpositionBuilder) {
+    match ProcessStats::read() {
+        Ok(stats) => stats.write_prometheus(builder),
+        Err(error) => debug!(%error, "Process metrics are unavailable"),
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use crate::prometheus::ExpositionArena;
+
+    use super::*;
+
+    #[test]
+    fn stats_of_this_process_are_positive() {
+        // Burn some CPU time so that at least one tick has passed
+        let mut sum = 0u64;
+        let mut stats = ProcessStats::read().unwrap();
+        while stats.cpu_seconds == 0. {
+            sum = (0..1_000_000u64).fold(sum, |acc, it| acc.wrapping_add(it * it));
+            stats = ProcessStats::read().unwrap();
+        }
+        std::hint::black_box(sum);
+        assert!(stats.resident_memory_bytes > 0);
+        assert!(stats.virtual_memory_bytes >= stats.resident_memory_bytes);
+        assert!(stats.open_fds > 0);
+
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        stats.write_prometheus(&mut builder);
+        assert_eq!(builder.validate(), Ok(()));
+        assert!(builder
+            .to_string()
+            .contains("\nspeedtest_process_open_fds "));
+    }
+}
@@ -1,21 +1,35 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fmt::{self, Debug, Display, Write},
     mem,
     time::SystemTime,
 };
 
+mod arena;
 mod go_floats;
+mod naming;
+#[cfg(test)]
+pub(crate) mod parser;
 mod strings;
+mod validate;
 
+pub use arena::*;
 pub use go_floats::*;
+pub use naming::*;
 pub use strings::*;
-use typed_arena::Arena;
+use tracing::warn;
+pub use validate::ExpositionValidationError;
 
 pub struct ExpositionBuilder<'a> {
-    alloc: &'a Arena<u8>,
+    alloc: &'a ExpositionArena,
+    format: ExpositionFormat,
+    naming: MetricNaming,
+    renames: BTreeMap<PNameBuf, PNameBuf>,
     buffer: String,
     entries: HashMap<&'a PName, MetricGroup<'a>>,
+    /// Bytes of the headers and lines added so far
+    added_len: usize,
+    size_limit: usize,
     pub labels: LabelBuilder,
     pub name: PNameBuilder,
 }
@@ -25,18 +39,79 @@ struct MetricGroup<'a> {
     lines: Vec<&'a str>,
 }
 
+/// Text format written by an [`ExpositionBuilder`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ExpositionFormat {
+    /// Prometheus text format version 0.0.4
+    #[default]
+    Prometheus,
+    /// OpenMetrics text format version 1.0.0
+    OpenMetrics,
+}
+
 impl<'a> ExpositionBuilder<'a> {
     #[inline]
-    pub fn new(alloc: &'a Arena<u8>) -> Self {
+    pub fn new(alloc: &'a ExpositionArena) -> Self {
+        Self::with_format(alloc, ExpositionFormat::default())
+    }
+
+    #[inline]
+    pub fn with_format(alloc: &'a ExpositionArena, format: ExpositionFormat) -> Self {
         Self {
             alloc,
+            format,
+            naming: MetricNaming::default(),
+            renames: BTreeMap::new(),
             buffer: String::new(),
             entries: HashMap::new(),
+            added_len: 0,
+            size_limit: usize::MAX,
             labels: LabelBuilder::new(),
             name: PNameBuilder::new(),
         }
     }
 
+    /// Selects the names of the renamed metrics, see [`CONVENTIONAL_NAMES`]
+    #[inline]
+    pub fn set_naming(&mut self, naming: MetricNaming) {
+        self.naming = naming;
+    }
+
+    /// Exposes the metrics named like the keys of `renames` under its values
+    /// instead
+    #[inline]
+    pub fn set_renames(&mut self, renames: &BTreeMap<PNameBuf, PNameBuf>) {
+        self.renames.clone_from(renames);
+    }
+
+    /// Stops adding metrics to the arena once the rendered exposition would
+    /// exceed `limit` bytes, see [`Self::exceeds_size_limit`]
+    #[inline]
+    pub fn set_size_limit(&mut self, limit: usize) {
+        self.size_limit = limit;
+    }
+
+    /// Whether metrics were left out because of the size limit, in which
+    /// case the rendered exposition is incomplete
+    #[inline]
+    pub fn exceeds_size_limit(&self) -> bool {
+        self.added_len + self.eof().len() > self.size_limit
+    }
+
+    /// Bytes of the headers and lines added so far, which stops growing once
+    /// the size limit is exceeded
+    #[inline]
+    pub fn added_len(&self) -> usize {
+        self.added_len
+    }
+
+    /// Name, unit and value conversion of the metric with the legacy name
+    /// `legacy` in the selected naming
+    #[inline]
+    pub fn metric_name(&self, legacy: &'static PName) -> NamedMetric {
+        self.naming.metric(legacy)
+    }
+
     #[inline]
     pub fn with_label<R>(
         &mut self,
@@ -66,77 +141,215 @@ impl<'a> ExpositionBuilder<'a> {
         help_text: impl PrometheusHelpTextSource,
         closure: impl FnOnce(ExpositionMetricBuilder<'a, '_>) -> R,
     ) -> R {
-        self.name.push(metric_suffix);
-
-        let group_name = if let Some((key, _)) = self.entries.get_key_value(self.name.as_ref()) {
-            key
-        } else {
-            let group_name = self.alloc_pname(self.name.as_ref());
-            let metric_name = self.name.as_ref();
-            self.buffer.clear();
-            write!(self.buffer, "# HELP {metric_name} ").unwrap();
-            let help_text_start = self.buffer.len();
-            help_text.write_help_text(&mut self.buffer);
-            // SAFETY: Replaces ASCII char with another ASCII char
-            unsafe {
-                let raw = &mut self.buffer.as_mut_vec()[help_text_start..];
-                for byte in raw {
-                    if *byte == b'\n' {
-                        *byte = b' ';
-                    }
-                }
-            }
-            writeln!(self.buffer, "\n# TYPE {metric_name} {metric_type}").unwrap();
-            let group = MetricGroup {
-                help: self.alloc.alloc_str(&self.buffer[..]),
-                lines: Vec::new(),
-            };
-            self.entries.insert(group_name, group);
-            group_name
-        };
+        self.add_metric_with_unit(metric_suffix, metric_type, None, help_text, closure)
+    }
 
-        let saved_name = mem::take(&mut self.name);
-        // Only store added suffixes
+    /// Like [`Self::add_metric`], but declares the unit of the metric in the
+    /// OpenMetrics format. The metric name must end with `_<unit>`, followed
+    /// by `_total` for counters.
+    #[inline]
+    pub fn add_metric_with_unit<R>(
+        &mut self,
+        metric_suffix: &PName,
+        metric_type: MetricType,
+        unit: Option<&PName>,
+        help_text: impl PrometheusHelpTextSource,
+        closure: impl FnOnce(ExpositionMetricBuilder<'a, '_>) -> R,
+    ) -> R {
+        self.name.push(metric_suffix);
+        // The metric builder only pushes the suffixes of its lines
+        let full_name = mem::take(&mut self.name);
+        let group_name = self.register(full_name.as_ref(), metric_type, unit, help_text);
         let r = closure(ExpositionMetricBuilder {
             inner: self,
             group_name,
         });
-        self.name = saved_name;
+        self.name = full_name;
         self.name.pop();
         r
     }
 
-    fn alloc_pname(&self, pname: &PName) -> &'a PName {
-        unsafe { PName::new_unchecked(self.alloc.alloc_str(pname.as_ref())) }
+    /// Returns the group of the metric exposed as `name` or its new name,
+    /// creating it on first use, or `None` once the size limit is exceeded
+    fn register(
+        &mut self,
+        name: &PName,
+        metric_type: MetricType,
+        unit: Option<&PName>,
+        help_text: impl PrometheusHelpTextSource,
+    ) -> Option<&'a PName> {
+        if self.exceeds_size_limit() {
+            return None;
+        }
+        let mut unit = unit;
+        let name = match self.renames.get(name) {
+            Some(renamed) => {
+                // The unit is only declared if the new name still ends with it
+                let family = match metric_type {
+                    MetricType::Counter => renamed.strip_suffix("_total"),
+                    _ => None,
+                };
+                unit = unit.filter(|unit| {
+                    (family.unwrap_or(renamed).strip_suffix(&***unit))
+                        .is_some_and(|rest| rest.ends_with('_'))
+                });
+                renamed.as_ref()
+            }
+            None => name,
+        };
+        write_header(
+            &mut self.buffer,
+            self.format,
+            name,
+            metric_type,
+            unit,
+            help_text,
+        );
+
+        if let Some((key, group)) = self.entries.get_key_value(name) {
+            // Lines of both registrations would end up below the first header
+            if group.help != self.buffer {
+                let message = format!(
+                    "metric {key} was registered with conflicting metadata:\n{}\n{}",
+                    group.help.trim_end(),
+                    self.buffer.trim_end()
+                );
+                if cfg!(debug_assertions) {
+                    panic!("{message}");
+                }
+                warn!("{message}");
+            }
+            return Some(key);
+        }
+        self.added_len += self.buffer.len();
+        if self.exceeds_size_limit() {
+            return None;
+        }
+        let group_name = self.alloc.alloc_pname(name);
+        let group = MetricGroup {
+            help: self.alloc.alloc_str(&self.buffer[..]),
+            lines: Vec::new(),
+        };
+        self.entries.insert(group_name, group);
+        Some(group_name)
     }
 }
 
-impl<'s, 'a> Display for ExpositionBuilder<'a>
-where
-    Self: 's,
-{
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut sorted: Vec<_> = self
-            .entries
-            .iter()
+/// Writes the `# HELP`, `# TYPE` and `# UNIT` lines of `metric_name` into
+/// `buffer`.
+fn write_header(
+    buffer: &mut String,
+    format: ExpositionFormat,
+    metric_name: &PName,
+    metric_type: MetricType,
+    unit: Option<&PName>,
+    help_text: impl PrometheusHelpTextSource,
+) {
+    // OpenMetrics names the family of a counter without its `_total` suffix,
+    // which only its samples carry
+    let family = match (format, metric_type) {
+        (ExpositionFormat::OpenMetrics, MetricType::Counter) => {
+            metric_name.strip_suffix("_total").unwrap_or(metric_name)
+        }
+        _ => metric_name,
+    };
+    buffer.clear();
+    write!(buffer, "# HELP {family} ").unwrap();
+    let help_text_start = buffer.len();
+    help_text.write_help_text(buffer);
+    // Help texts are a single line, multi-line ones are rare
+    if buffer[help_text_start..].contains('\n') {
+        let help_text = buffer[help_text_start..].replace('\n', " ");
+        buffer.truncate(help_text_start);
+        buffer.push_str(&help_text);
+    }
+    match format {
+        ExpositionFormat::Prometheus => {
+            writeln!(buffer, "\n# TYPE {family} {metric_type}").unwrap();
+        }
+        ExpositionFormat::OpenMetrics => {
+            let metric_type = metric_type.openmetrics_name();
+            writeln!(buffer, "\n# TYPE {family} {metric_type}").unwrap();
+            if let Some(unit) = unit {
+                debug_assert!(
+                    (family.strip_suffix(&**unit)).is_some_and(|rest| rest.ends_with('_')),
+                    "metric name {metric_name} must end with its unit {unit}"
+                );
+                writeln!(buffer, "# UNIT {family} {unit}").unwrap();
+            }
+        }
+    }
+}
+
+impl ExpositionBuilder<'_> {
+    /// Renders the exposition like [`ToString::to_string`], but into a
+    /// string allocated once with the exact length instead of growing it
+    /// line by line.
+    pub fn render(&self) -> String {
+        let groups = self.sorted_groups();
+        let mut out = String::with_capacity(self.rendered_len());
+        self.write_groups(&groups, &mut out).unwrap();
+        out
+    }
+
+    /// Length of the rendered exposition in bytes, computed without
+    /// rendering it
+    pub fn rendered_len(&self) -> usize {
+        (self.entries.iter())
             .filter(|(_, group)| !group.lines.is_empty())
-            .map(|(k, v)| (*k, v))
-            .collect();
+            .map(|(name, group)| {
+                let lines: usize = group.lines.iter().map(|line| name.len() + line.len()).sum();
+                group.help.len() + lines
+            })
+            .sum::<usize>()
+            + self.eof().len()
+    }
+
+    /// Groups with at least one line, sorted by name
+    fn sorted_groups(&self) -> Vec<(&PName, &MetricGroup<'_>)> {
+        let mut sorted = Vec::with_capacity(self.entries.len());
+        sorted.extend(
+            (self.entries.iter())
+                .filter(|(_, group)| !group.lines.is_empty())
+                .map(|(k, v)| (*k, v)),
+        );
         sorted.sort_unstable_by_key(|(k, _)| *k);
-        for (name, group) in sorted {
-            f.write_str(group.help)?;
+        sorted
+    }
+
+    fn write_groups(
+        &self,
+        groups: &[(&PName, &MetricGroup<'_>)],
+        out: &mut impl Write,
+    ) -> fmt::Result {
+        for (name, group) in groups {
+            out.write_str(group.help)?;
             for line in &group.lines {
-                f.write_str(name)?;
-                f.write_str(line)?;
+                out.write_str(name)?;
+                out.write_str(line)?;
             }
         }
-        Ok(())
+        out.write_str(self.eof())
+    }
+
+    fn eof(&self) -> &'static str {
+        match self.format {
+            ExpositionFormat::Prometheus => "",
+            ExpositionFormat::OpenMetrics => "# EOF\n",
+        }
+    }
+}
+
+impl Display for ExpositionBuilder<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_groups(&self.sorted_groups(), f)
     }
 }
 
 pub struct ExpositionMetricBuilder<'a, 'b> {
     inner: &'b mut ExpositionBuilder<'a>,
-    group_name: &'a PName,
+    /// `None` if the metric was left out because of the size limit
+    group_name: Option<&'a PName>,
 }
 
 impl ExpositionMetricBuilder<'_, '_> {
@@ -146,7 +359,7 @@ impl ExpositionMetricBuilder<'_, '_> {
         // Note that this is only the suffix being pushed, if any
         self.inner.buffer.push_str(self.inner.name.as_ref());
         write!(self.inner.buffer, "{} ", self.inner.labels).unwrap();
-        data.serialize_go_float(&mut self.inner.buffer).unwrap();
+        self.write_value(data);
         if let Some(at) = at {
             write!(
                 self.inner.buffer,
@@ -175,7 +388,7 @@ impl ExpositionMetricBuilder<'_, '_> {
         self.inner.with_label(label, value, |builder| {
             write!(builder.buffer, "{} ", builder.labels).unwrap();
         });
-        data.serialize_go_float(&mut self.inner.buffer).unwrap();
+        self.write_value(data);
         if let Some(at) = at {
             write!(
                 self.inner.buffer,
@@ -190,10 +403,48 @@ impl ExpositionMetricBuilder<'_, '_> {
         self.add_line_entry();
     }
 
+    /// Adds a line labeled with `quantile` and its configured `name`, if any
+    pub fn add_quantile_line(
+        &mut self,
+        quantile: f64,
+        name: Option<&str>,
+        data: &(impl SerializeGoFloat + ?Sized),
+    ) {
+        self.with_label(
+            PName::QUANTILE,
+            quantile.to_string().as_str(),
+            |builder| match name {
+                Some(name) => builder.add_line_labeled(PName::QUANTILE_NAME, name, data, None),
+                None => builder.add_line(data, None),
+            },
+        );
+    }
+
+    /// Writes `data` in the float syntax of the format
+    #[inline]
+    fn write_value(&mut self, data: &(impl SerializeGoFloat + ?Sized)) {
+        let buffer = &mut self.inner.buffer;
+        match self.inner.format {
+            ExpositionFormat::Prometheus => data.serialize_go_float(buffer),
+            ExpositionFormat::OpenMetrics => data.serialize_decimal_float(buffer),
+        }
+        .unwrap();
+    }
+
     #[inline]
     fn add_line_entry(&mut self) {
+        let Some(group_name) = self.group_name else {
+            return;
+        };
+        if self.inner.exceeds_size_limit() {
+            return;
+        }
+        self.inner.added_len += group_name.len() + self.inner.buffer.len();
+        if self.inner.exceeds_size_limit() {
+            return;
+        }
         let line = self.inner.alloc.alloc_str(&self.inner.buffer[..]);
-        let existing = self.inner.entries.get_mut(self.group_name).unwrap();
+        let existing = self.inner.entries.get_mut(group_name).unwrap();
         existing.lines.push(line);
     }
 
@@ -232,14 +483,29 @@ pub enum MetricType {
 impl Display for MetricType {
     #[inline]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let name = match *self {
+        f.write_str(self.prometheus_name())
+    }
+}
+
+impl MetricType {
+    /// Name of the type in the OpenMetrics format
+    #[inline]
+    pub fn openmetrics_name(&self) -> &'static str {
+        match *self {
+            Self::Untyped => "unknown",
+            _ => self.prometheus_name(),
+        }
+    }
+
+    #[inline]
+    fn prometheus_name(&self) -> &'static str {
+        match *self {
             Self::Counter => "counter",
             Self::Gauge => "gauge",
             Self::Histogram => "histogram",
             Self::Summary => "summary",
             Self::Untyped => "untyped",
-        };
-        f.write_str(name)
+        }
     }
 }
 
@@ -307,6 +573,23 @@ impl LabelBuilder {
         }
     }
 
+    /// Number of pushed labels, to be passed to [`Self::restore`]
+    #[inline]
+    pub fn checkpoint(&self) -> usize {
+        self.waypoints.len()
+    }
+
+    /// Pops labels until only `depth` are left, e.g. after an early return
+    /// skipped the matching [`Self::pop`] calls. Prefer
+    /// [`ExpositionBuilder::with_label`] where a closure fits.
+    #[inline]
+    pub fn restore(&mut self, depth: usize) {
+        if let Some(&len) = self.waypoints.get(depth) {
+            self.buf.truncate(len);
+            self.waypoints.truncate(depth);
+        }
+    }
+
     #[inline]
     pub fn clear(&mut self) {
         self.buf.clear();
@@ -347,3 +630,230 @@ impl PrometheusHelpTextSource for &str {
         buf.push_str(self);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+    };
+
+    use super::*;
+    use crate::{
+        config::PingConfig,
+        ping::{PingResult, PingTarget},
+    };
+
+    /// Counts the allocations of each thread, so that tests running in
+    /// parallel do not interfere
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            // Fails while the thread is torn down
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Runs `f` and returns the number of allocations and reallocations it
+    /// made on this thread
+    fn allocations<R>(f: impl FnOnce() -> R) -> (R, usize) {
+        let before = ALLOCATIONS.with(Cell::get);
+        let r = f();
+        (r, ALLOCATIONS.with(Cell::get) - before)
+    }
+
+    /// Ping results of `targets` targets with ten quantiles each
+    fn ping_results(targets: u8) -> (Vec<PingResult>, PingConfig) {
+        let config = PingConfig {
+            quantiles: vec![0., 0.1, 0.25, 0.5, 0.75, 0.9, 0.95, 0.99, 0.999, 1.].into(),
+            ..Default::default()
+        };
+        let results = (0..targets)
+            .map(|i| {
+                let samples = (0..60).map(|it| 10. + it as f32 / 4.).collect();
+                PingResult::mock(PingTarget::Ip([10, 0, 0, i].into()), samples, &config)
+            })
+            .collect();
+        (results, config)
+    }
+
+    fn write_pings(builder: &mut ExpositionBuilder, results: &[PingResult], config: &PingConfig) {
+        for result in results {
+            result.write_prometheus(builder, config);
+        }
+    }
+
+    #[test]
+    // Too slow for miri
+    #[cfg_attr(miri, ignore)]
+    fn pooled_arenas_are_not_reallocated() {
+        let (results, config) = ping_results(100);
+        let build = |alloc: &ExpositionArena| {
+            let mut builder = ExpositionBuilder::new(alloc);
+            write_pings(&mut builder, &results, &config);
+            builder.render()
+        };
+        let (fresh, fresh_allocations) = allocations(|| build(&ExpositionArena::new()));
+        // Grows the pooled arena
+        ExpositionArena::with_pooled(build);
+        let (pooled, pooled_allocations) = allocations(|| ExpositionArena::with_pooled(build));
+        assert_eq!(fresh, pooled);
+        assert!(
+            pooled_allocations < fresh_allocations,
+            "{pooled_allocations} allocations with pooled arena, {fresh_allocations} without"
+        );
+    }
+
+    #[test]
+    // Too slow for miri
+    #[cfg_attr(miri, ignore)]
+    fn rendering_allocates_the_output_once() {
+        let (results, config) = ping_results(100);
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        write_pings(&mut builder, &results, &config);
+
+        let (rendered, rendering) = allocations(|| builder.render());
+        let (displayed, displaying) = allocations(|| builder.to_string());
+        assert_eq!(rendered, displayed);
+        assert_eq!(rendered.len(), rendered.capacity());
+        assert_eq!(rendered.len(), builder.rendered_len());
+        // The sorted groups and the output
+        assert_eq!(rendering, 2);
+        assert!(displaying > rendering, "{displaying} allocations");
+    }
+
+    fn register(builder: &mut ExpositionBuilder, metric_type: MetricType, help: &str) {
+        builder.add_metric(
+            PName::new("ping_ms").unwrap(),
+            metric_type,
+            help,
+            |mut builder| builder.add_line(&1, None),
+        );
+    }
+
+    #[test]
+    fn labels_are_restored_to_checkpoint() {
+        let mut labels = LabelBuilder::new();
+        let target = PName::new("target").unwrap();
+        labels.push(target, "1.1.1.1");
+        let checkpoint = labels.checkpoint();
+        labels.push(PName::new("netns").unwrap(), "blue");
+        labels.push(PName::new("address").unwrap(), "10.0.0.1");
+        assert_eq!(labels.checkpoint(), 3);
+
+        labels.restore(checkpoint);
+        assert_eq!(labels.checkpoint(), 1);
+        assert_eq!(labels.to_string(), "{target=\"1.1.1.1\"}");
+        // Restoring to a deeper checkpoint has no effect
+        labels.restore(2);
+        assert_eq!(labels.checkpoint(), 1);
+        labels.restore(0);
+        assert!(labels.is_empty());
+    }
+
+    #[test]
+    fn identical_registrations_are_merged() {
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        register(&mut builder, MetricType::Gauge, "ping");
+        register(&mut builder, MetricType::Gauge, "ping");
+        assert_eq!(
+            builder.to_string(),
+            "# HELP ping_ms ping\n# TYPE ping_ms gauge\nping_ms 1\nping_ms 1\n"
+        );
+    }
+
+    #[test]
+    fn help_texts_are_one_line() {
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        register(&mut builder, MetricType::Gauge, "round trip\ntime");
+        let prefixed = PName::new("probe_").unwrap();
+        builder.with_name(prefixed, |builder| {
+            register(builder, MetricType::Gauge, "prefixed");
+        });
+        assert_eq!(
+            builder.to_string(),
+            "# HELP ping_ms round trip time\n# TYPE ping_ms gauge\nping_ms 1\n\
+             # HELP probe_ping_ms prefixed\n# TYPE probe_ping_ms gauge\nprobe_ping_ms 1\n"
+        );
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "conflicting metadata"))]
+    fn conflicting_registrations_are_rejected() {
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        register(&mut builder, MetricType::Gauge, "ping");
+        register(&mut builder, MetricType::Summary, "ping");
+    }
+
+    #[test]
+    fn counter_units_precede_the_total_suffix() {
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::with_format(&alloc, ExpositionFormat::OpenMetrics);
+        builder.add_metric_with_unit(
+            PName::new("cpu_seconds_total").unwrap(),
+            MetricType::Counter,
+            Some(PName::UNIT_SECONDS),
+            "CPU time",
+            |mut builder| builder.add_line(&1.5, None),
+        );
+        assert_eq!(
+            builder.to_string(),
+            "# HELP cpu_seconds CPU time\n\
+             # TYPE cpu_seconds counter\n\
+             # UNIT cpu_seconds seconds\n\
+             cpu_seconds_total 1.5\n\
+             # EOF\n"
+        );
+    }
+
+    #[test]
+    fn arena_stops_growing_at_the_size_limit() {
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        builder.set_size_limit(1000);
+        let write_lines = |builder: &mut ExpositionBuilder| {
+            builder.add_metric(
+                PName::new("huge").unwrap(),
+                MetricType::Gauge,
+                "artificially huge metric",
+                |mut builder| {
+                    for i in 0..1000 {
+                        builder.add_line_labeled(PName::new("i").unwrap(), &i, &i, None);
+                    }
+                },
+            );
+        };
+        write_lines(&mut builder);
+        assert!(builder.exceeds_size_limit());
+        let added = builder.added_len();
+        assert!((1000..1100).contains(&added), "{added}");
+        assert!(builder.rendered_len() <= 1000);
+        let capacity = alloc.capacity();
+
+        write_lines(&mut builder);
+        assert_eq!(builder.added_len(), added);
+        assert_eq!(alloc.capacity(), capacity);
+    }
+}
@@ -0,0 +1,125 @@
+use std::cell::RefCell;
+
+use super::PName;
+
+/// Capacity of the first chunk of an arena
+const MIN_CHUNK_CAPACITY: usize = 4096;
+
+/// Bump allocator of the strings of an
+/// [`ExpositionBuilder`](super::ExpositionBuilder). Unlike `typed_arena`, it
+/// can be [reset](Self::reset) to reuse its memory for the next exposition.
+#[derive(Debug, Default)]
+pub struct ExpositionArena {
+    /// Chunks are never grown, so that allocated strings stay in place
+    chunks: RefCell<Vec<String>>,
+}
+
+thread_local! {
+    static POOLED: RefCell<Option<ExpositionArena>> = const { RefCell::new(None) };
+}
+
+impl ExpositionArena {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `f` with the arena of this thread, which is reset afterwards.
+    /// Nested calls get a fresh arena.
+    pub fn with_pooled<R>(f: impl FnOnce(&ExpositionArena) -> R) -> R {
+        let mut arena = POOLED.with(|pooled| pooled.take()).unwrap_or_default();
+        let r = f(&arena);
+        arena.reset();
+        POOLED.with(|pooled| *pooled.borrow_mut() = Some(arena));
+        r
+    }
+
+    pub fn alloc_str(&self, s: &str) -> &str {
+        let mut chunks = self.chunks.borrow_mut();
+        let fits = (chunks.last()).is_some_and(|chunk| chunk.capacity() - chunk.len() >= s.len());
+        if !fits {
+            let capacity = (chunks.last())
+                .map_or(MIN_CHUNK_CAPACITY, |chunk| chunk.capacity() * 2)
+                .max(s.len());
+            chunks.push(String::with_capacity(capacity));
+        }
+        let chunk = chunks.last_mut().unwrap();
+        let start = chunk.len();
+        chunk.push_str(s);
+        let allocated: *const str = &chunk[start..];
+        // SAFETY: The chunk had enough capacity and did not reallocate.
+        // Chunks are only dropped or cleared by `reset`, which requires that
+        // no allocated string is borrowed anymore.
+        unsafe { &*allocated }
+    }
+
+    pub fn alloc_pname(&self, name: &PName) -> &PName {
+        let allocated = self.alloc_str(name.as_ref());
+        // SAFETY: An exact copy of a valid name is valid
+        unsafe { PName::new_unchecked(allocated) }
+    }
+
+    /// Forgets every allocated string, keeping only the largest chunk so
+    /// that the next exposition of similar size allocates nothing
+    pub fn reset(&mut self) {
+        let chunks = self.chunks.get_mut();
+        if let Some(mut largest) = chunks.pop() {
+            largest.clear();
+            chunks.clear();
+            chunks.push(largest);
+        }
+    }
+
+    /// Total capacity of the chunks in bytes
+    pub fn capacity(&self) -> usize {
+        self.chunks.borrow().iter().map(String::capacity).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_stay_in_place() {
+        let mut arena = ExpositionArena::new();
+        let long = "x".repeat(3 * MIN_CHUNK_CAPACITY);
+        let strings: Vec<&str> = (0..100)
+            .map(|i| match i % 10 {
+                0 => arena.alloc_str(&long),
+                _ => arena.alloc_str("line\n"),
+            })
+            .collect();
+        for (i, s) in strings.iter().enumerate() {
+            assert_eq!(*s, if i % 10 == 0 { &long[..] } else { "line\n" });
+        }
+
+        let capacity = arena.capacity();
+        arena.reset();
+        let largest = arena.capacity();
+        assert!(largest > 0 && largest < capacity);
+        arena.alloc_str("line\n");
+        assert_eq!(arena.capacity(), largest);
+    }
+
+    #[test]
+    fn names_are_copied() {
+        let arena = ExpositionArena::new();
+        let mut owned = PName::new("ping_ms").unwrap().to_owned();
+        let name = arena.alloc_pname(&owned);
+        owned = PName::new("packet_loss").unwrap().to_owned();
+        assert_eq!(name.as_ref(), "ping_ms");
+        assert_eq!(owned.as_ref() as &str, "packet_loss");
+    }
+
+    #[test]
+    fn pooled_arena_is_reused() {
+        let first = ExpositionArena::with_pooled(|arena| {
+            arena.alloc_str("line\n");
+            ExpositionArena::with_pooled(|nested| assert_eq!(nested.capacity(), 0));
+            arena.capacity()
+        });
+        let second = ExpositionArena::with_pooled(|arena| arena.capacity());
+        assert_eq!(first, second);
+    }
+}
@@ -1,7 +1,21 @@
+//! Floats as exact hexfloats such as `+0x1.4p-3` that Go's
+//! `strconv.ParseFloat`, and thus Prometheus, reads back bit for bit. Only
+//! parity with Go's parser matters, the output differs from Go's
+//! `strconv.FormatFloat(f, 'x', -1, 64)`, which writes `0x1.4p-03`.
+//!
+//! OpenMetrics does not allow hexfloats, so the same values are written as
+//! the shortest decimal that reads back exactly when that format is used.
+
 use core::fmt;
 
 pub trait SerializeGoFloat {
     fn serialize_go_float<W: fmt::Write>(&self, write: &mut W) -> fmt::Result;
+
+    /// Writes the value as a decimal number, which OpenMetrics requires
+    #[inline]
+    fn serialize_decimal_float<W: fmt::Write>(&self, write: &mut W) -> fmt::Result {
+        self.serialize_go_float(write)
+    }
 }
 
 macro_rules! display_impl {
@@ -18,19 +32,29 @@ macro_rules! display_impl {
 }
 
 macro_rules! delegate_impl {
-    ($Type:ty => $impl:path) => {
+    ($Type:ty => $impl:path, $decimal_impl:path) => {
         impl SerializeGoFloat for $Type {
             #[inline]
             fn serialize_go_float<W: fmt::Write>(&self, write: &mut W) -> fmt::Result {
                 $impl(*self, write)
             }
+
+            #[inline]
+            fn serialize_decimal_float<W: fmt::Write>(&self, write: &mut W) -> fmt::Result {
+                $decimal_impl(*self, write)
+            }
         }
     };
-    ($($Type:ty => $impl:path),*) => {$(delegate_impl!{$Type => $impl})*};
+    ($($Type:ty => $impl:path, $decimal_impl:path);*) => {
+        $(delegate_impl!{$Type => $impl, $decimal_impl})*
+    };
 }
 
 display_impl!(u8, i8, u16, i16, u32, i32, u64, i64, usize, isize);
-delegate_impl!(f32 => f32_to_go_string, f64 => f64_to_go_string);
+delegate_impl!(
+    f32 => f32_to_go_string, f32_to_decimal_string;
+    f64 => f64_to_go_string, f64_to_decimal_string
+);
 
 impl SerializeGoFloat for bool {
     #[inline]
@@ -45,14 +69,40 @@ impl SerializeGoFloat for bool {
 
 const ALPH: &[u8; 16] = b"0123456789abcdef";
 
+/// Prometheus' stale marker, a `NaN` with a specific payload that marks a
+/// series as gone instead of reporting a measured `NaN`.
+///
+/// Stale markers cannot be carried in the text formats: neither Go's float
+/// syntax nor OpenMetrics can write the payload of a `NaN`, so both write a
+/// plain `NaN`. Only [`StaleMarker::to_f64`] keeps the payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StaleMarker;
+
+impl StaleMarker {
+    pub const BITS: u64 = 0x7ff0_0000_0000_0002;
+
+    pub fn to_f64(self) -> f64 {
+        f64::from_bits(Self::BITS)
+    }
+}
+
+impl SerializeGoFloat for StaleMarker {
+    /// The payload is lost, Go parses any `NaN` as a measured one
+    #[inline]
+    fn serialize_go_float<W: fmt::Write>(&self, write: &mut W) -> fmt::Result {
+        write.write_str("NaN")
+    }
+
+    /// OpenMetrics has no stale markers, the payload is lost
+    #[inline]
+    fn serialize_decimal_float<W: fmt::Write>(&self, write: &mut W) -> fmt::Result {
+        write.write_str("NaN")
+    }
+}
+
 macro_rules! to_go_string_impl {
-    ($fname:ident, $Type:ty, $Bits:ty) => {
+    ($fname:ident, $bits_fname:ident, $Type:ty, $Bits:ty) => {
         fn $fname(float: $Type, out: &mut impl fmt::Write) -> fmt::Result {
-            const FRAC_BITS: u32 = <$Type>::MANTISSA_DIGITS - 1;
-            const FRAC_MASK: $Bits = (1 << FRAC_BITS) - 1;
-            const EXP_BITS: u32 = <$Bits>::BITS - FRAC_BITS - 1;
-            const EXP_MASK: $Bits = (1 << EXP_BITS) - 1;
-
             if float.is_nan() {
                 return out.write_str("NaN");
             }
@@ -64,16 +114,30 @@ macro_rules! to_go_string_impl {
                 });
             }
             if float == 0. {
-                return out.write_char('0');
+                // Like Go, which keeps the sign of zero
+                return out.write_str(if float.is_sign_positive() { "0" } else { "-0" });
             }
+            $bits_fname(float.to_bits(), out)
+        }
 
-            let bits: $Bits = float.to_bits();
+        /// Writes `bits` as a hexfloat without special-casing `NaN`s and
+        /// infinities.
+        fn $bits_fname(bits: $Bits, out: &mut impl fmt::Write) -> fmt::Result {
+            const FRAC_BITS: u32 = <$Type>::MANTISSA_DIGITS - 1;
+            const FRAC_MASK: $Bits = (1 << FRAC_BITS) - 1;
+            const EXP_BITS: u32 = <$Bits>::BITS - FRAC_BITS - 1;
+            const EXP_MASK: $Bits = (1 << EXP_BITS) - 1;
 
             let mut fraction = bits & FRAC_MASK;
             let exponent = ((bits >> FRAC_BITS) & EXP_MASK);
-            let sign = if float.is_sign_positive() { '+' } else { '-' };
+            let sign = if bits >> (<$Bits>::BITS - 1) == 0 {
+                '+'
+            } else {
+                '-'
+            };
             let leading = if exponent == 0 { '0' } else { '1' };
-            let exponent = exponent as i16 - ((1 << (EXP_BITS - 1)) - 1);
+            // Subnormals share the exponent of the smallest normal numbers
+            let exponent = exponent.max(1) as i16 - ((1 << (EXP_BITS - 1)) - 1);
             write!(out, "{sign}0x{leading}.")?;
             fraction <<= <$Bits>::BITS - FRAC_BITS;
             while fraction != 0 {
@@ -86,8 +150,30 @@ macro_rules! to_go_string_impl {
     };
 }
 
-to_go_string_impl!(f32_to_go_string, f32, u32);
-to_go_string_impl!(f64_to_go_string, f64, u64);
+macro_rules! to_decimal_string_impl {
+    ($fname:ident, $Type:ty) => {
+        fn $fname(float: $Type, out: &mut impl fmt::Write) -> fmt::Result {
+            if float.is_nan() {
+                return out.write_str("NaN");
+            }
+            if float.is_infinite() {
+                return out.write_str(if float.is_sign_positive() {
+                    "+Inf"
+                } else {
+                    "-Inf"
+                });
+            }
+            // The shortest representation that round-trips, with an
+            // exponent for very large and small magnitudes
+            write!(out, "{float:?}")
+        }
+    };
+}
+
+to_go_string_impl!(f32_to_go_string, f32_bits_to_go_string, f32, u32);
+to_go_string_impl!(f64_to_go_string, f64_bits_to_go_string, f64, u64);
+to_decimal_string_impl!(f32_to_decimal_string, f32);
+to_decimal_string_impl!(f64_to_decimal_string, f64);
 
 #[cfg(test)]
 mod tests {
@@ -134,21 +220,21 @@ mod tests {
     #[test]
     fn subnormal_f32_to_go_string() {
         let mut buf = String::new();
-        // Exponent 2^(0-127)
+        // 2^-23 * 2^(1-127)
         let float = f32::from_bits(1);
         assert!(float.is_sign_positive() && float.is_subnormal());
         f32_to_go_string(float, &mut buf).unwrap();
-        assert_eq!(buf, "+0x0.000002p-127");
+        assert_eq!(buf, "+0x0.000002p-126");
     }
 
     #[test]
     fn subnormal_f64_to_go_string() {
         let mut buf = String::new();
-        // Exponent 2^(0-1023)
+        // 2^-52 * 2^(1-1023)
         let float = f64::from_bits(1);
         assert!(float.is_sign_positive() && float.is_subnormal());
         f64_to_go_string(float, &mut buf).unwrap();
-        assert_eq!(buf, "+0x0.0000000000001p-1023");
+        assert_eq!(buf, "+0x0.0000000000001p-1022");
     }
 
     #[test]
@@ -188,6 +274,9 @@ mod tests {
         let mut buf = String::new();
         f32_to_go_string(0., &mut buf).unwrap();
         assert_eq!(buf, "0");
+        buf.clear();
+        f32_to_go_string(-0., &mut buf).unwrap();
+        assert_eq!(buf, "-0");
     }
 
     #[test]
@@ -195,6 +284,125 @@ mod tests {
         let mut buf = String::new();
         f64_to_go_string(0., &mut buf).unwrap();
         assert_eq!(buf, "0");
+        buf.clear();
+        f64_to_go_string(-0., &mut buf).unwrap();
+        assert_eq!(buf, "-0");
+    }
+
+    fn f32_string(float: f32) -> String {
+        let mut buf = String::new();
+        f32_to_go_string(float, &mut buf).unwrap();
+        buf
+    }
+
+    fn f64_string(float: f64) -> String {
+        let mut buf = String::new();
+        f64_to_go_string(float, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn f32_edge_cases_to_go_string() {
+        assert_eq!(f32_string(f32::MAX), "+0x1.fffffep127");
+        assert_eq!(f32_string(f32::MIN), "-0x1.fffffep127");
+        assert_eq!(f32_string(f32::MIN_POSITIVE), "+0x1.p-126");
+        assert_eq!(f32_string(f32::from_bits(0x8000_0001)), "-0x0.000002p-126");
+        // Largest subnormal
+        assert_eq!(f32_string(f32::from_bits(0x007f_ffff)), "+0x0.fffffep-126");
+        // Trailing zero nibbles are omitted, inner ones are kept
+        assert_eq!(f32_string(1.5), "+0x1.8p0");
+        assert_eq!(f32_string(1. + f32::EPSILON), "+0x1.000002p0");
+        assert_eq!(f32_string(f32::from_bits(0x3f80_1000)), "+0x1.002p0");
+    }
+
+    #[test]
+    fn f64_edge_cases_to_go_string() {
+        assert_eq!(f64_string(f64::MAX), "+0x1.fffffffffffffp1023");
+        assert_eq!(f64_string(f64::MIN), "-0x1.fffffffffffffp1023");
+        assert_eq!(f64_string(f64::MIN_POSITIVE), "+0x1.p-1022");
+        assert_eq!(
+            f64_string(f64::from_bits(0x8000_0000_0000_0001)),
+            "-0x0.0000000000001p-1022"
+        );
+        assert_eq!(
+            f64_string(f64::from_bits(0x000f_ffff_ffff_ffff)),
+            "+0x0.fffffffffffffp-1022"
+        );
+        assert_eq!(f64_string(1.5), "+0x1.8p0");
+        assert_eq!(f64_string(1. + f64::EPSILON), "+0x1.0000000000001p0");
+        assert_eq!(
+            f64_string(f64::from_bits(0x3ff0_0100_0000_0000)),
+            "+0x1.001p0"
+        );
+    }
+
+    /// Reference reader of the hexfloats, following the rules of Go's
+    /// `strconv.ParseFloat`: the value is the hex mantissa times 2 to the
+    /// power of the decimal exponent.
+    fn parse_go_float(s: &str) -> f64 {
+        match s {
+            "NaN" => return f64::NAN,
+            "+Inf" => return f64::INFINITY,
+            "-Inf" => return f64::NEG_INFINITY,
+            "0" => return 0.,
+            "-0" => return -0.,
+            _ => {}
+        }
+        let (sign, rest) = s.split_at(1);
+        let rest = rest.strip_prefix("0x").unwrap();
+        let (mantissa, exponent) = rest.split_once('p').unwrap();
+        let (int, frac) = mantissa.split_once('.').unwrap();
+        assert!(frac.len() <= 13 && !frac.ends_with('0'), "{s}");
+        let digits = format!("{int}{frac:0<13}");
+        let mantissa = u64::from_str_radix(&digits, 16).unwrap();
+        let exponent = exponent.parse::<i32>().unwrap() - 52;
+        // Exact, since the mantissa has at most 53 bits and the result is
+        // representable
+        let value = mantissa as f64 * pow2(exponent);
+        match sign {
+            "+" => value,
+            "-" => -value,
+            _ => panic!("{s} has no sign"),
+        }
+    }
+
+    /// Exact power of two, including subnormal ones
+    fn pow2(exponent: i32) -> f64 {
+        if exponent >= -1022 {
+            f64::from_bits(((exponent + 1023) as u64) << 52)
+        } else {
+            f64::from_bits(1 << (exponent + 1074))
+        }
+    }
+
+    #[test]
+    fn random_floats_round_trip() {
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0x60f1);
+        let special = [0, 1, 0x000f_ffff_ffff_ffff, 0x0010_0000_0000_0000];
+        let bits = (special.into_iter())
+            .flat_map(|bits| [bits, bits | 1 << 63])
+            .chain((0..100_000).map(|_| rng.gen::<u64>()));
+        for bits in bits {
+            let float = f64::from_bits(bits);
+            let parsed = parse_go_float(&f64_string(float));
+            if float.is_nan() {
+                assert!(parsed.is_nan());
+            } else {
+                assert_eq!(parsed.to_bits(), bits, "{}", f64_string(float));
+            }
+        }
+        for _ in 0..100_000 {
+            let bits = rng.gen::<u32>();
+            let float = f32::from_bits(bits);
+            let parsed = parse_go_float(&f32_string(float));
+            if float.is_nan() {
+                assert!(parsed.is_nan());
+            } else {
+                assert_eq!((parsed as f32).to_bits(), bits, "{}", f32_string(float));
+            }
+        }
     }
 
     #[test]
@@ -226,4 +434,34 @@ mod tests {
         f64_to_go_string(float, &mut buf).unwrap();
         assert_eq!(buf, "-Inf");
     }
+
+    #[test]
+    fn stale_marker_to_go_string() {
+        assert!(StaleMarker.to_f64().is_nan());
+        let mut buf = String::new();
+        StaleMarker.serialize_go_float(&mut buf).unwrap();
+        // Accepted by Go's strconv.ParseFloat, unlike a hexfloat of the bits
+        assert_eq!(buf, "NaN");
+        assert!(buf.parse::<f64>().unwrap().is_nan());
+    }
+
+    #[test]
+    fn floats_to_decimal_string() {
+        let decimal = |float: f64| {
+            let mut buf = String::new();
+            float.serialize_decimal_float(&mut buf).unwrap();
+            buf
+        };
+        assert_eq!(decimal(1.5), "1.5");
+        assert_eq!(decimal(-0.), "-0.0");
+        assert_eq!(decimal(1e-7), "1e-7");
+        assert_eq!(decimal(f64::NAN), "NaN");
+        assert_eq!(decimal(f64::NEG_INFINITY), "-Inf");
+        for float in [f64::MAX, f64::MIN_POSITIVE, 0.1 + 0.2, 1. / 3.] {
+            assert_eq!(decimal(float).parse::<f64>().unwrap(), float);
+        }
+        let mut buf = String::new();
+        0.1f32.serialize_decimal_float(&mut buf).unwrap();
+        assert_eq!(buf, "0.1");
+    }
 }
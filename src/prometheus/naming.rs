@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+
+use super::PName;
+
+/// Naming scheme of the metrics whose legacy names violate the Prometheus
+/// naming conventions, set by `metrics.naming`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricNaming {
+    /// Names of earlier versions, kept for existing dashboards
+    #[default]
+    Legacy,
+    /// Base units and unit suffixes, see [`CONVENTIONAL_NAMES`]
+    Conventional,
+}
+
+/// A metric exposed under a different name and unit in
+/// [`MetricNaming::Conventional`]
+#[derive(Debug, Clone, Copy)]
+pub struct RenamedMetric {
+    pub legacy: &'static PName,
+    pub conventional: &'static PName,
+    /// Unit declared in the OpenMetrics format, a suffix of `conventional`
+    pub unit: &'static PName,
+    /// Factor converting legacy values to conventional ones
+    pub scale: f32,
+}
+
+macro_rules! renamed {
+    ($legacy:literal => $conventional:literal in $unit:literal, scale $scale:expr) => {
+        // SAFETY: Literals below are valid metric names
+        RenamedMetric {
+            legacy: unsafe { PName::new_unchecked($legacy) },
+            conventional: unsafe { PName::new_unchecked($conventional) },
+            unit: unsafe { PName::new_unchecked($unit) },
+            scale: $scale,
+        }
+    };
+}
+
+/// Mapping of legacy to conventional metric names. Latencies are measured in
+/// milliseconds and converted to seconds, the packet loss already is a ratio.
+pub const CONVENTIONAL_NAMES: &[RenamedMetric] = &[
+    renamed!("ping_ms" => "ping_duration_seconds" in "seconds", scale 1e-3),
+    renamed!("ping_mean_ms" => "ping_mean_duration_seconds" in "seconds", scale 1e-3),
+    renamed!("ping_stddev" => "ping_stddev_seconds" in "seconds", scale 1e-3),
+    renamed!("ping_sample_ms" => "ping_sample_duration_seconds" in "seconds", scale 1e-3),
+    renamed!("packet_loss" => "ping_packet_loss_ratio" in "ratio", scale 1.),
+];
+
+/// Name, unit and value conversion of a metric in a [`MetricNaming`]
+#[derive(Debug, Clone, Copy)]
+pub struct NamedMetric {
+    pub name: &'static PName,
+    pub unit: Option<&'static PName>,
+    scale: f32,
+}
+
+impl NamedMetric {
+    /// Converts a value in the unit of the legacy metric
+    #[inline]
+    pub fn value(&self, legacy_value: f32) -> f32 {
+        legacy_value * self.scale
+    }
+}
+
+impl MetricNaming {
+    /// Looks up the metric with the legacy name `legacy`, which is kept if
+    /// it is not renamed
+    pub fn metric(self, legacy: &'static PName) -> NamedMetric {
+        let renamed = CONVENTIONAL_NAMES.iter().find(|it| it.legacy == legacy);
+        match (self, renamed) {
+            (Self::Conventional, Some(renamed)) => NamedMetric {
+                name: renamed.conventional,
+                unit: Some(renamed.unit),
+                scale: renamed.scale,
+            },
+            _ => NamedMetric {
+                name: legacy,
+                unit: None,
+                scale: 1.,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conventional_names_end_with_their_unit() {
+        for renamed in CONVENTIONAL_NAMES {
+            let name: &str = renamed.conventional.as_ref();
+            assert!(
+                name.ends_with(&format!("_{}", &**renamed.unit)),
+                "{name} must end with _{}",
+                &**renamed.unit
+            );
+            assert!(PName::new(name).is_ok());
+            assert!(PName::new(renamed.legacy.as_ref()).is_ok());
+        }
+    }
+}
@@ -0,0 +1,319 @@
+//! Strict parser of the text exposition format as specified by Prometheus,
+//! used to check that the output of
+//! [`ExpositionBuilder`](super::ExpositionBuilder) is accepted by scrapers.
+
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    /// Unescaped label values in order of appearance
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+}
+
+impl Sample {
+    pub fn label(&self, name: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(label, _)| label == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Parses every line of `text`, rejecting malformed lines, duplicate
+/// metadata and duplicate series.
+pub fn parse(text: &str) -> Result<Vec<Sample>, String> {
+    let mut samples = Vec::new();
+    let mut metadata = HashSet::new();
+    let mut series = HashSet::new();
+    for (number, line) in text.lines().enumerate() {
+        let error = |message: &str| format!("line {}: {message}: {line:?}", number + 1);
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(comment) = line.strip_prefix('#') {
+            if let Some((keyword, name)) = parse_metadata(comment).map_err(|e| error(&e))? {
+                if !metadata.insert((keyword, name)) {
+                    return Err(error("duplicate metadata"));
+                }
+            }
+            continue;
+        }
+        let sample = parse_sample(line).map_err(|e| error(&e))?;
+        if !series.insert(format!("{}{:?}", sample.name, sample.labels)) {
+            return Err(error("duplicate series"));
+        }
+        samples.push(sample);
+    }
+    Ok(samples)
+}
+
+/// Returns the keyword and metric name of `# HELP` and `# TYPE` lines,
+/// `None` for other comments
+fn parse_metadata(comment: &str) -> Result<Option<(&str, &str)>, String> {
+    let mut parts = comment.trim_start_matches(' ').splitn(3, ' ');
+    let keyword = parts.next().unwrap_or_default();
+    if keyword != "HELP" && keyword != "TYPE" {
+        return Ok(None);
+    }
+    let name = parts.next().filter(|name| is_metric_name(name));
+    let name = name.ok_or("invalid metric name")?;
+    let rest = parts.next().unwrap_or_default();
+    if keyword == "TYPE" {
+        if !matches!(
+            rest,
+            "counter" | "gauge" | "histogram" | "summary" | "untyped"
+        ) {
+            return Err("invalid metric type".to_owned());
+        }
+    } else {
+        unescape(rest, false)?;
+    }
+    Ok(Some((keyword, name)))
+}
+
+fn parse_sample(line: &str) -> Result<Sample, String> {
+    let name_len = line.find(['{', ' ', '\t']).ok_or("missing value")?;
+    let (name, mut rest) = line.split_at(name_len);
+    if !is_metric_name(name) {
+        return Err("invalid metric name".to_owned());
+    }
+
+    let mut labels = Vec::new();
+    if let Some(mut inner) = rest.strip_prefix('{') {
+        loop {
+            inner = inner.trim_start_matches([' ', '\t']);
+            if let Some(after) = inner.strip_prefix('}') {
+                rest = after;
+                break;
+            }
+            let (label, after) = inner.split_once("=\"").ok_or("invalid label")?;
+            if !is_label_name(label) {
+                return Err("invalid label name".to_owned());
+            }
+            let end = find_closing_quote(after).ok_or("unterminated label value")?;
+            labels.push((label.to_owned(), unescape(&after[..end], true)?));
+            inner = &after[end + 1..];
+            if let Some(after) = inner.strip_prefix(',') {
+                inner = after;
+            } else if !inner.starts_with('}') {
+                return Err("expected , or } after label".to_owned());
+            }
+        }
+    }
+
+    let mut fields = rest.split([' ', '\t']).filter(|field| !field.is_empty());
+    let value = fields.next().ok_or("missing value")?;
+    let value = match value {
+        "NaN" => f64::NAN,
+        "+Inf" | "Inf" => f64::INFINITY,
+        "-Inf" => f64::NEG_INFINITY,
+        value => parse_float(value).ok_or("invalid value")?,
+    };
+    if let Some(timestamp) = fields.next() {
+        timestamp.parse::<i64>().map_err(|_| "invalid timestamp")?;
+    }
+    if fields.next().is_some() {
+        return Err("trailing characters".to_owned());
+    }
+    Ok(Sample {
+        name: name.to_owned(),
+        labels,
+        value,
+    })
+}
+
+/// Parses decimal floats and the hexadecimal floats written by
+/// [`SerializeGoFloat`](super::SerializeGoFloat), like Go's `ParseFloat`
+fn parse_float(value: &str) -> Option<f64> {
+    let (sign, unsigned) = match value.as_bytes().first()? {
+        b'-' => (-1., &value[1..]),
+        b'+' => (1., &value[1..]),
+        _ => (1., value),
+    };
+    let Some(hex) = unsigned.strip_prefix("0x") else {
+        return value.parse().ok();
+    };
+    let (mantissa, exponent) = hex.split_once('p')?;
+    let mut exponent: i32 = exponent.parse().ok()?;
+    let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if int.is_empty() && frac.is_empty() {
+        return None;
+    }
+    let mut significand = 0f64;
+    for digit in int.chars().chain(frac.chars()) {
+        significand = significand * 16. + f64::from(digit.to_digit(16)?);
+    }
+    exponent -= 4 * frac.len() as i32;
+    // Split to avoid overflowing the power for large significands
+    let half = exponent / 2;
+    let value = significand * 2f64.powi(half) * 2f64.powi(exponent - half);
+    value.is_finite().then_some(sign * value)
+}
+
+fn is_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|ch| ch.is_ascii_alphabetic() || ch == '_' || ch == ':')
+        && chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_' || ch == ':')
+}
+
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|ch| ch.is_ascii_alphabetic() || ch == '_')
+        && chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+}
+
+/// Index of the first unescaped `"`
+fn find_closing_quote(value: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (index, ch) in value.char_indices() {
+        match ch {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Label values may escape `\`, `"` and newlines, help texts only `\` and
+/// newlines. Control characters are rejected, since scrapers disagree on
+/// whether e.g. a carriage return ends the line.
+fn unescape(value: &str, allow_quote: bool) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(ch) = chars.next() {
+        if ch.is_control() {
+            return Err(format!("control character {ch:?}"));
+        }
+        if ch != '\\' {
+            unescaped.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('n') => unescaped.push('\n'),
+            Some('"') if allow_quote => unescaped.push('"'),
+            other => {
+                return Err(format!(
+                    "invalid escape sequence \\{}",
+                    other.unwrap_or(' ')
+                ))
+            }
+        }
+    }
+    Ok(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prometheus::{
+        escape_prometheus_str, ExpositionArena, ExpositionBuilder, MetricType, PName,
+    };
+
+    #[test]
+    fn escaped_label_values_round_trip() {
+        let values = ["io error: broken pipe", "a \"quoted\"\\path\nnext", ""];
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        builder.add_metric(
+            PName::new("ping_errors").unwrap(),
+            MetricType::Gauge,
+            "number of ping errors",
+            |mut builder| {
+                for (count, value) in values.iter().enumerate() {
+                    builder.add_line_labeled(PName::new("error").unwrap(), *value, &count, None);
+                }
+            },
+        );
+        let exposition = builder.to_string();
+        // Colons and spaces are valid in label values as they are
+        assert!(exposition.contains(r#"{error="io error: broken pipe"}"#));
+
+        let samples = parse(&exposition).unwrap();
+        let parsed: Vec<_> = samples.iter().map(|s| s.label("error").unwrap()).collect();
+        assert_eq!(parsed, values);
+    }
+
+    #[test]
+    fn arbitrary_label_values_round_trip() {
+        use rand::{seq::SliceRandom, Rng};
+
+        // Mostly characters that need escaping or replacing, as in error
+        // messages of resolvers and sockets
+        let alphabet = [
+            'a', ' ', ':', '\\', '"', '\n', '\r', '\t', '\0', '\x7f', '\u{85}', '{', '}', ',', '=',
+            'ä', '€', '🦀', '\u{2028}', '\u{feff}',
+        ];
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let len = rng.gen_range(0..32);
+            let value: String = (0..len)
+                .map(|_| match rng.gen_bool(0.8) {
+                    true => *alphabet.choose(&mut rng).unwrap(),
+                    false => rng.gen::<char>(),
+                })
+                .collect();
+            let alloc = ExpositionArena::new();
+            let mut builder = ExpositionBuilder::new(&alloc);
+            builder.add_metric(
+                PName::new("ping_errors").unwrap(),
+                MetricType::Gauge,
+                "number of ping errors",
+                |mut builder| {
+                    builder.add_line_labeled(PName::new("error").unwrap(), &*value, &1, None)
+                },
+            );
+            let exposition = builder.to_string();
+
+            let samples = parse(&exposition).unwrap_or_else(|e| panic!("{value:?}: {e}"));
+            let expected: String = (value.chars())
+                .map(|ch| match ch {
+                    '\n' => '\n',
+                    ch if ch.is_control() => ' ',
+                    ch => ch,
+                })
+                .collect();
+            assert_eq!(samples.len(), 1, "{value:?}");
+            assert_eq!(samples[0].label("error"), Some(&*expected));
+        }
+    }
+
+    #[test]
+    fn escaper_size_hint_bounds_the_output() {
+        for value in ["", "plain", "\\\\\"\"\n\n", "\r\t\0", "ä€🦀"] {
+            let escaped = escape_prometheus_str(value);
+            let (lower, upper) = escaped.size_hint();
+            let len = escaped.count();
+            assert!(lower <= len && len <= upper.unwrap(), "{value:?}");
+        }
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        for line in [
+            "metric{label=\"unterminated} 1",
+            "metric{label=\"\\x\"} 1",
+            "metric{0label=\"\"} 1",
+            "metric{a=\"\" b=\"\"} 1",
+            "metric one",
+            "metric",
+            "# TYPE metric kind",
+            "metric 1\nmetric 2",
+            "metric{label=\"\r\"} 1",
+        ] {
+            assert!(parse(line).is_err(), "{line:?}");
+        }
+        assert!(parse("# HELP m a\n# TYPE m gauge\nm{a=\"1\", b=\"\\\"\",} 1 123\n").is_ok());
+        assert_eq!(parse_float("+0x1.8p1"), Some(3.));
+        assert_eq!(parse_float("-0x1p-2"), Some(-0.25));
+        assert_eq!(parse_float("1e3"), Some(1000.));
+    }
+}
@@ -7,8 +7,14 @@ use std::{
     str::Chars,
 };
 
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use thiserror::Error;
 
+/// Escapes label values for the text exposition format. `\\`, `"` and
+/// newlines are escaped, the only escape sequences that scrapers accept. Other
+/// control characters such as `\r`, `\t` or NUL cannot be escaped and are
+/// written as a space, since some parsers split lines at a carriage return.
+/// Any other character, including multi-byte UTF-8, is written as it is.
 #[derive(Debug, Clone)]
 pub struct EscapePrometheus<'s> {
     inner: Chars<'s>,
@@ -28,11 +34,21 @@ impl Iterator for EscapePrometheus<'_> {
             '\n' => Some('n'),
             _ => None,
         };
-        Some(if self.esc_char.is_some() { '\\' } else { ch })
+        Some(match ch {
+            _ if self.esc_char.is_some() => '\\',
+            ch if ch.is_control() => ' ',
+            ch => ch,
+        })
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        self.inner.size_hint()
+        // Every character is escaped by at most one more
+        let pending = usize::from(self.esc_char.is_some());
+        let (lower, upper) = self.inner.size_hint();
+        (
+            lower + pending,
+            upper.and_then(|upper| upper.checked_mul(2)?.checked_add(pending)),
+        )
     }
 }
 
@@ -45,7 +61,7 @@ impl Display for EscapePrometheus<'_> {
     }
 }
 
-pub fn escape_prometheus_str(str: &str) -> EscapePrometheus {
+pub fn escape_prometheus_str(str: &str) -> EscapePrometheus<'_> {
     EscapePrometheus {
         inner: str.chars(),
         esc_char: None,
@@ -67,10 +83,14 @@ pub struct PName(str);
 
 impl PName {
     pub const QUANTILE: &'static Self = unsafe { Self::new_unchecked("quantile") };
+    /// Name of a configured quantile, see [`Quantiles`](crate::config::Quantiles)
+    pub const QUANTILE_NAME: &'static Self = unsafe { Self::new_unchecked("name") };
     pub const LE: &'static Self = unsafe { Self::new_unchecked("le") };
     pub const SUFFIX_BUCKET: &'static Self = unsafe { Self::new_unchecked("_bucket") };
     pub const SUFFIX_SUM: &'static Self = unsafe { Self::new_unchecked("_sum") };
     pub const SUFFIX_COUNT: &'static Self = unsafe { Self::new_unchecked("_count") };
+    pub const UNIT_SECONDS: &'static Self = unsafe { Self::new_unchecked("seconds") };
+    pub const UNIT_BYTES: &'static Self = unsafe { Self::new_unchecked("bytes") };
 
     pub fn new(name: &str) -> Result<&Self, InvalidPrometheusNameError> {
         if is_valid_prometheus_name(name) {
@@ -157,6 +177,28 @@ impl PNameBuf {
     }
 }
 
+impl TryFrom<String> for PNameBuf {
+    type Error = InvalidPrometheusNameError;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        PName::new(&name)?;
+        Ok(Self(name))
+    }
+}
+
+impl Serialize for PNameBuf {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for PNameBuf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Self::try_from(name).map_err(serde::de::Error::custom)
+    }
+}
+
 impl AsRef<PName> for PNameBuf {
     fn as_ref(&self) -> &PName {
         unsafe { PName::new_unchecked(&self.0) }
@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+
+use thiserror::Error;
+
+use super::{ExpositionBuilder, PName};
+
+/// Problem found by [`ExpositionBuilder::validate`]
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ExpositionValidationError {
+    #[error("metric family {family} has no samples")]
+    EmptyFamily { family: String },
+    #[error("invalid metric name {name:?}")]
+    InvalidName { name: String },
+    #[error("duplicate series {series} in metric family {family}")]
+    DuplicateSeries { family: String, series: String },
+    #[error("exposition does not end with a newline")]
+    MissingTrailingNewline,
+}
+
+impl ExpositionBuilder<'_> {
+    /// Checks the rendered exposition for mistakes that scrapers reject or
+    /// silently misinterpret.
+    pub fn validate(&self) -> Result<(), Vec<ExpositionValidationError>> {
+        let errors = validate_exposition(&self.to_string());
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+fn validate_exposition(text: &str) -> Vec<ExpositionValidationError> {
+    use ExpositionValidationError::*;
+
+    struct Family<'t> {
+        name: &'t str,
+        samples: usize,
+    }
+
+    fn finish(family: Option<Family>, errors: &mut Vec<ExpositionValidationError>) {
+        if let Some(family) = family.filter(|family| family.samples == 0) {
+            errors.push(EmptyFamily {
+                family: family.name.to_owned(),
+            });
+        }
+    }
+
+    let mut errors = Vec::new();
+    let mut family: Option<Family> = None;
+    let mut series = HashSet::new();
+
+    for line in text.lines() {
+        if let Some(comment) = line.strip_prefix("# ") {
+            let mut parts = comment.split(' ');
+            let (Some("HELP" | "TYPE" | "UNIT"), Some(name)) = (parts.next(), parts.next()) else {
+                continue;
+            };
+            if family.as_ref().is_some_and(|family| family.name == name) {
+                continue;
+            }
+            finish(family.take(), &mut errors);
+            if PName::new(name).is_err() {
+                errors.push(InvalidName {
+                    name: name.to_owned(),
+                });
+            }
+            family = Some(Family { name, samples: 0 });
+            continue;
+        }
+
+        // Label values never contain `}` unescaped after the last label
+        let key_len = match line.rfind('}') {
+            Some(end) => end + 1,
+            None => line.find(' ').unwrap_or(line.len()),
+        };
+        let key = &line[..key_len];
+        let name = &key[..key.find('{').unwrap_or(key.len())];
+        if PName::new(name).is_err() {
+            errors.push(InvalidName {
+                name: name.to_owned(),
+            });
+        }
+        let family_name = family.as_ref().map_or(name, |family| family.name);
+        if !series.insert((family_name, key)) {
+            errors.push(DuplicateSeries {
+                family: family_name.to_owned(),
+                series: key.to_owned(),
+            });
+        }
+        if let Some(family) = &mut family {
+            family.samples += 1;
+        }
+    }
+    finish(family, &mut errors);
+
+    if !text.is_empty() && !text.ends_with('\n') {
+        errors.push(MissingTrailingNewline);
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prometheus::{ExpositionArena, MetricType};
+
+    #[test]
+    fn built_expositions_are_valid() {
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        let target = PName::new("target").unwrap();
+        builder.add_metric(
+            PName::new("ping_ms").unwrap(),
+            MetricType::Summary,
+            "ping to target",
+            |mut builder| {
+                for host in ["1.1.1.1", "a }b"] {
+                    builder.with_label(target, host, |builder| {
+                        builder.add_quantile_line(0.5, None, &12.);
+                        builder.with_name(PName::SUFFIX_COUNT, |builder| {
+                            builder.add_line(&4, None);
+                        });
+                    });
+                }
+            },
+        );
+        assert_eq!(builder.validate(), Ok(()));
+    }
+
+    #[test]
+    fn mistakes_are_reported() {
+        let errors = validate_exposition(
+            "# HELP a_b help\n# TYPE a_b gauge\n\
+             # HELP empty help\n# TYPE empty gauge\n\
+             # HELP c gauge\nc{x=\"1\"} 1\nc{x=\"1\"} 2\nc_Total 3",
+        );
+        assert_eq!(
+            errors,
+            [
+                ExpositionValidationError::EmptyFamily {
+                    family: "a_b".to_owned()
+                },
+                ExpositionValidationError::EmptyFamily {
+                    family: "empty".to_owned()
+                },
+                ExpositionValidationError::DuplicateSeries {
+                    family: "c".to_owned(),
+                    series: "c{x=\"1\"}".to_owned()
+                },
+                ExpositionValidationError::InvalidName {
+                    name: "c_Total".to_owned()
+                },
+                ExpositionValidationError::MissingTrailingNewline,
+            ]
+        );
+    }
+}
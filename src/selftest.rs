@@ -0,0 +1,194 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::{
+    config::Config,
+    phases::PhaseRecorder,
+    ping::{dns_cache::DnsCache, perform_ping, PingResult},
+    speedtest::{http::HttpSpeedtestProvider, SpeedtestProvider, StandardSpeedtestProvider},
+};
+
+const PING_SAMPLES: usize = 2;
+const PING_DELAY: Duration = Duration::from_millis(100);
+const DOWNLOAD_DURATION: Duration = Duration::from_secs(2);
+/// Small enough not to disturb a concurrent speedtest
+const DOWNLOAD_BYTES: u64 = 1 << 20;
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+/// Requests within this interval receive the previous report
+const MIN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Outcome of a tiny measurement, independent of the configured schedule
+#[derive(Debug, Serialize)]
+pub(crate) struct SelftestReport {
+    pub passed: bool,
+    pub ping: Check<PingResult>,
+    pub download: Check<DownloadCheck>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub(crate) enum Check<T> {
+    Passed(T),
+    Failed {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<T>,
+        error: String,
+    },
+}
+
+impl<T> Check<T> {
+    fn failed(error: impl ToString) -> Self {
+        Self::Failed {
+            data: None,
+            error: error.to_string(),
+        }
+    }
+
+    fn passed(&self) -> bool {
+        matches!(self, Self::Passed(_))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct DownloadCheck {
+    pub bytes: f64,
+    pub seconds: f64,
+    pub bps: f64,
+}
+
+/// Runs at most one self-test per [`MIN_INTERVAL`], concurrent requests wait
+/// for the running one.
+#[derive(Debug, Default)]
+pub(crate) struct SelftestLimiter(Mutex<Option<(Instant, Arc<SelftestReport>)>>);
+
+impl SelftestLimiter {
+    pub async fn run(&self, config: &Config, dns: Arc<DnsCache>) -> Arc<SelftestReport> {
+        let mut last = self.0.lock().await;
+        if let Some((at, report)) = &*last {
+            if at.elapsed() < MIN_INTERVAL {
+                return report.clone();
+            }
+        }
+        let report = Arc::new(run_selftest(config, dns).await);
+        *last = Some((Instant::now(), report.clone()));
+        report
+    }
+}
+
+/// Pings the first configured target twice and downloads for two seconds.
+pub(crate) async fn run_selftest(config: &Config, dns: Arc<DnsCache>) -> SelftestReport {
+    let (ping, download) = tokio::join!(check_ping(config, dns), check_download(config));
+    SelftestReport {
+        passed: ping.passed() && download.passed(),
+        ping,
+        download,
+    }
+}
+
+async fn check_ping(config: &Config, dns: Arc<DnsCache>) -> Check<PingResult> {
+    let Some(target) = config.ping.servers.first() else {
+        return Check::failed("no ping target configured");
+    };
+    let mut config = config.clone();
+    config.ping.samples = Some(PING_SAMPLES);
+    config.ping.total_duration = None;
+    config.ping.delay = PING_DELAY;
+    config.ping.outlier_rejection = None;
+
+    let mut results = perform_ping(
+        Arc::new(config),
+        vec![target.clone()],
+        dns,
+        PhaseRecorder::default(),
+    )
+    .await;
+    match results.pop() {
+        Some(result) if result.is_reachable() => Check::Passed(result),
+        Some(result) => Check::Failed {
+            data: Some(result),
+            error: "target is unreachable".to_owned(),
+        },
+        None => Check::failed("no ping result"),
+    }
+}
+
+async fn check_download(config: &Config) -> Check<DownloadCheck> {
+    let provider = match &config.speedtest.provider {
+        StandardSpeedtestProvider::Http(provider) => limit_download(provider.clone()),
+        #[cfg(feature = "quic")]
+        StandardSpeedtestProvider::Quic(provider) => limit_download(provider.to_http()),
+        StandardSpeedtestProvider::LibreSpeed(provider) => limit_download(provider.to_http()),
+        provider @ StandardSpeedtestProvider::Null(_) => provider.clone(),
+    };
+    let data = match tokio::time::timeout(DOWNLOAD_TIMEOUT, provider.measure_download()).await {
+        Ok(Ok(data)) => data,
+        Ok(Err(error)) => return Check::failed(error),
+        Err(_) => return Check::failed("download timed out"),
+    };
+    let check = DownloadCheck {
+        bytes: data.total.bytes,
+        seconds: data.total.seconds,
+        bps: data.total.bps_f64(),
+    };
+    if check.bytes > 0. {
+        Check::Passed(check)
+    } else {
+        Check::Failed {
+            data: Some(check),
+            error: "nothing was downloaded".to_owned(),
+        }
+    }
+}
+
+/// Shortens the download of an HTTP provider to a quick check
+fn limit_download(mut provider: HttpSpeedtestProvider) -> StandardSpeedtestProvider {
+    provider.download_duration = DOWNLOAD_DURATION;
+    provider.early_stop = None;
+    provider.max_download_bytes = Some(
+        provider
+            .max_download_bytes
+            .map_or(DOWNLOAD_BYTES, |bytes| bytes.min(DOWNLOAD_BYTES)),
+    );
+    StandardSpeedtestProvider::Http(provider)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::speedtest::{null::NullSpeedtestProvider, SpeedtestSample};
+
+    fn null_config(download_samples: Vec<SpeedtestSample>) -> Config {
+        let mut config = Config::default();
+        config.speedtest.provider = StandardSpeedtestProvider::Null(NullSpeedtestProvider {
+            download_samples,
+            upload_samples: Vec::new(),
+        });
+        config
+    }
+
+    #[tokio::test]
+    async fn download_check_requires_bytes() {
+        let sample = SpeedtestSample {
+            bytes: 125_000.,
+            seconds: 1.,
+        };
+        let check = check_download(&null_config(vec![sample; 2])).await;
+        assert!(matches!(check, Check::Passed(DownloadCheck { bps, .. }) if bps == 1e6));
+
+        let check = check_download(&null_config(Vec::new())).await;
+        assert!(!check.passed());
+    }
+
+    #[tokio::test]
+    async fn ping_check_requires_target() {
+        let mut config = null_config(Vec::new());
+        config.ping.servers.clear();
+        let check = check_ping(&config, Arc::default()).await;
+        assert!(matches!(check, Check::Failed { data: None, .. }));
+    }
+}
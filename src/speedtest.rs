@@ -1,25 +1,363 @@
 use std::{
+    borrow::Cow,
+    fmt::{self, Display},
     future::Future,
     iter::Sum,
     ops::{self, Div},
     pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use tokio::task;
+use tracing::{info, warn};
+use url::Url;
 
-use crate::prometheus::{ExpositionBuilder, MetricType, PName};
+use crate::{
+    ansi::AnsiRenderer,
+    config::{Config, Quantiles},
+    json::{serialize_quantile_map, serialize_timestamp, JsonSchema, Versioned},
+    phases::PhaseRecorder,
+    prometheus::{ExpositionBuilder, MetricType, PName},
+    text::{decimal, Table},
+};
 
-use self::http::HttpSpeedtestProvider;
+#[cfg(feature = "quic")]
+use self::quic::QuicSpeedtestProvider;
+use self::{
+    http::HttpSpeedtestProvider,
+    librespeed::LibreSpeedProvider,
+    null::NullSpeedtestProvider,
+    server_info::{ServerInfo, ServerInfoCache},
+    tcp_info::TcpStats,
+};
 
 pub mod http;
+pub mod librespeed;
+pub mod null;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod server_info;
+pub mod tcp_info;
+
+pub(crate) async fn perform_speedtest(
+    config: Arc<Config>,
+    server_info: Arc<ServerInfoCache>,
+    phases: PhaseRecorder,
+) -> reqwest::Result<SpeedtestResult> {
+    let timeout = config.speedtest.provider_timeout;
+    let provider = match &config.speedtest.interface {
+        Some(interface) => Cow::Owned(config.speedtest.provider.bound_to(interface)),
+        None => Cow::Borrowed(&config.speedtest.provider),
+    };
+
+    let concurrent = config.speedtest.allow_concurrent_dl_ul;
+    let measure_download = phases.time_async(
+        "download",
+        with_timeout(timeout, "down", provider.measure_download()),
+    );
+    let measure_upload = phases.time_async(
+        "upload",
+        with_timeout(timeout, "up", provider.measure_upload()),
+    );
+    let digest = |rates: Option<(SpeedtestData, Duration)>| {
+        let config = config.clone();
+        let phases = phases.clone();
+        rates.map(|(rates, measure_duration)| {
+            task::spawn_blocking(move || {
+                let mut summary = phases.time("digest", || {
+                    SpeedtestSummary::digest_data(rates, &config.speedtest.quantiles)
+                });
+                summary.measure_duration = Some(measure_duration);
+                summary
+            })
+        })
+    };
+
+    let (download_data, upload_data) = if concurrent {
+        let (download, upload) = tokio::join!(measure_download, measure_upload);
+        (digest(download?), digest(upload?))
+    } else {
+        // The download is digested while the upload is measured
+        let download_data = digest(measure_download.await?);
+        (download_data, digest(measure_upload.await?))
+    };
+
+    let result = SpeedtestResult {
+        down: match download_data {
+            Some(handle) => Some(handle.await.unwrap()),
+            None => None,
+        },
+        up: match upload_data {
+            Some(handle) => Some(handle.await.unwrap()),
+            None => None,
+        },
+        concurrent,
+        protocol: provider.protocol(),
+        server: match (&config.speedtest.server_lookup, provider.endpoint()) {
+            (Some(source), Some(endpoint)) => server_info.lookup(source, &endpoint).await,
+            _ => None,
+        },
+        interface: config.speedtest.interface.clone(),
+        measured_at: SystemTime::now(),
+    };
+    info!(%result, "Speedtest finished");
+    Ok(result)
+}
+
+/// Stable identifier of the cause of a failed speedtest, used as `kind` in
+/// JSON error responses
+pub(crate) fn error_kind(error: &reqwest::Error) -> &'static str {
+    if error.is_timeout() {
+        "timeout"
+    } else if error.is_connect() {
+        "connect"
+    } else if error.is_redirect() {
+        "redirect"
+    } else if error.is_status() {
+        "status"
+    } else if error.is_builder() {
+        "builder"
+    } else if error.is_body() || error.is_decode() {
+        "body"
+    } else {
+        "request"
+    }
+}
+
+/// Bounds an entire provider call, returns `None` if it timed out and the
+/// data along with the wall time of the call otherwise.
+async fn with_timeout(
+    timeout: Option<Duration>,
+    direction: &str,
+    measure: impl Future<Output = reqwest::Result<SpeedtestData>>,
+) -> reqwest::Result<Option<(SpeedtestData, Duration)>> {
+    let start = Instant::now();
+    let Some(timeout) = timeout else {
+        return measure.await.map(|data| Some((data, start.elapsed())));
+    };
+    match tokio::time::timeout(timeout, measure).await {
+        Ok(result) => result.map(|data| Some((data, start.elapsed()))),
+        Err(_) => {
+            warn!(direction, ?timeout, "Speedtest provider timed out");
+            Ok(None)
+        }
+    }
+}
+
+/// Summaries are absent if the provider timed out
+#[derive(Debug, Serialize)]
+pub struct SpeedtestResult {
+    pub down: Option<SpeedtestSummary>,
+    pub up: Option<SpeedtestSummary>,
+    /// Download and upload were measured at the same time and may have
+    /// competed for bandwidth
+    pub concurrent: bool,
+    /// Transport protocol of the provider, `quic` or `tcp`
+    #[serde(skip)]
+    pub protocol: Option<&'static str>,
+    /// Set by `speedtest.server_lookup`, labels every speedtest metric
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<ServerInfo>,
+    /// Set by `speedtest.interface`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    /// When the measurement finished, only part of [`JsonSchema::V2`]
+    #[serde(skip)]
+    pub measured_at: SystemTime,
+}
+
+impl SpeedtestResult {
+    pub fn write_prometheus(&self, builder: &mut ExpositionBuilder, quantiles: &Quantiles) {
+        match &self.server {
+            Some(server) => server.with_labels(builder, |builder| {
+                self.write_metrics(builder, quantiles);
+            }),
+            None => self.write_metrics(builder, quantiles),
+        }
+    }
+
+    fn write_metrics(&self, builder: &mut ExpositionBuilder, quantiles: &Quantiles) {
+        builder.add_metric(
+            PName::new("speedtest_concurrent").unwrap(),
+            MetricType::Gauge,
+            "whether download and upload were measured concurrently, results may be affected by contention",
+            |mut builder| builder.add_line(&u8::from(self.concurrent), None),
+        );
+        if let Some(current) = self.down.as_ref().and_then(|down| down.current) {
+            builder.add_metric(
+                PName::new("speedtest_download_current_bps").unwrap(),
+                MetricType::Gauge,
+                "download speed of the last sample in bits per second",
+                |mut builder| builder.add_line(&current, None),
+            );
+        }
+        let direction = PName::new("direction").unwrap();
+        for (label, summary) in [("down", &self.down), ("up", &self.up)] {
+            builder.with_label(direction, label, |builder| {
+                builder.add_metric(
+                    PName::new("speedtest_timeout").unwrap(),
+                    MetricType::Gauge,
+                    "whether the speedtest provider timed out",
+                    |mut builder| builder.add_line(&u8::from(summary.is_none()), None),
+                );
+                builder.add_metric(
+                    PName::new("speedtest_measurement_info").unwrap(),
+                    MetricType::Gauge,
+                    "measurement quality: samples collected and whether the provider finished",
+                    |mut builder| {
+                        let field = PName::new("field").unwrap();
+                        let samples = summary.as_ref().map_or(0, |summary| summary.count);
+                        builder.add_line_labeled(field, "samples", &samples, None);
+                        builder.add_line_labeled(
+                            field,
+                            "complete",
+                            &u8::from(summary.is_some()),
+                            None,
+                        );
+                    },
+                );
+                if let (Some(interface), Some(_)) = (&self.interface, summary) {
+                    builder.add_metric(
+                        PName::new("speedtest_interface_label").unwrap(),
+                        MetricType::Gauge,
+                        "network interface the speedtest was bound to",
+                        |mut builder| {
+                            let label = PName::new("interface").unwrap();
+                            builder.add_line_labeled(label, interface.as_str(), &1, None);
+                        },
+                    );
+                }
+                if let (Some(protocol), Some(_)) = (self.protocol, summary) {
+                    builder.add_metric(
+                        PName::new("speedtest_protocol").unwrap(),
+                        MetricType::Gauge,
+                        "transport protocol of the measurement, quic or tcp",
+                        |mut builder| {
+                            let label = PName::new("protocol").unwrap();
+                            builder.add_line_labeled(label, protocol, &1, None);
+                        },
+                    );
+                }
+                if let Some(summary) = summary {
+                    summary.write_prometheus(builder, quantiles);
+                }
+            });
+        }
+    }
+}
+
+/// JSON representation of a [`SpeedtestResult`], optionally with
+/// convenience values in Mbit/s that are never part of the exposition.
+#[derive(Debug, Serialize)]
+pub struct SpeedtestJson<'a> {
+    pub down: Option<SpeedtestSummaryJson<'a>>,
+    pub up: Option<SpeedtestSummaryJson<'a>>,
+    pub concurrent: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SpeedtestSummaryJson<'a> {
+    pub unit: &'static str,
+    #[serde(flatten)]
+    pub summary: &'a SpeedtestSummary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mbps: Option<MbpsSummary>,
+}
+
+/// Values of a [`SpeedtestSummary`] in Mbit/s
+#[derive(Debug, Serialize)]
+pub struct MbpsSummary {
+    pub unit: &'static str,
+    pub mean: f64,
+    pub stddev: f64,
+    pub quantiles: Vec<(f64, f64)>,
+}
+
+impl SpeedtestResult {
+    pub fn to_json(&self, include_mbps: bool) -> SpeedtestJson<'_> {
+        SpeedtestJson {
+            down: self.down.as_ref().map(|it| it.to_json(include_mbps)),
+            up: self.up.as_ref().map(|it| it.to_json(include_mbps)),
+            concurrent: self.concurrent,
+        }
+    }
+
+    pub fn to_json_v2(&self, include_mbps: bool) -> SpeedtestJsonV2<'_> {
+        SpeedtestJsonV2 {
+            measured_at: self.measured_at,
+            down: self.down.as_ref().map(|it| it.to_json_v2(include_mbps)),
+            up: self.up.as_ref().map(|it| it.to_json_v2(include_mbps)),
+            concurrent: self.concurrent,
+            server: self.server.as_ref(),
+        }
+    }
+
+    pub(crate) fn to_versioned_json(
+        &self,
+        schema: JsonSchema,
+        include_mbps: bool,
+    ) -> Versioned<SpeedtestJson<'_>, SpeedtestJsonV2<'_>> {
+        match schema {
+            JsonSchema::V1 => Versioned::V1(self.to_json(include_mbps)),
+            JsonSchema::V2 => Versioned::V2(self.to_json_v2(include_mbps)),
+        }
+    }
+}
+
+/// [`SpeedtestJson`] in [`JsonSchema::V2`]
+#[derive(Debug, Serialize)]
+pub struct SpeedtestJsonV2<'a> {
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub measured_at: SystemTime,
+    pub down: Option<SpeedtestSummaryJsonV2<'a>>,
+    pub up: Option<SpeedtestSummaryJsonV2<'a>>,
+    pub concurrent: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<&'a ServerInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SpeedtestSummaryJsonV2<'a> {
+    pub unit: &'static str,
+    #[serde(serialize_with = "serialize_quantile_map")]
+    pub quantiles: &'a [(f64, u64)],
+    pub mean: u64,
+    pub stddev: f64,
+    pub sum: u64,
+    pub count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<&'a TcpStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<&'a Url>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mbps: Option<MbpsSummaryJsonV2>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub measure_duration_seconds: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MbpsSummaryJsonV2 {
+    pub unit: &'static str,
+    pub mean: f64,
+    pub stddev: f64,
+    #[serde(serialize_with = "serialize_quantile_map")]
+    pub quantiles: Vec<(f64, f64)>,
+}
 
 pub struct SpeedtestData {
     pub samples: Vec<SpeedtestSample>,
     pub total: SpeedtestSample,
+    /// Kernel statistics of the connection, if available
+    pub tcp: Option<TcpStats>,
+    /// URL measured after following redirects, if the provider knows it
+    pub url: Option<Url>,
 }
 
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct SpeedtestSample {
     pub bytes: f64,
     pub seconds: f64,
@@ -69,9 +407,55 @@ pub trait SpeedtestProvider: Serialize + Deserialize<'static> + 'static {
     async fn measure_upload(&self) -> reqwest::Result<SpeedtestData>;
 }
 
+// Only ever held once by the config, boxing is not worth it
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum StandardSpeedtestProvider {
     Http(HttpSpeedtestProvider),
+    #[cfg(feature = "quic")]
+    Quic(QuicSpeedtestProvider),
+    LibreSpeed(LibreSpeedProvider),
+    Null(NullSpeedtestProvider),
+}
+
+impl StandardSpeedtestProvider {
+    /// This provider measuring on the network interface `interface`
+    pub fn bound_to(&self, interface: &str) -> Self {
+        let http = match self {
+            Self::Http(p) => p.clone(),
+            Self::LibreSpeed(p) => p.to_http(),
+            #[cfg(feature = "quic")]
+            Self::Quic(p) => p.to_http(),
+            Self::Null(_) => return self.clone(),
+        };
+        Self::Http(HttpSpeedtestProvider {
+            interface: Some(interface.to_owned()),
+            ..http
+        })
+    }
+
+    /// Download endpoint, which identifies the server measured against
+    pub fn endpoint(&self) -> Option<Url> {
+        match self {
+            Self::Http(p) => Some(p.download_endpoint.clone()),
+            #[cfg(feature = "quic")]
+            Self::Quic(p) => Some(p.download_endpoint.clone()),
+            Self::LibreSpeed(p) => Some(p.to_http().download_endpoint),
+            Self::Null(_) => None,
+        }
+    }
+
+    /// Transport protocol of the measurements, `None` for the `Null`
+    /// provider that transfers nothing
+    pub fn protocol(&self) -> Option<&'static str> {
+        match self {
+            Self::Http(p) => Some(p.protocol()),
+            #[cfg(feature = "quic")]
+            Self::Quic(_) => Some("quic"),
+            Self::LibreSpeed(_) => Some("tcp"),
+            Self::Null(_) => None,
+        }
+    }
 }
 
 impl SpeedtestProvider for StandardSpeedtestProvider {
@@ -84,6 +468,10 @@ impl SpeedtestProvider for StandardSpeedtestProvider {
     {
         match self {
             Self::Http(p) => p.measure_download(),
+            #[cfg(feature = "quic")]
+            Self::Quic(p) => p.measure_download(),
+            Self::LibreSpeed(p) => p.measure_download(),
+            Self::Null(p) => p.measure_download(),
         }
     }
 
@@ -96,6 +484,10 @@ impl SpeedtestProvider for StandardSpeedtestProvider {
     {
         match self {
             Self::Http(p) => p.measure_upload(),
+            #[cfg(feature = "quic")]
+            Self::Quic(p) => p.measure_upload(),
+            Self::LibreSpeed(p) => p.measure_upload(),
+            Self::Null(p) => p.measure_upload(),
         }
     }
 }
@@ -107,13 +499,84 @@ pub struct SpeedtestSummary {
     pub stddev: f64,
     pub sum: u64,
     pub count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<TcpStats>,
+    /// Rate of the chronologically last sample, what a user would see live
+    #[serde(skip)]
+    pub current: Option<u64>,
+    /// Only part of [`JsonSchema::V2`]
+    #[serde(skip)]
+    pub url: Option<Url>,
+    /// Wall time of the provider call, only part of [`JsonSchema::V2`]
+    #[serde(skip)]
+    pub measure_duration: Option<Duration>,
 }
 
 impl SpeedtestSummary {
+    pub fn to_json(&self, include_mbps: bool) -> SpeedtestSummaryJson<'_> {
+        SpeedtestSummaryJson {
+            unit: "bit/s",
+            summary: self,
+            mbps: include_mbps.then(|| self.to_mbps()),
+        }
+    }
+
+    pub fn to_json_v2(&self, include_mbps: bool) -> SpeedtestSummaryJsonV2<'_> {
+        SpeedtestSummaryJsonV2 {
+            unit: "bit/s",
+            quantiles: &self.quantiles,
+            mean: self.mean,
+            stddev: self.stddev,
+            sum: self.sum,
+            count: self.count,
+            tcp: self.tcp.as_ref(),
+            url: self.url.as_ref(),
+            measure_duration_seconds: self.measure_duration.map(|it| it.as_secs_f64()),
+            mbps: include_mbps.then(|| {
+                let MbpsSummary {
+                    unit,
+                    mean,
+                    stddev,
+                    quantiles,
+                } = self.to_mbps();
+                MbpsSummaryJsonV2 {
+                    unit,
+                    mean,
+                    stddev,
+                    quantiles,
+                }
+            }),
+        }
+    }
+
+    /// The only place where values are converted to Mbit/s
+    pub fn to_mbps(&self) -> MbpsSummary {
+        const MBPS: f64 = 1e6;
+        MbpsSummary {
+            unit: "Mbit/s",
+            mean: self.mean as f64 / MBPS,
+            stddev: self.stddev / MBPS,
+            quantiles: self
+                .quantiles
+                .iter()
+                .map(|&(q, bps)| (q, bps as f64 / MBPS))
+                .collect(),
+        }
+    }
+
     pub fn digest_data(
-        SpeedtestData { mut samples, total }: SpeedtestData,
+        SpeedtestData {
+            mut samples,
+            total,
+            tcp,
+            url,
+        }: SpeedtestData,
         quantiles: &[f64],
     ) -> Self {
+        // Samples arrive in time order, which sorting destroys
+        let current = samples
+            .last()
+            .map(|sample| sample.bps().try_into().unwrap());
         samples.sort_unstable_by_key(|d| d.bps());
 
         let mut quantiles_map = Vec::with_capacity(quantiles.len());
@@ -172,22 +635,21 @@ impl SpeedtestSummary {
                 .try_into()
                 .unwrap(),
             count: samples.len(),
+            tcp,
+            current,
+            url,
+            measure_duration: None,
         }
     }
 
-    pub fn write_prometheus(&self, builder: &mut ExpositionBuilder) {
+    pub fn write_prometheus(&self, builder: &mut ExpositionBuilder, names: &Quantiles) {
         builder.add_metric(
             PName::new("network_speed_bps").unwrap(),
             MetricType::Summary,
             "network speed in bits per second",
             |mut builder| {
                 for (quantile, value) in &self.quantiles {
-                    builder.add_line_labeled(
-                        PName::QUANTILE,
-                        quantile.to_string().as_str(),
-                        value,
-                        None,
-                    );
+                    builder.add_quantile_line(*quantile, names.name(*quantile), value);
                 }
                 builder.with_name(PName::SUFFIX_SUM, |builder| {
                     builder.add_line(&self.sum, None);
@@ -211,5 +673,326 @@ impl SpeedtestSummary {
             "network speed standard deviation",
             |mut builder| builder.add_line(&self.stddev, None),
         );
+
+        if let Some(duration) = self.measure_duration {
+            builder.add_metric_with_unit(
+                PName::new("network_speed_measure_duration_seconds").unwrap(),
+                MetricType::Gauge,
+                Some(PName::UNIT_SECONDS),
+                "wall time of measuring the direction in seconds",
+                |mut builder| builder.add_line(&duration.as_secs_f64(), None),
+            );
+        }
+
+        if let Some(tcp) = &self.tcp {
+            builder.add_metric(
+                PName::new("speedtest_tcp_retransmits").unwrap(),
+                MetricType::Gauge,
+                "retransmitted TCP segments",
+                |mut builder| builder.add_line(&tcp.retransmits, None),
+            );
+
+            builder.add_metric_with_unit(
+                PName::new("speedtest_tcp_rtt_seconds").unwrap(),
+                MetricType::Gauge,
+                Some(PName::UNIT_SECONDS),
+                "smoothed TCP round trip time in seconds",
+                |mut builder| builder.add_line(&tcp.rtt_seconds, None),
+            );
+
+            builder.add_metric_with_unit(
+                PName::new("speedtest_tcp_rtt_var_seconds").unwrap(),
+                MetricType::Gauge,
+                Some(PName::UNIT_SECONDS),
+                "TCP round trip time variance in seconds",
+                |mut builder| builder.add_line(&tcp.rtt_var_seconds, None),
+            );
+
+            builder.add_metric(
+                PName::new("speedtest_tcp_snd_cwnd").unwrap(),
+                MetricType::Gauge,
+                "TCP congestion window in segments",
+                |mut builder| builder.add_line(&tcp.snd_cwnd, None),
+            );
+        }
+
+        if let Some(url) = &self.url {
+            builder.add_metric(
+                PName::new("speedtest_endpoint_info").unwrap(),
+                MetricType::Gauge,
+                "URL measured after following redirects",
+                |mut builder| {
+                    builder.add_line_labeled(PName::new("url").unwrap(), url.as_str(), &1, None)
+                },
+            );
+        }
+    }
+}
+
+impl SpeedtestResult {
+    /// Table of both directions for terminals, in Mbit/s
+    pub(crate) fn to_table(&self, renderer: &AnsiRenderer) -> Table {
+        let header = ["direction", "mean Mbit/s", "p50", "p90"].map(|it| renderer.header(it));
+        let mut table = Table::new(header.to_vec());
+        for (direction, summary) in [("down", &self.down), ("up", &self.up)] {
+            let mut row = vec![direction.to_owned()];
+            match summary {
+                Some(summary) => {
+                    let mbps = summary.to_mbps();
+                    let quantile = |quantile| {
+                        let found = mbps.quantiles.iter().find(|(q, _)| *q == quantile);
+                        found.map(|(_, value)| *value)
+                    };
+                    row.extend([
+                        decimal(Some(mbps.mean)),
+                        decimal(quantile(0.5)),
+                        decimal(quantile(0.9)),
+                    ]);
+                }
+                None => row.push(renderer.error("timed out".to_owned())),
+            }
+            table.push(row);
+        }
+        table
+    }
+}
+
+impl Display for SpeedtestResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (direction, summary)) in [("down", &self.down), ("up", &self.up)]
+            .into_iter()
+            .enumerate()
+        {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            match summary {
+                Some(summary) => write!(f, "{direction}: {summary}")?,
+                None => write!(f, "{direction}: timed out")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for SpeedtestSummary {
+    /// One-line summary in Mbps, e.g. `94.3 Mbps (p50: 95.1, p99: 72.4, stddev: 8.2)`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const MBPS: f64 = 1e6;
+        write!(f, "{:.1} Mbps (", self.mean as f64 / MBPS)?;
+        for (label, quantile) in [("p50", 0.5), ("p99", 0.99)] {
+            if let Some((_, bps)) = self.quantiles.iter().find(|(q, _)| *q == quantile) {
+                write!(f, "{label}: {:.1}, ", *bps as f64 / MBPS)?;
+            }
+        }
+        write!(f, "stddev: {:.1})", self.stddev / MBPS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prometheus::ExpositionArena;
+
+    #[tokio::test]
+    async fn provider_timeout_yields_no_data() {
+        let timeout = Some(Duration::from_millis(10));
+        let result = with_timeout(timeout, "down", std::future::pending()).await;
+        assert!(result.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn measure_durations_cover_the_provider_call() {
+        let provider_call = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(SpeedtestData {
+                samples: Vec::new(),
+                total: SpeedtestSample::default(),
+                tcp: None,
+                url: None,
+            })
+        };
+        let timeout = Some(Duration::from_secs(5));
+        let (_, duration) = (with_timeout(timeout, "down", provider_call).await)
+            .unwrap()
+            .unwrap();
+        assert!(
+            duration >= Duration::from_millis(50) && duration < Duration::from_secs(5),
+            "{duration:?}"
+        );
+
+        let mut summary = SpeedtestSummary::digest_data(
+            SpeedtestData {
+                samples: vec![SpeedtestSample::default()],
+                total: SpeedtestSample::default(),
+                tcp: None,
+                url: None,
+            },
+            &[],
+        );
+        summary.measure_duration = Some(Duration::from_secs(2));
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        summary.write_prometheus(&mut builder, &Quantiles::default());
+        let exposition = builder.to_string();
+        assert!(
+            exposition.contains("\nnetwork_speed_measure_duration_seconds +0x1.p1\n"),
+            "{exposition}"
+        );
+        let json = serde_json::to_value(summary.to_json_v2(false)).unwrap();
+        assert_eq!(json["measure_duration_seconds"], 2.);
+    }
+
+    #[test]
+    fn json_mbps_is_optional() {
+        let samples = vec![SpeedtestSample {
+            bytes: 12_500_000.,
+            seconds: 1.,
+        }];
+        let summary = SpeedtestSummary::digest_data(
+            SpeedtestData {
+                total: samples[0],
+                samples,
+                tcp: None,
+                url: None,
+            },
+            &[0.5],
+        );
+
+        let json = serde_json::to_value(summary.to_json(false)).unwrap();
+        assert_eq!(json["unit"], "bit/s");
+        assert_eq!(json["mean"], 100_000_000);
+        assert!(json.get("mbps").is_none());
+
+        let json = serde_json::to_value(summary.to_json(true)).unwrap();
+        assert_eq!(json["mbps"]["unit"], "Mbit/s");
+        assert_eq!(json["mbps"]["mean"], 100.);
+        assert_eq!(json["mbps"]["quantiles"][0][1], 100.);
+    }
+
+    #[test]
+    fn current_rate_is_the_last_sample() {
+        let sample = |bytes| SpeedtestSample { bytes, seconds: 1. };
+        let samples = vec![sample(250_000.), sample(125_000.), sample(375_000.)];
+        let summary = SpeedtestSummary::digest_data(
+            SpeedtestData {
+                total: samples.iter().copied().sum(),
+                samples: samples.clone(),
+                tcp: None,
+                url: None,
+            },
+            &[0.5],
+        );
+        assert_eq!(summary.current, Some(3_000_000));
+        assert_eq!(summary.mean, 2_000_000);
+
+        let result = SpeedtestResult {
+            down: Some(summary),
+            up: None,
+            concurrent: false,
+            protocol: Some("tcp"),
+            server: None,
+            interface: Some("eth0".to_owned()),
+            measured_at: SystemTime::UNIX_EPOCH,
+        };
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        let quantiles = toml::from_str("median = 0.5").unwrap();
+        result.write_prometheus(&mut builder, &quantiles);
+        assert_eq!(builder.validate(), Ok(()));
+        let exposition = builder.to_string();
+        assert!(exposition.contains("\nspeedtest_download_current_bps 3000000\n"));
+        assert!(
+            exposition.contains("\nspeedtest_protocol{direction=\"down\", protocol=\"tcp\"} 1\n")
+        );
+        assert!(!exposition.contains("speedtest_protocol{direction=\"up\""));
+        assert!(exposition
+            .contains("\nspeedtest_interface_label{direction=\"down\", interface=\"eth0\"} 1\n"));
+        assert!(!exposition.contains("speedtest_interface_label{direction=\"up\""));
+        assert!(exposition.contains(
+            "\nnetwork_speed_bps{direction=\"down\", quantile=\"0.5\", name=\"median\"} "
+        ));
+    }
+
+    #[test]
+    fn metrics_are_labeled_with_server() {
+        let result = SpeedtestResult {
+            down: None,
+            up: None,
+            concurrent: false,
+            server: Some(ServerInfo {
+                asn: 64512,
+                country: "ZZ".to_owned(),
+            }),
+            protocol: None,
+            interface: None,
+            measured_at: SystemTime::UNIX_EPOCH,
+        };
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        result.write_prometheus(&mut builder, &Quantiles::default());
+        assert_eq!(builder.validate(), Ok(()));
+        let exposition = builder.to_string();
+        assert!(exposition
+            .contains("\nspeedtest_concurrent{server_asn=\"64512\", server_country=\"ZZ\"} 0\n"));
+        assert!(exposition.contains(
+            "\nspeedtest_timeout{server_asn=\"64512\", server_country=\"ZZ\", direction=\"up\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn json_schemas() {
+        let samples = vec![SpeedtestSample {
+            bytes: 12_500_000.,
+            seconds: 1.,
+        }];
+        let summary = SpeedtestSummary::digest_data(
+            SpeedtestData {
+                total: samples[0],
+                samples,
+                tcp: None,
+                url: None,
+            },
+            &[0.5],
+        );
+        let result = SpeedtestResult {
+            down: Some(summary),
+            up: None,
+            concurrent: false,
+            protocol: None,
+            server: None,
+            interface: None,
+            measured_at: SystemTime::UNIX_EPOCH,
+        };
+        let json =
+            |schema| serde_json::to_string(&result.to_versioned_json(schema, false)).unwrap();
+
+        assert_eq!(
+            json(JsonSchema::V1),
+            r#"{"down":{"unit":"bit/s","quantiles":[[0.5,100000000]],"mean":100000000,"stddev":0.0,"sum":100000000,"count":1},"up":null,"concurrent":false}"#
+        );
+        assert_eq!(
+            json(JsonSchema::V2),
+            r#"{"measured_at":"1970-01-01T00:00:00.000Z","down":{"unit":"bit/s","quantiles":{"0.5":100000000},"mean":100000000,"stddev":0.0,"sum":100000000,"count":1},"up":null,"concurrent":false}"#
+        );
+    }
+
+    #[test]
+    fn summary_display() {
+        let sample = |mbit: f64| SpeedtestSample {
+            bytes: mbit * 125_000.,
+            seconds: 1.,
+        };
+        let samples = vec![sample(90.), sample(100.), sample(110.)];
+        let summary = SpeedtestSummary::digest_data(
+            SpeedtestData {
+                total: samples.iter().copied().sum(),
+                samples,
+                tcp: None,
+                url: None,
+            },
+            &[0.5],
+        );
+        assert_eq!(summary.to_string(), "100.0 Mbps (p50: 100.0, stddev: 8.2)");
     }
 }
@@ -1,5 +1,6 @@
 use core::task;
 use std::{
+    collections::VecDeque,
     convert::Infallible,
     pin::Pin,
     time::{Duration, Instant},
@@ -9,10 +10,17 @@ use axum::{async_trait, body::Bytes};
 use http::header;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
-use tokio_stream::Stream;
+use thiserror::Error;
+use tokio_stream::{Stream, StreamExt};
 use url::Url;
 
-use super::{SpeedtestData as Data, SpeedtestProvider, SpeedtestSample as Sample};
+use super::{
+    tcp_info::{TcpConnections, TcpStats},
+    SpeedtestData as Data, SpeedtestProvider, SpeedtestSample as Sample,
+};
+
+/// Minimum duration of a download sample, averages out spikes
+const MIN_SAMPLE_TIME: Duration = Duration::from_millis(50);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HttpSpeedtestProvider {
@@ -23,18 +31,308 @@ pub struct HttpSpeedtestProvider {
     #[serde(with = "humantime_serde")]
     pub upload_duration: Duration,
     pub upload_chunk_size: usize,
+    #[serde(default = "HttpSpeedtestProvider::default_upload_content_type")]
+    pub upload_content_type: String,
+    #[serde(default)]
+    pub upload_encoding: UploadEncoding,
+    /// Bytes that the uploads consist of, to compare links that compress
+    /// traffic with ones that do not
+    #[serde(default)]
+    pub upload_payload: UploadPayload,
+    /// Stops the download early once throughput has stabilized
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub early_stop: Option<EarlyStop>,
+    /// Samples the download rate over overlapping windows instead of
+    /// consecutive ones of at least 50ms
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_window: Option<SampleWindow>,
+    /// Stops the download after consuming this many bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_download_bytes: Option<u64>,
+    #[serde(default)]
+    pub total_time: TotalTime,
+    /// Refuses to connect with older TLS versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_tls_version: Option<TlsVersion>,
+    /// Refuses to connect with newer TLS versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tls_version: Option<TlsVersion>,
+    /// Gives up connecting to an endpoint after this long, e.g. if a
+    /// firewall drops the connection attempt
+    #[serde(
+        default = "HttpSpeedtestProvider::default_connect_timeout",
+        with = "humantime_serde"
+    )]
+    pub connect_timeout: Duration,
+    /// Fails the measurement if no data could be read for this long,
+    /// defaults to the measurement duration
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub read_timeout: Option<Duration>,
+    /// Certificate authorities in PEM format to trust in addition to those
+    /// of the system, e.g. of a self-hosted server
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub root_certificates: Vec<String>,
+    /// Requests the endpoints with HTTP/3 over QUIC instead of TCP, set by
+    /// the `Quic` provider
+    #[serde(skip)]
+    pub http3: bool,
+    #[serde(default)]
+    pub redirects: Redirects,
+    /// Idle connections kept open for the next request of a measurement,
+    /// unbounded by default. `0` opens a new connection per request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Closes idle connections after this long, 90 seconds by default
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pool_idle_timeout: Option<Duration>,
+    /// Network interface to connect from, set by `speedtest.interface`
+    #[serde(skip)]
+    pub interface: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls1_0,
+    #[serde(rename = "1.1")]
+    Tls1_1,
+    #[serde(rename = "1.2")]
+    Tls1_2,
+    #[serde(rename = "1.3")]
+    Tls1_3,
+}
+
+impl From<TlsVersion> for reqwest::tls::Version {
+    fn from(version: TlsVersion) -> Self {
+        match version {
+            TlsVersion::Tls1_0 => Self::TLS_1_0,
+            TlsVersion::Tls1_1 => Self::TLS_1_1,
+            TlsVersion::Tls1_2 => Self::TLS_1_2,
+            TlsVersion::Tls1_3 => Self::TLS_1_3,
+        }
+    }
+}
+
+impl From<TlsVersion> for tokio_native_tls::native_tls::Protocol {
+    fn from(version: TlsVersion) -> Self {
+        match version {
+            TlsVersion::Tls1_0 => Self::Tlsv10,
+            TlsVersion::Tls1_1 => Self::Tlsv11,
+            TlsVersion::Tls1_2 => Self::Tlsv12,
+            TlsVersion::Tls1_3 => Self::Tlsv13,
+        }
+    }
+}
+
+/// How the uploaded data is framed in the request body
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadEncoding {
+    /// The data itself with a `Content-Length`
+    #[default]
+    Raw,
+    /// The data as the file `upload` of a `multipart/form-data` form
+    Multipart,
+    /// The data itself with `Transfer-Encoding: chunked`
+    Chunked,
+}
+
+/// Content of the uploads. Throughput is always measured in the bytes sent
+/// before any compression along the path.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadPayload {
+    /// Random bytes that only repeat after 1 MiB, too far apart for common
+    /// compression windows
+    #[default]
+    Random,
+    /// Zeroes, which compress to almost nothing
+    Zeros,
+    /// These bytes over and over, e.g. `{ pattern = [0, 1, 2, 3] }`
+    Pattern(Vec<u8>),
+}
+
+/// Size of the block of random data that uploads repeat
+const RANDOM_PAYLOAD_SIZE: usize = 1024 * 1024;
+/// Minimum size of the block of zeroes or patterns that uploads repeat
+const REPEATED_PAYLOAD_SIZE: usize = 64 * 1024;
+
+impl UploadPayload {
+    /// Block of data that uploads of `chunk_size` bytes repeat
+    fn block(&self, chunk_size: usize) -> Vec<u8> {
+        match self {
+            Self::Random => {
+                let mut data = vec![0; chunk_size.clamp(1, RANDOM_PAYLOAD_SIZE)];
+                rand::thread_rng().fill_bytes(&mut data);
+                data
+            }
+            Self::Zeros => vec![0; REPEATED_PAYLOAD_SIZE],
+            // Whole repetitions, so that the pattern continues across blocks
+            Self::Pattern(pattern) => {
+                let repetitions = REPEATED_PAYLOAD_SIZE.div_ceil(pattern.len());
+                pattern.repeat(repetitions)
+            }
+        }
+    }
+}
+
+/// Time that the total throughput of a measurement is divided by
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TotalTime {
+    /// Until the last recorded sample. The tail after it is not counted,
+    /// which slightly overstates the throughput.
+    #[default]
+    LastChunk,
+    /// Until the measurement ended, usually the configured duration. More
+    /// conservative, since bytes still in flight at the deadline are lost.
+    WallClock,
+}
+
+/// How redirects of the endpoints are handled. The URL that was measured in
+/// the end is reported by `speedtest_endpoint_info`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Redirects {
+    /// Follows up to 10 redirects
+    #[default]
+    Follow,
+    /// Fails the measurement on the first redirect
+    None,
+    /// Follows up to this many redirects
+    Max(usize),
+}
+
+impl Redirects {
+    fn policy(self) -> reqwest::redirect::Policy {
+        use reqwest::redirect::Policy;
+        match self {
+            Self::Follow => Policy::default(),
+            Self::None => Policy::custom(|attempt| {
+                let refused = RedirectRefused(attempt.url().clone());
+                attempt.error(refused)
+            }),
+            Self::Max(max) => Policy::limited(max),
+        }
+    }
+}
+
+/// Source of the error of a redirect that `redirects = "none"` refused
+#[derive(Debug, Error)]
+#[error("redirected to {0}, which is not followed because redirects are disabled")]
+pub struct RedirectRefused(pub Url);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EarlyStop {
+    /// Maximum coefficient of variation (stddev / mean) of the recent
+    /// sample rates for the throughput to be considered stable
+    pub stability_threshold: f64,
+    /// Minimum time to measure before stopping early
+    #[serde(with = "humantime_serde")]
+    pub min_duration: Duration,
+    /// Number of most recent samples to consider
+    #[serde(default = "EarlyStop::default_window")]
+    pub window: usize,
+}
+
+impl EarlyStop {
+    fn default_window() -> usize {
+        20
+    }
+
+    fn should_stop(&self, locals: &MeasurementLocals) -> bool {
+        let window = self.window.max(2);
+        let elapsed = locals.last_chunk_time.duration_since(locals.start_time);
+        elapsed >= self.min_duration
+            && locals.samples.len() >= window
+            && is_stable(
+                &locals.samples[locals.samples.len() - window..],
+                self.stability_threshold,
+            )
+    }
+}
+
+/// Overlapping windows of the download rate
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SampleWindow {
+    /// Length of the window that a sample reports the rate of
+    #[serde(with = "humantime_serde")]
+    pub width: Duration,
+    /// Time between samples, at most `width`
+    #[serde(with = "humantime_serde")]
+    pub step: Duration,
+}
+
+/// Bookkeeping of [`SampleWindow`]. Each sample reports the rate over the
+/// last `width`, but only lasts the `step` since the previous sample, so that
+/// the samples still add up to the duration of the measurement.
+struct SlidingWindow {
+    width: Duration,
+    /// Times and total bytes of the previous samples within `width`, and of
+    /// the start of the measurement until it is outside of it
+    points: VecDeque<(Instant, f64)>,
+}
+
+impl SlidingWindow {
+    fn new(width: Duration, start_time: Instant) -> Self {
+        Self {
+            width,
+            points: VecDeque::from([(start_time, 0.)]),
+        }
+    }
+
+    fn sample(&mut self, now: Instant, total_bytes: f64, last_sample_time: Instant) -> Sample {
+        while (self.points.get(1)).is_some_and(|&(time, _)| now.duration_since(time) >= self.width)
+        {
+            self.points.pop_front();
+        }
+        let (since, bytes_since) = self.points[0];
+        self.points.push_back((now, total_bytes));
+        let rate = (total_bytes - bytes_since) / now.duration_since(since).as_secs_f64();
+        let seconds = now.duration_since(last_sample_time).as_secs_f64();
+        Sample {
+            bytes: rate * seconds,
+            seconds,
+        }
+    }
+}
+
+/// Whether the coefficient of variation of the sample rates is at most `threshold`
+fn is_stable(samples: &[Sample], threshold: f64) -> bool {
+    let total: Sample = samples.iter().copied().sum();
+    if total.seconds <= 0. {
+        return false;
+    }
+    let mean = total.bps_f64();
+    let variance = samples
+        .iter()
+        .map(|x| {
+            let diff = mean - x.bps_f64();
+            x.seconds * (diff * diff)
+        })
+        .sum::<f64>()
+        / total.seconds;
+    mean > 0. && variance.sqrt() / mean <= threshold
 }
 
 #[async_trait]
 impl SpeedtestProvider for HttpSpeedtestProvider {
     async fn measure_download(&self) -> reqwest::Result<Data> {
-        let mut locals = self.prepare_measurements(self.download_duration);
-        self.collect_download_data(&mut locals).await?;
-        Ok(self.finish_measurements(locals))
+        Ok(self.measure_download_timed().await?.0)
     }
 
     async fn measure_upload(&self) -> reqwest::Result<Data> {
-        let mut locals = self.prepare_measurements(self.download_duration);
+        let mut locals = self.prepare_measurements(self.download_duration, 0)?;
         self.collect_upload_data(&mut locals).await?;
         Ok(self.finish_measurements(locals))
     }
@@ -42,79 +340,179 @@ impl SpeedtestProvider for HttpSpeedtestProvider {
 
 struct MeasurementLocals {
     client: reqwest::Client,
+    connections: TcpConnections,
     start_time: Instant,
     end_time: Instant,
     samples: Vec<Sample>,
     total_bytes: f64,
     last_chunk_time: Instant,
+    tcp: Option<TcpStats>,
+    first_byte_time: Option<Instant>,
+    url: Option<Url>,
 }
 
 impl HttpSpeedtestProvider {
+    pub(crate) fn default_connect_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    pub(crate) fn default_upload_content_type() -> String {
+        mime::APPLICATION_OCTET_STREAM.to_string()
+    }
+
+    /// Transport protocol of the measurements, `quic` or `tcp`
+    pub fn protocol(&self) -> &'static str {
+        if self.http3 {
+            "quic"
+        } else {
+            "tcp"
+        }
+    }
+
+    /// HTTP/3 must be requested explicitly, HTTP/2 is negotiated by the
+    /// client for HTTP/1.1 requests
+    fn http_version(&self) -> reqwest::Version {
+        if self.http3 {
+            reqwest::Version::HTTP_3
+        } else {
+            reqwest::Version::HTTP_11
+        }
+    }
+
+    /// Measures the download like [`SpeedtestProvider::measure_download`],
+    /// additionally returning the time until the first byte was received.
+    pub(crate) async fn measure_download_timed(&self) -> reqwest::Result<(Data, Option<Duration>)> {
+        // At most one sample is taken per step
+        let step = self.sample_step().as_millis().max(1);
+        let capacity = self.download_duration.as_millis() / step + 1;
+        let mut locals = self.prepare_measurements(self.download_duration, capacity as usize)?;
+        self.collect_download_data(&mut locals).await?;
+        let first_byte =
+            (locals.first_byte_time).map(|first_byte| first_byte.duration_since(locals.start_time));
+        Ok((self.finish_measurements(locals), first_byte))
+    }
+
     #[inline(always)]
-    fn prepare_measurements(&self, duration: Duration) -> MeasurementLocals {
+    fn prepare_measurements(
+        &self,
+        duration: Duration,
+        capacity: usize,
+    ) -> reqwest::Result<MeasurementLocals> {
+        // Loading the root certificates may take a considerable part of short
+        // measurements
+        let connections = TcpConnections::default();
+        let client = self.build_client(duration, &connections)?;
         let start_time = Instant::now();
         let last_chunk_time = start_time;
         let end_time = start_time + duration;
 
-        MeasurementLocals {
-            client: self.build_client(),
+        Ok(MeasurementLocals {
+            client,
+            connections,
             start_time,
             end_time,
-            samples: Vec::new(),
+            // Bounded in case of absurdly long durations
+            samples: Vec::with_capacity(capacity.min(1 << 16)),
             total_bytes: 0.,
             last_chunk_time,
-        }
+            tcp: None,
+            first_byte_time: None,
+            url: None,
+        })
     }
 
     #[inline(always)]
     fn finish_measurements(&self, locals: MeasurementLocals) -> Data {
+        let end = match self.total_time {
+            TotalTime::LastChunk => locals.last_chunk_time,
+            TotalTime::WallClock => Instant::now(),
+        };
         Data {
             samples: locals.samples,
             total: Sample {
                 bytes: locals.total_bytes,
-                seconds: locals
-                    .last_chunk_time
-                    .duration_since(locals.start_time)
-                    .as_secs_f64(),
+                seconds: end.duration_since(locals.start_time).as_secs_f64(),
             },
+            tcp: locals.tcp,
+            url: locals.url,
+        }
+    }
+
+    /// Minimum time between two download samples
+    fn sample_step(&self) -> Duration {
+        match &self.sample_window {
+            Some(window) => window.step,
+            None => MIN_SAMPLE_TIME,
         }
     }
 
     #[inline(always)]
     async fn collect_download_data(&self, locals: &mut MeasurementLocals) -> reqwest::Result<()> {
-        // Averages out spikes
-        const MIN_SAMPLE_TIME: Duration = Duration::from_millis(50);
         let mut sample_bytes = 0.;
+        let step = self.sample_step();
+        let mut sliding = (self.sample_window.as_ref())
+            .map(|window| SlidingWindow::new(window.width, locals.start_time));
 
-        'outer: loop {
+        // Short responses may be buffered completely, in which case reading
+        // them never hits the timeout
+        while Instant::now() < locals.end_time {
             let mut response = locals
                 .client
                 .get(self.download_endpoint.clone())
+                .version(self.http_version())
                 .send()
                 .await?
                 .error_for_status()?;
+            locals.url = Some(response.url().clone());
 
-            loop {
+            let finished = loop {
                 match tokio::time::timeout_at(locals.end_time.into(), response.chunk()).await {
                     Ok(result) => {
                         let Some(chunk) = result? else {
-                            break;
+                            break false;
                         };
-                        let bytes = chunk.len() as f64;
+                        locals.first_byte_time.get_or_insert_with(Instant::now);
+                        let mut bytes = chunk.len() as f64;
+                        let limit = self.max_download_bytes.map(|limit| limit as f64);
+                        let limit_reached = limit.is_some_and(|limit| {
+                            bytes = bytes.min(limit - locals.total_bytes);
+                            locals.total_bytes + bytes >= limit
+                        });
                         locals.total_bytes += bytes;
                         sample_bytes += bytes;
                         let now = Instant::now();
-                        if now.duration_since(locals.last_chunk_time) >= MIN_SAMPLE_TIME {
-                            locals.samples.push(Sample {
-                                bytes: sample_bytes,
-                                seconds: now.duration_since(locals.last_chunk_time).as_secs_f64(),
+                        if limit_reached || now.duration_since(locals.last_chunk_time) >= step {
+                            locals.samples.push(match &mut sliding {
+                                Some(sliding) => {
+                                    sliding.sample(now, locals.total_bytes, locals.last_chunk_time)
+                                }
+                                None => Sample {
+                                    bytes: sample_bytes,
+                                    seconds: (now.duration_since(locals.last_chunk_time))
+                                        .as_secs_f64(),
+                                },
                             });
                             sample_bytes = 0.;
                             locals.last_chunk_time = now;
+                            if limit_reached {
+                                break true;
+                            }
+
+                            if let Some(early_stop) = &self.early_stop {
+                                if early_stop.should_stop(locals) {
+                                    break true;
+                                }
+                            }
                         }
                     }
-                    Err(_) => break 'outer,
+                    Err(_) => break true,
                 }
+            };
+
+            // The connection is still open while the response is alive
+            locals.tcp = locals.connections.stats().or(locals.tcp);
+            if finished {
+                break;
             }
         }
         Ok(())
@@ -122,9 +520,7 @@ impl HttpSpeedtestProvider {
 
     #[inline(always)]
     async fn collect_upload_data(&self, locals: &mut MeasurementLocals) -> reqwest::Result<()> {
-        let mut data = vec![0; 256];
-        rand::thread_rng().fill_bytes(&mut data);
-        let data = data; // immutable
+        let data = self.upload_payload.block(self.upload_chunk_size);
 
         while let Ok(result) = tokio::time::timeout_at(
             locals.end_time.into(),
@@ -132,7 +528,7 @@ impl HttpSpeedtestProvider {
         )
         .await
         {
-            result?;
+            locals.url = Some(result?.url().clone());
             let now = Instant::now();
             let size = self.upload_chunk_size as f64;
             locals.samples.push(Sample {
@@ -150,28 +546,149 @@ impl HttpSpeedtestProvider {
         client: &reqwest::Client,
         data: &[u8],
     ) -> reqwest::Result<reqwest::Response> {
-        client
-            .post(self.upload_endpoint.clone())
-            .header(
-                header::CONTENT_TYPE,
-                mime::APPLICATION_OCTET_STREAM.as_ref(),
-            )
-            .body(reqwest::Body::wrap_stream(Infinistream::new(
-                data,
-                self.upload_chunk_size,
-            )))
-            .send()
-            .await?
-            .error_for_status()
+        let data = Infinistream::new(data, self.upload_chunk_size);
+        let request = (client.post(self.upload_endpoint.clone())).version(self.http_version());
+        let request = match self.upload_encoding {
+            UploadEncoding::Raw => request
+                .header(header::CONTENT_TYPE, &self.upload_content_type)
+                .header(header::CONTENT_LENGTH, self.upload_chunk_size)
+                .body(reqwest::Body::wrap_stream(data)),
+            UploadEncoding::Chunked => request
+                .header(header::CONTENT_TYPE, &self.upload_content_type)
+                .header(header::TRANSFER_ENCODING, "chunked")
+                .body(reqwest::Body::wrap_stream(data)),
+            UploadEncoding::Multipart => {
+                let form = MultipartForm::new(&self.upload_content_type);
+                request
+                    .header(header::CONTENT_TYPE, form.content_type())
+                    .header(header::CONTENT_LENGTH, form.len(self.upload_chunk_size))
+                    .body(reqwest::Body::wrap_stream(form.wrap(data)))
+            }
+        };
+        request.send().await?.error_for_status()
     }
 
-    fn build_client(&self) -> reqwest::Client {
-        reqwest::Client::builder()
+    /// Fails if the TLS backend cannot enforce `min_tls_version` or
+    /// `max_tls_version`, native-tls cannot require TLS 1.3.
+    fn build_client(
+        &self,
+        duration: Duration,
+        connections: &TcpConnections,
+    ) -> reqwest::Result<reqwest::Client> {
+        let mut builder = connections
+            .install(reqwest::Client::builder())
             .no_brotli()
             .no_deflate()
             .no_gzip()
-            .build()
-            .unwrap()
+            .connect_timeout(self.connect_timeout)
+            .read_timeout(self.read_timeout.unwrap_or(duration))
+            .redirect(self.redirects.policy());
+        // QUIC requires TLS 1.3, which only rustls provides. reqwest defaults
+        // to it once HTTP/3 is enabled, TCP keeps using native-tls.
+        #[cfg(feature = "quic")]
+        {
+            builder = if self.http3 {
+                builder.use_rustls_tls().http3_prior_knowledge()
+            } else {
+                builder.use_native_tls()
+            };
+        }
+        for pem in &self.root_certificates {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(pem.as_bytes())?);
+        }
+        if let Some(max_idle) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        if let Some(interface) = &self.interface {
+            builder = bind_interface(builder, interface);
+        }
+        if let Some(version) = self.min_tls_version {
+            builder = builder.min_tls_version(version.into());
+        }
+        // 1.3 is the newest version and no bound at all, which native-tls
+        // could not express otherwise
+        if let Some(version) = self.max_tls_version.filter(|it| *it < TlsVersion::Tls1_3) {
+            builder = builder.max_tls_version(version.into());
+        }
+        builder.build()
+    }
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "fuchsia",
+    target_os = "illumos",
+    target_os = "ios",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "solaris",
+    target_os = "tvos",
+    target_os = "visionos",
+    target_os = "watchos",
+))]
+fn bind_interface(builder: reqwest::ClientBuilder, interface: &str) -> reqwest::ClientBuilder {
+    builder.interface(interface)
+}
+
+/// Connects from any interface, binding to one is not supported here
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "fuchsia",
+    target_os = "illumos",
+    target_os = "ios",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "solaris",
+    target_os = "tvos",
+    target_os = "visionos",
+    target_os = "watchos",
+)))]
+fn bind_interface(builder: reqwest::ClientBuilder, interface: &str) -> reqwest::ClientBuilder {
+    tracing::warn!(
+        interface,
+        "Binding to a network interface is not supported on this platform"
+    );
+    builder
+}
+
+/// `multipart/form-data` body with the uploaded data as its only file. Written
+/// by hand since reqwest can only build forms with its `multipart` feature.
+struct MultipartForm {
+    boundary: String,
+    head: String,
+    tail: String,
+}
+
+impl MultipartForm {
+    fn new(content_type: &str) -> Self {
+        let boundary = format!("speedtest-{:016x}", rand::random::<u64>());
+        Self {
+            head: format!(
+                "--{boundary}\r\n\
+                 Content-Disposition: form-data; name=\"upload\"; filename=\"upload.bin\"\r\n\
+                 Content-Type: {content_type}\r\n\r\n"
+            ),
+            tail: format!("\r\n--{boundary}--\r\n"),
+            boundary,
+        }
+    }
+
+    fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    /// Length of the body with `data_len` bytes of data
+    fn len(&self, data_len: usize) -> usize {
+        self.head.len() + data_len + self.tail.len()
+    }
+
+    fn wrap(self, data: Infinistream) -> impl Stream<Item = Result<Bytes, Infallible>> {
+        tokio_stream::once(Ok(Bytes::from(self.head)))
+            .chain(data)
+            .chain(tokio_stream::once(Ok(Bytes::from(self.tail))))
     }
 }
 
@@ -205,3 +722,464 @@ impl Stream for Infinistream {
         task::Poll::Ready(Some(Ok(self.data.slice(..len))))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr, TcpStream},
+        sync::{Arc, Mutex},
+    };
+
+    use axum::extract::ConnectInfo;
+    use socket2::{Domain, Socket, Type};
+
+    use super::*;
+
+    fn sample(bytes: f64) -> Sample {
+        Sample { bytes, seconds: 1. }
+    }
+
+    #[test]
+    fn constant_rate_is_stable() {
+        let samples = [sample(1000.); 10];
+        assert!(is_stable(&samples, 0.01));
+    }
+
+    #[test]
+    fn varying_rate_is_unstable() {
+        let samples = [sample(1000.), sample(100.), sample(1000.), sample(100.)];
+        assert!(!is_stable(&samples, 0.1));
+        assert!(is_stable(&samples, 1.));
+    }
+
+    #[test]
+    fn empty_samples_are_unstable() {
+        assert!(!is_stable(&[], 1.));
+    }
+
+    #[test]
+    fn sliding_windows_report_recent_rates() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut window = SlidingWindow::new(Duration::from_millis(200), start);
+
+        // 1000 bytes per 100ms, then 3000 bytes per 100ms
+        let mut samples = Vec::new();
+        let mut last = start;
+        for (time, total) in [(100, 1000.), (200, 2000.), (300, 5000.), (400, 8000.)] {
+            samples.push(window.sample(ms(time), total, last));
+            last = ms(time);
+        }
+        let rates: Vec<_> = samples.iter().map(|sample| sample.bps_f64() / 8.).collect();
+        assert_eq!(rates, [10_000., 10_000., 20_000., 30_000.]);
+        // Every sample lasts one step
+        assert!(samples.iter().all(|sample| sample.seconds == 0.1));
+    }
+
+    #[tokio::test]
+    async fn overlapping_windows_keep_the_total() {
+        let provider = HttpSpeedtestProvider {
+            download_duration: Duration::from_millis(300),
+            sample_window: Some(SampleWindow {
+                width: Duration::from_millis(100),
+                step: Duration::from_millis(10),
+            }),
+            ..local_provider().await
+        };
+        let data = provider.measure_download().await.unwrap();
+
+        assert!(
+            data.samples.len() > 300 / 50,
+            "{} samples",
+            data.samples.len()
+        );
+        let seconds: f64 = data.samples.iter().map(|sample| sample.seconds).sum();
+        assert!((seconds - data.total.seconds).abs() < 1e-9);
+        assert!(data.total.bytes > 0.);
+    }
+
+    /// Provider downloading from a local server that sends zeroes
+    async fn local_provider() -> HttpSpeedtestProvider {
+        let app = axum::Router::new().route(
+            "/data",
+            axum::routing::get(|| async { vec![0u8; 64 * 1024] }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let endpoint: Url = format!("http://{addr}/data").parse().unwrap();
+        HttpSpeedtestProvider {
+            download_endpoint: endpoint.clone(),
+            upload_endpoint: endpoint,
+            download_duration: Duration::from_secs(30),
+            upload_duration: Duration::from_secs(30),
+            upload_chunk_size: 1024,
+            upload_content_type: HttpSpeedtestProvider::default_upload_content_type(),
+            upload_encoding: UploadEncoding::Raw,
+            upload_payload: UploadPayload::Random,
+            early_stop: None,
+            sample_window: None,
+            max_download_bytes: None,
+            total_time: TotalTime::LastChunk,
+            min_tls_version: None,
+            max_tls_version: None,
+            connect_timeout: HttpSpeedtestProvider::default_connect_timeout(),
+            read_timeout: None,
+            root_certificates: Vec::new(),
+            http3: false,
+            redirects: Redirects::Follow,
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: None,
+            interface: None,
+        }
+    }
+
+    /// Endpoint at `/redirect` that redirects to the data of `provider`
+    async fn redirecting(provider: &HttpSpeedtestProvider) -> Url {
+        let target = provider.download_endpoint.to_string();
+        let app = axum::Router::new().route(
+            "/redirect",
+            axum::routing::get(|| async move { axum::response::Redirect::temporary(&target) }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{addr}/redirect").parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn redirects_are_followed_and_recorded() {
+        let provider = local_provider().await;
+        let provider = HttpSpeedtestProvider {
+            download_endpoint: redirecting(&provider).await,
+            download_duration: Duration::from_millis(200),
+            ..provider.clone()
+        };
+        let data = provider.measure_download().await.unwrap();
+        assert_eq!(data.url.as_ref().unwrap().path(), "/data");
+
+        let limited = HttpSpeedtestProvider {
+            redirects: Redirects::Max(0),
+            ..provider.clone()
+        };
+        let Err(error) = limited.measure_download().await else {
+            panic!("followed more redirects than allowed");
+        };
+        assert!(error.is_redirect(), "{error:?}");
+    }
+
+    #[tokio::test]
+    async fn disabled_redirects_fail_clearly() {
+        let provider = local_provider().await;
+        let provider = HttpSpeedtestProvider {
+            download_endpoint: redirecting(&provider).await,
+            redirects: Redirects::None,
+            ..provider
+        };
+        let Err(error) = provider.measure_download().await else {
+            panic!("followed a redirect although they are disabled");
+        };
+        assert!(error.is_redirect(), "{error:?}");
+        let source = std::error::Error::source(&error).unwrap();
+        assert!(
+            source.to_string().starts_with("redirected to http://"),
+            "{source}"
+        );
+        assert!(source
+            .to_string()
+            .ends_with("/data, which is not followed because redirects are disabled"));
+
+        let redirects =
+            |toml: &str| toml::from_str::<HttpSpeedtestProvider>(toml).map(|it| it.redirects);
+        let base = "download_endpoint = \"http://a\"\nupload_endpoint = \"http://a\"\n\
+             download_duration = \"1s\"\nupload_duration = \"1s\"\nupload_chunk_size = 1\n";
+        assert_eq!(redirects(base).unwrap(), Redirects::Follow);
+        assert_eq!(
+            redirects(&format!("{base}redirects = \"none\"")).unwrap(),
+            Redirects::None
+        );
+        assert_eq!(
+            redirects(&format!("{base}redirects = {{ max = 3 }}")).unwrap(),
+            Redirects::Max(3)
+        );
+    }
+
+    /// Provider uploading to a local server that records the headers and
+    /// bodies of the uploads
+    async fn recording_provider(
+        upload_encoding: UploadEncoding,
+    ) -> (
+        HttpSpeedtestProvider,
+        Arc<Mutex<Vec<(http::HeaderMap, Bytes)>>>,
+    ) {
+        let uploads = Arc::new(Mutex::new(Vec::new()));
+        let app = axum::Router::new().route(
+            "/upload",
+            axum::routing::post({
+                let uploads = uploads.clone();
+                move |headers: http::HeaderMap, body: Bytes| async move {
+                    uploads.lock().unwrap().push((headers, body));
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let provider = HttpSpeedtestProvider {
+            upload_endpoint: format!("http://{addr}/upload").parse().unwrap(),
+            upload_duration: Duration::from_millis(100),
+            download_duration: Duration::from_millis(100),
+            upload_content_type: "application/x-test".to_owned(),
+            upload_encoding,
+            ..local_provider().await
+        };
+        (provider, uploads)
+    }
+
+    #[tokio::test]
+    async fn uploads_are_encoded_as_configured() {
+        let header = |headers: &http::HeaderMap, name| {
+            (headers.get(name)).map(|value: &http::HeaderValue| value.to_str().unwrap().to_owned())
+        };
+
+        let (provider, uploads) = recording_provider(UploadEncoding::Raw).await;
+        provider.measure_upload().await.unwrap();
+        let (headers, body) = uploads.lock().unwrap()[0].clone();
+        assert_eq!(
+            header(&headers, header::CONTENT_TYPE).unwrap(),
+            "application/x-test"
+        );
+        assert_eq!(header(&headers, header::CONTENT_LENGTH).unwrap(), "1024");
+        assert_eq!(header(&headers, header::TRANSFER_ENCODING), None);
+        assert_eq!(body.len(), 1024);
+
+        let (provider, uploads) = recording_provider(UploadEncoding::Chunked).await;
+        provider.measure_upload().await.unwrap();
+        let (headers, body) = uploads.lock().unwrap()[0].clone();
+        assert_eq!(
+            header(&headers, header::CONTENT_TYPE).unwrap(),
+            "application/x-test"
+        );
+        assert_eq!(header(&headers, header::CONTENT_LENGTH), None);
+        assert_eq!(
+            header(&headers, header::TRANSFER_ENCODING).unwrap(),
+            "chunked"
+        );
+        assert_eq!(body.len(), 1024);
+
+        let (provider, uploads) = recording_provider(UploadEncoding::Multipart).await;
+        provider.measure_upload().await.unwrap();
+        let (headers, body) = uploads.lock().unwrap()[0].clone();
+        let content_type = header(&headers, header::CONTENT_TYPE).unwrap();
+        let boundary = (content_type.strip_prefix("multipart/form-data; boundary="))
+            .unwrap_or_else(|| panic!("{content_type}"));
+        let length: usize = header(&headers, header::CONTENT_LENGTH)
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(length, body.len());
+        let head = format!(
+            "--{boundary}\r\n\
+             Content-Disposition: form-data; name=\"upload\"; filename=\"upload.bin\"\r\n\
+             Content-Type: application/x-test\r\n\r\n"
+        );
+        let tail = format!("\r\n--{boundary}--\r\n");
+        assert!(body.starts_with(head.as_bytes()), "{body:?}");
+        assert!(body.ends_with(tail.as_bytes()), "{body:?}");
+        assert_eq!(body.len(), head.len() + 1024 + tail.len());
+    }
+
+    #[tokio::test]
+    async fn uploads_consist_of_the_configured_payload() {
+        let upload = |upload_payload| async move {
+            let (provider, uploads) = recording_provider(UploadEncoding::Raw).await;
+            let provider = HttpSpeedtestProvider {
+                upload_chunk_size: 200_000,
+                upload_payload,
+                ..provider
+            };
+            provider.measure_upload().await.unwrap();
+            let (_, body) = uploads.lock().unwrap()[0].clone();
+            assert_eq!(body.len(), 200_000);
+            body
+        };
+
+        let body = upload(UploadPayload::Zeros).await;
+        assert!(body.iter().all(|byte| *byte == 0));
+        // Continues across the repeated blocks
+        let body = upload(UploadPayload::Pattern(vec![1, 2, 3])).await;
+        assert!(body
+            .iter()
+            .enumerate()
+            .all(|(i, byte)| *byte == [1, 2, 3][i % 3]));
+        // Random data does not repeat within the upload
+        let body = upload(UploadPayload::Random).await;
+        let (first, second) = body.split_at(100_000);
+        assert!(!second.starts_with(&first[..256]));
+        assert!(!first[256..].windows(256).any(|it| it == &first[..256]));
+    }
+
+    #[test]
+    fn upload_payload_is_parsed() {
+        let payload = |toml| toml::from_str::<toml::Table>(toml).unwrap()["upload_payload"].clone();
+        let parse = |toml| payload(toml).try_into::<UploadPayload>().unwrap();
+        assert_eq!(parse("upload_payload = \"zeros\""), UploadPayload::Zeros);
+        assert_eq!(
+            parse("upload_payload = { pattern = [0, 255] }"),
+            UploadPayload::Pattern(vec![0, 255])
+        );
+        assert!(payload("upload_payload = { pattern = [256] }")
+            .try_into::<UploadPayload>()
+            .is_err());
+    }
+
+    /// Number of connections used by a short download of `provider`
+    async fn download_connections(provider: HttpSpeedtestProvider) -> usize {
+        let peers = Arc::new(Mutex::new(std::collections::HashSet::new()));
+        let app = axum::Router::new().route(
+            "/data",
+            axum::routing::get({
+                let peers = peers.clone();
+                move |ConnectInfo(peer): ConnectInfo<SocketAddr>| async move {
+                    peers.lock().unwrap().insert(peer);
+                    vec![0u8; 1024]
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            axum::serve(listener, app).await
+        });
+
+        let provider = HttpSpeedtestProvider {
+            download_endpoint: format!("http://{addr}/data").parse().unwrap(),
+            download_duration: Duration::from_millis(100),
+            ..provider
+        };
+        provider.measure_download().await.unwrap();
+        let connections = peers.lock().unwrap().len();
+        connections
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_reused() {
+        assert_eq!(download_connections(local_provider().await).await, 1);
+        let unpooled = HttpSpeedtestProvider {
+            pool_max_idle_per_host: Some(0),
+            ..local_provider().await
+        };
+        assert!(download_connections(unpooled).await > 1);
+    }
+
+    #[tokio::test]
+    async fn download_stops_at_max_bytes() {
+        let provider = HttpSpeedtestProvider {
+            max_download_bytes: Some(200_000),
+            ..local_provider().await
+        };
+        let data = provider.measure_download().await.unwrap();
+
+        assert_eq!(data.total.bytes, 200_000.);
+        assert!(data.total.seconds < 30.);
+    }
+
+    #[tokio::test]
+    async fn first_byte_is_timed() {
+        let provider = HttpSpeedtestProvider {
+            download_duration: Duration::from_millis(200),
+            ..local_provider().await
+        };
+        let (data, first_byte) = provider.measure_download_timed().await.unwrap();
+
+        let first_byte = first_byte.unwrap();
+        assert!(first_byte > Duration::ZERO);
+        assert!(first_byte.as_secs_f64() <= data.total.seconds);
+    }
+
+    #[tokio::test]
+    async fn wall_clock_includes_tail() {
+        let provider = HttpSpeedtestProvider {
+            download_duration: Duration::from_millis(200),
+            total_time: TotalTime::WallClock,
+            ..local_provider().await
+        };
+        let data = provider.measure_download().await.unwrap();
+
+        assert!(data.total.seconds >= 0.2);
+    }
+
+    #[tokio::test]
+    async fn min_tls_version_is_applied() {
+        let version: TlsVersion = serde_json::from_str(r#""1.2""#).unwrap();
+        assert_eq!(version, TlsVersion::Tls1_2);
+        let provider = HttpSpeedtestProvider {
+            min_tls_version: Some(version),
+            ..local_provider().await
+        };
+        assert!(provider
+            .build_client(Duration::from_secs(1), &TcpConnections::default())
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn connect_timeout_fires() {
+        // Connection attempts beyond the full backlog are dropped instead of
+        // refused, like a firewall would do
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        socket
+            .bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into())
+            .unwrap();
+        socket.listen(0).unwrap();
+        let addr = socket.local_addr().unwrap().as_socket().unwrap();
+        let _backlog: Vec<_> = (0..4)
+            .filter_map(|_| TcpStream::connect_timeout(&addr, Duration::from_millis(100)).ok())
+            .collect();
+
+        let endpoint: Url = format!("http://{addr}/data").parse().unwrap();
+        let provider = HttpSpeedtestProvider {
+            download_endpoint: endpoint,
+            connect_timeout: Duration::from_millis(300),
+            ..local_provider().await
+        };
+        let start = Instant::now();
+        let Err(error) = provider.measure_download().await else {
+            panic!("connected despite the full backlog");
+        };
+        let elapsed = start.elapsed();
+
+        assert!(error.is_connect(), "{error:?}");
+        assert!(elapsed >= Duration::from_millis(300), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(5), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn tls_version_bounds() {
+        let provider = |min_tls_version, max_tls_version| async move {
+            HttpSpeedtestProvider {
+                min_tls_version,
+                max_tls_version,
+                ..local_provider().await
+            }
+        };
+        let tls1_2 = Some(TlsVersion::Tls1_2);
+        let tls1_3 = Some(TlsVersion::Tls1_3);
+        assert!(provider(tls1_2, tls1_3)
+            .await
+            .build_client(Duration::from_secs(1), &TcpConnections::default())
+            .is_ok());
+        assert!(provider(tls1_2, tls1_2)
+            .await
+            .build_client(Duration::from_secs(1), &TcpConnections::default())
+            .is_ok());
+        // native-tls cannot require TLS 1.3, which is refused before any
+        // server is contacted
+        assert!(provider(tls1_3, None)
+            .await
+            .build_client(Duration::from_secs(1), &TcpConnections::default())
+            .is_err());
+    }
+}
@@ -0,0 +1,187 @@
+use std::time::Duration;
+
+use axum::async_trait;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use super::{
+    http::{HttpSpeedtestProvider, Redirects, TotalTime, UploadEncoding, UploadPayload},
+    SpeedtestData as Data, SpeedtestProvider,
+};
+
+/// Provider measuring against a self-hosted [LibreSpeed] server, which
+/// streams `ckSize` chunks of 1 MiB of garbage from `garbage.php` and
+/// discards uploads to `empty.php`.
+///
+/// [LibreSpeed]: https://github.com/librespeed/speedtest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LibreSpeedProvider {
+    /// Directory of the backend scripts, e.g.
+    /// `https://speed.example.com/backend/`
+    pub server: Url,
+    /// Number of 1 MiB chunks per download request, LibreSpeed caps it at
+    /// 1024
+    #[serde(default = "LibreSpeedProvider::default_download_chunks")]
+    pub download_chunks: u32,
+    #[serde(
+        default = "LibreSpeedProvider::default_duration",
+        with = "humantime_serde"
+    )]
+    pub download_duration: Duration,
+    #[serde(
+        default = "LibreSpeedProvider::default_duration",
+        with = "humantime_serde"
+    )]
+    pub upload_duration: Duration,
+    #[serde(default = "LibreSpeedProvider::default_upload_chunk_size")]
+    pub upload_chunk_size: usize,
+    #[serde(
+        default = "HttpSpeedtestProvider::default_connect_timeout",
+        with = "humantime_serde"
+    )]
+    pub connect_timeout: Duration,
+}
+
+#[async_trait]
+impl SpeedtestProvider for LibreSpeedProvider {
+    async fn measure_download(&self) -> reqwest::Result<Data> {
+        self.to_http().measure_download().await
+    }
+
+    async fn measure_upload(&self) -> reqwest::Result<Data> {
+        self.to_http().measure_upload().await
+    }
+}
+
+impl LibreSpeedProvider {
+    fn default_download_chunks() -> u32 {
+        100
+    }
+
+    fn default_duration() -> Duration {
+        Duration::from_secs(15)
+    }
+
+    fn default_upload_chunk_size() -> usize {
+        1_000_000
+    }
+
+    /// The generic provider requesting the LibreSpeed endpoints
+    pub(crate) fn to_http(&self) -> HttpSpeedtestProvider {
+        HttpSpeedtestProvider {
+            download_endpoint: self.download_endpoint(),
+            upload_endpoint: self.endpoint("empty.php"),
+            download_duration: self.download_duration,
+            upload_duration: self.upload_duration,
+            upload_chunk_size: self.upload_chunk_size,
+            upload_content_type: HttpSpeedtestProvider::default_upload_content_type(),
+            upload_encoding: UploadEncoding::Raw,
+            upload_payload: UploadPayload::Random,
+            early_stop: None,
+            sample_window: None,
+            max_download_bytes: None,
+            total_time: TotalTime::LastChunk,
+            min_tls_version: None,
+            max_tls_version: None,
+            connect_timeout: self.connect_timeout,
+            read_timeout: None,
+            root_certificates: Vec::new(),
+            http3: false,
+            redirects: Redirects::Follow,
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: None,
+            interface: None,
+        }
+    }
+
+    fn download_endpoint(&self) -> Url {
+        let mut url = self.endpoint("garbage.php");
+        (url.query_pairs_mut()).append_pair("ckSize", &self.download_chunks.to_string());
+        url
+    }
+
+    /// Script within the backend directory, even if `server` lacks the
+    /// trailing slash
+    fn endpoint(&self, script: &str) -> Url {
+        let mut url = self.server.clone();
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+        url.join(script).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        extract::Query,
+        routing::{get, post},
+        Router,
+    };
+
+    use super::*;
+
+    fn provider(server: &str) -> LibreSpeedProvider {
+        LibreSpeedProvider {
+            server: server.parse().unwrap(),
+            download_chunks: 1,
+            download_duration: Duration::from_millis(300),
+            upload_duration: Duration::from_millis(300),
+            upload_chunk_size: 1024,
+            connect_timeout: HttpSpeedtestProvider::default_connect_timeout(),
+        }
+    }
+
+    #[test]
+    fn endpoints_are_in_the_backend_directory() {
+        for server in [
+            "https://speed.example.com/backend",
+            "https://speed.example.com/backend/",
+        ] {
+            let http = provider(server).to_http();
+            assert_eq!(
+                http.download_endpoint.as_str(),
+                "https://speed.example.com/backend/garbage.php?ckSize=1"
+            );
+            assert_eq!(
+                http.upload_endpoint.as_str(),
+                "https://speed.example.com/backend/empty.php"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn measures_against_backend() {
+        let chunk_sizes = Arc::new(Mutex::new(Vec::new()));
+        let app = Router::new()
+            .route(
+                "/backend/garbage.php",
+                get({
+                    let chunk_sizes = chunk_sizes.clone();
+                    move |Query(query): Query<HashMap<String, String>>| async move {
+                        let chunks: usize = query["ckSize"].parse().unwrap();
+                        chunk_sizes.lock().unwrap().push(chunks);
+                        vec![0u8; chunks << 20]
+                    }
+                }),
+            )
+            .route("/backend/empty.php", post(|_: axum::body::Bytes| async {}));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let provider = provider(&format!("http://{addr}/backend/"));
+        let download = provider.measure_download().await.unwrap();
+        assert!(download.total.bytes >= f64::from(1 << 20));
+        assert!(chunk_sizes.lock().unwrap().iter().all(|&it| it == 1));
+
+        let upload = provider.measure_upload().await.unwrap();
+        assert!(upload.total.bytes > 0.);
+    }
+}
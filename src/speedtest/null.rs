@@ -0,0 +1,33 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use super::{SpeedtestData as Data, SpeedtestProvider, SpeedtestSample as Sample};
+
+/// Provider returning preconfigured samples without any network access,
+/// useful for testing and dry runs.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct NullSpeedtestProvider {
+    pub download_samples: Vec<Sample>,
+    pub upload_samples: Vec<Sample>,
+}
+
+#[async_trait]
+impl SpeedtestProvider for NullSpeedtestProvider {
+    async fn measure_download(&self) -> reqwest::Result<Data> {
+        Ok(to_data(&self.download_samples))
+    }
+
+    async fn measure_upload(&self) -> reqwest::Result<Data> {
+        Ok(to_data(&self.upload_samples))
+    }
+}
+
+fn to_data(samples: &[Sample]) -> Data {
+    Data {
+        samples: samples.to_vec(),
+        total: samples.iter().copied().sum(),
+        tcp: None,
+        url: None,
+    }
+}
@@ -0,0 +1,201 @@
+use std::time::Duration;
+
+use axum::async_trait;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use super::{
+    http::{HttpSpeedtestProvider, Redirects, TotalTime, UploadEncoding, UploadPayload},
+    SpeedtestData as Data, SpeedtestProvider,
+};
+
+/// Provider measuring with HTTP/3 over QUIC, which runs on UDP and thus
+/// shows different throughput than TCP. The server must support HTTP/3 and
+/// UDP port 443, or the port of the endpoints, must not be firewalled.
+/// Samples are collected like those of the `Http` provider.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuicSpeedtestProvider {
+    pub download_endpoint: Url,
+    pub upload_endpoint: Url,
+    #[serde(with = "humantime_serde")]
+    pub download_duration: Duration,
+    #[serde(with = "humantime_serde")]
+    pub upload_duration: Duration,
+    pub upload_chunk_size: usize,
+    #[serde(
+        default = "HttpSpeedtestProvider::default_connect_timeout",
+        with = "humantime_serde"
+    )]
+    pub connect_timeout: Duration,
+    /// Certificate authorities in PEM format to trust in addition to those
+    /// of the system, e.g. of a self-hosted server
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub root_certificates: Vec<String>,
+}
+
+#[async_trait]
+impl SpeedtestProvider for QuicSpeedtestProvider {
+    async fn measure_download(&self) -> reqwest::Result<Data> {
+        self.to_http().measure_download().await
+    }
+
+    async fn measure_upload(&self) -> reqwest::Result<Data> {
+        self.to_http().measure_upload().await
+    }
+}
+
+impl QuicSpeedtestProvider {
+    /// The generic provider requesting the endpoints with HTTP/3
+    pub(crate) fn to_http(&self) -> HttpSpeedtestProvider {
+        HttpSpeedtestProvider {
+            download_endpoint: self.download_endpoint.clone(),
+            upload_endpoint: self.upload_endpoint.clone(),
+            download_duration: self.download_duration,
+            upload_duration: self.upload_duration,
+            upload_chunk_size: self.upload_chunk_size,
+            upload_content_type: HttpSpeedtestProvider::default_upload_content_type(),
+            upload_encoding: UploadEncoding::Raw,
+            upload_payload: UploadPayload::Random,
+            early_stop: None,
+            sample_window: None,
+            max_download_bytes: None,
+            total_time: TotalTime::LastChunk,
+            min_tls_version: None,
+            max_tls_version: None,
+            connect_timeout: self.connect_timeout,
+            read_timeout: None,
+            redirects: Redirects::Follow,
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: None,
+            interface: None,
+            root_certificates: self.root_certificates.clone(),
+            http3: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use axum::body::Bytes;
+    use http::{Method, Response, StatusCode};
+
+    use super::*;
+
+    /// Requests answered by [`h3_server`], by method
+    #[derive(Default)]
+    struct Requests {
+        get: AtomicUsize,
+        post: AtomicUsize,
+    }
+
+    /// HTTP/3 server on an OS-assigned UDP port with a self-signed
+    /// certificate for 127.0.0.1, returned in PEM format. GET requests are
+    /// answered with 1 MiB of zeroes and POST bodies are discarded.
+    fn h3_server() -> (SocketAddr, String, Arc<Requests>) {
+        let key = rcgen::generate_simple_self_signed(["127.0.0.1".to_owned()]).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![key.cert.der().clone()],
+                rustls::pki_types::PrivatePkcs8KeyDer::from(key.key_pair.serialize_der()).into(),
+            )
+            .unwrap();
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+        let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls).unwrap();
+        let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        let endpoint = quinn::Endpoint::server(config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+
+        let requests = Arc::new(Requests::default());
+        let counts = requests.clone();
+        tokio::spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                let counts = counts.clone();
+                tokio::spawn(async move {
+                    let connection = h3_quinn::Connection::new(incoming.await.unwrap());
+                    let mut connection = h3::server::Connection::<_, Bytes>::new(connection)
+                        .await
+                        .unwrap();
+                    while let Ok(Some(resolver)) = connection.accept().await {
+                        let counts = counts.clone();
+                        tokio::spawn(async move {
+                            let (request, mut stream) = resolver.resolve_request().await?;
+                            if request.method() == Method::POST {
+                                counts.post.fetch_add(1, Ordering::Relaxed);
+                                while stream.recv_data().await?.is_some() {}
+                            }
+                            let response = Response::builder().status(StatusCode::OK);
+                            stream.send_response(response.body(()).unwrap()).await?;
+                            if request.method() == Method::GET {
+                                counts.get.fetch_add(1, Ordering::Relaxed);
+                                for _ in 0..16 {
+                                    stream.send_data(Bytes::from(vec![0; 64 * 1024])).await?;
+                                }
+                            }
+                            stream.finish().await?;
+                            Ok::<_, h3::error::StreamError>(())
+                        });
+                    }
+                });
+            }
+        });
+        (addr, key.cert.pem(), requests)
+    }
+
+    #[test]
+    fn endpoints_are_requested_with_http3() {
+        let provider: QuicSpeedtestProvider = toml::from_str(
+            r#"
+download_endpoint = "https://speed.example.com/down"
+upload_endpoint = "https://speed.example.com/up"
+download_duration = "10s"
+upload_duration = "5s"
+upload_chunk_size = 1000000
+"#,
+        )
+        .unwrap();
+        let http = provider.to_http();
+        assert!(http.http3);
+        assert_eq!(http.protocol(), "quic");
+        assert_eq!(
+            http.download_endpoint.as_str(),
+            "https://speed.example.com/down"
+        );
+        assert_eq!(http.connect_timeout, Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn measures_against_local_server() {
+        let (addr, certificate, requests) = h3_server();
+        let provider = QuicSpeedtestProvider {
+            download_endpoint: format!("https://{addr}/download").parse().unwrap(),
+            upload_endpoint: format!("https://{addr}/upload").parse().unwrap(),
+            download_duration: Duration::from_secs(1),
+            upload_duration: Duration::from_secs(1),
+            upload_chunk_size: 100_000,
+            connect_timeout: HttpSpeedtestProvider::default_connect_timeout(),
+            root_certificates: vec![certificate],
+        };
+
+        let data = provider.measure_download().await.unwrap();
+        assert!(data.total.bytes >= (1024 * 1024) as f64);
+        assert!(requests.get.load(Ordering::Relaxed) > 0);
+
+        let data = provider.measure_upload().await.unwrap();
+        assert!(!data.samples.is_empty());
+        assert_eq!(data.total.bytes, 100_000. * data.samples.len() as f64);
+        assert!(requests.post.load(Ordering::Relaxed) >= data.samples.len());
+    }
+}
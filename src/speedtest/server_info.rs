@@ -0,0 +1,233 @@
+//! Autonomous system and country of the speedtest server, looked up once per
+//! server and attached to the speedtest metrics as labels.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+use url::{Host, Url};
+
+use crate::prometheus::{ExpositionBuilder, PName};
+
+/// Source of [`ServerInfo`], set by `speedtest.server_lookup`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub enum ServerLookup {
+    /// Offline database in the TSV format of <https://iptoasn.com>, e.g.
+    /// `ip2asn-combined.tsv`
+    Ip2asn(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ServerInfo {
+    pub asn: u32,
+    /// ISO 3166 alpha-2 code
+    pub country: String,
+}
+
+impl ServerInfo {
+    /// Runs `write` with the `server_asn` and `server_country` labels
+    pub fn with_labels<R>(
+        &self,
+        builder: &mut ExpositionBuilder,
+        write: impl FnOnce(&mut ExpositionBuilder) -> R,
+    ) -> R {
+        builder.with_label(PName::new("server_asn").unwrap(), &self.asn, |builder| {
+            builder.with_label(
+                PName::new("server_country").unwrap(),
+                self.country.as_str(),
+                write,
+            )
+        })
+    }
+}
+
+/// Announced address ranges sorted by their first address
+#[derive(Debug, Default)]
+pub(crate) struct Ip2AsnDatabase(Vec<AsnRange>);
+
+#[derive(Debug)]
+struct AsnRange {
+    start: IpAddr,
+    end: IpAddr,
+    info: ServerInfo,
+}
+
+impl Ip2AsnDatabase {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses lines of `start end asn country description`, separated by
+    /// tabs. Ranges of AS 0 are not announced and skipped.
+    pub fn parse(tsv: &str) -> io::Result<Self> {
+        let mut ranges = Vec::new();
+        for (number, line) in tsv.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: malformed range {line:?}", number + 1),
+                )
+            };
+            let mut fields = line.split('\t');
+            let mut field = || fields.next().ok_or_else(invalid);
+            let start: IpAddr = field()?.parse().map_err(|_| invalid())?;
+            let end: IpAddr = field()?.parse().map_err(|_| invalid())?;
+            let asn: u32 = field()?.parse().map_err(|_| invalid())?;
+            let country = field()?.to_owned();
+            if start.is_ipv4() != end.is_ipv4() || start > end {
+                return Err(invalid());
+            }
+            if asn != 0 {
+                ranges.push(AsnRange {
+                    start,
+                    end,
+                    info: ServerInfo { asn, country },
+                });
+            }
+        }
+        ranges.sort_unstable_by_key(|range| range.start);
+        Ok(Self(ranges))
+    }
+
+    pub fn lookup(&self, addr: IpAddr) -> Option<&ServerInfo> {
+        let candidates = self.0.partition_point(|range| range.start <= addr);
+        let range = self.0[..candidates].last()?;
+        (addr <= range.end).then_some(&range.info)
+    }
+}
+
+/// Looks up each speedtest server once, the database is loaded on first use
+#[derive(Debug, Default)]
+pub(crate) struct ServerInfoCache {
+    database: OnceCell<Option<Arc<Ip2AsnDatabase>>>,
+    servers: Mutex<HashMap<Url, Option<ServerInfo>>>,
+}
+
+impl ServerInfoCache {
+    /// Info of the server of `endpoint`, `None` if it could not be looked up
+    pub async fn lookup(&self, source: &ServerLookup, endpoint: &Url) -> Option<ServerInfo> {
+        let key = server_of(endpoint);
+        if let Some(info) = self.servers.lock().unwrap().get(&key) {
+            return info.clone();
+        }
+        let info = self.look_up(source, endpoint).await;
+        if let Some(info) = &info {
+            info!(server = %key, asn = info.asn, country = %info.country, "Looked up speedtest server");
+        }
+        (self.servers.lock().unwrap()).insert(key, info.clone());
+        info
+    }
+
+    async fn look_up(&self, source: &ServerLookup, endpoint: &Url) -> Option<ServerInfo> {
+        let ServerLookup::Ip2asn(path) = source;
+        let database = (self.database)
+            .get_or_init(|| load_database(path.clone()))
+            .await
+            .as_ref()?;
+        let addr = match resolve(endpoint).await {
+            Ok(addr) => addr,
+            Err(error) => {
+                warn!(%endpoint, %error, "Could not resolve speedtest server");
+                return None;
+            }
+        };
+        database.lookup(addr).cloned()
+    }
+}
+
+/// Loads the database without blocking the runtime, `None` if it failed
+async fn load_database(path: PathBuf) -> Option<Arc<Ip2AsnDatabase>> {
+    let loaded = tokio::task::spawn_blocking({
+        let path = path.clone();
+        move || Ip2AsnDatabase::load(&path)
+    });
+    match loaded.await.unwrap() {
+        Ok(database) => Some(Arc::new(database)),
+        Err(error) => {
+            warn!(path = %path.display(), %error, "Could not load ASN database");
+            None
+        }
+    }
+}
+
+/// Scheme, host and port of `endpoint`, which identify the server
+fn server_of(endpoint: &Url) -> Url {
+    let mut server = endpoint.clone();
+    server.set_path("");
+    server.set_query(None);
+    server.set_fragment(None);
+    server
+}
+
+async fn resolve(endpoint: &Url) -> io::Result<IpAddr> {
+    let port = endpoint.port_or_known_default().unwrap_or(443);
+    match endpoint.host() {
+        Some(Host::Ipv4(addr)) => Ok(addr.into()),
+        Some(Host::Ipv6(addr)) => Ok(addr.into()),
+        Some(Host::Domain(domain)) => (tokio::net::lookup_host((domain, port)).await?)
+            .next()
+            .map(|addr| addr.ip())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address")),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput, "no host")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TSV: &str = "\
+1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET
+1.0.1.0\t1.0.3.255\t0\tNone\tNot routed
+127.0.0.0\t127.255.255.255\t64512\tZZ\tLOOPBACK
+2001:4860::\t2001:4860:ffff:ffff:ffff:ffff:ffff:ffff\t15169\tUS\tGOOGLE
+";
+
+    #[test]
+    fn addresses_are_found_in_their_range() {
+        let database = Ip2AsnDatabase::parse(TSV).unwrap();
+        let lookup = |addr: &str| database.lookup(addr.parse().unwrap()).map(|it| it.asn);
+        assert_eq!(lookup("1.0.0.0"), Some(13335));
+        assert_eq!(lookup("1.0.0.255"), Some(13335));
+        assert_eq!(lookup("1.0.2.1"), None);
+        assert_eq!(lookup("0.255.255.255"), None);
+        assert_eq!(lookup("2001:4860::8888"), Some(15169));
+        assert_eq!(lookup("::1"), None);
+
+        assert!(Ip2AsnDatabase::parse("1.0.0.0\t1.0.0.255\tAS1\tUS\t").is_err());
+        assert!(Ip2AsnDatabase::parse("1.0.0.255\t1.0.0.0\t1\tUS\t").is_err());
+    }
+
+    #[tokio::test]
+    async fn servers_are_looked_up_once() {
+        let path = std::env::temp_dir().join(format!("ip2asn-{}.tsv", std::process::id()));
+        fs::write(&path, TSV).unwrap();
+        let source = ServerLookup::Ip2asn(path.clone());
+        let cache = ServerInfoCache::default();
+
+        let endpoint: Url = "http://127.0.0.1:8080/data.bin".parse().unwrap();
+        let expected = ServerInfo {
+            asn: 64512,
+            country: "ZZ".to_owned(),
+        };
+        assert_eq!(
+            cache.lookup(&source, &endpoint).await,
+            Some(expected.clone())
+        );
+        fs::remove_file(&path).unwrap();
+        // Neither the database nor the server are looked up again
+        let other_path: Url = "http://127.0.0.1:8080/upload".parse().unwrap();
+        assert_eq!(cache.lookup(&source, &other_path).await, Some(expected));
+    }
+}
@@ -0,0 +1,307 @@
+//! Kernel TCP statistics of the connections a measurement ran over.
+//!
+//! reqwest does not hand out the sockets of its connections, so the client
+//! records the local and remote address of every connection it establishes
+//! through a connector layer. The statistics of exactly these connections
+//! are then queried from the kernel by their addresses using `sock_diag`,
+//! without touching any file descriptor owned by reqwest.
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct TcpStats {
+    /// Total number of retransmitted segments
+    pub retransmits: u32,
+    /// Smoothed round trip time in seconds
+    pub rtt_seconds: f64,
+    /// Round trip time variance in seconds
+    pub rtt_var_seconds: f64,
+    /// Congestion window in segments
+    pub snd_cwnd: u32,
+}
+
+#[cfg(all(feature = "tcp-info", target_os = "linux"))]
+pub use linux::TcpConnections;
+
+/// Connections of a client, without any statistics on this platform
+#[cfg(not(all(feature = "tcp-info", target_os = "linux")))]
+#[derive(Debug, Default)]
+pub struct TcpConnections(());
+
+#[cfg(not(all(feature = "tcp-info", target_os = "linux")))]
+impl TcpConnections {
+    #[inline(always)]
+    pub fn install(&self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        builder
+    }
+
+    #[inline(always)]
+    pub fn stats(&self) -> Option<TcpStats> {
+        None
+    }
+}
+
+#[cfg(all(feature = "tcp-info", target_os = "linux"))]
+mod linux {
+    use std::{
+        future::Future,
+        io::Read,
+        mem,
+        net::{IpAddr, SocketAddr},
+        pin::Pin,
+        ptr,
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+    };
+
+    use hyper_util::client::legacy::connect::{Connection, HttpInfo};
+    use socket2::{Domain, Protocol, Socket, Type};
+    use tower_layer::Layer;
+    use tower_service::Service;
+
+    use super::TcpStats;
+
+    const SOCK_DIAG_BY_FAMILY: u16 = 20;
+    const INET_DIAG_INFO: u16 = 2;
+    const NLMSG_HDRLEN: usize = 16;
+    /// Size of `struct inet_diag_msg`
+    const INET_DIAG_MSG_LEN: usize = 72;
+
+    /// Connections established by a client, see
+    /// [`install`](TcpConnections::install)
+    #[derive(Debug, Default, Clone)]
+    pub struct TcpConnections(Arc<Mutex<Vec<RecordedConnection>>>);
+
+    #[derive(Debug)]
+    struct RecordedConnection {
+        local: SocketAddr,
+        remote: SocketAddr,
+        /// Last statistics, kept once the connection is closed
+        stats: Option<TcpStats>,
+    }
+
+    impl TcpConnections {
+        /// Records the connections that the built client establishes
+        pub fn install(&self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+            builder.connector_layer(self.clone())
+        }
+
+        /// Statistics of the recorded connections: the retransmits of all of
+        /// them and the other values of the latest one. Connections must be
+        /// open to update their statistics.
+        pub fn stats(&self) -> Option<TcpStats> {
+            let mut connections = self.0.lock().unwrap();
+            for connection in connections.iter_mut() {
+                if let Some(stats) = query(connection.local, connection.remote) {
+                    connection.stats = Some(stats);
+                }
+            }
+            let mut all = connections.iter().filter_map(|it| it.stats);
+            let latest = all.next_back()?;
+            Some(TcpStats {
+                retransmits: all.map(|it| it.retransmits).sum::<u32>() + latest.retransmits,
+                ..latest
+            })
+        }
+
+        fn record(&self, connection: &impl Connection) {
+            let mut extensions = http::Extensions::new();
+            connection.connected().get_extras(&mut extensions);
+            // Missing for connections that are not TCP
+            if let Some(info) = extensions.get::<HttpInfo>() {
+                self.0.lock().unwrap().push(RecordedConnection {
+                    local: info.local_addr(),
+                    remote: info.remote_addr(),
+                    stats: None,
+                });
+            }
+        }
+    }
+
+    impl<S> Layer<S> for TcpConnections {
+        type Service = RecordConnections<S>;
+
+        fn layer(&self, inner: S) -> Self::Service {
+            RecordConnections {
+                inner,
+                connections: self.clone(),
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct RecordConnections<S> {
+        inner: S,
+        connections: TcpConnections,
+    }
+
+    impl<S, R> Service<R> for RecordConnections<S>
+    where
+        S: Service<R>,
+        S::Response: Connection,
+        S::Future: Send + 'static,
+    {
+        type Response = S::Response;
+        type Error = S::Error;
+        type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+            self.inner.poll_ready(cx)
+        }
+
+        fn call(&mut self, request: R) -> Self::Future {
+            let connecting = self.inner.call(request);
+            let connections = self.connections.clone();
+            Box::pin(async move {
+                let connection = connecting.await?;
+                connections.record(&connection);
+                Ok(connection)
+            })
+        }
+    }
+
+    /// Queries the `tcp_info` of the open TCP socket connecting `local` to
+    /// `remote`
+    pub(super) fn query(local: SocketAddr, remote: SocketAddr) -> Option<TcpStats> {
+        let socket = Socket::new(
+            Domain::from(libc::AF_NETLINK),
+            Type::DGRAM.cloexec(),
+            Some(Protocol::from(libc::NETLINK_SOCK_DIAG)),
+        )
+        .ok()?;
+        socket.send(&request(local, remote)).ok()?;
+        let mut buffer = [0; 4096];
+        let len = (&socket).read(&mut buffer).ok()?;
+        parse_response(&buffer[..len]).map(|info| TcpStats {
+            retransmits: info.tcpi_total_retrans,
+            rtt_seconds: info.tcpi_rtt as f64 / 1e6,
+            rtt_var_seconds: info.tcpi_rttvar as f64 / 1e6,
+            snd_cwnd: info.tcpi_snd_cwnd,
+        })
+    }
+
+    /// `SOCK_DIAG_BY_FAMILY` request for the socket with the given addresses,
+    /// a `struct nlmsghdr` followed by a `struct inet_diag_req_v2`
+    fn request(local: SocketAddr, remote: SocketAddr) -> Vec<u8> {
+        let family = match local.ip() {
+            IpAddr::V4(_) => libc::AF_INET,
+            IpAddr::V6(_) => libc::AF_INET6,
+        };
+        let mut request = Vec::with_capacity(NLMSG_HDRLEN + 56);
+        request.extend(0u32.to_ne_bytes()); // Length, set below
+        request.extend(SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+        request.extend((libc::NLM_F_REQUEST as u16).to_ne_bytes());
+        request.extend(0u64.to_ne_bytes()); // Sequence number and port id
+        request.extend([family as u8, libc::IPPROTO_TCP as u8]);
+        request.extend([1 << (INET_DIAG_INFO - 1), 0]);
+        request.extend(u32::MAX.to_ne_bytes()); // All states
+        request.extend(local.port().to_be_bytes());
+        request.extend(remote.port().to_be_bytes());
+        request.extend(address_bytes(local.ip()));
+        request.extend(address_bytes(remote.ip()));
+        request.extend(0u32.to_ne_bytes()); // Any interface
+        request.extend([u8::MAX; 8]); // INET_DIAG_NOCOOKIE
+        let len = request.len() as u32;
+        request[..4].copy_from_slice(&len.to_ne_bytes());
+        request
+    }
+
+    /// Address in network byte order, padded to the size of an IPv6 one
+    fn address_bytes(address: IpAddr) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        match address {
+            IpAddr::V4(address) => bytes[..4].copy_from_slice(&address.octets()),
+            IpAddr::V6(address) => bytes = address.octets(),
+        }
+        bytes
+    }
+
+    /// The `INET_DIAG_INFO` attribute of a `struct inet_diag_msg` response,
+    /// `None` if the kernel reported an error such as a closed socket
+    fn parse_response(response: &[u8]) -> Option<libc::tcp_info> {
+        let u16_at = |at: usize| {
+            Some(u16::from_ne_bytes(
+                response.get(at..at + 2)?.try_into().ok()?,
+            ))
+        };
+        let len = u32::from_ne_bytes(response.get(..4)?.try_into().ok()?) as usize;
+        if u16_at(4)? != SOCK_DIAG_BY_FAMILY {
+            return None;
+        }
+        let response = response.get(..len)?;
+        let mut attributes = response.get(NLMSG_HDRLEN + INET_DIAG_MSG_LEN..)?;
+        while attributes.len() >= 4 {
+            let len = u16::from_ne_bytes([attributes[0], attributes[1]]) as usize;
+            let kind = u16::from_ne_bytes([attributes[2], attributes[3]]);
+            let payload = attributes.get(4..len)?;
+            if kind == INET_DIAG_INFO {
+                // SAFETY: `tcp_info` consists of integers only. Older kernels
+                // send fewer fields, which are left zeroed
+                let mut info: libc::tcp_info = unsafe { mem::zeroed() };
+                let size = payload.len().min(mem::size_of::<libc::tcp_info>());
+                unsafe {
+                    ptr::copy_nonoverlapping(payload.as_ptr(), ptr::addr_of_mut!(info).cast(), size)
+                };
+                return Some(info);
+            }
+            // Attributes are aligned to 4 bytes
+            attributes = attributes.get(len.next_multiple_of(4).min(attributes.len())..)?;
+        }
+        None
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::net::{TcpListener, TcpStream};
+
+        use super::*;
+
+        #[test]
+        fn statistics_of_open_connections_are_queried() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let local = stream.local_addr().unwrap();
+            let remote = stream.peer_addr().unwrap();
+
+            let stats = query(local, remote).unwrap();
+            assert_eq!(stats.retransmits, 0);
+            assert!(stats.snd_cwnd > 0);
+
+            // Unrelated addresses do not match any other socket
+            let unknown = SocketAddr::from(([127, 0, 0, 1], 1));
+            assert!(query(local, unknown).is_none());
+            drop(stream);
+        }
+
+        #[tokio::test]
+        async fn connections_of_the_client_are_recorded() {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/", listener.local_addr().unwrap());
+            tokio::spawn(async move {
+                use tokio::io::{AsyncReadExt, AsyncWriteExt};
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await.unwrap();
+                let response = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok";
+                stream.write_all(response).await.unwrap();
+                // Keep the connection open for the query
+                let _ = stream.read(&mut request).await;
+            });
+
+            let connections = TcpConnections::default();
+            let client = (connections.install(reqwest::Client::builder()).build()).unwrap();
+            let response = client.get(url).send().await.unwrap();
+            assert!(connections.stats().is_some());
+            drop(response);
+        }
+
+        #[test]
+        fn error_responses_are_rejected() {
+            let mut response = [0; NLMSG_HDRLEN + 4];
+            response[..4].copy_from_slice(&(NLMSG_HDRLEN as u32 + 4).to_ne_bytes());
+            response[4..6].copy_from_slice(&(libc::NLMSG_ERROR as u16).to_ne_bytes());
+            assert!(parse_response(&response).is_none());
+            assert!(parse_response(&[]).is_none());
+        }
+    }
+}
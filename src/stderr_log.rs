@@ -0,0 +1,76 @@
+//! Minimal logger for embedded builds, which writes one line per event to
+//! stderr instead of pulling in `tracing-subscriber`. Spans are not recorded
+//! and lines carry no timestamp, the service manager usually adds one.
+
+use std::{
+    fmt::{self, Write},
+    io::{self, Write as _},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span, Event, Level, Metadata, Subscriber,
+};
+
+pub struct StderrSubscriber {
+    max_level: Level,
+    next_span: AtomicU64,
+}
+
+impl StderrSubscriber {
+    pub fn new(max_level: Level) -> Self {
+        Self {
+            max_level,
+            next_span: AtomicU64::new(1),
+        }
+    }
+}
+
+impl Subscriber for StderrSubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        *metadata.level() <= self.max_level
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(LevelFilter::from_level(self.max_level))
+    }
+
+    fn new_span(&self, _span: &span::Attributes<'_>) -> span::Id {
+        span::Id::from_u64(self.next_span.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let metadata = event.metadata();
+        let mut line = format!("{:>5} {}:", metadata.level(), metadata.target());
+        event.record(&mut LineVisitor(&mut line));
+        line.push('\n');
+        // Logging must never take the exporter down
+        let _ = io::stderr().lock().write_all(line.as_bytes());
+    }
+
+    fn enter(&self, _span: &span::Id) {}
+
+    fn exit(&self, _span: &span::Id) {}
+}
+
+/// Appends ` message key=value ...` to the line
+struct LineVisitor<'a>(&'a mut String);
+
+impl Visit for LineVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{value}"));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let _ = match field.name() {
+            "message" => write!(self.0, " {value:?}"),
+            name => write!(self.0, " {name}={value:?}"),
+        };
+    }
+}
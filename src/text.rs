@@ -0,0 +1,86 @@
+use std::fmt::{self, Display};
+
+/// Plain text table for humans, the first column is aligned to the left and
+/// all others to the right. Cells may contain ANSI escape sequences.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Table {
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(header: Vec<String>) -> Self {
+        Self { rows: vec![header] }
+    }
+
+    pub fn push(&mut self, row: Vec<String>) {
+        self.rows.push(row);
+    }
+}
+
+/// Formats an optional value with one decimal, `-` if it is absent
+pub(crate) fn decimal(value: Option<f64>) -> String {
+    value.map_or_else(|| "-".to_owned(), |value| format!("{value:.1}"))
+}
+
+/// Number of characters of `cell` without SGR escape sequences such as
+/// `\x1b[31m`
+fn visible_width(cell: &str) -> usize {
+    let mut width = 0;
+    let mut chars = cell.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\x1b' {
+            chars.by_ref().find(|ch| *ch == 'm');
+        } else {
+            width += 1;
+        }
+    }
+    width
+}
+
+impl Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let columns = self.rows.iter().map(Vec::len).max().unwrap_or_default();
+        let widths: Vec<usize> = (0..columns)
+            .map(|column| {
+                let cells = self.rows.iter().filter_map(|row| row.get(column));
+                cells
+                    .map(|cell| visible_width(cell))
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect();
+        for row in &self.rows {
+            let mut line = String::new();
+            for (column, cell) in row.iter().enumerate() {
+                let padding = " ".repeat(widths[column] - visible_width(cell));
+                if column == 0 {
+                    line.push_str(cell);
+                    line.push_str(&padding);
+                } else {
+                    line.push_str("  ");
+                    line.push_str(&padding);
+                    line.push_str(cell);
+                }
+            }
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns_are_aligned() {
+        let mut table = Table::new(vec!["direction".to_owned(), "mean".to_owned()]);
+        table.push(vec!["down".to_owned(), decimal(Some(94.31))]);
+        table.push(vec!["up".to_owned(), decimal(None)]);
+        assert_eq!(
+            table.to_string(),
+            "direction  mean\ndown       94.3\nup            -\n"
+        );
+        assert_eq!(visible_width("\x1b[1;32m12.5\x1b[0m"), 4);
+    }
+}
@@ -0,0 +1,166 @@
+//! `textfile` subcommand, writes the exposition for the textfile collector of
+//! node_exporter instead of serving it.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use rand::Rng;
+use tokio::time::Instant;
+use tracing::info;
+
+use crate::{
+    cache::MeasurementCache,
+    config::Config,
+    phases::PhaseRecorder,
+    ping::{availability::AvailabilityHistory, dns_cache::DnsCache, reply_order::ReplyOrderTotals},
+    prometheus::ExpositionFormat,
+    render_exposition,
+    speedtest::server_info::ServerInfoCache,
+    write_measurements,
+};
+
+/// Measures every `interval`, varied by up to the fraction `jitter`, and
+/// replaces `path` with the exposition, until writing fails.
+pub(crate) async fn run_textfile(
+    config: Arc<Config>,
+    path: &Path,
+    interval: Duration,
+    jitter: f64,
+) -> io::Result<()> {
+    let cache = MeasurementCache::new(None);
+    let dns = Arc::<DnsCache>::default();
+    let server_info = Arc::<ServerInfoCache>::default();
+    let availability = AvailabilityHistory::default();
+    let reply_order = ReplyOrderTotals::default();
+    loop {
+        let start = Instant::now();
+        let exposition = measure(
+            &config,
+            &cache,
+            &availability,
+            &reply_order,
+            dns.clone(),
+            server_info.clone(),
+        )
+        .await;
+        write_atomically(path, &exposition)?;
+        info!(path = %path.display(), "Wrote textfile");
+        // Measurements taking longer than the interval are followed directly
+        let next = start + jittered(interval, jitter, &mut rand::thread_rng());
+        tokio::time::sleep_until(next).await;
+    }
+}
+
+/// `interval` randomly shortened or lengthened by up to `jitter` times itself
+fn jittered(interval: Duration, jitter: f64, rng: &mut impl Rng) -> Duration {
+    if jitter == 0. {
+        return interval;
+    }
+    interval.mul_f64(1. + rng.gen_range(-jitter..=jitter))
+}
+
+async fn measure(
+    config: &Arc<Config>,
+    cache: &MeasurementCache,
+    availability: &AvailabilityHistory,
+    reply_order: &ReplyOrderTotals,
+    dns: Arc<DnsCache>,
+    server_info: Arc<ServerInfoCache>,
+) -> String {
+    let phases = PhaseRecorder::default();
+    let (ping_data, speedtest_data) = tokio::join!(
+        cache.ping(config.clone(), dns.clone(), phases.clone()),
+        cache.speedtest(config.clone(), server_info, phases)
+    );
+    availability.record(&ping_data, config.ping.availability_window);
+    reply_order.record(&ping_data);
+    render_exposition(ExpositionFormat::Prometheus, &config.metrics, |builder| {
+        write_measurements(
+            builder,
+            config,
+            &dns,
+            availability,
+            reply_order,
+            &ping_data,
+            &speedtest_data,
+        );
+    })
+}
+
+/// Writes to a temporary file next to `path` and renames it, so that readers
+/// never see a partially written file. The collector ignores the temporary
+/// file since it only reads files ending in `.prom`.
+fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let mut temp = OsString::from(path.as_os_str());
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let mut file = File::create(&temp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::speedtest::{
+        null::NullSpeedtestProvider, SpeedtestSample, StandardSpeedtestProvider,
+    };
+
+    #[test]
+    fn intervals_vary_within_jitter() {
+        let interval = Duration::from_secs(100);
+        let mut rng = rand::thread_rng();
+        assert_eq!(jittered(interval, 0., &mut rng), interval);
+        let intervals: Vec<_> = (0..100)
+            .map(|_| jittered(interval, 0.1, &mut rng))
+            .collect();
+        let range = Duration::from_secs(90)..=Duration::from_secs(110);
+        assert!(intervals.iter().all(|it| range.contains(it)));
+        assert!(intervals.iter().any(|it| *it != interval));
+    }
+
+    #[tokio::test]
+    async fn exposition_replaces_file() {
+        let dir = std::env::temp_dir().join(format!("textfile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("speedtest.prom");
+        fs::write(&path, "stale").unwrap();
+
+        let mut config = Config::default();
+        config.ping.servers.clear();
+        let sample = SpeedtestSample {
+            bytes: 125_000.,
+            seconds: 1.,
+        };
+        config.speedtest.provider = StandardSpeedtestProvider::Null(NullSpeedtestProvider {
+            download_samples: vec![sample; 2],
+            upload_samples: vec![sample; 2],
+        });
+        let exposition = measure(
+            &Arc::new(config),
+            &MeasurementCache::new(None),
+            &AvailabilityHistory::default(),
+            &ReplyOrderTotals::default(),
+            Arc::default(),
+            Arc::default(),
+        )
+        .await;
+        write_atomically(&path, &exposition).unwrap();
+
+        let written = fs::read_to_string(&path).unwrap();
+        assert!(written.contains("\nnetwork_speed_mean_bps{direction=\"down\"} 1000000\n"));
+        let files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|it| it.unwrap().path())
+            .collect();
+        assert_eq!(files, [path]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
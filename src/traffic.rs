@@ -0,0 +1,168 @@
+//! Bytes of the responses per route and content type, counted while the body
+//! is sent so that streamed bodies are counted as well, and the size of the
+//! latest exposition per endpoint.
+
+use std::{
+    collections::BTreeMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use axum::body::{Body, Bytes};
+use http_body::{Frame, SizeHint};
+
+use crate::prometheus::{ExpositionBuilder, MetricType, PName};
+
+/// Counts the bytes of response bodies. Like
+/// [`InflightRequests`](crate::inflight::InflightRequests), only the routes
+/// given on creation are counted.
+#[derive(Debug, Default)]
+pub(crate) struct ResponseBytes {
+    paths: Vec<&'static str>,
+    counts: Mutex<BTreeMap<(&'static str, String), Arc<AtomicU64>>>,
+}
+
+impl ResponseBytes {
+    pub fn new(paths: impl IntoIterator<Item = &'static str>) -> Self {
+        Self {
+            paths: paths.into_iter().collect(),
+            counts: Mutex::default(),
+        }
+    }
+
+    /// Wraps `body` so that its bytes are counted while it is sent
+    pub fn count(&self, path: &str, content_type: &str, body: Body) -> Body {
+        let Some(path) = self.paths.iter().find(|it| **it == path) else {
+            return body;
+        };
+        let mut counts = self.counts.lock().unwrap();
+        let counter = counts
+            .entry((path, content_type.to_owned()))
+            .or_default()
+            .clone();
+        Body::new(CountingBody {
+            inner: body,
+            counter,
+        })
+    }
+
+    pub fn write_prometheus(&self, builder: &mut ExpositionBuilder) {
+        let counts = self.counts.lock().unwrap();
+        if counts.is_empty() {
+            return;
+        }
+        builder.add_metric(
+            PName::new("exporter_response_bytes_total").unwrap(),
+            MetricType::Counter,
+            "number of bytes of response bodies sent",
+            |mut builder| {
+                for ((path, content_type), count) in counts.iter() {
+                    builder.with_label(PName::new("path").unwrap(), *path, |builder| {
+                        builder.add_line_labeled(
+                            PName::new("content_type").unwrap(),
+                            content_type.as_str(),
+                            &count.load(Ordering::Relaxed),
+                            None,
+                        );
+                    });
+                }
+            },
+        );
+    }
+}
+
+/// Size of the latest exposition of every endpoint, including those that
+/// exceeded `server.limits.max_exposition_bytes`
+#[derive(Debug, Default)]
+pub(crate) struct ExpositionSizes(Mutex<BTreeMap<&'static str, usize>>);
+
+impl ExpositionSizes {
+    pub fn record(&self, endpoint: &'static str, bytes: usize) {
+        self.0.lock().unwrap().insert(endpoint, bytes);
+    }
+
+    pub fn write_prometheus(&self, builder: &mut ExpositionBuilder) {
+        let sizes = self.0.lock().unwrap();
+        if sizes.is_empty() {
+            return;
+        }
+        builder.add_metric_with_unit(
+            PName::new("exporter_exposition_bytes").unwrap(),
+            MetricType::Gauge,
+            Some(PName::UNIT_BYTES),
+            "size of the latest rendered exposition in bytes",
+            |mut builder| {
+                for (endpoint, bytes) in sizes.iter() {
+                    builder.add_line_labeled(
+                        PName::new("endpoint").unwrap(),
+                        *endpoint,
+                        bytes,
+                        None,
+                    );
+                }
+            },
+        );
+    }
+}
+
+struct CountingBody {
+    inner: Body,
+    counter: Arc<AtomicU64>,
+}
+
+impl http_body::Body for CountingBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let polled = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &polled {
+            if let Some(data) = frame.data_ref() {
+                self.counter.fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prometheus::ExpositionArena;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn sent_bytes_are_counted() {
+        let bytes = ResponseBytes::new(["/ping"]);
+        let body = bytes.count("/ping", "text/plain", Body::from("hello"));
+        assert_eq!(http_body::Body::size_hint(&body).exact(), Some(5));
+        axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        let unknown = bytes.count("/unknown", "text/plain", Body::from("hello"));
+        axum::body::to_bytes(unknown, usize::MAX).await.unwrap();
+
+        let alloc = ExpositionArena::new();
+        let mut builder = ExpositionBuilder::new(&alloc);
+        bytes.write_prometheus(&mut builder);
+        assert_eq!(builder.validate(), Ok(()));
+        let exposition = builder.to_string();
+        assert!(exposition.contains(
+            "\nexporter_response_bytes_total{path=\"/ping\", content_type=\"text/plain\"} 5\n"
+        ));
+        assert!(!exposition.contains("/unknown"));
+    }
+}
@@ -0,0 +1,36 @@
+use std::{
+    io::Read,
+    process::{Command, Stdio},
+};
+
+const BINARY: &str = env!("CARGO_BIN_EXE_prometheus-speedtest");
+
+#[test]
+fn default_config_is_printed() {
+    let output = Command::new(BINARY)
+        .arg("print-default-config")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let config = String::from_utf8(output.stdout).unwrap();
+    assert!(config.contains("[server]"), "{config}");
+}
+
+/// Like `prometheus-speedtest print-default-config | head -1`
+#[test]
+#[cfg(unix)]
+fn closed_stdout_exits_cleanly() {
+    let mut child = Command::new(BINARY)
+        .arg("print-default-config")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Closed before the output is written
+    drop(child.stdout.take());
+    let status = child.wait().unwrap();
+    let mut stderr = String::new();
+    child.stderr.unwrap().read_to_string(&mut stderr).unwrap();
+    assert!(status.success(), "{status}: {stderr}");
+    assert!(stderr.is_empty(), "{stderr}");
+}
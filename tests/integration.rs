@@ -0,0 +1,157 @@
+//! Serves the router of the exporter on an OS-assigned port and requests its
+//! endpoints over TCP. Pings and the speedtest are answered by the `Null`
+//! providers and domain targets are resolved by a mock lookup, so no network
+//! access, ICMP sockets or DNS are needed.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use hickory_resolver::error::ResolveError;
+use prometheus_speedtest::{
+    config::Config,
+    create_router_with_dns,
+    ping::{
+        dns_cache::{AddressLookup, DnsCache, Records},
+        AddressFamily,
+    },
+};
+use reqwest::{header, StatusCode};
+
+/// Resolves every domain to the loopback address and counts the lookups
+#[derive(Default)]
+struct MockLookup(AtomicUsize);
+
+#[async_trait]
+impl AddressLookup for MockLookup {
+    async fn lookup(&self, _domain: &str, family: AddressFamily) -> Result<Records, ResolveError> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        let addrs = match family {
+            AddressFamily::Ipv4 => vec![IpAddr::from(Ipv4Addr::LOCALHOST)],
+            AddressFamily::Ipv6 => Vec::new(),
+        };
+        Ok(Records {
+            addrs,
+            valid_until: Instant::now() + Duration::from_secs(60),
+        })
+    }
+}
+
+/// Running exporter with its mock lookup
+struct Exporter {
+    addr: SocketAddr,
+    lookup: Arc<MockLookup>,
+}
+
+impl Exporter {
+    async fn start() -> Self {
+        let config: Config = toml::from_str(
+            r#"
+[ping]
+servers = ["127.0.0.1", "exporter.test"]
+samples = 4
+delay = "10ms"
+quantiles = [0.0, 1.0]
+
+[ping.provider.Null]
+rtt_ms = [10.0, 20.0, 30.0, nan]
+
+[speedtest.provider.Null]
+download_samples = [{ bytes = 1000000.0, seconds = 1.0 }]
+upload_samples = [{ bytes = 250000.0, seconds = 0.5 }]
+"#,
+        )
+        .unwrap();
+        let lookup = Arc::new(MockLookup::default());
+        let dns = Arc::new(DnsCache::with_lookup(lookup.clone()));
+        let app = create_router_with_dns(Arc::new(config), dns)
+            .unwrap()
+            .into_make_service_with_connect_info::<SocketAddr>();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Self { addr, lookup }
+    }
+
+    async fn get(&self, path: &str, accept: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("http://{}{path}", self.addr))
+            .header(header::ACCEPT, accept)
+            .send()
+            .await
+            .unwrap()
+    }
+}
+
+fn content_type(response: &reqwest::Response) -> &str {
+    response.headers()[header::CONTENT_TYPE].to_str().unwrap()
+}
+
+#[tokio::test]
+async fn serves_all_endpoints() {
+    let exporter = Exporter::start().await;
+
+    let response = exporter.get("/", "text/html").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(content_type(&response).starts_with("text/html"));
+    let response = exporter.get("/", "text/plain").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(content_type(&response).starts_with("text/plain"));
+
+    let response = exporter.get("/ping", "text/plain; version=0.0.4").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(content_type(&response).starts_with("text/plain; version=0.0.4"));
+    let exposition = response.text().await.unwrap();
+    assert!(exposition.contains("\n# TYPE ping_configured_samples gauge\n"));
+    assert!(
+        exposition.contains("target=\"exporter.test\""),
+        "{exposition}"
+    );
+    for line in [
+        "packet_loss{target=\"exporter.test\"} +0x1.p-2",
+        "ping_mean_ms{target=\"exporter.test\"} +0x1.4p4",
+        "ping_ms_sum{target=\"127.0.0.1\"} +0x1.ep5",
+        "ping_ms_count{target=\"127.0.0.1\"} 3",
+        "ping_errors{target=\"127.0.0.1\", error=\"timeout\"} 1",
+    ] {
+        assert!(exposition.contains(&format!("\n{line}\n")), "{line}");
+    }
+    assert!(exporter.lookup.0.load(Ordering::Relaxed) > 0);
+    for line in exposition.lines().filter(|line| !line.starts_with('#')) {
+        // Values are Go floats, which may be written in hexadecimal
+        let (series, value) = line.rsplit_once(' ').unwrap();
+        assert!(
+            series.starts_with(|c: char| c.is_ascii_alphabetic()),
+            "{line}"
+        );
+        let value = value.trim_start_matches(['+', '-']);
+        assert!(
+            value.starts_with(|c: char| c.is_ascii_digit()) || value == "Inf" || value == "NaN",
+            "{line}"
+        );
+    }
+
+    let response = exporter.get("/ping", "application/json").await;
+    let json: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    let resolved = (json.as_array().unwrap().iter())
+        .find(|result| result["target"] == "exporter.test")
+        .unwrap();
+    // Only the address of the mock lookup has a family
+    assert_eq!(resolved["address_family"], "ipv4", "{resolved}");
+
+    let response = exporter.get("/speedtest", "application/json").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(content_type(&response), "application/json");
+    let json: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(json["down"]["mean"], 8_000_000, "{json}");
+    assert_eq!(json["up"]["mean"], 4_000_000, "{json}");
+
+    let response = exporter.get("/unknown", "*/*").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}